@@ -0,0 +1,37 @@
+//! The overhead per call with and without the fast path, run with
+//! `cargo bench --bench fast_path`.
+
+use std::{
+    convert::Infallible,
+    time::{Duration, Instant},
+};
+
+use little_loadshedder::LoadShedLayer;
+use tower::{Layer, ServiceExt};
+
+const TARGET: Duration = Duration::from_millis(100);
+const CALLS: u32 = 200_000;
+
+fn main() {
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .unwrap();
+    runtime.block_on(async {
+        for fast_path in [false, true] {
+            let service = LoadShedLayer::new(0.1, TARGET)
+                .fast_path(fast_path)
+                .layer(tower::service_fn(|()| async { Ok::<_, Infallible>(()) }));
+            // Warm up, so the first calls don't count allocating the stats.
+            for _ in 0..CALLS / 10 {
+                service.clone().oneshot(()).await.unwrap();
+            }
+            let start = Instant::now();
+            for _ in 0..CALLS {
+                service.clone().oneshot(()).await.unwrap();
+            }
+            let per_call = start.elapsed() / CALLS;
+            println!("fast_path={fast_path}: {per_call:?} per call");
+        }
+    });
+}
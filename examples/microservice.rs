@@ -41,9 +41,13 @@ async fn main() {
     let service = ServiceBuilder::new()
         .layer(MapResponseLayer::new(|resp| match resp {
             LoadShedResponse::Inner(inner) => inner,
-            LoadShedResponse::Overload => {
-                Response::builder().status(503).body(String::new()).unwrap()
+            LoadShedResponse::Panicked => {
+                Response::builder().status(500).body(String::new()).unwrap()
             }
+            LoadShedResponse::RateLimited => {
+                Response::builder().status(429).body(String::new()).unwrap()
+            }
+            _ => Response::builder().status(503).body(String::new()).unwrap(),
         }))
         .layer(LoadShedLayer::new(0.01, Duration::from_millis(2000)))
         .service(LinearService::new(multiplier_rx));
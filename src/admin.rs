@@ -0,0 +1,102 @@
+//! A service for controlling a load shedder remotely, such as from an admin
+//! endpoint.
+
+use std::{
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use tower::Service;
+
+use crate::{BoxFuture, ConfigError, LoadShed, LoadShedStats};
+
+/// A command for an [`AdminService`].
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[non_exhaustive]
+pub enum AdminCommand {
+    /// Change the target latency, see [`LoadShed::set_target`].
+    SetTarget(Duration),
+    /// Change the moving average parameter, see [`LoadShed::set_ewma_param`].
+    SetEwmaParam(f64),
+    /// Shed every request, or stop doing so, see [`LoadShed::force_shed`].
+    ForceShed(bool),
+    /// Shed every request and wait for the ones in flight to complete, see
+    /// [`LoadShed::drain`].
+    Drain,
+    /// Forget what's been learned about the inner service, see
+    /// [`LoadShed::reset`].
+    Reset,
+    /// Take a snapshot of the statistics, see [`LoadShed::stats`].
+    Stats,
+}
+
+/// The response to an [`AdminCommand`].
+#[derive(Debug, Clone, PartialEq)]
+#[non_exhaustive]
+pub enum AdminResponse {
+    /// The command has been carried out.
+    Done,
+    /// The statistics asked for by [`AdminCommand::Stats`], boxed as they're
+    /// far bigger than the other responses.
+    Stats(Box<LoadShedStats>),
+}
+
+/// A [`Service`] that carries out [`AdminCommand`]s on a load shedder, see
+/// [`LoadShed::admin`].
+///
+/// This gathers the runtime controls into one place, so they can be driven
+/// by a single handler on an admin endpoint. Commands with invalid values
+/// fail with a [`ConfigError`] and change nothing.
+#[derive(Debug, Clone)]
+pub struct AdminService<Inner, C, I> {
+    load_shed: LoadShed<Inner, C, I>,
+}
+
+impl<Inner: Clone, C: Clone, I: Clone> LoadShed<Inner, C, I> {
+    /// A service that controls this load shedder and all its clones, see
+    /// [`AdminService`].
+    pub fn admin(&self) -> AdminService<Inner, C, I> {
+        AdminService {
+            load_shed: self.clone(),
+        }
+    }
+}
+
+impl<Inner, C, I> Service<AdminCommand> for AdminService<Inner, C, I> {
+    type Response = AdminResponse;
+    type Error = ConfigError;
+    type Future = BoxFuture<Result<AdminResponse, ConfigError>>;
+
+    /// Always ready, the commands are carried out straight away.
+    fn poll_ready(&mut self, _: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, command: AdminCommand) -> Self::Future {
+        let load_shed = &self.load_shed;
+        let done = match command {
+            AdminCommand::SetTarget(target) => load_shed.set_target(target),
+            AdminCommand::SetEwmaParam(ewma_param) => load_shed.set_ewma_param(ewma_param),
+            AdminCommand::ForceShed(shed) => {
+                load_shed.force_shed(shed);
+                Ok(())
+            }
+            AdminCommand::Drain => {
+                let conf = load_shed.conf.clone();
+                return Box::pin(async move {
+                    conf.drain().await;
+                    Ok(AdminResponse::Done)
+                });
+            }
+            AdminCommand::Reset => {
+                load_shed.reset();
+                Ok(())
+            }
+            AdminCommand::Stats => {
+                let stats = load_shed.stats();
+                return Box::pin(async move { Ok(AdminResponse::Stats(Box::new(stats))) });
+            }
+        };
+        Box::pin(async move { done.map(|()| AdminResponse::Done) })
+    }
+}
@@ -0,0 +1,142 @@
+//! Holding on to requests until their response body has been sent.
+
+use std::{
+    pin::Pin,
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use http::Response;
+use http_body::{Body, Frame, SizeHint};
+use pin_project_lite::pin_project;
+use tower::{Layer, Service};
+
+use crate::{
+    conf::Completion, BoxFuture, Classify, DefaultClassifier, DefaultRequestInfo, LoadShed,
+    LoadShedLayer, LoadShedResponse, RequestInfo,
+};
+
+/// A [`Layer`] that wraps services in a [`LoadShed`] middleware which counts
+/// requests as in flight until their response body has been read to the end or
+/// dropped.
+///
+/// A plain [`LoadShed`] service releases a request's concurrency permit as soon
+/// as the inner service returns the response, for streaming responses most of
+/// the work can happen after that. With this the permit is held by the
+/// [`LoadShedBody`] instead, so the measured latency is to the last byte of the
+/// response rather than the first.
+#[derive(Debug, Clone)]
+pub struct BodyLoadShedLayer<C = DefaultClassifier, I = DefaultRequestInfo> {
+    layer: LoadShedLayer<C, I>,
+}
+
+impl BodyLoadShedLayer {
+    /// Create a new layer with the given target average latency and
+    /// computing the current average latency using an exponentially weighted
+    /// moving average with the given parameter.
+    pub fn new(ewma_param: f64, target: Duration) -> Self {
+        LoadShedLayer::new(ewma_param, target).into()
+    }
+}
+
+impl<C, I> From<LoadShedLayer<C, I>> for BodyLoadShedLayer<C, I> {
+    fn from(layer: LoadShedLayer<C, I>) -> Self {
+        Self { layer }
+    }
+}
+
+impl<Inner, C: Clone, I: Clone> Layer<Inner> for BodyLoadShedLayer<C, I> {
+    type Service = BodyLoadShed<Inner, C, I>;
+
+    fn layer(&self, inner: Inner) -> Self::Service {
+        BodyLoadShed {
+            inner: self.layer.layer(inner),
+        }
+    }
+}
+
+/// A [`LoadShed`] service that counts requests as in flight until their
+/// response body has been read to the end or dropped, see
+/// [`BodyLoadShedLayer`].
+#[derive(Debug, Clone)]
+pub struct BodyLoadShed<Inner, C = DefaultClassifier, I = DefaultRequestInfo> {
+    inner: LoadShed<Inner, C, I>,
+}
+
+impl<Inner, C, I> BodyLoadShed<Inner, C, I> {
+    /// The load shedder that bodies are counted against, whose statistics
+    /// include the time spent streaming each response body.
+    pub fn load_shed(&self) -> &LoadShed<Inner, C, I> {
+        &self.inner
+    }
+}
+
+impl<Request, Inner, B, C, I> Service<Request> for BodyLoadShed<Inner, C, I>
+where
+    Request: Send + 'static,
+    Inner: Service<Request, Response = Response<B>> + Clone + Send + 'static,
+    Inner::Future: Send,
+    C: Classify<Inner::Response> + Clone + Send + 'static,
+    I: RequestInfo<Request>,
+{
+    type Response = LoadShedResponse<Response<LoadShedBody<B>>>;
+    type Error = Inner::Error;
+    type Future = BoxFuture<Result<Self::Response, Self::Error>>;
+
+    /// Always ready because there's a queue between this service and the inner one.
+    fn poll_ready(&mut self, _: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, req: Request) -> Self::Future {
+        self.inner.shed(
+            req,
+            |response, completion| {
+                LoadShedResponse::Inner(response.map(|body| LoadShedBody {
+                    inner: body,
+                    completion: Some(completion),
+                }))
+            },
+            |shed, _| shed.response(),
+            |_| LoadShedResponse::Panicked,
+        )
+    }
+}
+
+pin_project! {
+    /// A response body that keeps its request counted as in flight until it's
+    /// been read to the end or dropped, see [`BodyLoadShedLayer`].
+    #[derive(Debug)]
+    pub struct LoadShedBody<B> {
+        #[pin]
+        inner: B,
+        completion: Option<Completion>,
+    }
+}
+
+impl<B: Body> Body for LoadShedBody<B> {
+    type Data = B::Data;
+    type Error = B::Error;
+
+    fn poll_frame(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        let mut this = self.project();
+        let frame = this.inner.as_mut().poll_frame(cx);
+        // Release the request as soon as the last frame has been read, rather
+        // than waiting for the body to be dropped.
+        if matches!(frame, Poll::Ready(None)) || this.inner.is_end_stream() {
+            this.completion.take();
+        }
+        frame
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
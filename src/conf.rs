@@ -0,0 +1,3532 @@
+//! The state of the load shedder and the algorithm that controls it.
+
+use std::{
+    cmp::Ordering,
+    collections::VecDeque,
+    fmt,
+    sync::{
+        atomic::{self, AtomicBool, AtomicU64, AtomicUsize},
+        Arc, Mutex, Once,
+    },
+    time::Duration,
+};
+
+#[cfg(feature = "metrics")]
+use metrics::{decrement_gauge, gauge, histogram, increment_counter, increment_gauge, Label};
+use tokio::{
+    sync::{watch, Notify, OwnedSemaphorePermit, Semaphore, TryAcquireError},
+    time::{Instant, MissedTickBehavior},
+};
+
+#[cfg(feature = "decision-log")]
+use crate::decisions::{Decision, DecisionLog};
+use crate::{
+    event::{EventHandler, LatencyAttribution, LoadShedEvent},
+    histogram::LatencyHistogram,
+    persist::{PersistedState, Persistence},
+    policy::{AdmissionContext, AdmissionDecision, AdmissionPolicy},
+    queue::{hand_off, QueueFactory, SharedQueue},
+    reset::ResetSignal,
+    schedule::TargetSchedule,
+    sequence::{Sequencer, Ticket},
+    EstimatedWait, Outcome, ShedReason, Waiter,
+};
+
+/// The configuration of a load shedder, shared by the layer and the service.
+///
+/// This can be built up field by field and then checked with
+/// [`validate`](Self::validate), or passed to
+/// [`LoadShed::from_config`](crate::LoadShed::from_config) or
+/// [`LoadShedLayer::from_config`](crate::LoadShedLayer::from_config) which
+/// validate it for you. The options are documented on the matching
+/// [`LoadShedLayer`](crate::LoadShedLayer) methods.
+#[derive(Debug, Clone)]
+#[non_exhaustive]
+pub struct LoadShedConfig {
+    /// The exponentially weighted moving average parameter, in the range
+    /// (0, 1).
+    pub ewma_param: f64,
+    /// The target average latency.
+    pub target: Duration,
+    /// The percentile of the latency held at the target, rather than the
+    /// average, if one is chosen.
+    pub target_percentile: Option<f64>,
+    /// The maximum number of bytes allowed in flight at once, if limited.
+    pub byte_budget: Option<u64>,
+    /// The number of units of the byte budget each unit of cost is worth, if
+    /// requests have fractional costs.
+    pub cost_resolution: Option<u32>,
+    /// The most a single request's latency can be over the target, as a
+    /// fraction of the target, when updating the averages, if it's limited.
+    pub max_target_error: Option<f64>,
+    /// The maximum rate of requests admitted per second, if limited.
+    pub rate_limit: Option<f64>,
+    /// The number of requests that can be admitted in a burst above the rate
+    /// limit.
+    pub rate_burst: f64,
+    /// Whether failed requests should be left out of the average latency at
+    /// capacity.
+    pub exclude_failures_at_capacity: bool,
+    /// How often the background task adjusts the concurrency, if the
+    /// adjustments are made in the background.
+    pub control_interval: Option<Duration>,
+    /// Creates the queue of waiting requests, if not using the default queue.
+    pub(crate) queue: Option<QueueFactory>,
+    /// Requests older than this when they arrive are shed.
+    pub max_age: Option<Duration>,
+    /// Whether latencies are measured from when requests originally arrived,
+    /// where that's known, rather than from when they were admitted.
+    pub latency_from_arrival: bool,
+    /// Whether retried requests are left out of the arrival rate.
+    pub exclude_retries: bool,
+    /// Whether requests must enter the inner service in the order they
+    /// arrived.
+    pub strict_fifo: bool,
+    /// Whether to reduce the moving average parameter when the latency is
+    /// volatile.
+    pub adaptive_ewma: bool,
+    /// The time constant the averages decay over, if they're weighted by the
+    /// time between requests rather than by the moving average parameter.
+    pub ewma_time_constant: Option<Duration>,
+    /// How far back the latency samples the concurrency is controlled on go,
+    /// if it's controlled on a window of samples rather than on the moving
+    /// average.
+    pub latency_window: Option<Duration>,
+    /// The longest wait reported by the estimated wait, if it's capped.
+    pub max_estimated_wait: Option<Duration>,
+    /// The fraction an increase in concurrency must raise the throughput by
+    /// for increases to carry on, if increases can be halted.
+    pub throughput_halt_margin: Option<f64>,
+    /// The smallest the queue capacity is allowed to shrink to.
+    pub min_queue: usize,
+    /// The largest the queue capacity is allowed to grow to.
+    pub max_queue: usize,
+    /// The lowest the concurrency is allowed to fall to, this is also the
+    /// starting concurrency.
+    pub min_concurrency: usize,
+    /// The highest the concurrency is allowed to rise to.
+    pub max_concurrency: usize,
+    /// The fraction of the requests completed between adjustments that can
+    /// fail before the concurrency is decreased.
+    pub max_failure_rate: f64,
+    /// Failed requests are counted as taking at least this long, if set.
+    pub failure_latency_penalty: Option<Duration>,
+    /// Supplies new values of the runtime tunable options, if they can be
+    /// changed while running.
+    pub(crate) runtime: Option<watch::Receiver<LoadShedRuntimeConfig>>,
+    /// The largest ratio of total latency to service latency to allow, by
+    /// shrinking the queue, if it's limited.
+    pub max_tail_amplification: Option<f64>,
+    /// The semaphore limiting concurrency, if it's shared with something else.
+    pub(crate) concurrency_semaphore: Option<Arc<Semaphore>>,
+    /// Decides whether to admit each request first, if there's a policy.
+    pub(crate) admission_policy: Option<Arc<dyn AdmissionPolicy>>,
+    /// A process wide limit on concurrency that every request must also take
+    /// a permit from, if there is one.
+    pub(crate) global_concurrency: Option<Arc<Semaphore>>,
+    /// Whether requests are admitted to the queue when the service is called,
+    /// rather than when the response future is first polled.
+    pub eager_admission: bool,
+    /// How often to probe for a better maximum concurrency, if the maximum is
+    /// tuned automatically.
+    pub probe_interval: Option<Duration>,
+    /// Whether requests skip the queue when there's a concurrency permit free.
+    pub fast_path: bool,
+    /// What to do with admitted requests when the inner service isn't ready.
+    pub readiness: ReadinessPolicy,
+    /// When a queued request gives up its place in the queue.
+    pub queue_slot: QueueSlotPolicy,
+    /// Whether requests skip the queue, and are shed if they can't, while the
+    /// service is overloaded at the minimum concurrency.
+    pub fail_fast_at_min_concurrency: bool,
+    /// Whether panics in the inner service are caught and turned into
+    /// responses.
+    pub catch_panics: bool,
+    /// How often to let probe requests past a full queue, if at all.
+    pub recovery_probe_interval: Option<Duration>,
+    /// How many probe requests to let past a full queue each interval.
+    pub recovery_probes: usize,
+    /// The interval the queue's growth is limited over, if it's limited.
+    pub queue_growth_interval: Option<Duration>,
+    /// How much the queue capacity can grow by each interval.
+    pub max_queue_growth: usize,
+    /// The fraction of the target the average latency must stay below for the
+    /// queue to be shrunk to its minimum, if it's shrunk at all.
+    pub fast_response_fraction: Option<f64>,
+    /// The fraction of the queue capacity that, once the queue has grown past
+    /// it, decreases the concurrency as if the latency were over the target.
+    pub queue_backoff_fraction: Option<f64>,
+    /// The average latency below which the concurrency isn't increased, if
+    /// there is one.
+    pub latency_floor: Option<Duration>,
+    /// The largest step the concurrency is increased by, if the steps are
+    /// proportional to how far the latency is below the target.
+    pub max_increase_step: Option<usize>,
+    /// The moving average parameter of the slow average latency, if the
+    /// latency's trend is used to back off early.
+    pub slow_ewma_param: Option<f64>,
+    /// The fraction the main average latency must be below the slow one for
+    /// the latency to count as recovering, if recovery is favoured.
+    pub recovery_margin: Option<f64>,
+    /// How long the maximum concurrency takes to ramp back up from the
+    /// minimum after a start or reset, if it's ramped.
+    pub warmup: Option<Duration>,
+    /// How long the average latency must stay below the fraction of the
+    /// target before the queue's shrunk.
+    pub fast_response_window: Duration,
+    /// Called with events as they happen, if anything's listening.
+    pub(crate) on_event: Option<EventHandler>,
+    /// How long the service must stay overloaded, or not, before the change
+    /// is reported.
+    pub overload_debounce: Duration,
+    /// Labels added to every metric emitted.
+    pub metric_labels: Vec<(&'static str, String)>,
+    /// The number of decisions to keep a record of, if they're recorded.
+    #[cfg(feature = "decision-log")]
+    pub decision_log: Option<usize>,
+    /// The schedule the target follows, overriding the target, if it has one.
+    pub(crate) target_schedule: Option<TargetSchedule>,
+    /// Triggered when the load shedder should forget what it's learned, if
+    /// anything can trigger that.
+    pub(crate) reset_signal: Option<ResetSignal>,
+    /// Where the learned state is saved and how often, if it's saved.
+    pub(crate) persistence: Option<Persistence>,
+}
+
+impl LoadShedConfig {
+    /// Create a configuration with the given target average latency and
+    /// moving average parameter, and everything else set to the defaults.
+    pub fn new(ewma_param: f64, target: Duration) -> Self {
+        Self {
+            ewma_param,
+            target,
+            target_percentile: None,
+            byte_budget: None,
+            cost_resolution: None,
+            max_target_error: None,
+            rate_limit: None,
+            rate_burst: 1.0,
+            exclude_failures_at_capacity: false,
+            control_interval: None,
+            queue: None,
+            max_age: None,
+            latency_from_arrival: false,
+            exclude_retries: false,
+            strict_fifo: false,
+            adaptive_ewma: false,
+            ewma_time_constant: None,
+            latency_window: None,
+            max_estimated_wait: None,
+            throughput_halt_margin: None,
+            min_queue: 1,
+            max_queue: usize::MAX,
+            min_concurrency: 1,
+            max_concurrency: usize::MAX,
+            max_failure_rate: 0.05,
+            failure_latency_penalty: None,
+            runtime: None,
+            max_tail_amplification: None,
+            concurrency_semaphore: None,
+            global_concurrency: None,
+            admission_policy: None,
+            eager_admission: false,
+            probe_interval: None,
+            fast_path: false,
+            readiness: ReadinessPolicy::Wait,
+            queue_slot: QueueSlotPolicy::Hold,
+            fail_fast_at_min_concurrency: false,
+            catch_panics: false,
+            recovery_probe_interval: None,
+            recovery_probes: 1,
+            queue_growth_interval: None,
+            max_queue_growth: usize::MAX,
+            fast_response_fraction: None,
+            queue_backoff_fraction: None,
+            latency_floor: None,
+            max_increase_step: None,
+            slow_ewma_param: None,
+            recovery_margin: None,
+            warmup: None,
+            fast_response_window: Duration::ZERO,
+            on_event: None,
+            overload_debounce: Duration::ZERO,
+            metric_labels: Vec::new(),
+            #[cfg(feature = "decision-log")]
+            decision_log: None,
+            target_schedule: None,
+            reset_signal: None,
+            persistence: None,
+        }
+    }
+
+    /// Check that the options make sense, individually and together.
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        self.static_runtime_config().validate()?;
+        if let Some(param) = self.slow_ewma_param {
+            if param.is_nan() || param <= 0.0 || param >= 1.0 {
+                return Err(ConfigError::EwmaParam(param));
+            }
+        }
+        if let Some(runtime) = &self.runtime {
+            runtime.borrow().validate()?;
+        }
+        if let Some(schedule) = &self.target_schedule {
+            if schedule.targets().any(|target| target.is_zero()) {
+                return Err(ConfigError::Target);
+            }
+        }
+        if self
+            .control_interval
+            .is_some_and(|interval| interval.is_zero())
+            || self
+                .probe_interval
+                .is_some_and(|interval| interval.is_zero())
+            || self
+                .recovery_probe_interval
+                .is_some_and(|interval| interval.is_zero())
+            || self
+                .queue_growth_interval
+                .is_some_and(|interval| interval.is_zero())
+            || self
+                .ewma_time_constant
+                .is_some_and(|constant| constant.is_zero())
+            || self.latency_window.is_some_and(|window| window.is_zero())
+            || self.warmup.is_some_and(|warmup| warmup.is_zero())
+            || self
+                .persistence
+                .as_ref()
+                .is_some_and(|persistence| persistence.interval.is_zero())
+        {
+            return Err(ConfigError::ControlInterval);
+        }
+        if self
+            .throughput_halt_margin
+            .is_some_and(|margin| margin.is_nan() || margin < 0.0)
+        {
+            return Err(ConfigError::ThroughputHaltMargin);
+        }
+        if self
+            .max_tail_amplification
+            .is_some_and(|max| max.is_nan() || max < 1.0)
+        {
+            return Err(ConfigError::TailAmplification);
+        }
+        if self
+            .max_target_error
+            .is_some_and(|max| max.is_nan() || max <= 0.0)
+        {
+            return Err(ConfigError::TargetError);
+        }
+        if let Some(rate) = self.rate_limit {
+            if !(rate.is_finite() && rate > 0.0 && self.rate_burst >= 1.0) {
+                return Err(ConfigError::RateLimit);
+            }
+        }
+        if let Some(percentile) = self.target_percentile {
+            if percentile.is_nan() || percentile <= 0.0 || percentile >= 1.0 {
+                return Err(ConfigError::Percentile(percentile));
+            }
+        }
+        if let Some(fraction) = self.fast_response_fraction {
+            if fraction.is_nan() || fraction <= 0.0 || fraction >= 1.0 {
+                return Err(ConfigError::FastResponseFraction(fraction));
+            }
+        }
+        if let Some(fraction) = self.queue_backoff_fraction {
+            if fraction.is_nan() || fraction <= 0.0 || fraction > 1.0 {
+                return Err(ConfigError::QueueBackoffFraction(fraction));
+            }
+        }
+        if !(0.0..1.0).contains(&self.max_failure_rate) {
+            return Err(ConfigError::FailureRate(self.max_failure_rate));
+        }
+        if let Some(margin) = self.recovery_margin {
+            if margin.is_nan() || margin <= 0.0 || margin >= 1.0 {
+                return Err(ConfigError::RecoveryMargin(margin));
+            }
+        }
+        Ok(())
+    }
+
+    /// Preset the tuning options for the given goal, see
+    /// [`OptimizationGoal`].
+    ///
+    /// Only the options that haven't been set yet are filled in, so an option
+    /// set explicitly, before or after this, always wins.
+    pub fn optimize_for(&mut self, goal: OptimizationGoal) {
+        // The hysteresis band that halts increases, the queue's share of the
+        // latency, how eagerly the concurrency is increased and, through the
+        // trend, how early it's decreased.
+        let (throughput_halt_margin, max_tail_amplification, max_increase_step, trend) = match goal
+        {
+            OptimizationGoal::MinimizeLatency => (Some(0.05), Some(2.0), None, Some(10.0)),
+            OptimizationGoal::Balanced => (Some(0.01), Some(4.0), Some(4), None),
+            OptimizationGoal::MaximizeThroughput => (None, None, Some(16), None),
+        };
+        self.throughput_halt_margin = self.throughput_halt_margin.or(throughput_halt_margin);
+        self.max_tail_amplification = self.max_tail_amplification.or(max_tail_amplification);
+        self.max_increase_step = self.max_increase_step.or(max_increase_step);
+        // The trend is followed over this many times as many samples as the
+        // main average.
+        self.slow_ewma_param = self
+            .slow_ewma_param
+            .or(trend.map(|samples: f64| self.ewma_param / samples));
+    }
+
+    /// The runtime tunable options as they're set in this configuration.
+    fn static_runtime_config(&self) -> LoadShedRuntimeConfig {
+        LoadShedRuntimeConfig {
+            target: self.target,
+            ewma_param: self.ewma_param,
+            min_queue: self.min_queue,
+            max_queue: self.max_queue,
+            min_concurrency: self.min_concurrency,
+            max_concurrency: self.max_concurrency,
+        }
+    }
+
+    /// The runtime tunable options the load shedder should start with.
+    pub(crate) fn runtime_config(&self) -> LoadShedRuntimeConfig {
+        let mut runtime = match &self.runtime {
+            Some(runtime) => *runtime.borrow(),
+            None => self.static_runtime_config(),
+        };
+        if let Some(schedule) = &self.target_schedule {
+            runtime.target = schedule.current_target();
+        }
+        runtime
+    }
+}
+
+/// What the load shedder should prioritise when it has a choice, a single knob
+/// over the tuning options, see [`LoadShedConfig::optimize_for`].
+///
+/// The latency is always kept to the target, these decide how much it's worth
+/// paying in latency for extra throughput below the target.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum OptimizationGoal {
+    /// Stop increasing the concurrency as soon as it buys less than 5% more
+    /// throughput, keep the time spent queued below the time spent in the
+    /// inner service, increase the concurrency one step at a time and back off
+    /// as soon as the latency's trend is rising towards the target, see
+    /// [`LoadShedLayer::trend_backoff`](crate::LoadShedLayer::trend_backoff).
+    MinimizeLatency,
+    /// Stop increasing the concurrency once it buys less than 1% more
+    /// throughput, keep the time spent queued below three times the time
+    /// spent in the inner service, and increase the concurrency by up to 4 at
+    /// a time, see
+    /// [`LoadShedLayer::proportional_increase`](crate::LoadShedLayer::proportional_increase).
+    Balanced,
+    /// Increase the concurrency and queue as far as the target latency allows,
+    /// by up to 16 at a time, backing off only once the latency reaches the
+    /// target.
+    MaximizeThroughput,
+}
+
+/// What to do with an admitted request when the inner service isn't ready for
+/// it, see [`LoadShedLayer::readiness_policy`](crate::LoadShedLayer::readiness_policy).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum ReadinessPolicy {
+    /// Hold the request's concurrency permit while waiting for the inner
+    /// service, counting the wait as part of its latency, this is the default.
+    ///
+    /// This suits services that aren't ready because they're overloaded, as
+    /// the slower latency reduces the concurrency.
+    #[default]
+    Wait,
+    /// Release the request's concurrency permit while waiting for the inner
+    /// service, then send it through the queue again.
+    ///
+    /// This suits services that are occasionally not ready for reasons other
+    /// than load, so other requests aren't held up. Requeued requests skip
+    /// [`strict_fifo`](crate::LoadShedLayer::strict_fifo) ordering.
+    Requeue,
+    /// Shed the request with [`LoadShedResponse::Overload`](crate::LoadShedResponse::Overload).
+    ///
+    /// This suits services that are rarely not ready, where it's a sign
+    /// they're in trouble.
+    Shed,
+}
+
+/// When a queued request gives up its place in the queue, see
+/// [`LoadShedLayer::queue_slot_policy`](crate::LoadShedLayer::queue_slot_policy).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum QueueSlotPolicy {
+    /// Keep the place in the queue until the request has a concurrency permit,
+    /// this is the default.
+    ///
+    /// Every admitted request holds either a place in the queue or a
+    /// concurrency permit, so no more than the queue capacity can be waiting.
+    #[default]
+    Hold,
+    /// Give up the place in the queue once the request has joined it, before
+    /// waiting for a concurrency permit.
+    ///
+    /// More requests can then wait than the queue capacity allows, which keeps
+    /// the inner service busier through bursts at the cost of longer waits.
+    ReleaseEarly,
+}
+
+/// The options of a load shedder that can be changed while it's running, see
+/// [`LoadShedLayer::runtime_config`](crate::LoadShedLayer::runtime_config).
+///
+/// These are the same as the matching [`LoadShedConfig`] options.
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[non_exhaustive]
+pub struct LoadShedRuntimeConfig {
+    /// The target average latency.
+    pub target: Duration,
+    /// The exponentially weighted moving average parameter, in the range
+    /// (0, 1).
+    pub ewma_param: f64,
+    /// The smallest the queue capacity is allowed to shrink to.
+    pub min_queue: usize,
+    /// The largest the queue capacity is allowed to grow to.
+    pub max_queue: usize,
+    /// The lowest the concurrency is allowed to fall to.
+    pub min_concurrency: usize,
+    /// The highest the concurrency is allowed to rise to.
+    pub max_concurrency: usize,
+}
+
+impl LoadShedRuntimeConfig {
+    /// Create a runtime configuration with the given target average latency
+    /// and moving average parameter, and no limits on the queue capacity or
+    /// concurrency.
+    pub fn new(ewma_param: f64, target: Duration) -> Self {
+        LoadShedConfig::new(ewma_param, target).static_runtime_config()
+    }
+
+    /// Check that the options make sense, individually and together.
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        if self.ewma_param.is_nan() || self.ewma_param <= 0.0 || self.ewma_param >= 1.0 {
+            return Err(ConfigError::EwmaParam(self.ewma_param));
+        }
+        if self.target.is_zero() {
+            return Err(ConfigError::Target);
+        }
+        Limits {
+            min_concurrency: self.min_concurrency,
+            max_concurrency: self.max_concurrency,
+            min_queue: self.min_queue,
+            max_queue: self.max_queue,
+        }
+        .validate()
+    }
+}
+
+/// The limits on a load shedder's concurrency and queue capacity, to change
+/// them together while it's running, see
+/// [`LoadShed::reconfigure`](crate::LoadShed::reconfigure).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Limits {
+    /// The lowest the concurrency is allowed to fall to.
+    pub min_concurrency: usize,
+    /// The highest the concurrency is allowed to rise to.
+    pub max_concurrency: usize,
+    /// The smallest the queue capacity is allowed to shrink to.
+    pub min_queue: usize,
+    /// The largest the queue capacity is allowed to grow to.
+    pub max_queue: usize,
+}
+
+impl Limits {
+    /// Check that the limits are at least 1 and in order.
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        if self.min_concurrency == 0 || self.min_concurrency > self.max_concurrency {
+            return Err(ConfigError::Concurrency {
+                min: self.min_concurrency,
+                max: self.max_concurrency,
+            });
+        }
+        if self.min_queue == 0 || self.min_queue > self.max_queue {
+            return Err(ConfigError::Queue {
+                min: self.min_queue,
+                max: self.max_queue,
+            });
+        }
+        Ok(())
+    }
+
+    /// Apply these limits to the runtime options.
+    fn apply(&self, runtime: &mut LoadShedRuntimeConfig) {
+        runtime.min_concurrency = self.min_concurrency;
+        runtime.max_concurrency = self.max_concurrency;
+        runtime.min_queue = self.min_queue;
+        runtime.max_queue = self.max_queue;
+    }
+}
+
+/// An invalid [`LoadShedConfig`] or [`LoadShedRuntimeConfig`].
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[non_exhaustive]
+pub enum ConfigError {
+    /// The moving average parameter isn't in the range (0, 1).
+    EwmaParam(f64),
+    /// The target latency is zero.
+    Target,
+    /// One of the intervals, such as the control interval or the moving
+    /// average's time constant, is zero.
+    ControlInterval,
+    /// The throughput halt margin is negative or NaN.
+    ThroughputHaltMargin,
+    /// The maximum tail amplification is less than 1 or NaN.
+    TailAmplification,
+    /// The fraction of the target counted as a fast response isn't in the
+    /// range (0, 1).
+    FastResponseFraction(f64),
+    /// The fraction of the queue that triggers a backoff isn't in the range
+    /// (0, 1].
+    QueueBackoffFraction(f64),
+    /// The maximum failure rate isn't in the range [0, 1).
+    FailureRate(f64),
+    /// The margin the latency must fall by to count as recovering isn't in the
+    /// range (0, 1).
+    RecoveryMargin(f64),
+    /// The target percentile isn't in the range (0, 1).
+    Percentile(f64),
+    /// The rate limit isn't a positive number, or the burst is less than 1.
+    RateLimit,
+    /// The maximum target error isn't a positive number.
+    TargetError,
+    /// The concurrency limits aren't `1 <= min <= max`.
+    Concurrency {
+        /// The minimum concurrency.
+        min: usize,
+        /// The maximum concurrency.
+        max: usize,
+    },
+    /// The queue capacity limits aren't `1 <= min <= max`.
+    Queue {
+        /// The minimum queue capacity.
+        min: usize,
+        /// The maximum queue capacity.
+        max: usize,
+    },
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::EwmaParam(param) => {
+                write!(f, "moving average parameter {param} is not in (0, 1)")
+            }
+            ConfigError::Target => f.write_str("target latency is zero"),
+            ConfigError::ControlInterval => f.write_str("an interval is zero"),
+            ConfigError::ThroughputHaltMargin => {
+                f.write_str("throughput halt margin is not a non-negative number")
+            }
+            ConfigError::TailAmplification => {
+                f.write_str("maximum tail amplification is not at least 1")
+            }
+            ConfigError::FastResponseFraction(fraction) => {
+                write!(f, "fast response fraction {fraction} is not in (0, 1)")
+            }
+            ConfigError::QueueBackoffFraction(fraction) => {
+                write!(f, "queue backoff fraction {fraction} is not in (0, 1]")
+            }
+            ConfigError::FailureRate(rate) => {
+                write!(f, "maximum failure rate {rate} is not in [0, 1)")
+            }
+            ConfigError::RecoveryMargin(margin) => {
+                write!(f, "recovery margin {margin} is not in (0, 1)")
+            }
+            ConfigError::Percentile(percentile) => {
+                write!(f, "target percentile {percentile} is not in (0, 1)")
+            }
+            ConfigError::RateLimit => {
+                f.write_str("rate limit is not positive or burst is less than 1")
+            }
+            ConfigError::TargetError => f.write_str("maximum target error is not positive"),
+            ConfigError::Concurrency { min, max } => write!(
+                f,
+                "concurrency limits {min}..={max} are not at least 1 and in order"
+            ),
+            ConfigError::Queue { min, max } => write!(
+                f,
+                "queue capacity limits {min}..={max} are not at least 1 and in order"
+            ),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+/// Load Shed service's current state of the world
+#[derive(Debug, Clone)]
+pub struct LoadShedConf {
+    /// Semaphore controlling the waiting queue of requests.
+    pub(crate) available_queue: Arc<Semaphore>,
+    /// Semaphore controlling concurrency to the inner service.
+    pub(crate) available_concurrency: Arc<Semaphore>,
+    /// The concurrency permits currently held by requests.
+    pub(crate) held_concurrency: Arc<HeldPermits>,
+    /// The number of requests that have been admitted and shed.
+    pub(crate) counts: Arc<RequestCounts>,
+    /// Labels added to every metric emitted.
+    pub(crate) labels: MetricLabels,
+    /// The record of decisions made, if they're recorded.
+    #[cfg(feature = "decision-log")]
+    pub(crate) decisions: Option<Arc<DecisionLog>>,
+    /// Whether every concurrency permit was taken when last checked.
+    pub(crate) at_capacity: Arc<AtomicBool>,
+    /// Whether the service was last reported as overloaded, and since when
+    /// it's been otherwise if it has been.
+    pub(crate) overloaded: Arc<Mutex<(bool, Option<Instant>)>>,
+    /// Whether every new request is being shed, regardless of the load.
+    pub(crate) force_shed: Arc<AtomicBool>,
+    /// Whether the concurrency may still be waiting for permits to be
+    /// released so it can forget them, which the fast path has to check the
+    /// stats for.
+    pub(crate) forgets_pending: Arc<AtomicBool>,
+    /// Stats about the latency that change with each completed request.
+    pub(crate) stats: Arc<Mutex<ConfStats>>,
+    /// The budget of bytes in flight, if there is one.
+    pub(crate) bytes: Option<Arc<ByteBudget>>,
+    /// The tokens for admitting requests, if their rate is limited.
+    pub(crate) rate: Option<Arc<TokenBucket>>,
+    /// The average time between requests arriving in seconds, and when the
+    /// last one arrived, once there have been enough arrivals for each.
+    pub(crate) arrival_interval: Arc<Mutex<(Option<f64>, Option<Instant>)>>,
+    /// The bits of the runtime moving average parameter, copied out of the
+    /// stats whenever the runtime options are refreshed so arrivals can be
+    /// smoothed without locking the stats.
+    pub(crate) ewma_param: Arc<AtomicU64>,
+    /// The queue capacity, copied out of the stats whenever it changes so it
+    /// can be read without locking them.
+    pub(crate) queue_capacity: Arc<AtomicUsize>,
+    /// The rest of the configuration.
+    pub(crate) config: Arc<LoadShedConfig>,
+    /// Used to start the background control task exactly once.
+    pub(crate) control_task: Arc<Once>,
+    /// Used to start the background task saving the state exactly once.
+    pub(crate) persist_task: Arc<Once>,
+    /// The requests waiting for a concurrency permit, if the queue discipline
+    /// is customised, otherwise they wait on the semaphore directly.
+    pub(crate) waiting: Option<SharedQueue>,
+    /// Hands out tickets to arriving requests if the order they enter the
+    /// inner service in is strictly enforced.
+    pub(crate) arrivals: Option<Arc<Sequencer>>,
+}
+
+/// Stats about the latency that change with each completed request.
+#[derive(Debug)]
+pub struct ConfStats {
+    /// The current average latency in seconds.
+    pub(crate) average_latency: f64,
+    /// An average of the latency that moves more slowly, for telling which
+    /// way the latency is heading.
+    pub(crate) slow_average_latency: f64,
+    /// The average of the latency measured when
+    /// `available_concurrent.available_permits() == 0`.
+    pub(crate) average_latency_at_capacity: f64,
+    /// The number of available permits in the queue semaphore
+    /// (the current capacity of the queue).
+    pub(crate) queue_capacity: usize,
+    /// The number of permits in the available_concurrency semaphore.
+    pub(crate) concurrency: usize,
+    /// The concurrency the controller wants, which is a step above the
+    /// concurrency while the maximum holds it back.
+    pub(crate) desired_concurrency: usize,
+    /// The value of `self.concurrency` before it was last changed.
+    pub(crate) previous_concurrency: usize,
+    /// The number of requests queued when the concurrency was last adjusted.
+    pub(crate) previous_queued: usize,
+    /// When the concurrency started warming up, if it's still warming up.
+    pub(crate) warmup_started: Option<Instant>,
+    /// The time that the concurrency was last adjusted, to rate limit changing it.
+    pub(crate) last_changed: Instant,
+    /// Average throughput when at the previous concurrency value.
+    pub(crate) previous_throughput: f64,
+    /// The distribution of time spent waiting in the queue.
+    pub(crate) queue_latency: LatencyHistogram,
+    /// The distribution of time spent in the inner service.
+    pub(crate) service_latency: LatencyHistogram,
+    /// The distribution of the latencies the concurrency is controlled on, if
+    /// it's controlled on a percentile. Unlike `service_latency` these have
+    /// the failure penalty and outlier clamp applied, and are smoothed like
+    /// the average.
+    pub(crate) control_latency: LatencyHistogram,
+    /// The recent latency samples, if the concurrency is controlled on a
+    /// window of samples.
+    pub(crate) recent_latencies: LatencyWindow,
+    /// The number of permits that should have been forgotten to decrease the
+    /// concurrency, but weren't available at the time.
+    pub(crate) pending_forgets: usize,
+    /// Whether a request has completed at capacity since the background task
+    /// last ran.
+    pub(crate) sampled_at_capacity: bool,
+    /// The number of requests that have completed, and of those that failed,
+    /// since the concurrency was last adjusted.
+    pub(crate) outcomes: (u64, u64),
+    /// The exponentially weighted variance of the latency.
+    pub(crate) latency_variance: f64,
+    /// Whether the last adjustment increased the concurrency.
+    pub(crate) last_increased: bool,
+    /// Whether increases are halted because the last one didn't improve the
+    /// throughput.
+    pub(crate) increase_halted: bool,
+    /// The throughput and average latency when increases were halted, they
+    /// resume once either moves away from these.
+    pub(crate) halted_at: (f64, f64),
+    /// A multiplier on the queue capacity given by Little's law.
+    pub(crate) queue_scale: f64,
+    /// The current values of the runtime tunable options.
+    pub(crate) runtime: LoadShedRuntimeConfig,
+    /// The difference between the number of permits the concurrency semaphore
+    /// has and should have, when it was last checked.
+    pub(crate) drift: isize,
+    /// The average ratio of total latency to service latency.
+    pub(crate) tail_amplification: f64,
+    /// The number of times the concurrency has been increased.
+    pub(crate) increases: u64,
+    /// The number of times the concurrency has been decreased.
+    pub(crate) decreases: u64,
+    /// The maximum concurrency found by probing, if it's been probed.
+    pub(crate) probed_max_concurrency: Option<usize>,
+    /// The configured maximum concurrency that probing started from.
+    pub(crate) probe_base: usize,
+    /// When the maximum concurrency was last probed.
+    pub(crate) last_probe: Instant,
+    /// Whether the concurrency has reached the maximum since it was last
+    /// probed.
+    pub(crate) reached_max: bool,
+    /// The average latency and previous maximum concurrency, if the maximum has
+    /// been raised to see whether the service copes.
+    pub(crate) probe: Option<(f64, usize)>,
+    /// When the current recovery probe interval started and how many probes
+    /// have been let past the full queue in it.
+    pub(crate) recovery_probes: (Instant, usize),
+    /// When the current queue growth interval started and how much the queue
+    /// has grown in it.
+    pub(crate) queue_growth: (Instant, usize),
+    /// When the average latency went below the fast response fraction of the
+    /// target, if it's below it.
+    pub(crate) fast_since: Option<Instant>,
+    /// The limits set while running, these override the runtime options, if
+    /// they've been set.
+    pub(crate) limits: Option<Limits>,
+    /// The target latency set while running, this overrides the runtime
+    /// options and the schedule, if it's been set.
+    pub(crate) target_override: Option<Duration>,
+    /// The moving average parameter set while running, if it's been set.
+    pub(crate) ewma_param_override: Option<f64>,
+    /// The generation of the reset signal last acted on.
+    pub(crate) reset_generation: u64,
+    /// Whether the concurrency should have been decreased last time it was
+    /// adjusted, but was already at the minimum.
+    pub(crate) at_min_concurrency: bool,
+    /// The average estimated size of completed requests in bytes.
+    pub(crate) average_request_bytes: f64,
+    /// The average cost of completed requests in units, if there's a cost
+    /// budget, which takes the place of their size.
+    pub(crate) average_request_cost: f64,
+    /// When the last request completed.
+    pub(crate) last_sample: Instant,
+    /// The average time between requests completing in seconds.
+    pub(crate) completion_interval: f64,
+    /// The fraction of completed requests that succeeded within their
+    /// deadline.
+    pub(crate) good_fraction: f64,
+    /// The average latency of successful requests in seconds.
+    pub(crate) success_latency: f64,
+    /// The average latency of failed requests in seconds.
+    pub(crate) failure_latency: f64,
+}
+
+// size of system [req] = target latency [s] * throughput [r/s]
+// size of queue [req] = size of system [req] - concurrency [req]
+// throughput [req/s] = concurrency [req] / average latency of service [s]
+// => (size of queue [req] + concurrency[req]) = target latency [s] * concurrency[req] / latency [s]
+// => size of queue [req] = concurrency [req] * (target latency [s] / latency [s] - 1)
+//
+// Control the concurrency:
+// increase concurrency but not beyond target latency
+//
+// Control queue length:
+// queue capacity = concurrency * ((target latency / average latency of service) - 1)
+
+/// The queue capacity that keeps the average latency at the target, by
+/// Little's law, scaled by `scale` and limited to between `min` and `max`.
+///
+/// The latencies are in seconds, the queue is never shorter than `min` even if
+/// that's greater than `max`, but it's never more than
+/// [`Semaphore::MAX_PERMITS`].
+pub(crate) fn desired_queue_capacity(
+    concurrency: usize,
+    target: f64,
+    average_latency_at_capacity: f64,
+    scale: f64,
+    min: usize,
+    max: usize,
+) -> usize {
+    // If the service is slower than the target this is negative, clamp it (and
+    // NaN) to zero rather than relying on how negative floats are cast.
+    let queue_factor = ((target / average_latency_at_capacity) - 1.0).max(0.0);
+    // Float to integer casts saturate, so a huge (or infinite) capacity is
+    // just limited to the maximum. The semaphore panics if it's given more
+    // permits than it can hold, so that's a hard limit.
+    let capacity = (concurrency as f64 * queue_factor * scale.max(0.0)).floor() as usize;
+    capacity.min(max).max(min).min(Semaphore::MAX_PERMITS)
+}
+
+impl LoadShedConf {
+    pub(crate) fn new(config: &LoadShedConfig) -> Self {
+        let runtime = config.runtime_config();
+        let target = runtime.target.as_secs_f64();
+        let available_concurrency = match &config.concurrency_semaphore {
+            Some(semaphore) => {
+                // Start with the permits the semaphore already has, but at
+                // least the minimum concurrency.
+                let available = semaphore.available_permits();
+                let min_concurrency = runtime.min_concurrency.min(Semaphore::MAX_PERMITS);
+                semaphore.add_permits(min_concurrency.saturating_sub(available));
+                semaphore.clone()
+            }
+            None => Arc::new(Semaphore::new(
+                runtime.min_concurrency.min(Semaphore::MAX_PERMITS),
+            )),
+        };
+        let concurrency = available_concurrency.available_permits();
+        let queue_capacity = runtime.min_queue.min(Semaphore::MAX_PERMITS);
+        let labels = MetricLabels::new(&config.metric_labels);
+        #[cfg(feature = "metrics")]
+        {
+            gauge!(
+                "loadshedder.capacity",
+                concurrency as f64,
+                labels.with([("component", "service")])
+            );
+            gauge!(
+                "loadshedder.capacity",
+                queue_capacity as f64,
+                labels.with([("component", "queue")])
+            );
+            gauge!(
+                "loadshedder.size",
+                0.0,
+                labels.with([("component", "service")])
+            );
+            gauge!(
+                "loadshedder.size",
+                0.0,
+                labels.with([("component", "queue")])
+            );
+            gauge!("loadshedder.average_latency", target, labels.get());
+            gauge!("loadshedder.at_capacity", 0.0, labels.get());
+        }
+        let conf = Self {
+            available_concurrency,
+            held_concurrency: Arc::default(),
+            counts: Arc::default(),
+            labels,
+            #[cfg(feature = "decision-log")]
+            decisions: config
+                .decision_log
+                .map(|capacity| Arc::new(DecisionLog::new(capacity))),
+            at_capacity: Arc::default(),
+            overloaded: Arc::default(),
+            force_shed: Arc::default(),
+            forgets_pending: Arc::default(),
+            available_queue: Arc::new(Semaphore::new(queue_capacity)),
+            stats: Arc::new(Mutex::new(ConfStats {
+                average_latency: target,
+                slow_average_latency: target,
+                average_latency_at_capacity: target,
+                queue_capacity,
+                concurrency,
+                desired_concurrency: concurrency,
+                previous_concurrency: 0,
+                previous_queued: 0,
+                warmup_started: config.warmup.map(|_| Instant::now()),
+                last_changed: Instant::now(),
+                previous_throughput: 0.0,
+                queue_latency: LatencyHistogram::new(config.ewma_param),
+                service_latency: LatencyHistogram::new(config.ewma_param),
+                control_latency: LatencyHistogram::new(config.ewma_param),
+                recent_latencies: LatencyWindow::default(),
+                pending_forgets: 0,
+                sampled_at_capacity: false,
+                outcomes: (0, 0),
+                latency_variance: 0.0,
+                last_increased: false,
+                increase_halted: false,
+                halted_at: (0.0, 0.0),
+                queue_scale: 1.0,
+                runtime,
+                drift: 0,
+                tail_amplification: 1.0,
+                increases: 0,
+                decreases: 0,
+                probed_max_concurrency: None,
+                probe_base: config.max_concurrency,
+                last_probe: Instant::now(),
+                reached_max: false,
+                probe: None,
+                recovery_probes: (Instant::now(), 0),
+                queue_growth: (Instant::now(), 0),
+                fast_since: None,
+                last_sample: Instant::now(),
+                completion_interval: 0.0,
+                good_fraction: 1.0,
+                limits: None,
+                target_override: None,
+                ewma_param_override: None,
+                reset_generation: config
+                    .reset_signal
+                    .as_ref()
+                    .map_or(0, ResetSignal::generation),
+                at_min_concurrency: false,
+                average_request_bytes: 0.0,
+                average_request_cost: 0.0,
+                success_latency: target,
+                failure_latency: target,
+            })),
+            bytes: config.byte_budget.map(|limit| {
+                Arc::new(ByteBudget {
+                    limit,
+                    in_flight: AtomicU64::new(0),
+                })
+            }),
+            rate: config
+                .rate_limit
+                .map(|rate| Arc::new(TokenBucket::new(rate, config.rate_burst))),
+            arrival_interval: Arc::new(Mutex::new((None, None))),
+            ewma_param: Arc::new(AtomicU64::new(runtime.ewma_param.to_bits())),
+            queue_capacity: Arc::new(AtomicUsize::new(queue_capacity)),
+            config: Arc::new(config.clone()),
+            control_task: Arc::new(Once::new()),
+            persist_task: Arc::new(Once::new()),
+            waiting: config
+                .queue
+                .as_ref()
+                .map(|factory| Arc::new(Mutex::new((factory.0)()))),
+            arrivals: config.strict_fifo.then(Default::default),
+        };
+        // Resizing the queue adds and removes permits relative to its
+        // capacity, so they must start out equal.
+        debug_assert_eq!(
+            conf.available_queue.available_permits(),
+            conf.stats.lock().unwrap().queue_capacity,
+            "queue permits don't match the queue capacity"
+        );
+        let persisted = config
+            .persistence
+            .as_ref()
+            .and_then(|persistence| persistence.store.load());
+        if let Some(state) = persisted {
+            conf.import_state(&mut conf.stats.lock().unwrap(), state);
+        }
+        conf
+    }
+
+    /// What's been learned about the inner service.
+    pub(crate) fn export_state(stats: &ConfStats) -> PersistedState {
+        PersistedState {
+            average_latency: Duration::from_secs_f64(stats.average_latency),
+            average_latency_at_capacity: Duration::from_secs_f64(stats.average_latency_at_capacity),
+            concurrency: stats.concurrency,
+        }
+    }
+
+    /// Pick up from previously learned state, the concurrency is kept within
+    /// the current limits.
+    pub(crate) fn import_state(&self, stats: &mut ConfStats, state: PersistedState) {
+        // A zero latency would make the queue infinitely long.
+        if !state.average_latency.is_zero() {
+            stats.average_latency = state.average_latency.as_secs_f64();
+        }
+        if !state.average_latency_at_capacity.is_zero() {
+            stats.average_latency_at_capacity = state.average_latency_at_capacity.as_secs_f64();
+        }
+        self.resize_concurrency(stats, state.concurrency);
+        self.start_warmup(stats);
+    }
+
+    /// Drop the concurrency to the minimum and ramp it back up, if the
+    /// concurrency is warmed up.
+    fn start_warmup(&self, stats: &mut ConfStats) {
+        if self.config.warmup.is_some() {
+            stats.warmup_started = Some(Instant::now());
+            let min_concurrency = stats.runtime.min_concurrency;
+            self.resize_concurrency(stats, min_concurrency);
+        }
+    }
+
+    /// Limit the maximum concurrency while it's warming up, it rises linearly
+    /// from the minimum over the warm up.
+    fn warmup_max_concurrency(&self, stats: &mut ConfStats, max_concurrency: usize) -> usize {
+        let (Some(started), Some(warmup)) = (stats.warmup_started, self.config.warmup) else {
+            return max_concurrency;
+        };
+        let progress = started.elapsed().as_secs_f64() / warmup.as_secs_f64();
+        if progress >= 1.0 {
+            stats.warmup_started = None;
+            return max_concurrency;
+        }
+        let min_concurrency = stats.runtime.min_concurrency.min(max_concurrency);
+        min_concurrency + ((max_concurrency - min_concurrency) as f64 * progress) as usize
+    }
+
+    /// Change the limits on the concurrency and queue capacity together,
+    /// bringing the concurrency within them straight away. The queue capacity
+    /// is brought within them as requests arrive.
+    pub(crate) fn reconfigure(&self, limits: Limits) -> Result<(), ConfigError> {
+        limits.validate()?;
+        let mut stats = self.stats.lock().unwrap();
+        stats.limits = Some(limits);
+        limits.apply(&mut stats.runtime);
+        stats.probed_max_concurrency = None;
+        stats.probe = None;
+        let concurrency = stats.concurrency;
+        self.resize_concurrency(&mut stats, concurrency);
+        Ok(())
+    }
+
+    /// Add concurrency permits, first cancelling any waiting to be forgotten
+    /// so the semaphore doesn't end up with more permits than the concurrency.
+    fn add_concurrency(&self, stats: &mut ConfStats, permits: usize) {
+        let cancelled = permits.min(stats.pending_forgets);
+        stats.pending_forgets -= cancelled;
+        self.available_concurrency.add_permits(permits - cancelled);
+        self.dispatch();
+    }
+
+    /// Set the concurrency, kept within the current limits.
+    fn resize_concurrency(&self, stats: &mut ConfStats, concurrency: usize) {
+        let concurrency = concurrency
+            .min(stats.runtime.max_concurrency)
+            .max(stats.runtime.min_concurrency)
+            .min(Semaphore::MAX_PERMITS);
+        match concurrency.cmp(&stats.concurrency) {
+            Ordering::Less => {
+                let excess = stats.concurrency - concurrency;
+                // Forget the permits that are free now, and the rest as
+                // requests complete.
+                let free = self.available_concurrency.available_permits().min(excess);
+                let free = u32::try_from(free).unwrap_or(u32::MAX);
+                let forgotten = match self.available_concurrency.try_acquire_many(free) {
+                    Ok(permits) => {
+                        permits.forget();
+                        free as usize
+                    }
+                    Err(_) => 0,
+                };
+                stats.pending_forgets += excess - forgotten;
+                self.forgets_pending
+                    .store(stats.pending_forgets > 0, atomic::Ordering::Release);
+            }
+            Ordering::Equal => {}
+            Ordering::Greater => self.add_concurrency(stats, concurrency - stats.concurrency),
+        }
+        #[cfg(feature = "decision-log")]
+        self.record(Decision::Concurrency {
+            from: stats.concurrency,
+            to: concurrency,
+        });
+        stats.concurrency = concurrency;
+        stats.desired_concurrency = concurrency;
+        #[cfg(feature = "metrics")]
+        gauge!(
+            "loadshedder.capacity",
+            concurrency as f64,
+            self.labels.with([("component", "service")])
+        );
+    }
+
+    /// Count a request arriving in the arrival rate, unless it's a retry and
+    /// those are left out.
+    pub(crate) fn arrive(&self, retry: bool) {
+        if retry && self.config.exclude_retries {
+            return;
+        }
+        {
+            let mut arrivals = self.arrival_interval.lock().unwrap();
+            let now = Instant::now();
+            // The first arrival only starts the first interval.
+            if let Some(last) = arrivals.1.replace(now) {
+                let interval = now.duration_since(last).as_secs_f64();
+                let ewma_param = match self.config.ewma_time_constant {
+                    Some(constant) => 1.0 - (-interval / constant.as_secs_f64()).exp(),
+                    // Smooth like the latency, the runtime options are kept
+                    // up to date as requests complete.
+                    None => f64::from_bits(self.ewma_param.load(atomic::Ordering::Relaxed)),
+                };
+                // Start from the first interval seen, rather than from zero,
+                // which would overstate the rate until enough had arrived.
+                arrivals.0 = Some(match arrivals.0 {
+                    Some(average) => (average * (1.0 - ewma_param)) + (ewma_param * interval),
+                    None => interval,
+                });
+            }
+        }
+        #[cfg(feature = "metrics")]
+        gauge!(
+            "loadshedder.arrival_rate",
+            self.arrival_rate(),
+            self.labels.get()
+        );
+    }
+
+    /// The rate requests are arriving at, per second.
+    pub(crate) fn arrival_rate(&self) -> f64 {
+        let (Some(interval), Some(last)) = *self.arrival_interval.lock().unwrap() else {
+            return 0.0;
+        };
+        // Fall off while nothing's arriving, rather than staying at the rate
+        // from before.
+        let interval = interval.max(last.elapsed().as_secs_f64());
+        if interval > 0.0 {
+            1.0 / interval
+        } else {
+            0.0
+        }
+    }
+
+    /// Whether there are enough free permits for `cost` requests, between the
+    /// inner service and the queue.
+    pub(crate) fn would_admit(&self, cost: u32) -> bool {
+        !self.available_queue.is_closed()
+            && self.available_concurrency.available_permits()
+                + self.available_queue.available_permits()
+                >= cost as usize
+    }
+
+    /// Decide whether to admit a request that arrived at the given time and
+    /// will hold the given number of bytes, taking a place in the queue for it
+    /// if so.
+    ///
+    /// The time this takes, whether the request is admitted or shed, is the
+    /// `loadshedder.admission_overhead` histogram. That includes taking the
+    /// place in the queue, but not waiting in the queue for a place in the
+    /// service.
+    pub(crate) fn enter(
+        &self,
+        arrival: Option<Instant>,
+        bytes: u64,
+    ) -> Result<(Place, ByteReservation), ShedReason> {
+        #[cfg(feature = "metrics")]
+        let start = Instant::now();
+        let entered = self.decide(arrival, bytes);
+        // The time spent deciding is overhead added by the load shedder, if
+        // this grows then it's become a bottleneck itself.
+        #[cfg(feature = "metrics")]
+        histogram!(
+            "loadshedder.admission_overhead",
+            start.elapsed(),
+            self.labels.get()
+        );
+        entered
+    }
+
+    /// Decide whether to admit a request, see [`enter`](Self::enter).
+    fn decide(
+        &self,
+        arrival: Option<Instant>,
+        bytes: u64,
+    ) -> Result<(Place, ByteReservation), ShedReason> {
+        if self.available_queue.is_closed() {
+            return Err(ShedReason::ShuttingDown);
+        }
+        if self.is_force_shedding() {
+            return Err(ShedReason::Overload);
+        }
+        if let (Some(max_age), Some(arrival)) = (self.config.max_age, arrival) {
+            // The client has probably given up on this request by now.
+            if arrival.elapsed() > max_age {
+                return Err(ShedReason::Expired);
+            }
+        }
+        if self.rate.as_ref().is_some_and(|rate| !rate.take()) {
+            return Err(ShedReason::RateLimited);
+        }
+        let place = match self.join_queue() {
+            Ok(place) => Some(place),
+            Err(ShedReason::Overload) => None,
+            Err(shed) => return Err(shed),
+        };
+        let queue_full = place.is_none();
+        let entered = self.place(place, arrival, bytes);
+        if queue_full && entered.is_err() {
+            // Only a full queue means the service is overloaded, the other
+            // reasons for shedding say nothing about the load.
+            self.check_overload(true);
+        }
+        entered
+    }
+
+    /// Take a place for a request that's joined the queue, or that couldn't
+    /// because it's full, if the admission policy admits it.
+    fn place(
+        &self,
+        place: Option<Place>,
+        arrival: Option<Instant>,
+        bytes: u64,
+    ) -> Result<(Place, ByteReservation), ShedReason> {
+        let bytes = match &self.config.admission_policy {
+            Some(policy) => {
+                let mut ctx = self.admission_context(arrival, bytes);
+                ctx.overloaded = place.is_none();
+                match policy.admit(ctx) {
+                    AdmissionDecision::Admit { cost } => cost,
+                    AdmissionDecision::Shed { reason } => return Err(reason),
+                }
+            }
+            // The same as the default policy.
+            None if place.is_none() => return Err(ShedReason::Overload),
+            None => bytes,
+        };
+        let place = match place {
+            Some(place) => place,
+            None => self.extra_place()?,
+        };
+        let reservation = self.reserve_bytes(bytes)?;
+        Ok((place, reservation))
+    }
+
+    /// Wait for an entered request to get through the queue, counting whether
+    /// it was admitted.
+    pub(crate) async fn admit(
+        &self,
+        entered: Result<(Place, ByteReservation), ShedReason>,
+        ticket: Option<Ticket>,
+    ) -> Result<(Permit, ByteReservation), ShedReason> {
+        let admitted = self.wait_admitted(entered, ticket).await;
+        self.count_admission(
+            admitted
+                .as_ref()
+                .map(|(_, reservation)| reservation.bytes)
+                .map_err(|shed| *shed),
+        );
+        admitted
+    }
+
+    /// Wait for an entered request to get through the queue, leaving counting
+    /// it to [`count_admission`](Self::count_admission).
+    pub(crate) async fn wait_admitted(
+        &self,
+        entered: Result<(Place, ByteReservation), ShedReason>,
+        ticket: Option<Ticket>,
+    ) -> Result<(Permit, ByteReservation), ShedReason> {
+        let (place, reservation) = entered?;
+        let mut permit = match place {
+            Place::Queued(queue_permit) => self.start(queue_permit, ticket).await?,
+            Place::Admitted(permit) => permit,
+        };
+        if let Some(global) = &self.config.global_concurrency {
+            // Wait for the global limit while holding the local permit, so
+            // the wait counts against this service's capacity.
+            let global = global.clone().acquire_owned().await;
+            permit.global = Some(global.map_err(|_| ShedReason::ShuttingDown)?);
+        }
+        Ok((permit, reservation))
+    }
+
+    /// Count a request as admitted with the given number of bytes reserved, or
+    /// as shed. This should happen once per request.
+    pub(crate) fn count_admission(&self, admitted: Result<u64, ShedReason>) {
+        let count = match admitted {
+            Ok(_bytes) => {
+                #[cfg(feature = "metrics")]
+                match self.config.cost_resolution {
+                    Some(_) => histogram!(
+                        "loadshedder.request_cost",
+                        self.bytes_to_cost(_bytes),
+                        self.labels.get()
+                    ),
+                    None => histogram!(
+                        "loadshedder.request_bytes",
+                        _bytes as f64,
+                        self.labels.get()
+                    ),
+                }
+                self.check_capacity();
+                #[cfg(feature = "decision-log")]
+                self.record(Decision::Admitted);
+                &self.counts.accepted
+            }
+            Err(_shed) => {
+                #[cfg(feature = "decision-log")]
+                self.record(Decision::Shed(ShedReason::response(_shed)));
+                &self.counts.shed
+            }
+        };
+        count.fetch_add(1, atomic::Ordering::Relaxed);
+        if admitted.is_ok() {
+            self.check_overload(false);
+        }
+    }
+
+    /// Report when the service starts or stops shedding requests because its
+    /// queue is full, once it's stayed that way for the debounce duration.
+    fn check_overload(&self, overloaded: bool) {
+        let Some(on_event) = &self.config.on_event else {
+            return;
+        };
+        {
+            let mut state = self.overloaded.lock().unwrap();
+            let (reported, changed_at) = &mut *state;
+            if overloaded == *reported {
+                *changed_at = None;
+                return;
+            }
+            if changed_at.get_or_insert_with(Instant::now).elapsed() < self.config.overload_debounce
+            {
+                return;
+            }
+            *state = (overloaded, None);
+        }
+        if overloaded {
+            self.watch_overload();
+            on_event.emit(LoadShedEvent::OverloadStarted);
+        } else {
+            on_event.emit(LoadShedEvent::OverloadEnded);
+        }
+    }
+
+    /// Check whether the overload has ended every so often, as requests that
+    /// would show it has may have stopped arriving.
+    fn watch_overload(&self) {
+        // The most often the state is checked, however short the debounce.
+        const MIN_INTERVAL: Duration = Duration::from_millis(100);
+        let Ok(runtime) = tokio::runtime::Handle::try_current() else {
+            return;
+        };
+        let conf = self.clone();
+        runtime.spawn(async move {
+            let interval = conf.config.overload_debounce.max(MIN_INTERVAL);
+            while conf.overloaded.lock().unwrap().0 {
+                tokio::time::sleep(interval).await;
+                // Room in the queue means requests aren't being shed for the
+                // load, any that are reset the debounce.
+                if conf.available_queue.available_permits() > 0 {
+                    conf.check_overload(false);
+                }
+            }
+        });
+    }
+
+    /// Check whether every concurrency permit is taken, emitting the
+    /// `loadshedder.at_capacity` gauge and counting the times it becomes so.
+    pub(crate) fn check_capacity(&self) {
+        let at_capacity = self.available_concurrency.available_permits() == 0;
+        let was_at_capacity = self
+            .at_capacity
+            .swap(at_capacity, atomic::Ordering::Relaxed);
+        if at_capacity != was_at_capacity {
+            #[cfg(feature = "metrics")]
+            {
+                gauge!(
+                    "loadshedder.at_capacity",
+                    f64::from(u8::from(at_capacity)),
+                    self.labels.get()
+                );
+                if at_capacity {
+                    increment_counter!("loadshedder.at_capacity_transitions", self.labels.get());
+                }
+            }
+        }
+    }
+
+    /// The number of requests currently queued or being processed.
+    pub(crate) fn queue_len(&self, stats: &ConfStats) -> usize {
+        let current_concurrency = (stats.concurrency + stats.pending_forgets)
+            .saturating_sub(self.available_concurrency.available_permits());
+        let current_queue = stats.queue_capacity - self.available_queue.available_permits();
+        current_concurrency + current_queue
+    }
+
+    /// How long a request arriving now would wait in the queue, capped if
+    /// there's a maximum.
+    pub(crate) fn estimated_wait(&self) -> EstimatedWait {
+        let stats = self.stats.lock().unwrap();
+        let queued = stats
+            .queue_capacity
+            .saturating_sub(self.available_queue.available_permits());
+        // Requests leave the queue at the rate the service completes them.
+        let wait = Duration::try_from_secs_f64(
+            (queued + 1) as f64 * stats.average_latency_at_capacity / stats.concurrency as f64,
+        )
+        .unwrap_or(Duration::MAX);
+        match self.config.max_estimated_wait {
+            Some(max) if wait > max => EstimatedWait {
+                wait: max,
+                capped: true,
+            },
+            _ => EstimatedWait {
+                wait,
+                capped: false,
+            },
+        }
+    }
+
+    /// What an admission policy is told about a request and the load shedder.
+    fn admission_context(&self, arrival: Option<Instant>, bytes: u64) -> AdmissionContext {
+        let stats = self.stats.lock().unwrap();
+        AdmissionContext {
+            concurrency: stats.concurrency,
+            in_flight: self.held_concurrency.count.load(atomic::Ordering::Acquire),
+            queue_capacity: stats.queue_capacity,
+            queued: stats
+                .queue_capacity
+                .saturating_sub(self.available_queue.available_permits()),
+            average_latency: Duration::from_secs_f64(stats.average_latency),
+            target: stats.runtime.target,
+            bytes,
+            arrival: arrival.map(Instant::into_std),
+            overloaded: false,
+        }
+    }
+
+    /// The number of bytes currently in flight, zero if there's no budget or
+    /// it's a cost budget.
+    pub(crate) fn bytes_in_flight(&self) -> u64 {
+        match self.config.cost_resolution {
+            Some(_) => 0,
+            None => self.budget_in_flight(),
+        }
+    }
+
+    /// The total cost in units of the requests currently in flight, zero if
+    /// there's no cost budget.
+    pub(crate) fn cost_in_flight(&self) -> f64 {
+        match self.config.cost_resolution {
+            Some(_) => self.bytes_to_cost(self.budget_in_flight()),
+            None => 0.0,
+        }
+    }
+
+    /// How much of the budget the requests in flight take up.
+    fn budget_in_flight(&self) -> u64 {
+        self.bytes
+            .as_ref()
+            .map_or(0, |budget| budget.in_flight.load(atomic::Ordering::Acquire))
+    }
+
+    /// Pick up the latest runtime tunable options, if they can change. Invalid
+    /// options are ignored.
+    ///
+    /// This also resets the learned state if the reset signal's been
+    /// triggered, since it's checked at the same points.
+    pub(crate) fn refresh_runtime_config(&self, stats: &mut ConfStats) {
+        if let Some(signal) = &self.config.reset_signal {
+            let generation = signal.generation();
+            if generation != stats.reset_generation {
+                stats.reset_generation = generation;
+                Self::reset_learned(stats);
+                self.start_warmup(stats);
+            }
+        }
+        if let Some(runtime) = &self.config.runtime {
+            let runtime = *runtime.borrow();
+            if runtime.validate().is_ok() {
+                stats.runtime = runtime;
+            }
+            if let Some(limits) = stats.limits {
+                limits.apply(&mut stats.runtime);
+            }
+        }
+        if let Some(schedule) = &self.config.target_schedule {
+            stats.runtime.target = schedule.current_target();
+        }
+        if let Some(target) = stats.target_override {
+            stats.runtime.target = target;
+        }
+        if let Some(ewma_param) = stats.ewma_param_override {
+            stats.runtime.ewma_param = ewma_param;
+        }
+        self.ewma_param.store(
+            stats.runtime.ewma_param.to_bits(),
+            atomic::Ordering::Relaxed,
+        );
+    }
+
+    /// Override the target latency while running.
+    pub(crate) fn set_target(&self, target: Duration) -> Result<(), ConfigError> {
+        if target.is_zero() {
+            return Err(ConfigError::Target);
+        }
+        let mut stats = self.stats.lock().unwrap();
+        stats.target_override = Some(target);
+        stats.runtime.target = target;
+        Ok(())
+    }
+
+    /// Override the moving average parameter while running.
+    pub(crate) fn set_ewma_param(&self, ewma_param: f64) -> Result<(), ConfigError> {
+        if ewma_param.is_nan() || ewma_param <= 0.0 || ewma_param >= 1.0 {
+            return Err(ConfigError::EwmaParam(ewma_param));
+        }
+        let mut stats = self.stats.lock().unwrap();
+        stats.ewma_param_override = Some(ewma_param);
+        stats.runtime.ewma_param = ewma_param;
+        self.ewma_param
+            .store(ewma_param.to_bits(), atomic::Ordering::Relaxed);
+        Ok(())
+    }
+
+    /// Shed every new request, or stop doing so.
+    pub(crate) fn set_force_shed(&self, shed: bool) {
+        self.force_shed.store(shed, atomic::Ordering::Relaxed);
+    }
+
+    /// Whether every new request is being shed.
+    pub(crate) fn is_force_shedding(&self) -> bool {
+        self.force_shed.load(atomic::Ordering::Relaxed)
+    }
+
+    /// Whether a new request would be shed right now, because the queue is
+    /// full, the service is shedding everything or shutting down, or the rate
+    /// limit has been reached.
+    pub(crate) fn is_shedding(&self) -> bool {
+        self.available_queue.is_closed()
+            || self.is_force_shedding()
+            || self.available_queue.available_permits() == 0
+            || self.rate.as_ref().is_some_and(|rate| rate.is_empty())
+    }
+
+    /// Shed new requests and wait until the ones already admitted have
+    /// completed.
+    pub(crate) async fn drain(&self) {
+        self.set_force_shed(true);
+        loop {
+            // Listen before checking, so a release in between isn't missed.
+            let mut released = std::pin::pin!(self.held_concurrency.released.notified());
+            released.as_mut().enable();
+            if self.admitted() == 0 {
+                break;
+            }
+            released.await;
+        }
+    }
+
+    /// The number of requests queued or holding a concurrency permit.
+    ///
+    /// Unlike [`queue_len`](Self::queue_len) this only counts the permits
+    /// held by requests, not any taken from a shared semaphore by others.
+    fn admitted(&self) -> usize {
+        let queued = self
+            .stats
+            .lock()
+            .unwrap()
+            .queue_capacity
+            .saturating_sub(self.available_queue.available_permits());
+        self.held_concurrency.count.load(atomic::Ordering::Acquire) + queued
+    }
+
+    /// Stop admitting requests, this closes the queue so there's nowhere for
+    /// them to go, while the requests already admitted keep their permits.
+    pub(crate) fn shutdown(&self) {
+        self.available_queue.close();
+    }
+
+    /// Forget the latencies and throughput learned from the inner service, so
+    /// they're learned again from scratch. The concurrency and queue capacity
+    /// are kept, they adjust as the service is re-learned, unless the
+    /// concurrency is warmed up again.
+    pub(crate) fn reset(&self) {
+        let mut stats = self.stats.lock().unwrap();
+        Self::reset_learned(&mut stats);
+        self.start_warmup(&mut stats);
+    }
+
+    fn reset_learned(stats: &mut ConfStats) {
+        let target = stats.runtime.target.as_secs_f64();
+        stats.average_latency = target;
+        stats.slow_average_latency = target;
+        stats.average_latency_at_capacity = target;
+        stats.success_latency = target;
+        stats.failure_latency = target;
+        stats.latency_variance = 0.0;
+        stats.queue_latency.reset();
+        stats.service_latency.reset();
+        stats.control_latency.reset();
+        stats.recent_latencies.clear();
+        stats.previous_throughput = 0.0;
+        stats.outcomes = (0, 0);
+        stats.last_increased = false;
+        stats.increase_halted = false;
+        stats.tail_amplification = 1.0;
+        stats.queue_scale = 1.0;
+        stats.probe = None;
+        stats.fast_since = None;
+        stats.at_min_concurrency = false;
+        stats.last_changed = Instant::now();
+    }
+
+    /// How much of the budget a request with the given cost takes up, rounded
+    /// up so that even the cheapest requests take some.
+    ///
+    /// Costs that aren't a non-negative number, or are too big to count, are
+    /// counted as more than any budget so the request is shed.
+    pub(crate) fn cost_to_bytes(&self, cost: f64) -> u64 {
+        let bytes = (cost * f64::from(self.config.cost_resolution.unwrap_or(1).max(1))).ceil();
+        if (0.0..u64::MAX as f64).contains(&bytes) {
+            bytes as u64
+        } else {
+            u64::MAX
+        }
+    }
+
+    /// The cost in units of a request that takes up the given amount of the
+    /// budget, the reverse of [`cost_to_bytes`](Self::cost_to_bytes).
+    pub(crate) fn bytes_to_cost(&self, bytes: u64) -> f64 {
+        bytes as f64 / f64::from(self.config.cost_resolution.unwrap_or(1).max(1))
+    }
+
+    /// Reserve space for a request of the given size in the byte budget,
+    /// failing if that would take us over budget.
+    pub(crate) fn reserve_bytes(&self, bytes: u64) -> Result<ByteReservation, ShedReason> {
+        let Some(budget) = &self.bytes else {
+            return Ok(ByteReservation {
+                budget: None,
+                bytes,
+            });
+        };
+        budget
+            .in_flight
+            .fetch_update(
+                atomic::Ordering::AcqRel,
+                atomic::Ordering::Acquire,
+                |in_flight| {
+                    in_flight
+                        .checked_add(bytes)
+                        .filter(|&in_flight| in_flight <= budget.limit)
+                },
+            )
+            .map_err(|_| ShedReason::Overload)?;
+        Ok(ByteReservation {
+            budget: Some(budget.clone()),
+            bytes,
+        })
+    }
+
+    /// Resize the queue and add ourselves to it, or skip it entirely if
+    /// there's a concurrency permit free and the fast path is enabled.
+    pub(crate) fn join_queue(&self) -> Result<Place, ShedReason> {
+        // The semaphore hands released permits straight to its waiters, so a
+        // free permit means nobody is queued for it. Custom queues and strict
+        // ordering have their own waiters though.
+        if self.config.fast_path && self.waiting.is_none() && self.arrivals.is_none() {
+            if let Ok(permit) = self.available_concurrency.clone().try_acquire_owned() {
+                let mut permit =
+                    Permit::new(permit, "service", &self.labels).counted(&self.held_concurrency);
+                permit.fast = true;
+                return Ok(Place::Admitted(permit));
+            }
+        }
+        {
+            // Work inside a block so we drop the stats lock asap.
+            let mut stats = self.stats.lock().unwrap();
+            self.refresh_runtime_config(&mut stats);
+            if self.config.fail_fast_at_min_concurrency && stats.at_min_concurrency {
+                // The concurrency can't go any lower to bring the latency
+                // down, so stop queueing until it recovers.
+                return match self.available_concurrency.clone().try_acquire_owned() {
+                    Ok(permit) => Ok(Place::Admitted(
+                        Permit::new(permit, "service", &self.labels)
+                            .counted(&self.held_concurrency),
+                    )),
+                    Err(_) => Err(ShedReason::Overload),
+                };
+            }
+            // Use average latency at (concurrency) capacity so that this doesn't
+            // grow too large while the system is under-utilised.
+            let desired_queue_capacity = if stats
+                .fast_since
+                .is_some_and(|since| since.elapsed() >= self.config.fast_response_window)
+            {
+                // The service has been comfortably fast for a while, so a
+                // long queue would only be a backlog if it suddenly slows.
+                stats.runtime.min_queue.min(Semaphore::MAX_PERMITS)
+            } else {
+                desired_queue_capacity(
+                    stats.concurrency,
+                    stats.runtime.target.as_secs_f64(),
+                    stats.average_latency_at_capacity,
+                    stats.queue_scale,
+                    stats.runtime.min_queue,
+                    stats.runtime.max_queue,
+                )
+            };
+            #[cfg(feature = "metrics")]
+            gauge!(
+                "loadshedder.capacity",
+                desired_queue_capacity as f64,
+                self.labels.with([("component", "queue")])
+            );
+
+            // Adjust the semaphore capacity by adding or acquiring many permits.
+            // If acquiring permits fails we can return overload and let the next
+            // request recompute the queue capacity.
+            match desired_queue_capacity.cmp(&stats.queue_capacity) {
+                Ordering::Less => {
+                    // Forget the permits that are free now, rather than shed
+                    // this request because the rest are held by queued
+                    // requests, those are removed by following requests once
+                    // they've been released. Permits can only be acquired a
+                    // u32 at a time too.
+                    let shrink = (stats.queue_capacity - desired_queue_capacity)
+                        .min(self.available_queue.available_permits());
+                    stats.queue_capacity -= self.shrink_queue(shrink)?;
+                }
+                Ordering::Equal => {}
+                Ordering::Greater => {
+                    let grow = desired_queue_capacity - stats.queue_capacity;
+                    let grow = self.queue_growth(&mut stats, grow);
+                    self.available_queue.add_permits(grow);
+                    stats.queue_capacity += grow;
+                }
+            }
+            self.queue_capacity
+                .store(stats.queue_capacity, atomic::Ordering::Relaxed);
+        }
+
+        // Finally get our queue permit, if this fails then the queue is full
+        // and we need to bail out.
+        match self.available_queue.clone().try_acquire_owned() {
+            Ok(queue_permit) => Ok(Place::Queued(Permit::new(
+                queue_permit,
+                "queue",
+                &self.labels,
+            ))),
+            Err(TryAcquireError::NoPermits) => self.recovery_probe(),
+            Err(TryAcquireError::Closed) => Err(ShedReason::ShuttingDown),
+        }
+    }
+
+    /// Limit how much the queue grows by, if its growth is limited, returning
+    /// how much it can grow by now.
+    ///
+    /// A short burst of fast responses can make the desired queue capacity
+    /// jump, and a long queue amplifies the latency of a slow period that
+    /// follows, so the queue can be made to grow gradually.
+    fn queue_growth(&self, stats: &mut ConfStats, grow: usize) -> usize {
+        let Some(interval) = self.config.queue_growth_interval else {
+            return grow;
+        };
+        let (started, grown) = &mut stats.queue_growth;
+        if started.elapsed() >= interval {
+            *started = Instant::now();
+            *grown = 0;
+        }
+        let grow = grow.min(self.config.max_queue_growth.saturating_sub(*grown));
+        *grown += grow;
+        grow
+    }
+
+    /// Force a probe through, bypassing the queue and concurrency limit,
+    /// unless the load shedder's shut down.
+    pub(crate) fn admit_probe(&self) -> Result<LoadShedGuard, ShedReason> {
+        if self.available_queue.is_closed() {
+            return Err(ShedReason::ShuttingDown);
+        }
+        // The probe is in addition to the concurrency limit, so it gets a
+        // permit of its own rather than one of the service's.
+        let permit = Arc::new(Semaphore::new(1))
+            .try_acquire_owned()
+            .expect("a new semaphore has a permit");
+        #[cfg(feature = "decision-log")]
+        self.record(Decision::Probe);
+        let mut guard = LoadShedGuard::new(
+            self.clone(),
+            Instant::now(),
+            Permit::new(permit, "probe", &self.labels),
+            ByteReservation {
+                budget: None,
+                bytes: 0,
+            },
+        );
+        guard.probe = true;
+        Ok(guard)
+    }
+
+    /// Let this request past the full queue if it's time for a recovery probe.
+    ///
+    /// When the service has been slow the queue can shrink so far that very
+    /// few requests get through to show it's recovered. Probes are queued like
+    /// any other request, so they measure the service as it is now, and if it
+    /// has recovered the latency falls and the queue grows back.
+    fn recovery_probe(&self) -> Result<Place, ShedReason> {
+        let Some(interval) = self.config.recovery_probe_interval else {
+            return Err(ShedReason::Overload);
+        };
+        let mut stats = self.stats.lock().unwrap();
+        let (started, probes) = &mut stats.recovery_probes;
+        if started.elapsed() >= interval {
+            *started = Instant::now();
+            *probes = 0;
+        }
+        if *probes >= self.config.recovery_probes {
+            return Err(ShedReason::Overload);
+        }
+        let place = self.make_room(&mut stats)?;
+        stats.recovery_probes.1 += 1;
+        #[cfg(feature = "metrics")]
+        increment_counter!("loadshedder.recovery_probe", self.labels.get());
+        Ok(place)
+    }
+
+    /// Forget `shrink` free queue permits, returning how many were forgotten,
+    /// which is none if other requests took some of them first.
+    fn shrink_queue(&self, shrink: usize) -> Result<usize, ShedReason> {
+        let shrink = u32::try_from(shrink).unwrap_or(u32::MAX);
+        match self.available_queue.try_acquire_many(shrink) {
+            Ok(permits) => {
+                permits.forget();
+                Ok(shrink as usize)
+            }
+            Err(TryAcquireError::NoPermits) => {
+                // Another request took the free permits first.
+                #[cfg(feature = "metrics")]
+                increment_counter!("loadshedder.resize_contention", self.labels.get());
+                Ok(0)
+            }
+            Err(TryAcquireError::Closed) => Err(ShedReason::ShuttingDown),
+        }
+    }
+
+    /// Give a request a place in the full queue because the admission policy
+    /// admitted it anyway.
+    fn extra_place(&self) -> Result<Place, ShedReason> {
+        self.make_room(&mut self.stats.lock().unwrap())
+    }
+
+    /// Add a place to the queue and take it, it's removed again the next time
+    /// the queue is resized.
+    fn make_room(&self, stats: &mut ConfStats) -> Result<Place, ShedReason> {
+        self.available_queue.add_permits(1);
+        match self.available_queue.clone().try_acquire_owned() {
+            Ok(queue_permit) => {
+                stats.queue_capacity += 1;
+                self.queue_capacity
+                    .store(stats.queue_capacity, atomic::Ordering::Relaxed);
+                Ok(Place::Queued(Permit::new(
+                    queue_permit,
+                    "queue",
+                    &self.labels,
+                )))
+            }
+            Err(TryAcquireError::NoPermits) => {
+                // Another request took the new place first, it's still part of
+                // the queue until it's next resized.
+                stats.queue_capacity += 1;
+                self.queue_capacity
+                    .store(stats.queue_capacity, atomic::Ordering::Relaxed);
+                Err(ShedReason::Overload)
+            }
+            Err(TryAcquireError::Closed) => Err(ShedReason::ShuttingDown),
+        }
+    }
+
+    /// Wait until we've made it through the queue and have obtained a permit to
+    /// send the request.
+    pub(crate) async fn start(
+        &self,
+        queue_permit: Permit,
+        ticket: Option<Ticket>,
+    ) -> Result<Permit, ShedReason> {
+        let queue_permit = match self.config.queue_slot {
+            QueueSlotPolicy::Hold => Some(queue_permit),
+            QueueSlotPolicy::ReleaseEarly => {
+                drop(queue_permit);
+                None
+            }
+        };
+        // We're in the queue now so wait until we get ourselves a concurrency permit.
+        let concurrency_permit = match &self.waiting {
+            Some(waiting) => self.wait_in(waiting).await?,
+            None => {
+                // Only let one request at a time wait for the semaphore so they
+                // get their permits in the order they arrived.
+                if let Some(ticket) = &ticket {
+                    ticket.turn().await;
+                }
+                // The semaphore's only closed if it's shared with something
+                // that's shutting down.
+                let permit = self
+                    .available_concurrency
+                    .clone()
+                    .acquire_owned()
+                    .await
+                    .map_err(|_| ShedReason::ShuttingDown)?;
+                let permit =
+                    Permit::new(permit, "service", &self.labels).counted(&self.held_concurrency);
+                if let Some(ticket) = ticket {
+                    ticket.finish();
+                }
+                permit
+            }
+        };
+        // Now we've got the permit required to send the request we can leave the queue.
+        drop(queue_permit);
+        Ok(concurrency_permit)
+    }
+
+    /// Wait in a custom queue for a concurrency permit.
+    pub(crate) async fn wait_in(&self, waiting: &SharedQueue) -> Result<Permit, ShedReason> {
+        let receiver = {
+            let mut queue = waiting.lock().unwrap();
+            // Permits are only handed out through the queue while it isn't
+            // empty, otherwise the request can skip straight to the front.
+            if queue.is_empty() {
+                if let Ok(permit) = self.available_concurrency.clone().try_acquire_owned() {
+                    return Ok(Permit::queued(
+                        permit,
+                        waiting.clone(),
+                        self.held_concurrency.clone(),
+                        &self.labels,
+                    ));
+                }
+            }
+            if queue.len() >= queue.capacity() {
+                return Err(ShedReason::Overload);
+            }
+            let (waiter, receiver) = Waiter::new();
+            queue.enqueue(waiter);
+            receiver
+        };
+        // A queue that drops a waiter rather than sending it a permit has
+        // evicted it to make room.
+        receiver.await.map_err(|_| ShedReason::Overload)
+    }
+
+    /// Hand out any free concurrency permits to requests waiting in a custom
+    /// queue, this must be called whenever permits are added.
+    pub(crate) fn dispatch(&self) {
+        let Some(waiting) = &self.waiting else {
+            return;
+        };
+        let mut queue = waiting.lock().unwrap();
+        while !queue.is_empty() {
+            let Ok(permit) = self.available_concurrency.clone().try_acquire_owned() else {
+                break;
+            };
+            if let Some(permit) = hand_off(
+                waiting,
+                &mut **queue,
+                permit,
+                &self.held_concurrency,
+                &self.labels,
+            ) {
+                drop(permit);
+                break;
+            }
+        }
+    }
+
+    /// Register a completed call of the inner service, providing the time it
+    /// spent queued, the latency and outcome to update the statistics.
+    pub(crate) fn stop(
+        &mut self,
+        queued: Duration,
+        elapsed: Duration,
+        outcome: Outcome,
+        bytes: u64,
+        concurrency_permit: Permit,
+    ) {
+        #[cfg(feature = "decision-log")]
+        self.record(Decision::Sample {
+            queued,
+            latency: elapsed,
+            success: outcome == Outcome::Success,
+        });
+        let queued = queued.as_secs_f64();
+        let elapsed = elapsed.as_secs_f64();
+        #[cfg(feature = "metrics")]
+        {
+            histogram!("loadshedder.queue_latency", queued, self.labels.get());
+            histogram!("loadshedder.latency", elapsed, self.labels.get());
+        }
+
+        // This function solely updates the stats (and is not async) so hold the
+        // lock for the entire function.
+        let mut stats = self.stats.lock().expect("To be able to lock stats");
+        self.refresh_runtime_config(&mut stats);
+        let now = Instant::now();
+        let since_last = now.duration_since(std::mem::replace(&mut stats.last_sample, now));
+        let base_ewma_param = match self.config.ewma_time_constant {
+            // Decay the old averages by the time since the last sample, so they
+            // smooth over the same time whatever the request rate.
+            Some(constant) => 1.0 - (-since_last.as_secs_f64() / constant.as_secs_f64()).exp(),
+            None => stats.runtime.ewma_param,
+        };
+        let (average, size) = match self.config.cost_resolution {
+            Some(_) => (&mut stats.average_request_cost, self.bytes_to_cost(bytes)),
+            None => (&mut stats.average_request_bytes, bytes as f64),
+        };
+        *average = (*average * (1.0 - base_ewma_param)) + (base_ewma_param * size);
+        let deadline = self
+            .config
+            .max_age
+            .unwrap_or(stats.runtime.target)
+            .as_secs_f64();
+        let good = outcome == Outcome::Success && queued + elapsed <= deadline;
+        stats.completion_interval = (stats.completion_interval * (1.0 - base_ewma_param))
+            + (base_ewma_param * since_last.as_secs_f64());
+        stats.good_fraction = (stats.good_fraction * (1.0 - base_ewma_param))
+            + (base_ewma_param * f64::from(u8::from(good)));
+        stats.outcomes.0 += 1;
+        stats.outcomes.1 += u64::from(outcome == Outcome::Failure);
+        #[cfg(feature = "metrics")]
+        gauge!(
+            "loadshedder.goodput",
+            stats.throughput() * stats.good_fraction,
+            self.labels.get()
+        );
+        stats.queue_latency.record(queued);
+        stats.service_latency.record(elapsed);
+        // How far this request was from the target, negative if it was faster,
+        // relative to the target so it can be compared across targets.
+        #[cfg(feature = "metrics")]
+        {
+            let target = stats.runtime.target.as_secs_f64();
+            histogram!(
+                "loadshedder.target_error",
+                (elapsed - target) / target,
+                self.labels.get()
+            );
+        }
+
+        // Track successes and failures separately too, so fast failures can't
+        // hide slow successes.
+        let outcome_latency = match outcome {
+            Outcome::Success => &mut stats.success_latency,
+            Outcome::Failure => &mut stats.failure_latency,
+        };
+        *outcome_latency =
+            (*outcome_latency * (1.0 - base_ewma_param)) + (base_ewma_param * elapsed);
+        #[cfg(feature = "metrics")]
+        match outcome {
+            Outcome::Success => {
+                gauge!(
+                    "loadshedder.outcome_latency",
+                    stats.success_latency,
+                    self.labels.with([("outcome", "success")])
+                )
+            }
+            Outcome::Failure => {
+                gauge!(
+                    "loadshedder.outcome_latency",
+                    stats.failure_latency,
+                    self.labels.with([("outcome", "failure")])
+                )
+            }
+        }
+
+        // Count failures as at least as slow as the penalty, so that a spike in
+        // failures pushes the averages up and the concurrency down.
+        let elapsed = match self.config.failure_latency_penalty {
+            Some(penalty) if outcome == Outcome::Failure => elapsed.max(penalty.as_secs_f64()),
+            _ => elapsed,
+        };
+
+        if let Some(max_amplification) = self.config.max_tail_amplification {
+            self.limit_tail_amplification(&mut stats, queued, elapsed, max_amplification);
+        }
+
+        // Stop a single outlier from moving the averages so far that the
+        // concurrency collapses, sustained errors still get through.
+        let elapsed = match self.config.max_target_error {
+            Some(max_error) => {
+                let target = stats.runtime.target.as_secs_f64();
+                // Only the upper side, fast requests have to be measured as
+                // they are or the queue is sized for a slower service.
+                elapsed.min(target * (1.0 + max_error))
+            }
+            None => elapsed,
+        };
+
+        let available_permits = self.available_concurrency.available_permits();
+        // Have some leeway on what "at max concurrency" means as you might
+        // otherwise never see this condition at large concurrency values.
+        let at_max_concurrency = available_permits <= usize::max(1, stats.concurrency / 10);
+
+        // Track the variance of the latency (around the average) incrementally.
+        let deviation = elapsed - stats.average_latency;
+        stats.latency_variance = (1.0 - base_ewma_param)
+            * (stats.latency_variance + base_ewma_param * deviation * deviation);
+        let ewma_param = if self.config.adaptive_ewma {
+            // Smooth more when the latency is volatile, using the squared
+            // coefficient of variation as a scale-free measure of volatility.
+            let volatility = stats.latency_variance / (stats.average_latency.powi(2));
+            base_ewma_param / (1.0 + volatility.max(0.0))
+        } else {
+            base_ewma_param
+        };
+
+        if let Some(window) = self.config.latency_window {
+            // Control on the samples from a recent window, so the order the
+            // samples arrive in doesn't matter. Only their mean is kept up to
+            // date, to keep this cheap, a percentile is found when the
+            // concurrency is adjusted.
+            stats.recent_latencies.record(now, elapsed, window);
+            if let Some(mean) = stats.recent_latencies.mean() {
+                stats.average_latency = mean;
+            }
+        } else {
+            // Update the average latency using the EWMA algorithm.
+            stats.average_latency =
+                (stats.average_latency * (1.0 - ewma_param)) + (ewma_param * elapsed);
+            // Control on a percentile of the latency instead, if asked to, the
+            // histogram is decayed in the same way as the average.
+            if let Some(percentile) = self.config.target_percentile {
+                stats.control_latency.set_ewma_param(ewma_param);
+                stats.control_latency.record(elapsed);
+                if let Some(latency) = stats.control_latency.quantile(percentile) {
+                    stats.average_latency = latency;
+                }
+            }
+        }
+        // Follow whatever the concurrency is controlled on, so the two can be
+        // compared.
+        if let Some(slow_param) = self.config.slow_ewma_param {
+            stats.slow_average_latency = (stats.slow_average_latency * (1.0 - slow_param))
+                + (slow_param * stats.average_latency);
+        }
+        #[cfg(feature = "metrics")]
+        gauge!(
+            "loadshedder.average_latency",
+            stats.average_latency,
+            self.labels.get()
+        );
+        if let Some(fraction) = self.config.fast_response_fraction {
+            if stats.average_latency < fraction * stats.runtime.target.as_secs_f64() {
+                stats.fast_since.get_or_insert_with(Instant::now);
+            } else {
+                stats.fast_since = None;
+            }
+        }
+        // Fast failures (e.g. because a downstream is unavailable) would drag
+        // the average down and shrink the queue, which is rarely helpful.
+        let exclude_failure =
+            self.config.exclude_failures_at_capacity && outcome == Outcome::Failure;
+        if at_max_concurrency && !exclude_failure {
+            stats.average_latency_at_capacity =
+                (stats.average_latency_at_capacity * (1.0 - ewma_param)) + (ewma_param * elapsed);
+        }
+
+        let concurrency_permit = if stats.pending_forgets > 0 {
+            // A previous decrease couldn't find a free permit to forget.
+            concurrency_permit.forget();
+            stats.pending_forgets -= 1;
+            None
+        } else {
+            Some(concurrency_permit)
+        };
+
+        if self.config.control_interval.is_some() {
+            // The background task makes the decisions, just leave it a note of
+            // what happened.
+            stats.sampled_at_capacity |= at_max_concurrency;
+            return;
+        }
+
+        // Only ever change max concurrency if we're at the limit as we need
+        // measurements to have happened at the current limit.
+        // Also, introduce a max rate of change that's somewhat magically
+        // related to the latency and ewma parameter to prevent this from
+        // changing too quickly.
+        if stats.last_changed.elapsed().as_secs_f64()
+            > (stats.average_latency / stats.runtime.ewma_param) / 10.0
+            && at_max_concurrency
+        {
+            self.adjust(&mut stats, available_permits, concurrency_permit);
+        }
+    }
+
+    /// Register a completed call of the inner service that was admitted on the
+    /// fast path, with the given latency.
+    ///
+    /// The fast path is taken while there's capacity to spare, when the
+    /// controller has nothing to learn, so these calls are left out of the
+    /// statistics and the stats are only locked if the concurrency is waiting
+    /// to shrink. Calls that fail, or that complete once the capacity has run
+    /// out, are measured as usual as the controller has to see those.
+    pub(crate) fn release_fast(
+        &mut self,
+        elapsed: Duration,
+        outcome: Outcome,
+        bytes: u64,
+        concurrency_permit: Permit,
+    ) {
+        let available_permits = self.available_concurrency.available_permits();
+        // The same leeway as `stop`, but from the permits as the concurrency
+        // is in the stats.
+        let concurrency =
+            available_permits + self.held_concurrency.count.load(atomic::Ordering::Acquire);
+        if outcome == Outcome::Failure || available_permits <= usize::max(1, concurrency / 10) {
+            self.stop(Duration::ZERO, elapsed, outcome, bytes, concurrency_permit);
+            return;
+        }
+        #[cfg(feature = "metrics")]
+        {
+            histogram!("loadshedder.queue_latency", 0.0, self.labels.get());
+            histogram!("loadshedder.latency", elapsed, self.labels.get());
+        }
+        if self.forgets_pending.load(atomic::Ordering::Acquire) {
+            let mut stats = self.stats.lock().unwrap();
+            if stats.pending_forgets > 0 {
+                concurrency_permit.forget();
+                stats.pending_forgets -= 1;
+            }
+            self.forgets_pending
+                .store(stats.pending_forgets > 0, atomic::Ordering::Release);
+        }
+    }
+
+    /// Shrink the queue while requests spend too much of their time in it,
+    /// every queued request inherits a slow period's backlog so this amplifies
+    /// the tail latency.
+    fn limit_tail_amplification(
+        &self,
+        stats: &mut ConfStats,
+        queued: f64,
+        elapsed: f64,
+        max_amplification: f64,
+    ) {
+        // The queue is never scaled down further than this, so it can recover.
+        const MIN_QUEUE_SCALE: f64 = 0.01;
+        let ewma_param = stats.runtime.ewma_param;
+        if elapsed > 0.0 {
+            let amplification = (queued + elapsed) / elapsed;
+            stats.tail_amplification =
+                (stats.tail_amplification * (1.0 - ewma_param)) + (ewma_param * amplification);
+        }
+        #[cfg(feature = "metrics")]
+        gauge!(
+            "loadshedder.tail_amplification",
+            stats.tail_amplification,
+            self.labels.get()
+        );
+        stats.queue_scale = if stats.tail_amplification > max_amplification {
+            stats.queue_scale * (1.0 - ewma_param)
+        } else {
+            stats.queue_scale / (1.0 - ewma_param)
+        }
+        .clamp(MIN_QUEUE_SCALE, 1.0);
+    }
+
+    /// Every interval, check whether the service can cope with a higher maximum
+    /// concurrency, returning the current maximum.
+    ///
+    /// If the concurrency is pressing against the maximum it's raised, then if
+    /// the latency is worse by the next check it's lowered again.
+    fn probe_max_concurrency(&self, stats: &mut ConfStats, interval: Duration) -> usize {
+        // How much worse the latency can get before a probe counts as failed.
+        const TOLERANCE: f64 = 0.1;
+        // Start again from the configured maximum whenever it's changed, so
+        // that an earlier probe can't keep the maximum above it.
+        if stats.probe_base != stats.runtime.max_concurrency {
+            stats.probe_base = stats.runtime.max_concurrency;
+            stats.probed_max_concurrency = None;
+            stats.probe = None;
+        }
+        let max_concurrency = *stats
+            .probed_max_concurrency
+            .get_or_insert(stats.runtime.max_concurrency);
+        if stats.last_probe.elapsed() < interval {
+            return max_concurrency;
+        }
+        stats.last_probe = Instant::now();
+        // The concurrency can be cycling below the maximum, so it counts if
+        // it's reached it at any point since the last probe.
+        let reached_max =
+            std::mem::take(&mut stats.reached_max) || stats.concurrency >= max_concurrency;
+        let max_concurrency = match stats.probe.take() {
+            Some((baseline_latency, previous_max)) => {
+                let degraded = stats.average_latency > baseline_latency * (1.0 + TOLERANCE)
+                    || stats.average_latency > stats.runtime.target.as_secs_f64();
+                if degraded {
+                    previous_max
+                } else {
+                    max_concurrency
+                }
+            }
+            None if reached_max => {
+                stats.probe = Some((stats.average_latency, max_concurrency));
+                max_concurrency.saturating_add(usize::max(1, max_concurrency / 10))
+            }
+            None => max_concurrency,
+        }
+        .min(Semaphore::MAX_PERMITS)
+        .max(stats.runtime.min_concurrency);
+        stats.probed_max_concurrency = Some(max_concurrency);
+        #[cfg(feature = "metrics")]
+        gauge!(
+            "loadshedder.max_concurrency",
+            max_concurrency as f64,
+            self.labels.get()
+        );
+        max_concurrency
+    }
+
+    /// Set the average latency from the latency window, if there is one and
+    /// it has any samples.
+    fn summarise_window(&self, stats: &mut ConfStats) {
+        let Some(window) = self.config.latency_window else {
+            return;
+        };
+        stats.recent_latencies.prune(Instant::now(), window);
+        if let Some(latency) = stats
+            .recent_latencies
+            .summarise(self.config.target_percentile)
+        {
+            stats.average_latency = latency;
+        }
+    }
+
+    /// Check the concurrency semaphore still has as many permits as the stats
+    /// say it should, correcting the stats if they've drifted apart.
+    pub(crate) fn reconcile(&self, stats: &mut ConfStats) {
+        let held = self.held_concurrency.count.load(atomic::Ordering::Acquire);
+        let mut total = self.available_concurrency.available_permits() + held;
+        let expected = stats.concurrency + stats.pending_forgets;
+        let drift = total as isize - expected as isize;
+        // Permits are taken and released without holding the stats lock, so a
+        // request can be caught part way through. Only correct drift that's
+        // seen twice in a row.
+        let persistent = drift != 0 && drift == stats.drift;
+        stats.drift = drift;
+        if !persistent {
+            return;
+        }
+        stats.drift = 0;
+        #[cfg(feature = "metrics")]
+        increment_counter!("loadshedder.concurrency_drift", self.labels.get());
+        if total == 0 {
+            // Everything would stop.
+            self.available_concurrency.add_permits(1);
+            self.dispatch();
+            total = 1;
+        }
+        stats.pending_forgets = stats.pending_forgets.min(total - 1);
+        #[cfg(feature = "decision-log")]
+        self.record(Decision::Concurrency {
+            from: stats.concurrency,
+            to: total - stats.pending_forgets,
+        });
+        stats.concurrency = total - stats.pending_forgets;
+        #[cfg(feature = "metrics")]
+        gauge!(
+            "loadshedder.capacity",
+            stats.concurrency as f64,
+            self.labels.with([("component", "service")])
+        );
+    }
+
+    /// Increase or decrease the concurrency based on how the throughput and
+    /// latency has changed since the last adjustment.
+    ///
+    /// The permit is forgotten if the concurrency is decreased, if there's no
+    /// permit to forget one will be forgotten when it's next available.
+    pub(crate) fn adjust(
+        &self,
+        stats: &mut ConfStats,
+        available_permits: usize,
+        concurrency_permit: Option<Permit>,
+    ) {
+        self.reconcile(stats);
+        self.summarise_window(stats);
+        // Plausibly should be using average latency at capacity here and
+        // stats.concurrency but this appears to work. It might do weird
+        // things if it's been running under capacity for a while then spikes.
+        let current_concurrency =
+            (stats.concurrency + stats.pending_forgets).saturating_sub(available_permits);
+        let throughput = current_concurrency as f64 / stats.average_latency;
+        #[cfg(feature = "metrics")]
+        gauge!("loadshedder.throughput", throughput, self.labels.get());
+        // Once a higher concurrency stops buying more throughput the service
+        // has hit its ceiling, so stop increasing until something changes.
+        if let Some(margin) = self.config.throughput_halt_margin {
+            if stats.increase_halted {
+                let (halted_throughput, halted_latency) = stats.halted_at;
+                let moved = |now: f64, then: f64| (now - then).abs() > then * margin;
+                if moved(throughput, halted_throughput)
+                    || moved(stats.average_latency, halted_latency)
+                {
+                    stats.increase_halted = false;
+                }
+            } else if stats.last_increased
+                && throughput < stats.previous_throughput * (1.0 + margin)
+            {
+                stats.increase_halted = true;
+                stats.halted_at = (throughput, stats.average_latency);
+            }
+        }
+        // Was the throughput better or worse than it was previously.
+        let negative_gradient = (throughput > stats.previous_throughput)
+            ^ (current_concurrency > stats.previous_concurrency);
+        // A queue filling up is a sign of overload before the latency shows
+        // it, so back off early if that's enabled.
+        let queued = stats
+            .queue_capacity
+            .saturating_sub(self.available_queue.available_permits());
+        let queue_growing = self.config.queue_backoff_fraction.is_some_and(|fraction| {
+            queued > stats.previous_queued && queued as f64 > fraction * stats.queue_capacity as f64
+        });
+        // The gap between the fast and slow averages is how fast the latency
+        // is rising, back off if it'll cross the target at that rate.
+        let trending_over = self.config.slow_ewma_param.is_some()
+            && 2.0 * stats.average_latency - stats.slow_average_latency
+                > stats.runtime.target.as_secs_f64();
+        // The main average falling well below the slow one means the service
+        // is recovering, so a throughput dip alone, which is likely the
+        // backlog clearing, isn't a reason to back off.
+        let recovering = self.recovering(stats);
+        let runtime = stats.runtime;
+        let max_concurrency = match self.config.probe_interval {
+            Some(interval) => self.probe_max_concurrency(stats, interval),
+            None => runtime.max_concurrency,
+        }
+        .min(Semaphore::MAX_PERMITS);
+        let max_concurrency = self.warmup_max_concurrency(stats, max_concurrency);
+        stats.reached_max |= stats.concurrency >= max_concurrency;
+        // The limits may have changed, so move back inside them.
+        let below_min = stats.concurrency < runtime.min_concurrency;
+        let above_max = stats.concurrency > max_concurrency;
+        // Failures are never evidence that the service can cope with more
+        // load, so too many of them count the same as being over the target
+        // latency. The odd failure is normal, so it takes more than the
+        // maximum rate.
+        let (completions, failures) = std::mem::take(&mut stats.outcomes);
+        let failing =
+            failures > 0 && failures as f64 > self.config.max_failure_rate * completions as f64;
+        if !below_min
+            && (above_max
+                || (negative_gradient && !recovering)
+                || (stats.average_latency > runtime.target.as_secs_f64())
+                || queue_growing
+                || trending_over
+                || failing)
+        {
+            // Don't reduce concurrency below the minimum, which is at least 1
+            // or everything stops.
+            if stats.concurrency > runtime.min_concurrency {
+                // negative gradient so decrease concurrency
+                match concurrency_permit {
+                    Some(permit) => permit.forget(),
+                    None => match self.available_concurrency.try_acquire() {
+                        Ok(permit) => permit.forget(),
+                        Err(_) => {
+                            stats.pending_forgets += 1;
+                            self.forgets_pending.store(true, atomic::Ordering::Release);
+                        }
+                    },
+                }
+                stats.concurrency -= 1;
+                stats.desired_concurrency = stats.concurrency;
+                stats.decreases += 1;
+                #[cfg(feature = "decision-log")]
+                self.record(Decision::Concurrency {
+                    from: stats.concurrency + 1,
+                    to: stats.concurrency,
+                });
+                #[cfg(feature = "metrics")]
+                {
+                    gauge!(
+                        "loadshedder.capacity",
+                        stats.concurrency as f64,
+                        self.labels.with([("component", "service")])
+                    );
+                    increment_counter!(
+                        "loadshedder.adjustment",
+                        self.labels.with([("direction", "down")])
+                    );
+                }
+
+                // Adjust the average latency assuming that the change in
+                // concurrency doesn't affect the service latency, which is
+                // closer to the truth than the latency not changing.
+                let latency_factor = stats.concurrency as f64 / (stats.concurrency as f64 + 1.0);
+                stats.average_latency *= latency_factor;
+                stats.slow_average_latency *= latency_factor;
+                stats.average_latency_at_capacity *= latency_factor;
+                stats.at_min_concurrency = false;
+            } else {
+                // The overload can't be handled by reducing the concurrency,
+                // so make sure it's seen.
+                #[cfg(feature = "metrics")]
+                increment_counter!("loadshedder.at_min_concurrency", self.labels.get());
+                if !std::mem::replace(&mut stats.at_min_concurrency, true) {
+                    if let Some(on_event) = &self.config.on_event {
+                        on_event.emit(LoadShedEvent::AtMinConcurrency);
+                    }
+                }
+            }
+            stats.last_increased = false;
+        } else if !below_min
+            && (stats.increase_halted
+                || stats.concurrency >= max_concurrency
+                || self.below_latency_floor(stats))
+        {
+            // Only the maximum stops the controller wanting to go higher.
+            stats.desired_concurrency = if stats.increase_halted || self.below_latency_floor(stats)
+            {
+                stats.concurrency
+            } else {
+                stats
+                    .concurrency
+                    .saturating_add(self.increase_step(stats, usize::MAX, recovering))
+            };
+            stats.at_min_concurrency = false;
+            stats.last_increased = false;
+        } else {
+            stats.at_min_concurrency = false;
+            let step = self.increase_step(stats, max_concurrency, recovering);
+            self.add_concurrency(stats, step);
+            stats.concurrency += step;
+            stats.desired_concurrency = stats.concurrency;
+            stats.increases += 1;
+            #[cfg(feature = "decision-log")]
+            self.record(Decision::Concurrency {
+                from: stats.concurrency - step,
+                to: stats.concurrency,
+            });
+            #[cfg(feature = "metrics")]
+            {
+                gauge!(
+                    "loadshedder.capacity",
+                    stats.concurrency as f64,
+                    self.labels.with([("component", "service")])
+                );
+                increment_counter!(
+                    "loadshedder.adjustment",
+                    self.labels.with([("direction", "up")])
+                );
+            }
+
+            // Adjust the average latency assuming that the change in
+            // concurrency doesn't affect the service latency, which is
+            // closer to the truth than the latency not changing.
+            let latency_factor =
+                stats.concurrency as f64 / (stats.concurrency as f64 - step as f64);
+            stats.average_latency *= latency_factor;
+            stats.slow_average_latency *= latency_factor;
+            stats.average_latency_at_capacity *= latency_factor;
+            stats.last_increased = true;
+        }
+
+        stats.previous_throughput = throughput;
+        stats.previous_concurrency = current_concurrency;
+        stats.previous_queued = queued;
+        stats.last_changed = Instant::now();
+        #[cfg(feature = "metrics")]
+        gauge!(
+            "loadshedder.concurrency_constrained",
+            stats
+                .desired_concurrency
+                .saturating_sub(self.effective_concurrency(stats)) as f64,
+            self.labels.get()
+        );
+    }
+
+    /// The concurrency that can actually be used, which is less than the
+    /// concurrency limit while permits are waiting to be forgotten or the
+    /// global concurrency limit is binding.
+    pub(crate) fn effective_concurrency(&self, stats: &ConfStats) -> usize {
+        let held = self.held_concurrency.count.load(atomic::Ordering::Acquire);
+        let local = (self.available_concurrency.available_permits() + held)
+            .saturating_sub(stats.pending_forgets)
+            .min(stats.concurrency);
+        match &self.config.global_concurrency {
+            // Only approximate, as some of the held permits may be waiting
+            // for a global permit rather than holding one.
+            Some(global) => local.min(held + global.available_permits()),
+            None => local,
+        }
+    }
+
+    /// How much to increase the concurrency by, one unless the steps are
+    /// proportional to the headroom below the target.
+    /// A recovering service gets twice the step, to use its spare capacity
+    /// sooner.
+    fn increase_step(&self, stats: &ConfStats, max_concurrency: usize, recovering: bool) -> usize {
+        let boost = if recovering { 2 } else { 1 };
+        let Some(max_step) = self.config.max_increase_step else {
+            return boost
+                .min(max_concurrency.saturating_sub(stats.concurrency))
+                .max(1);
+        };
+        // Step by as many times as the latency is below the target, so the
+        // steps shrink to one as the latency nears it.
+        let headroom = stats.runtime.target.as_secs_f64() / stats.average_latency - 1.0;
+        let step = if headroom.is_finite() {
+            headroom.max(1.0).min(max_step as f64) as usize
+        } else {
+            max_step
+        };
+        (step * boost)
+            .min(max_concurrency.saturating_sub(stats.concurrency))
+            .max(1)
+    }
+
+    /// Whether the latency is clearly falling, with the main average below the
+    /// slow one by more than the recovery margin.
+    fn recovering(&self, stats: &ConfStats) -> bool {
+        self.config.slow_ewma_param.is_some()
+            && self.config.recovery_margin.is_some_and(|margin| {
+                stats.average_latency < stats.slow_average_latency * (1.0 - margin)
+            })
+    }
+
+    /// Whether the average latency is so low that the work is trivial, and more
+    /// concurrency won't achieve anything.
+    fn below_latency_floor(&self, stats: &ConfStats) -> bool {
+        self.config
+            .latency_floor
+            .is_some_and(|floor| stats.average_latency < floor.as_secs_f64())
+    }
+
+    /// Start the background task that adjusts the concurrency, if it's enabled
+    /// and hasn't already been started.
+    ///
+    /// This must be called from within a Tokio runtime.
+    pub(crate) fn start_control_task(&self) {
+        let Some(interval) = self.config.control_interval else {
+            return;
+        };
+        self.control_task.call_once(|| {
+            let conf = self.clone();
+            tokio::spawn(async move {
+                let mut ticks = tokio::time::interval(interval);
+                ticks.set_missed_tick_behavior(MissedTickBehavior::Delay);
+                loop {
+                    ticks.tick().await;
+                    // Stop once the task holds the only reference to the stats,
+                    // as nothing else can start a request.
+                    if Arc::strong_count(&conf.stats) == 1 {
+                        break;
+                    }
+                    let mut stats = conf.stats.lock().unwrap();
+                    conf.refresh_runtime_config(&mut stats);
+                    while stats.pending_forgets > 0 {
+                        match conf.available_concurrency.try_acquire() {
+                            Ok(permit) => permit.forget(),
+                            Err(_) => break,
+                        }
+                        stats.pending_forgets -= 1;
+                    }
+                    if std::mem::take(&mut stats.sampled_at_capacity) {
+                        let available_permits = conf.available_concurrency.available_permits();
+                        conf.adjust(&mut stats, available_permits, None);
+                    }
+                }
+            });
+        });
+    }
+}
+
+impl LoadShedConf {
+    /// Start the background task that saves the learned state, if it's
+    /// persisted and the task hasn't already been started.
+    ///
+    /// This must be called from within a Tokio runtime.
+    pub(crate) fn start_persistence_task(&self) {
+        let Some(persistence) = self.config.persistence.clone() else {
+            return;
+        };
+        self.persist_task.call_once(|| {
+            // Stop saving once the service is gone.
+            let stats = Arc::downgrade(&self.stats);
+            tokio::spawn(async move {
+                let mut ticks = tokio::time::interval(persistence.interval);
+                ticks.set_missed_tick_behavior(MissedTickBehavior::Delay);
+                // The first tick is immediate, and there's nothing new to save.
+                ticks.tick().await;
+                loop {
+                    ticks.tick().await;
+                    let Some(stats) = stats.upgrade() else {
+                        break;
+                    };
+                    let state = Self::export_state(&stats.lock().unwrap());
+                    persistence.store.save(&state);
+                }
+            });
+        });
+    }
+}
+
+#[cfg(feature = "decision-log")]
+impl LoadShedConf {
+    /// Add a decision to the log, if there is one.
+    fn record(&self, decision: Decision) {
+        if let Some(decisions) = &self.decisions {
+            decisions.record(decision);
+        }
+    }
+
+    /// Remove and return the recorded decisions.
+    pub(crate) fn take_decisions(&self) -> Vec<crate::DecisionRecord> {
+        self.decisions
+            .as_ref()
+            .map_or_else(Vec::new, |decisions| decisions.take())
+    }
+}
+
+#[cfg(feature = "metrics")]
+impl LoadShedConf {
+    /// Spawn a task that emits the gauges every interval, until the service
+    /// it's reporting on is dropped.
+    pub(crate) fn spawn_reporter(&self, interval: Duration) -> tokio::task::JoinHandle<()> {
+        // Only hold a weak reference so that this doesn't keep the control
+        // task alive.
+        let stats = Arc::downgrade(&self.stats);
+        let counts = self.counts.clone();
+        let labels = self.labels.clone();
+        tokio::spawn(async move {
+            let mut ticks = tokio::time::interval(interval);
+            ticks.set_missed_tick_behavior(MissedTickBehavior::Delay);
+            let mut previous = (0, 0);
+            loop {
+                ticks.tick().await;
+                let Some(stats) = stats.upgrade() else {
+                    break;
+                };
+                let stats = stats.lock().unwrap();
+                gauge!(
+                    "loadshedder.capacity",
+                    stats.concurrency as f64,
+                    labels.with([("component", "service")])
+                );
+                gauge!(
+                    "loadshedder.capacity",
+                    stats.queue_capacity as f64,
+                    labels.with([("component", "queue")])
+                );
+                gauge!(
+                    "loadshedder.average_latency",
+                    stats.average_latency,
+                    labels.get()
+                );
+                gauge!(
+                    "loadshedder.average_latency_at_capacity",
+                    stats.average_latency_at_capacity,
+                    labels.get()
+                );
+                let current = (
+                    counts.accepted.load(atomic::Ordering::Relaxed),
+                    counts.shed.load(atomic::Ordering::Relaxed),
+                );
+                let accepted = current.0 - previous.0;
+                let shed = current.1 - previous.1;
+                previous = current;
+                let total = accepted + shed;
+                let shed_fraction = if total > 0 {
+                    shed as f64 / total as f64
+                } else {
+                    0.0
+                };
+                gauge!("loadshedder.shed_fraction", shed_fraction, labels.get());
+            }
+        })
+    }
+}
+
+#[cfg(feature = "internals")]
+impl LoadShedConf {
+    /// Lock and return the current statistics.
+    pub fn stats(&self) -> std::sync::MutexGuard<'_, ConfStats> {
+        self.stats.lock().unwrap()
+    }
+
+    /// The target average latency.
+    pub fn target(&self) -> Duration {
+        self.stats().runtime.target
+    }
+
+    /// The exponentially weighted moving average parameter.
+    pub fn ewma_param(&self) -> f64 {
+        self.stats().runtime.ewma_param
+    }
+
+    /// The number of permits currently available in the queue semaphore.
+    pub fn available_queue(&self) -> usize {
+        self.available_queue.available_permits()
+    }
+
+    /// The number of permits currently available in the concurrency
+    /// semaphore.
+    pub fn available_concurrency(&self) -> usize {
+        self.available_concurrency.available_permits()
+    }
+}
+
+impl ConfStats {
+    /// The rate requests are completing at, per second.
+    pub(crate) fn throughput(&self) -> f64 {
+        if self.completion_interval > 0.0 {
+            1.0 / self.completion_interval
+        } else {
+            0.0
+        }
+    }
+}
+
+#[cfg(feature = "internals")]
+impl ConfStats {
+    /// The current average latency in seconds.
+    pub fn average_latency(&self) -> f64 {
+        self.average_latency
+    }
+
+    /// The average latency in seconds measured while at the concurrency limit.
+    pub fn average_latency_at_capacity(&self) -> f64 {
+        self.average_latency_at_capacity
+    }
+
+    /// The capacity of the queue.
+    pub fn queue_capacity(&self) -> usize {
+        self.queue_capacity
+    }
+
+    /// The concurrency limit.
+    pub fn concurrency(&self) -> usize {
+        self.concurrency
+    }
+}
+
+#[cfg(feature = "internals")]
+impl Permit {
+    /// The name of the component this permit is for.
+    pub fn component(&self) -> &'static str {
+        self.component
+    }
+}
+
+/// Labels added to every metric a load shedder emits, see
+/// [`LoadShedLayer::metric_labels`](crate::LoadShedLayer::metric_labels).
+#[derive(Debug, Clone, Default)]
+pub(crate) struct MetricLabels {
+    #[cfg(feature = "metrics")]
+    labels: Arc<Vec<Label>>,
+}
+
+impl MetricLabels {
+    /// Create the labels from their keys and values.
+    #[cfg_attr(not(feature = "metrics"), allow(unused_variables))]
+    pub(crate) fn new(labels: &[(&'static str, String)]) -> Self {
+        Self {
+            #[cfg(feature = "metrics")]
+            labels: Arc::new(
+                labels
+                    .iter()
+                    .map(|(key, value)| Label::new(*key, value.clone()))
+                    .collect(),
+            ),
+        }
+    }
+
+    /// The labels to emit a metric with.
+    #[cfg(feature = "metrics")]
+    pub(crate) fn get(&self) -> Vec<Label> {
+        self.labels.to_vec()
+    }
+
+    /// The labels to emit a metric with, followed by the given ones.
+    #[cfg(feature = "metrics")]
+    pub(crate) fn with<const N: usize>(
+        &self,
+        extra: [(&'static str, &'static str); N],
+    ) -> Vec<Label> {
+        let mut labels = self.get();
+        labels.extend(
+            extra
+                .into_iter()
+                .map(|(key, value)| Label::from_static_parts(key, value)),
+        );
+        labels
+    }
+}
+
+/// A permit for something, this is used for updating metrics.
+#[derive(Debug)]
+pub struct Permit {
+    /// The permit, this is only optional to enable the forget function.
+    pub(crate) permit: Option<OwnedSemaphorePermit>,
+    /// The name of the component this permit is for, used as a metric label.
+    #[allow(unused)]
+    pub(crate) component: &'static str,
+    /// The custom queue to hand this permit on to when it's released.
+    pub(crate) handoff: Option<SharedQueue>,
+    /// Counts the concurrency permits held by requests, if this is one.
+    pub(crate) held: Option<Arc<HeldPermits>>,
+    /// The permit from the global concurrency limit, if there is one.
+    pub(crate) global: Option<OwnedSemaphorePermit>,
+    /// The labels to emit the size metric with.
+    pub(crate) labels: MetricLabels,
+    /// Whether this was taken on the fast path, so the request it's for is
+    /// only measured if it fails or completes at capacity.
+    pub(crate) fast: bool,
+}
+
+impl Permit {
+    /// Create a new permit for the given component.
+    pub(crate) fn new(
+        permit: OwnedSemaphorePermit,
+        component: &'static str,
+        labels: &MetricLabels,
+    ) -> Self {
+        #[cfg(feature = "metrics")]
+        increment_gauge!(
+            "loadshedder.size",
+            1.0,
+            labels.with([("component", component)])
+        );
+        Self {
+            permit: Some(permit),
+            component,
+            handoff: None,
+            held: None,
+            global: None,
+            labels: labels.clone(),
+            fast: false,
+        }
+    }
+
+    /// Count this permit as held in the given counter until it's released.
+    pub(crate) fn counted(mut self, held: &Arc<HeldPermits>) -> Self {
+        held.count.fetch_add(1, atomic::Ordering::AcqRel);
+        self.held = Some(held.clone());
+        self
+    }
+
+    /// Create a new permit that will be passed to the next request waiting in
+    /// the given queue when it's released.
+    pub(crate) fn queued(
+        permit: OwnedSemaphorePermit,
+        queue: SharedQueue,
+        held: Arc<HeldPermits>,
+        labels: &MetricLabels,
+    ) -> Self {
+        let mut permit = Self::new(permit, "service", labels).counted(&held);
+        permit.handoff = Some(queue);
+        permit
+    }
+
+    /// Forget the permit, essentially reducing the size of the semaphore by one.
+    /// Note this does still decrement the size metric.
+    pub(crate) fn forget(mut self) {
+        self.permit.take().unwrap().forget()
+    }
+}
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        #[cfg(feature = "metrics")]
+        decrement_gauge!(
+            "loadshedder.size",
+            1.0,
+            self.labels.with([("component", self.component)])
+        );
+        if let Some(held) = &self.held {
+            held.count.fetch_sub(1, atomic::Ordering::AcqRel);
+        }
+        if let (Some(permit), Some(queue), Some(held)) =
+            (self.permit.take(), &self.handoff, &self.held)
+        {
+            let mut waiting = queue.lock().unwrap();
+            // If nobody takes it the permit goes back to the semaphore.
+            drop(hand_off(queue, &mut **waiting, permit, held, &self.labels));
+        }
+        if let Some(held) = &self.held {
+            held.released.notify_waiters();
+        }
+    }
+}
+
+/// The concurrency permits held by requests.
+#[derive(Debug, Default)]
+pub(crate) struct HeldPermits {
+    /// The number of permits held.
+    pub(crate) count: AtomicUsize,
+    /// Notified whenever a permit is released.
+    pub(crate) released: Notify,
+}
+
+/// Where a request that's been let into the load shedder is.
+#[derive(Debug)]
+pub(crate) enum Place {
+    /// In the queue, holding a queue permit.
+    Queued(Permit),
+    /// Straight through to the inner service, holding a concurrency permit.
+    Admitted(Permit),
+}
+
+/// Counts of the requests that have been through the load shedder.
+#[derive(Debug, Default)]
+pub(crate) struct RequestCounts {
+    /// The number of requests admitted to the inner service.
+    pub(crate) accepted: AtomicU64,
+    /// The number of requests shed.
+    pub(crate) shed: AtomicU64,
+}
+
+/// A limit on the total size of the requests in flight.
+#[derive(Debug)]
+pub(crate) struct ByteBudget {
+    /// The maximum number of bytes allowed in flight.
+    pub(crate) limit: u64,
+    /// The number of bytes currently in flight.
+    pub(crate) in_flight: AtomicU64,
+}
+
+/// Space reserved in the byte budget, released when dropped.
+#[derive(Debug)]
+pub(crate) struct ByteReservation {
+    /// The budget this is reserved from, `None` if bytes aren't limited.
+    pub(crate) budget: Option<Arc<ByteBudget>>,
+    /// The number of bytes reserved.
+    pub(crate) bytes: u64,
+}
+
+impl Drop for ByteReservation {
+    fn drop(&mut self) {
+        if let Some(budget) = &self.budget {
+            budget
+                .in_flight
+                .fetch_sub(self.bytes, atomic::Ordering::AcqRel);
+        }
+    }
+}
+
+/// A token bucket limiting the rate requests are admitted at.
+#[derive(Debug)]
+pub(crate) struct TokenBucket {
+    /// The number of tokens added per second.
+    rate: f64,
+    /// The most tokens the bucket can hold.
+    burst: f64,
+    /// The tokens in the bucket and when they were last topped up.
+    tokens: Mutex<(f64, Instant)>,
+}
+
+impl TokenBucket {
+    pub(crate) fn new(rate: f64, burst: f64) -> Self {
+        Self {
+            rate,
+            burst,
+            tokens: Mutex::new((burst, Instant::now())),
+        }
+    }
+
+    /// Take a token if there's one available.
+    pub(crate) fn take(&self) -> bool {
+        let mut tokens = self.tokens.lock().unwrap();
+        let available = self.top_up(&mut tokens);
+        if *available >= 1.0 {
+            *available -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Whether there are no tokens to take right now.
+    pub(crate) fn is_empty(&self) -> bool {
+        *self.top_up(&mut self.tokens.lock().unwrap()) < 1.0
+    }
+
+    /// Add the tokens for the time since they were last topped up, returning
+    /// the tokens available.
+    fn top_up<'a>(&self, tokens: &'a mut (f64, Instant)) -> &'a mut f64 {
+        let (available, last) = tokens;
+        let now = Instant::now();
+        *available =
+            (*available + now.duration_since(*last).as_secs_f64() * self.rate).min(self.burst);
+        *last = now;
+        available
+    }
+}
+
+/// A request that has been admitted by a load shedder, see
+/// [`LoadShed::acquire`](crate::LoadShed::acquire).
+///
+/// The request counts as in flight until this is either completed, which
+/// records its latency and outcome, or dropped, which releases its place
+/// without recording anything, for example if it was cancelled. Completing
+/// consumes the guard, so a request can't be accounted for twice:
+///
+/// ```compile_fail,E0382
+/// # use little_loadshedder::{LoadShed, LoadShedResponse, Outcome};
+/// # async fn work(service: LoadShed<()>) {
+/// if let LoadShedResponse::Inner(guard) = service.acquire().await {
+///     guard.complete(Outcome::Success);
+///     guard.complete(Outcome::Success);
+/// }
+/// # }
+/// ```
+///
+/// Nor used after it's been completed:
+///
+/// ```compile_fail,E0382
+/// # use little_loadshedder::{LoadShed, LoadShedResponse, Outcome};
+/// # async fn work(service: LoadShed<()>) {
+/// if let LoadShedResponse::Inner(guard) = service.acquire().await {
+///     guard.complete(Outcome::Success);
+///     drop(guard);
+/// }
+/// # }
+/// ```
+#[derive(Debug)]
+#[must_use = "dropping the guard releases the request's place without recording its latency"]
+pub struct LoadShedGuard {
+    conf: LoadShedConf,
+    /// When the request arrived at the load shedder.
+    arrived: Instant,
+    /// When the request was admitted.
+    admitted: Instant,
+    /// How long after being admitted the inner service was ready.
+    ready: Duration,
+    /// When to measure the latency from instead of when the request was
+    /// admitted, if anything.
+    origin: Option<Instant>,
+    permit: Permit,
+    reservation: ByteReservation,
+    /// Whether this is a probe, which is left out of the statistics.
+    probe: bool,
+}
+
+impl LoadShedGuard {
+    pub(crate) fn new(
+        conf: LoadShedConf,
+        arrived: Instant,
+        permit: Permit,
+        reservation: ByteReservation,
+    ) -> Self {
+        Self {
+            conf,
+            arrived,
+            admitted: Instant::now(),
+            ready: Duration::ZERO,
+            origin: None,
+            permit,
+            reservation,
+            probe: false,
+        }
+    }
+
+    /// Measure the latency from the given time rather than from now.
+    pub(crate) fn measured_from(mut self, origin: Option<Instant>) -> Self {
+        self.origin = origin;
+        self
+    }
+
+    /// The number of bytes reserved for the request.
+    pub(crate) fn reserved_bytes(&self) -> u64 {
+        self.reservation.bytes
+    }
+
+    /// The inner service is now ready to be called.
+    pub(crate) fn ready(&mut self) {
+        self.ready = self.admitted.elapsed();
+    }
+
+    /// The request has completed with the given outcome, record the time
+    /// since it was admitted as its latency.
+    pub fn complete(self, outcome: Outcome) {
+        drop(self.finish(outcome));
+    }
+
+    /// The request has finished with the given outcome, but only record its
+    /// latency once the returned completion is dropped.
+    pub(crate) fn finish(self, outcome: Outcome) -> Completion {
+        Completion {
+            conf: self.conf,
+            queued: self.admitted - self.arrived,
+            start: self.admitted,
+            ready: self.ready,
+            origin: self.origin,
+            outcome,
+            permit: Some(self.permit),
+            reservation: self.reservation,
+            probe: self.probe,
+        }
+    }
+}
+
+/// A call of the inner service that's finished with the load shedder, it's
+/// registered as completed when this is dropped.
+#[derive(Debug)]
+pub(crate) struct Completion {
+    conf: LoadShedConf,
+    /// The time spent queued.
+    queued: Duration,
+    /// When the inner service was called.
+    start: Instant,
+    /// How long after being called the inner service was ready.
+    ready: Duration,
+    /// When to measure the latency from instead of the start, if anything.
+    origin: Option<Instant>,
+    outcome: Outcome,
+    /// Always `Some` until dropped.
+    permit: Option<Permit>,
+    reservation: ByteReservation,
+    probe: bool,
+}
+
+impl Drop for Completion {
+    fn drop(&mut self) {
+        if self.probe {
+            // Probes were forced past the limits, so they'd only mislead the
+            // controller.
+            #[cfg(feature = "metrics")]
+            histogram!(
+                "loadshedder.probe_latency",
+                self.start.elapsed(),
+                self.conf.labels.get()
+            );
+            return;
+        }
+        if let Some(permit) = self.permit.take() {
+            let elapsed = self.start.elapsed();
+            let latency = self.origin.map_or(elapsed, |origin| origin.elapsed());
+            if permit.fast {
+                self.conf
+                    .release_fast(latency, self.outcome, self.reservation.bytes, permit);
+            } else {
+                self.conf.stop(
+                    self.queued,
+                    latency,
+                    self.outcome,
+                    self.reservation.bytes,
+                    permit,
+                );
+            }
+            self.conf.check_capacity();
+            let attribution = LatencyAttribution {
+                queue: self.queued,
+                ready: self.ready,
+                service: elapsed.saturating_sub(self.ready),
+            };
+            #[cfg(feature = "metrics")]
+            histogram!(
+                "loadshedder.queue_ratio",
+                attribution.queue_ratio(),
+                self.conf.labels.get()
+            );
+            if let Some(on_event) = &self.conf.config.on_event {
+                on_event.emit(LoadShedEvent::Completed(attribution));
+            }
+        }
+    }
+}
+
+/// The most latency samples kept in the window, so a high request rate
+/// doesn't make each adjustment expensive.
+const MAX_WINDOW_SAMPLES: usize = 1024;
+
+/// The latency samples from a trailing window of time, see
+/// [`LoadShedLayer::latency_window`](crate::LoadShedLayer::latency_window).
+#[derive(Debug, Default)]
+pub(crate) struct LatencyWindow {
+    /// When each sample completed and its latency.
+    samples: VecDeque<(Instant, f64)>,
+    /// The sum of the latencies of the samples, so their mean is cheap.
+    total: f64,
+}
+
+impl LatencyWindow {
+    /// Add a sample that completed at `now`, dropping any that are now
+    /// outside the window.
+    fn record(&mut self, now: Instant, latency: f64, window: Duration) {
+        self.samples.push_back((now, latency));
+        self.total += latency;
+        self.prune(now, window);
+    }
+
+    /// Drop the samples that are older than the window, or past the most kept.
+    fn prune(&mut self, now: Instant, window: Duration) {
+        while self.samples.len() > MAX_WINDOW_SAMPLES
+            || self
+                .samples
+                .front()
+                .is_some_and(|&(at, _)| now.duration_since(at) > window)
+        {
+            if let Some((_, latency)) = self.samples.pop_front() {
+                self.total -= latency;
+            }
+        }
+        if self.samples.is_empty() {
+            // Don't let rounding errors build up.
+            self.total = 0.0;
+        }
+    }
+
+    /// Forget every sample.
+    pub(crate) fn clear(&mut self) {
+        self.samples.clear();
+        self.total = 0.0;
+    }
+
+    /// The mean latency of the samples, if there are any.
+    fn mean(&self) -> Option<f64> {
+        (!self.samples.is_empty()).then(|| self.total / self.samples.len() as f64)
+    }
+
+    /// The mean latency of the samples, or the given percentile of them, if
+    /// there are any.
+    fn summarise(&self, percentile: Option<f64>) -> Option<f64> {
+        let Some(percentile) = percentile else {
+            return self.mean();
+        };
+        if self.samples.is_empty() {
+            return None;
+        }
+        let mut latencies: Vec<f64> = self.samples.iter().map(|&(_, latency)| latency).collect();
+        let index = ((latencies.len() as f64 * percentile) as usize).min(latencies.len() - 1);
+        Some(*latencies.select_nth_unstable_by(index, f64::total_cmp).1)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn shrinking_the_queue_tolerates_permits_taken_first() {
+        let conf = LoadShedConf::new(&LoadShedConfig {
+            min_queue: 5,
+            ..LoadShedConfig::new(0.1, Duration::from_millis(100))
+        });
+        assert_eq!(conf.shrink_queue(2), Ok(2));
+        // Three were free, but another request takes two before the shrink.
+        let taken = conf.available_queue.try_acquire_many(2).unwrap();
+        assert_eq!(conf.shrink_queue(3), Ok(0));
+        assert_eq!(conf.available_queue.available_permits(), 1);
+        drop(taken);
+        conf.available_queue.close();
+        assert_eq!(conf.shrink_queue(1), Err(ShedReason::ShuttingDown));
+    }
+
+    #[test]
+    fn making_room_in_a_closed_queue_is_shutting_down() {
+        let conf = LoadShedConf::new(&LoadShedConfig::new(0.1, Duration::from_millis(100)));
+        let place = conf.extra_place().unwrap();
+        assert_eq!(conf.stats.lock().unwrap().queue_capacity, 2);
+        conf.shutdown();
+        assert!(matches!(conf.extra_place(), Err(ShedReason::ShuttingDown)));
+        assert_eq!(conf.stats.lock().unwrap().queue_capacity, 2);
+        drop(place);
+    }
+
+    #[test]
+    fn a_queue_shrink_is_completed_as_places_are_released() {
+        let conf = LoadShedConf::new(&LoadShedConfig::new(0.1, Duration::from_millis(100)));
+        let set_latency =
+            |latency| conf.stats.lock().unwrap().average_latency_at_capacity = latency;
+        let capacity = || conf.stats.lock().unwrap().queue_capacity;
+        // A tenth of the target leaves room for nine.
+        set_latency(0.01);
+        let mut places: Vec<_> = (0..6).map(|_| conf.join_queue().unwrap()).collect();
+        assert_eq!(capacity(), 9);
+
+        // Now there should only be room for one, but six places are held, so
+        // only the three free ones are removed.
+        set_latency(0.05);
+        assert!(conf.join_queue().is_err());
+        assert_eq!(capacity(), 6);
+        places.truncate(3);
+        assert!(conf.join_queue().is_err());
+        assert_eq!(capacity(), 3);
+        places.clear();
+        let place = conf.join_queue().unwrap();
+        assert_eq!(capacity(), 1);
+        assert_eq!(conf.available_queue.available_permits(), 0);
+        drop(place);
+        assert_eq!(conf.available_queue.available_permits(), 1);
+    }
+
+    #[test]
+    fn the_queue_permits_start_equal_to_its_capacity() {
+        let configs = [
+            LoadShedConfig::new(0.1, Duration::from_millis(100)),
+            LoadShedConfig {
+                min_queue: 5,
+                ..LoadShedConfig::new(0.1, Duration::from_millis(100))
+            },
+            LoadShedConfig {
+                min_queue: usize::MAX,
+                ..LoadShedConfig::new(0.1, Duration::from_millis(100))
+            },
+            LoadShedConfig {
+                concurrency_semaphore: Some(Arc::new(Semaphore::new(10))),
+                ..LoadShedConfig::new(0.1, Duration::from_millis(100))
+            },
+        ];
+        for config in configs {
+            let conf = LoadShedConf::new(&config);
+            assert_eq!(
+                conf.available_queue.available_permits(),
+                conf.stats.lock().unwrap().queue_capacity,
+            );
+        }
+    }
+
+    #[test]
+    fn a_service_slower_than_the_target_gets_the_minimum_queue() {
+        assert_eq!(desired_queue_capacity(10, 0.1, 0.2, 1.0, 1, usize::MAX), 1);
+        assert_eq!(desired_queue_capacity(10, 0.1, 1e9, 1.0, 1, usize::MAX), 1);
+        assert_eq!(
+            desired_queue_capacity(10, 0.1, f64::NAN, 1.0, 1, usize::MAX),
+            1
+        );
+    }
+
+    #[test]
+    fn the_queue_is_at_least_the_minimum() {
+        assert_eq!(desired_queue_capacity(10, 0.1, 0.2, 1.0, 4, usize::MAX), 4);
+        assert_eq!(desired_queue_capacity(10, 0.1, 0.05, 1.0, 4, 6), 6);
+        // The minimum wins over a lower maximum.
+        assert_eq!(desired_queue_capacity(10, 0.1, 0.05, 1.0, 8, 6), 8);
+    }
+
+    #[test]
+    fn the_queue_follows_littles_law() {
+        // Half the target leaves room for as many requests again.
+        assert_eq!(
+            desired_queue_capacity(10, 0.1, 0.05, 1.0, 1, usize::MAX),
+            10
+        );
+        assert_eq!(
+            desired_queue_capacity(10, 0.1, 0.025, 1.0, 1, usize::MAX),
+            30
+        );
+        assert_eq!(desired_queue_capacity(10, 0.1, 0.05, 0.5, 1, usize::MAX), 5);
+        // Exactly at the target there's no room at all.
+        assert_eq!(desired_queue_capacity(10, 0.1, 0.1, 1.0, 1, usize::MAX), 1);
+    }
+
+    #[test]
+    fn an_instant_service_gets_the_largest_queue() {
+        assert_eq!(desired_queue_capacity(10, 0.1, 0.0, 1.0, 1, 500), 500);
+        assert_eq!(
+            desired_queue_capacity(10, 0.1, 0.0, 1.0, 1, usize::MAX),
+            Semaphore::MAX_PERMITS
+        );
+    }
+
+    #[test]
+    fn a_huge_queue_is_limited_to_the_semaphore() {
+        assert_eq!(
+            desired_queue_capacity(usize::MAX, 0.1, 1e-9, 1.0, 1, usize::MAX),
+            Semaphore::MAX_PERMITS
+        );
+        assert_eq!(
+            desired_queue_capacity(10, 0.1, 0.05, 1.0, usize::MAX, usize::MAX),
+            Semaphore::MAX_PERMITS
+        );
+    }
+
+    #[test]
+    fn a_negative_or_nan_scale_gets_the_minimum_queue() {
+        assert_eq!(
+            desired_queue_capacity(10, 0.1, 0.05, -1.0, 2, usize::MAX),
+            2
+        );
+        assert_eq!(
+            desired_queue_capacity(10, 0.1, 0.05, f64::NAN, 2, usize::MAX),
+            2
+        );
+    }
+}
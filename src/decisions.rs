@@ -0,0 +1,86 @@
+//! A record of the decisions a load shedder makes, for replaying offline.
+
+use std::{
+    collections::VecDeque,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use crate::LoadShedResponse;
+
+/// A decision made by a load shedder and when it was made, see
+/// [`LoadShedLayer::decision_log`](crate::LoadShedLayer::decision_log).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DecisionRecord {
+    /// When the decision was made.
+    pub at: Instant,
+    /// What was decided.
+    pub decision: Decision,
+}
+
+/// The kinds of decision a load shedder records.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum Decision {
+    /// A request was admitted to the inner service.
+    Admitted,
+    /// A probe was forced through, see
+    /// [`LoadShed::admit_probe`](crate::LoadShed::admit_probe).
+    Probe,
+    /// A request was shed with the given response.
+    Shed(LoadShedResponse<()>),
+    /// A request completed, this is the sample the averages were updated with.
+    Sample {
+        /// The time it spent in the queue.
+        queued: Duration,
+        /// The time it spent in the inner service.
+        latency: Duration,
+        /// Whether it was classified as a success.
+        success: bool,
+    },
+    /// The concurrency limit was changed.
+    Concurrency {
+        /// The limit before.
+        from: usize,
+        /// The limit after.
+        to: usize,
+    },
+}
+
+/// The most recent decisions, up to a limit.
+#[derive(Debug)]
+pub(crate) struct DecisionLog {
+    records: Mutex<VecDeque<DecisionRecord>>,
+    capacity: usize,
+}
+
+impl DecisionLog {
+    /// Create a log that holds up to `capacity` records.
+    pub(crate) fn new(capacity: usize) -> Self {
+        Self {
+            records: Mutex::new(VecDeque::with_capacity(capacity.min(1024))),
+            capacity,
+        }
+    }
+
+    /// Record a decision made now, dropping the oldest record if the log is
+    /// full.
+    pub(crate) fn record(&self, decision: Decision) {
+        if self.capacity == 0 {
+            return;
+        }
+        let mut records = self.records.lock().unwrap();
+        if records.len() == self.capacity {
+            records.pop_front();
+        }
+        records.push_back(DecisionRecord {
+            at: tokio::time::Instant::now().into_std(),
+            decision,
+        });
+    }
+
+    /// Remove and return every record, oldest first.
+    pub(crate) fn take(&self) -> Vec<DecisionRecord> {
+        self.records.lock().unwrap().drain(..).collect()
+    }
+}
@@ -0,0 +1,76 @@
+//! Events describing what the load shedder is doing.
+
+use std::{fmt::Debug, sync::Arc, time::Duration};
+
+/// Something that happened in a load shedder, see
+/// [`LoadShedLayer::on_event`](crate::LoadShedLayer::on_event).
+#[derive(Debug, Clone, PartialEq)]
+#[non_exhaustive]
+pub enum LoadShedEvent {
+    /// A request that was admitted has completed.
+    Completed(LatencyAttribution),
+    /// The concurrency should be decreased because the service is overloaded,
+    /// but it's already at the minimum, see
+    /// [`LoadShedLayer::fail_fast_at_min_concurrency`](crate::LoadShedLayer::fail_fast_at_min_concurrency).
+    ///
+    /// This is emitted when the load shedder reaches this state, not for every
+    /// decrease it blocks.
+    AtMinConcurrency,
+    /// Requests have started being shed because the queue is full, see
+    /// [`LoadShedLayer::overload_debounce`](crate::LoadShedLayer::overload_debounce).
+    ///
+    /// This is emitted once when the load shedder enters this state, and is
+    /// followed by [`OverloadEnded`](Self::OverloadEnded) when it leaves.
+    OverloadStarted,
+    /// Requests have stopped being shed because the queue is full.
+    OverloadEnded,
+}
+
+/// Where a completed request spent its time.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct LatencyAttribution {
+    /// The time spent waiting in the queue.
+    pub queue: Duration,
+    /// The time spent waiting for the inner service to be ready.
+    pub ready: Duration,
+    /// The time spent in the inner service once it was ready.
+    pub service: Duration,
+}
+
+impl LatencyAttribution {
+    /// The total time the request spent in the load shedder.
+    pub fn total(&self) -> Duration {
+        self.queue + self.ready + self.service
+    }
+
+    /// The fraction of the total time spent waiting in the queue, this is also
+    /// emitted as the `loadshedder.queue_ratio` histogram.
+    ///
+    /// When this is high the load shedder's limits are the bottleneck rather
+    /// than the inner service, so there's room to raise them, when it's low
+    /// the inner service itself is slow.
+    pub fn queue_ratio(&self) -> f64 {
+        let total = self.total().as_secs_f64();
+        if total > 0.0 {
+            self.queue.as_secs_f64() / total
+        } else {
+            0.0
+        }
+    }
+}
+
+/// Receives the events from a load shedder.
+#[derive(Clone)]
+pub(crate) struct EventHandler(pub(crate) Arc<dyn Fn(&LoadShedEvent) + Send + Sync>);
+
+impl EventHandler {
+    pub(crate) fn emit(&self, event: LoadShedEvent) {
+        (self.0)(&event)
+    }
+}
+
+impl Debug for EventHandler {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("EventHandler").finish_non_exhaustive()
+    }
+}
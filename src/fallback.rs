@@ -0,0 +1,88 @@
+//! Sending shed requests to another service.
+
+use std::task::{Context, Poll};
+
+use tower::{Service, ServiceExt};
+
+use crate::{BoxFuture, Classify, DefaultClassifier, DefaultRequestInfo, LoadShed, RequestInfo};
+
+/// A [`LoadShed`] service that sends shed requests to a fallback service
+/// instead of rejecting them, see [`LoadShed::with_fallback_service`].
+#[derive(Debug, Clone)]
+pub struct FallbackLoadShed<Inner, Fallback, C = DefaultClassifier, I = DefaultRequestInfo> {
+    inner: LoadShed<Inner, C, I>,
+    fallback: Fallback,
+}
+
+impl<Inner, C, I> LoadShed<Inner, C, I> {
+    /// Send requests that would be shed to the `fallback` service instead, for
+    /// example a cheaper degraded version of the inner service.
+    ///
+    /// All shed requests are sent to the fallback, whatever the reason they
+    /// were shed, and it isn't load shed itself. The fallback is called with a
+    /// clone of itself in the same way as the inner service.
+    pub fn with_fallback_service<Fallback>(
+        self,
+        fallback: Fallback,
+    ) -> FallbackLoadShed<Inner, Fallback, C, I> {
+        FallbackLoadShed {
+            inner: self,
+            fallback,
+        }
+    }
+}
+
+impl<Inner, Fallback, C, I> FallbackLoadShed<Inner, Fallback, C, I> {
+    /// The load shedder that decides which requests go to the fallback, the
+    /// requests it sends there are counted in its statistics as shed.
+    pub fn load_shed(&self) -> &LoadShed<Inner, C, I> {
+        &self.inner
+    }
+
+    /// The fallback service.
+    pub fn fallback(&self) -> &Fallback {
+        &self.fallback
+    }
+}
+
+impl<Request, Inner, Fallback, C, I> Service<Request> for FallbackLoadShed<Inner, Fallback, C, I>
+where
+    Request: Send + 'static,
+    Inner: Service<Request> + Clone + Send + 'static,
+    Inner::Future: Send,
+    Fallback:
+        Service<Request, Response = Inner::Response, Error = Inner::Error> + Clone + Send + 'static,
+    Fallback::Future: Send,
+    C: Classify<Inner::Response> + Clone + Send + 'static,
+    I: RequestInfo<Request>,
+{
+    type Response = Inner::Response;
+    type Error = Inner::Error;
+    type Future = BoxFuture<Result<Self::Response, Self::Error>>;
+
+    /// Always ready because there's a queue between this service and the inner one.
+    fn poll_ready(&mut self, _: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, req: Request) -> Self::Future {
+        let response = self.inner.shed(
+            req,
+            |response, completion| {
+                drop(completion);
+                Ok(response)
+            },
+            |_, req| Err(req),
+            |panic| std::panic::resume_unwind(panic),
+        );
+        // As with the inner service, the clone hasn't been polled to readiness.
+        let fallback = self.fallback.clone();
+        Box::pin(async move {
+            let req = match response.await? {
+                Ok(response) => return Ok(response),
+                Err(req) => req,
+            };
+            fallback.oneshot(req).await
+        })
+    }
+}
@@ -0,0 +1,131 @@
+//! Injecting faults into an inner service, for testing how the load shedder
+//! reacts to them.
+
+use std::{
+    fmt::{self, Debug},
+    sync::{
+        atomic::{self, AtomicU64},
+        Arc,
+    },
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use tower::Service;
+
+use crate::BoxFuture;
+
+/// What to do to a call of the wrapped service, see [`FaultInjection`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum Fault {
+    /// Call the wrapped service as normal.
+    Pass,
+    /// Wait this long before calling the wrapped service.
+    Delay(Duration),
+    /// Fail with [`FaultError::Injected`] without calling the wrapped service.
+    Fail,
+    /// Never respond.
+    Hang,
+}
+
+/// The error from a [`FaultInjection`] service.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum FaultError<E> {
+    /// The call failed because [`Fault::Fail`] was injected.
+    Injected,
+    /// The wrapped service failed.
+    Inner(E),
+}
+
+impl<E: fmt::Display> fmt::Display for FaultError<E> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FaultError::Injected => f.write_str("injected fault"),
+            FaultError::Inner(error) => error.fmt(f),
+        }
+    }
+}
+
+impl<E: std::error::Error + 'static> std::error::Error for FaultError<E> {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            FaultError::Injected => None,
+            FaultError::Inner(error) => Some(error),
+        }
+    }
+}
+
+/// A service that injects faults into the calls of the service it wraps,
+/// following a schedule, to test a load shedder deterministically.
+///
+/// The schedule is given the number of each call, counting from zero across
+/// all clones, and returns the [`Fault`] to inject into it. For example
+/// `|call| if call % 10 == 0 { Fault::Fail } else { Fault::Pass }` fails every
+/// tenth call.
+#[derive(Clone)]
+pub struct FaultInjection<S> {
+    inner: S,
+    schedule: Arc<dyn Fn(u64) -> Fault + Send + Sync>,
+    calls: Arc<AtomicU64>,
+}
+
+impl<S> FaultInjection<S> {
+    /// Wrap `inner`, injecting the faults given by `schedule`.
+    pub fn new<F>(inner: S, schedule: F) -> Self
+    where
+        F: Fn(u64) -> Fault + Send + Sync + 'static,
+    {
+        Self {
+            inner,
+            schedule: Arc::new(schedule),
+            calls: Arc::default(),
+        }
+    }
+
+    /// The number of calls made so far.
+    pub fn calls(&self) -> u64 {
+        self.calls.load(atomic::Ordering::Relaxed)
+    }
+}
+
+impl<S: Debug> Debug for FaultInjection<S> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("FaultInjection")
+            .field("inner", &self.inner)
+            .field("calls", &self.calls)
+            .finish_non_exhaustive()
+    }
+}
+
+impl<Request, S> Service<Request> for FaultInjection<S>
+where
+    Request: Send + 'static,
+    S: Service<Request> + Clone + Send + 'static,
+    S::Future: Send,
+{
+    type Response = S::Response;
+    type Error = FaultError<S::Error>;
+    type Future = BoxFuture<Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx).map_err(FaultError::Inner)
+    }
+
+    fn call(&mut self, req: Request) -> Self::Future {
+        let call = self.calls.fetch_add(1, atomic::Ordering::Relaxed);
+        let fault = (self.schedule)(call);
+        // Take the service that was driven to readiness, leaving a clone.
+        let clone = self.inner.clone();
+        let mut inner = std::mem::replace(&mut self.inner, clone);
+        Box::pin(async move {
+            match fault {
+                Fault::Pass => {}
+                Fault::Delay(delay) => tokio::time::sleep(delay).await,
+                Fault::Fail => return Err(FaultError::Injected),
+                Fault::Hang => std::future::pending().await,
+            }
+            inner.call(req).await.map_err(FaultError::Inner)
+        })
+    }
+}
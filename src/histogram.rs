@@ -0,0 +1,204 @@
+//! A streaming estimator for latency percentiles.
+
+/// The smallest latency (in seconds) that gets its own bucket, anything
+/// smaller is counted in the first bucket.
+const MIN_LATENCY: f64 = 1e-6;
+/// The ratio between the upper and lower bound of each bucket.
+const BUCKET_RATIO: f64 = 1.1;
+/// The number of buckets, enough to cover from a microsecond to over an hour.
+const BUCKETS: usize = 240;
+/// Once the weight of new samples grows past this all the buckets are
+/// rescaled to keep the numbers in a sensible range.
+const RESCALE_THRESHOLD: f64 = 1e100;
+
+/// A histogram of latencies with logarithmically sized buckets.
+///
+/// Older samples are exponentially decayed, in the same way as an
+/// exponentially weighted moving average, so the percentiles track the recent
+/// behaviour of the service.
+///
+/// The memory used is fixed however many samples are recorded. Estimates are
+/// the geometric midpoint of a bucket, so they're within 5% of the true
+/// percentile of the decayed samples for latencies from a microsecond to over
+/// two hours, outside that range they're clamped to the first or last bucket.
+#[derive(Debug, Clone)]
+pub(crate) struct LatencyHistogram {
+    /// The (decayed) number of samples in each bucket.
+    buckets: Box<[f64; BUCKETS]>,
+    /// The total of all the buckets.
+    total: f64,
+    /// The weight that the next sample will be given, this grows with every
+    /// sample rather than decaying all the existing buckets.
+    weight: f64,
+    /// The amount the weight grows by with each sample.
+    growth: f64,
+}
+
+impl LatencyHistogram {
+    /// Create an empty histogram where each new sample accounts for
+    /// `ewma_param` of the distribution.
+    pub(crate) fn new(ewma_param: f64) -> Self {
+        Self {
+            buckets: Box::new([0.0; BUCKETS]),
+            total: 0.0,
+            weight: 1.0,
+            growth: 1.0 / (1.0 - ewma_param),
+        }
+    }
+
+    /// Give samples recorded from now on `ewma_param` of the distribution,
+    /// where the smoothing changes over time.
+    pub(crate) fn set_ewma_param(&mut self, ewma_param: f64) {
+        // A sample can't quite replace the whole distribution, or the weights
+        // would overflow.
+        let growth = 1.0 / (1.0 - ewma_param.min(1.0 - f64::EPSILON));
+        // Weight the next sample so it's exactly `ewma_param` of the
+        // distribution, unchanged the weights keep growing as they were.
+        if growth != self.growth && self.total > 0.0 {
+            self.weight = self.total * (growth - 1.0);
+        }
+        self.growth = growth;
+    }
+
+    /// Forget every sample recorded.
+    pub(crate) fn reset(&mut self) {
+        self.buckets.fill(0.0);
+        self.total = 0.0;
+        self.weight = 1.0;
+    }
+
+    /// Add a latency sample, in seconds.
+    pub(crate) fn record(&mut self, latency: f64) {
+        let bucket = if latency <= MIN_LATENCY {
+            0
+        } else {
+            ((latency / MIN_LATENCY).ln() / BUCKET_RATIO.ln()) as usize
+        };
+        self.buckets[bucket.min(BUCKETS - 1)] += self.weight;
+        self.total += self.weight;
+        self.weight *= self.growth;
+        if self.weight > RESCALE_THRESHOLD {
+            let scale = 1.0 / self.weight;
+            self.buckets.iter_mut().for_each(|count| *count *= scale);
+            self.total *= scale;
+            self.weight = 1.0;
+        }
+    }
+
+    /// Estimate the given quantile (in the range `[0, 1]`) in seconds, this
+    /// is `None` if no samples have been recorded.
+    pub(crate) fn quantile(&self, quantile: f64) -> Option<f64> {
+        if self.total == 0.0 {
+            return None;
+        }
+        let rank = quantile.clamp(0.0, 1.0) * self.total;
+        let mut seen = 0.0;
+        let bucket = self
+            .buckets
+            .iter()
+            .position(|&count| {
+                seen += count;
+                seen >= rank && count > 0.0
+            })
+            .unwrap_or(BUCKETS - 1);
+        // Use the geometric midpoint of the bucket as the estimate.
+        Some(MIN_LATENCY * BUCKET_RATIO.powf(bucket as f64 + 0.5))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Whether `estimate` is within the documented 5% of `expected`.
+    fn close(estimate: f64, expected: f64) -> bool {
+        (estimate - expected).abs() <= expected * 0.05
+    }
+
+    /// A histogram of `samples`, decayed so little they're all counted the
+    /// same.
+    fn histogram(samples: impl IntoIterator<Item = f64>) -> LatencyHistogram {
+        let mut histogram = LatencyHistogram::new(1e-9);
+        samples
+            .into_iter()
+            .for_each(|sample| histogram.record(sample));
+        histogram
+    }
+
+    #[test]
+    fn estimates_a_uniform_distribution() {
+        // From 1ms to 1s.
+        let histogram = histogram((1..=1000).map(|millis| f64::from(millis) / 1000.0));
+        for quantile in [0.1, 0.5, 0.9, 0.99] {
+            let estimate = histogram.quantile(quantile).unwrap();
+            assert!(close(estimate, quantile), "{quantile}: {estimate}");
+        }
+    }
+
+    #[test]
+    fn estimates_an_exponential_distribution() {
+        // Evenly spaced through the distribution with a mean of 100ms.
+        let samples = 10_000;
+        let histogram = histogram((0..samples).map(|sample| {
+            let quantile = (f64::from(sample) + 0.5) / f64::from(samples);
+            -(1.0 - quantile).ln() * 0.1
+        }));
+        for quantile in [0.5, 0.9, 0.99] {
+            let expected = -(1.0f64 - quantile).ln() * 0.1;
+            let estimate = histogram.quantile(quantile).unwrap();
+            assert!(close(estimate, expected), "{quantile}: {estimate}");
+        }
+    }
+
+    #[test]
+    fn latencies_outside_the_range_are_clamped() {
+        let histogram = histogram([0.0, 1e-9, 1e6]);
+        assert!(histogram.quantile(0.0).unwrap() < MIN_LATENCY * BUCKET_RATIO);
+        assert!(histogram.quantile(1.0).unwrap() > 2.0 * 60.0 * 60.0);
+    }
+
+    #[test]
+    fn memory_is_bounded_over_long_runs() {
+        let mut histogram = LatencyHistogram::new(0.1);
+        let size = std::mem::size_of_val(&*histogram.buckets);
+        // Enough samples to rescale the weights many times over.
+        for sample in 0..1_000_000 {
+            histogram.record(if sample % 2 == 0 { 0.01 } else { 0.1 });
+        }
+        assert_eq!(std::mem::size_of_val(&*histogram.buckets), size);
+        assert!(histogram.total.is_finite() && histogram.weight <= RESCALE_THRESHOLD);
+        assert!(histogram.buckets.iter().all(|count| count.is_finite()));
+        let median = histogram.quantile(0.5).unwrap();
+        assert!(close(median, 0.01) || close(median, 0.1), "{median}");
+    }
+
+    #[test]
+    fn old_samples_decay() {
+        let mut histogram = LatencyHistogram::new(0.1);
+        (0..100).for_each(|_| histogram.record(0.01));
+        (0..100).for_each(|_| histogram.record(0.1));
+        let p10 = histogram.quantile(0.1).unwrap();
+        assert!(close(p10, 0.1), "{p10}");
+    }
+
+    #[test]
+    fn the_smoothing_can_change_between_samples() {
+        let mut histogram = histogram((0..100).map(|_| 0.01));
+        histogram.set_ewma_param(0.9);
+        histogram.record(0.1);
+        let p50 = histogram.quantile(0.5).unwrap();
+        assert!(close(p50, 0.1), "{p50}");
+        let p5 = histogram.quantile(0.05).unwrap();
+        assert!(close(p5, 0.01), "{p5}");
+    }
+
+    #[test]
+    fn a_reset_forgets_every_sample() {
+        let mut histogram = histogram([0.01, 0.1]);
+        histogram.reset();
+        assert_eq!(histogram.quantile(0.5), None);
+        histogram.record(0.05);
+        let median = histogram.quantile(0.5).unwrap();
+        assert!(close(median, 0.05), "{median}");
+    }
+}
@@ -3,277 +3,263 @@
 //! This provides middleware for shedding load to maintain a target average
 //! latency, see the documentation on the [`LoadShed`] service for more detail.
 //!
+//! The `admin` feature provides `AdminService`, which carries out commands
+//! such as changing the target or draining the load shedder, for wiring up to
+//! an admin endpoint.
+//!
 //! The `metrics` feature uses the [metrics] crate to provide insight into the
 //! current queue sizes and measured latency.
 //!
+//! The `http` feature provides `BodyLoadShedLayer`, which counts requests as
+//! in flight until their response body has been sent.
+//!
+//! The `discover` feature provides `ResetOnChange`, which resets load
+//! shedders when the services found by a tower `Discover` change.
+//!
+//! The `decision-log` feature records the load shedder's decisions so they
+//! can be replayed offline, see `LoadShedLayer::decision_log`.
+//!
+//! The `test-util` feature provides `FaultInjection`, a wrapper that injects
+//! latency, errors and hangs into a service to test how the load shedder
+//! reacts to them.
+//!
+//! The `serde` feature makes `LoadShedStats` serializable, to expose them from
+//! an admin endpoint.
+//!
+//! The `internals` feature exposes the internal state of the load shedder in
+//! the `internals` module, without any stability guarantees.
+//!
 //! [Little's law]: https://en.wikipedia.org/wiki/Little%27s_law
 //! [metrics]: https://docs.rs/metrics/latest/metrics
 
 #![warn(missing_debug_implementations, missing_docs, non_ascii_idents)]
 #![forbid(unsafe_code)]
 
+#[cfg(feature = "admin")]
+mod admin;
+#[cfg(feature = "http")]
+mod body;
+mod conf;
+#[cfg(feature = "decision-log")]
+mod decisions;
+mod event;
+mod fallback;
+#[cfg(feature = "test-util")]
+mod fault;
+mod histogram;
+mod ordered;
+mod persist;
+mod policy;
+mod queue;
+mod reset;
+mod schedule;
+mod sequence;
+
 use std::{
-    cmp::Ordering,
+    any::Any,
+    borrow::Cow,
+    fmt,
     future::Future,
+    panic::AssertUnwindSafe,
     pin::Pin,
-    sync::{Arc, Mutex},
+    sync::Arc,
     task::{Context, Poll},
-    time::{Duration, Instant},
+    time::Duration,
 };
 
 #[cfg(feature = "metrics")]
-use metrics::{decrement_gauge, gauge, histogram, increment_counter, increment_gauge};
-use tokio::sync::{OwnedSemaphorePermit, Semaphore, TryAcquireError};
+use metrics::increment_counter;
+use tokio::{
+    sync::{watch, Semaphore},
+    time::Instant,
+};
 use tower::{Layer, Service, ServiceExt};
 
-/// Load Shed service's current state of the world
-#[derive(Debug, Clone)]
-struct LoadShedConf {
-    /// The target average latency in seconds.
-    target: f64,
-    /// The exponentially weighted moving average parameter.
-    /// Must be in the range (0, 1), `0.25` means new value accounts for 25% of
-    /// the moving average.
-    ewma_param: f64,
-    /// Semaphore controlling the waiting queue of requests.
-    available_queue: Arc<Semaphore>,
-    /// Semaphore controlling concurrency to the inner service.
-    available_concurrency: Arc<Semaphore>,
-    /// Stats about the latency that change with each completed request.
-    stats: Arc<Mutex<ConfStats>>,
+use crate::{
+    conf::{Completion, LoadShedConf},
+    event::EventHandler,
+    histogram::LatencyHistogram,
+    persist::Persistence,
+    queue::QueueFactory,
+};
+
+#[cfg(feature = "admin")]
+pub use admin::{AdminCommand, AdminResponse, AdminService};
+#[cfg(feature = "http")]
+pub use body::{BodyLoadShed, BodyLoadShedLayer, LoadShedBody};
+pub use conf::{
+    ConfigError, Limits, LoadShedConfig, LoadShedGuard, LoadShedRuntimeConfig, OptimizationGoal,
+    QueueSlotPolicy, ReadinessPolicy,
+};
+#[cfg(feature = "decision-log")]
+pub use decisions::{Decision, DecisionRecord};
+pub use event::{LatencyAttribution, LoadShedEvent};
+pub use fallback::FallbackLoadShed;
+#[cfg(feature = "test-util")]
+pub use fault::{Fault, FaultError, FaultInjection};
+pub use ordered::OrderedLoadShed;
+pub use persist::{PersistedState, StatePersistence};
+pub use policy::{AdmissionContext, AdmissionDecision, AdmissionPolicy, DefaultAdmissionPolicy};
+pub use queue::{AdmissionQueue, FifoQueue, LifoQueue, Waiter};
+#[cfg(feature = "discover")]
+pub use reset::ResetOnChange;
+pub use reset::ResetSignal;
+pub use schedule::TargetSchedule;
+
+/// Unstable access to the internal state of the load shedder, enabled by the
+/// `internals` feature.
+///
+/// This is intended for experimenting with extensions to the algorithm, these
+/// types may change in any release.
+#[cfg(feature = "internals")]
+pub mod internals {
+    pub use crate::conf::{ConfStats, LoadShedConf, Permit};
 }
 
-#[derive(Debug)]
-struct ConfStats {
-    /// The current average latency in seconds.
-    average_latency: f64,
-    /// The average of the latency measured when
-    /// `available_concurrent.available_permits() == 0`.
-    average_latency_at_capacity: f64,
-    /// The number of available permits in the queue semaphore
-    /// (the current capacity of the queue).
-    queue_capacity: usize,
-    /// The number of permits in the available_concurrency semaphore.
-    concurrency: usize,
-    /// The value of `self.concurrency` before it was last changed.
-    previous_concurrency: usize,
-    /// The time that the concurrency was last adjusted, to rate limit changing it.
-    last_changed: Instant,
-    /// Average throughput when at the previous concurrency value.
-    previous_throughput: f64,
+/// The outcome of a call to the inner service, used to feed the health of the
+/// service into the controller.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Outcome {
+    /// The request was handled successfully.
+    Success,
+    /// The request failed, this is never taken as a sign the inner service can
+    /// cope with more concurrency.
+    Failure,
 }
 
-// size of system [req] = target latency [s] * throughput [r/s]
-// size of queue [req] = size of system [req] - concurrency [req]
-// throughput [req/s] = concurrency [req] / average latency of service [s]
-// => (size of queue [req] + concurrency[req]) = target latency [s] * concurrency[req] / latency [s]
-// => size of queue [req] = concurrency [req] * (target latency [s] / latency [s] - 1)
-//
-// Control the concurrency:
-// increase concurrency but not beyond target latency
-//
-// Control queue length:
-// queue capacity = concurrency * ((target latency / average latency of service) - 1)
-
-impl LoadShedConf {
-    fn new(ewma_param: f64, target: f64) -> Self {
-        #[cfg(feature = "metrics")]
-        {
-            gauge!("loadshedder.capacity", 1.0, "component" => "service");
-            gauge!("loadshedder.capacity", 1.0, "component" => "queue");
-            gauge!("loadshedder.size", 0.0, "component" => "service");
-            gauge!("loadshedder.size", 0.0, "component" => "queue");
-            gauge!("loadshedder.average_latency", target);
-        }
-        Self {
-            target,
-            ewma_param,
-            available_concurrency: Arc::new(Semaphore::new(1)),
-            available_queue: Arc::new(Semaphore::new(1)),
-            stats: Arc::new(Mutex::new(ConfStats {
-                average_latency: target,
-                average_latency_at_capacity: target,
-                queue_capacity: 1,
-                concurrency: 1,
-                previous_concurrency: 0,
-                last_changed: Instant::now(),
-                previous_throughput: 0.0,
-            })),
-        }
+/// Classifies responses from the inner service as a success or failure.
+///
+/// Errors returned by the inner service are always considered failures, this
+/// allows responses that are `Ok` at the [`Service`] level to count as failures
+/// too, for example an HTTP `500` response.
+///
+/// This is implemented for all `Fn(&Response) -> Outcome` closures.
+pub trait Classify<Response> {
+    /// Classify a response from the inner service.
+    fn classify(&self, response: &Response) -> Outcome;
+}
+
+impl<F, Response> Classify<Response> for F
+where
+    F: Fn(&Response) -> Outcome,
+{
+    fn classify(&self, response: &Response) -> Outcome {
+        self(response)
     }
+}
 
-    /// Add ourselves to the queue and wait until we've made it through and have
-    /// obtained a permit to send the request.
-    async fn start(&self) -> Result<Permit, ()> {
-        {
-            // Work inside a block so we drop the stats lock asap.
-            let mut stats = self.stats.lock().unwrap();
-            let desired_queue_capacity = usize::max(
-                1, // The queue must always be at least 1 request long.
-                // Use average latency at (concurrency) capacity so that this doesn't
-                // grow too large while the system is under-utilised.
-                (stats.concurrency as f64
-                    * ((self.target / stats.average_latency_at_capacity) - 1.0))
-                    .floor() as usize,
-            );
-            #[cfg(feature = "metrics")]
-            gauge!("loadshedder.capacity", desired_queue_capacity as f64, "component" => "queue");
-
-            // Adjust the semaphore capacity by adding or acquiring many permits.
-            // If acquiring permits fails we can return overload and let the next
-            // request recompute the queue capacity.
-            match desired_queue_capacity.cmp(&stats.queue_capacity) {
-                Ordering::Less => {
-                    match self
-                        .available_queue
-                        .try_acquire_many((stats.queue_capacity - desired_queue_capacity) as u32)
-                    {
-                        Ok(permits) => permits.forget(),
-                        Err(TryAcquireError::NoPermits) => return Err(()),
-                        Err(TryAcquireError::Closed) => panic!(),
-                    }
-                }
-                Ordering::Equal => {}
-                Ordering::Greater => self
-                    .available_queue
-                    .add_permits(desired_queue_capacity - stats.queue_capacity),
-            }
-            stats.queue_capacity = desired_queue_capacity;
-        }
+/// The default [`Classify`] implementation, which considers every response
+/// that isn't an error a success.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct DefaultClassifier;
 
-        // Finally get our queue permit, if this fails then the queue is full
-        // and we need to bail out.
-        let queue_permit = match self.available_queue.clone().try_acquire_owned() {
-            Ok(queue_permit) => Permit::new(queue_permit, "queue"),
-            Err(TryAcquireError::NoPermits) => return Err(()),
-            Err(TryAcquireError::Closed) => panic!("queue semaphore closed?"),
-        };
-        // We're in the queue now so wait until we get ourselves a concurrency permit.
-        let concurrency_permit = self
-            .available_concurrency
-            .clone()
-            .acquire_owned()
-            .await
-            .unwrap();
-        // Now we've got the permit required to send the request we can leave the queue.
-        drop(queue_permit);
-        Ok(Permit::new(concurrency_permit, "service"))
+impl<Response> Classify<Response> for DefaultClassifier {
+    fn classify(&self, _: &Response) -> Outcome {
+        Outcome::Success
     }
+}
 
-    /// Register a completed call of the inner service, providing the latency to
-    /// update the statistics.
-    fn stop(&mut self, elapsed: Duration, concurrency_permit: Permit) {
-        let elapsed = elapsed.as_secs_f64();
-        #[cfg(feature = "metrics")]
-        histogram!("loadshedder.latency", elapsed);
+/// Extracts information about requests that is used by the load shedder.
+///
+/// All the methods have default implementations, so only the information that
+/// is relevant needs to be provided.
+pub trait RequestInfo<Request> {
+    /// An estimate of the number of bytes this request will hold in memory
+    /// while it is in flight, used by [`LoadShedLayer::byte_budget`].
+    ///
+    /// The estimates of admitted requests are recorded in the
+    /// `loadshedder.request_bytes` histogram, to check they're realistic.
+    fn bytes(&self, _request: &Request) -> u64 {
+        0
+    }
 
-        // This function solely updates the stats (and is not async) so hold the
-        // lock for the entire function.
-        let mut stats = self.stats.lock().expect("To be able to lock stats");
+    /// The cost of this request in the units of
+    /// [`LoadShedLayer::cost_budget`], which can be fractional, used in place
+    /// of [`bytes`](Self::bytes) if there is one. A cost that isn't a finite
+    /// non-negative number is counted as more than the budget.
+    ///
+    /// The costs of admitted requests are recorded, at the budget's
+    /// resolution, in the `loadshedder.request_cost` histogram.
+    fn cost(&self, _request: &Request) -> Option<f64> {
+        None
+    }
 
-        let available_permits = self.available_concurrency.available_permits();
-        // Have some leeway on what "at max concurrency" means as you might
-        // otherwise never see this condition at large concurrency values.
-        let at_max_concurrency = available_permits <= usize::max(1, stats.concurrency / 10);
+    /// When this request originally arrived, for example at the edge of the
+    /// system, used by [`LoadShedLayer::max_age`].
+    fn arrival(&self, _request: &Request) -> Option<std::time::Instant> {
+        None
+    }
 
-        // Update the average latency using the EWMA algorithm.
-        stats.average_latency =
-            (stats.average_latency * (1.0 - self.ewma_param)) + (self.ewma_param * elapsed);
-        #[cfg(feature = "metrics")]
-        gauge!("loadshedder.average_latency", stats.average_latency);
-        if at_max_concurrency {
-            stats.average_latency_at_capacity = (stats.average_latency_at_capacity
-                * (1.0 - self.ewma_param))
-                + (self.ewma_param * elapsed);
-        }
+    /// Whether this request is a retry of one that was shed, these can be left
+    /// out of the arrival rate with [`LoadShedLayer::exclude_retries`].
+    fn is_retry(&self, _request: &Request) -> bool {
+        false
+    }
 
-        // Only ever change max concurrency if we're at the limit as we need
-        // measurements to have happened at the current limit.
-        // Also, introduce a max rate of change that's somewhat magically
-        // related to the latency and ewma parameter to prevent this from
-        // changing too quickly.
-        if stats.last_changed.elapsed().as_secs_f64()
-            > (stats.average_latency / self.ewma_param) / 10.0
-            && at_max_concurrency
-        {
-            // Plausibly should be using average latency at capacity here and
-            // stats.concurrency but this appears to work. It might do weird
-            // things if it's been running under capacity for a while then spikes.
-            let current_concurrency = stats.concurrency - available_permits;
-            let throughput = current_concurrency as f64 / stats.average_latency;
-            // Was the throughput better or worse than it was previously.
-            let negative_gradient = (throughput > stats.previous_throughput)
-                ^ (current_concurrency > stats.previous_concurrency);
-            if negative_gradient || (stats.average_latency > self.target) {
-                // Don't reduce concurrency below 1 or everything stops.
-                if stats.concurrency > 1 {
-                    // negative gradient so decrease concurrency
-                    concurrency_permit.forget();
-                    stats.concurrency -= 1;
-                    #[cfg(feature = "metrics")]
-                    gauge!("loadshedder.capacity", stats.concurrency as f64, "component" => "service");
-
-                    // Adjust the average latency assuming that the change in
-                    // concurrency doesn't affect the service latency, which is
-                    // closer to the truth than the latency not changing.
-                    let latency_factor =
-                        stats.concurrency as f64 / (stats.concurrency as f64 + 1.0);
-                    stats.average_latency *= latency_factor;
-                    stats.average_latency_at_capacity *= latency_factor;
-                }
-            } else {
-                self.available_concurrency.add_permits(1);
-                stats.concurrency += 1;
-                #[cfg(feature = "metrics")]
-                gauge!("loadshedder.capacity", stats.concurrency as f64, "component" => "service");
-
-                // Adjust the average latency assuming that the change in
-                // concurrency doesn't affect the service latency, which is
-                // closer to the truth than the latency not changing.
-                let latency_factor = stats.concurrency as f64 / (stats.concurrency as f64 - 1.0);
-                stats.average_latency *= latency_factor;
-                stats.average_latency_at_capacity *= latency_factor;
-            }
+    /// Whether this request is a health check or other synthetic probe, these
+    /// are always admitted but left out of the statistics, like
+    /// [`LoadShed::admit_probe`], so they don't skew the control signal.
+    fn is_health_check(&self, _request: &Request) -> bool {
+        false
+    }
 
-            stats.previous_throughput = throughput;
-            stats.previous_concurrency = current_concurrency;
-            stats.last_changed = Instant::now()
-        }
+    /// A label identifying the kind of request, for example the path or
+    /// tenant, added to the request metrics so it's possible to tell which
+    /// requests are being shed.
+    ///
+    /// The label should have a small number of possible values, as each value
+    /// creates a new metric series.
+    fn label(&self, _request: &Request) -> Option<Cow<'static, str>> {
+        None
     }
 }
 
-/// A permit for something, this is used for updating metrics.
-#[derive(Debug)]
-struct Permit {
-    /// The permit, this is only optional to enable the forget function.
-    permit: Option<OwnedSemaphorePermit>,
-    /// The name of the component this permit is for, used as a metric label.
-    #[allow(unused)]
-    component: &'static str,
-}
+/// The default [`RequestInfo`] implementation, which provides no information.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct DefaultRequestInfo;
 
-impl Permit {
-    /// Create a new permit for the given component.
-    fn new(permit: OwnedSemaphorePermit, component: &'static str) -> Self {
-        #[cfg(feature = "metrics")]
-        increment_gauge!("loadshedder.size", 1.0, "component" => component);
-        Self {
-            permit: Some(permit),
-            component,
-        }
+impl<Request> RequestInfo<Request> for DefaultRequestInfo {}
+
+/// A request extension recording when the request originally arrived, such as
+/// at the edge of the system, read by [`ExtensionRequestInfo`].
+#[cfg(feature = "http")]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Arrival(pub std::time::Instant);
+
+/// A [`RequestInfo`] implementation for HTTP requests that takes their
+/// arrival time from an [`Arrival`] extension, and whether they're retries
+/// or health checks from [`Retry`] and [`HealthCheck`] extensions.
+#[cfg(feature = "http")]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ExtensionRequestInfo;
+
+/// A request extension marking the request as a retry, read by
+/// [`ExtensionRequestInfo`].
+#[cfg(feature = "http")]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub struct Retry;
+
+/// A request extension marking the request as a health check, read by
+/// [`ExtensionRequestInfo`].
+#[cfg(feature = "http")]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub struct HealthCheck;
+
+#[cfg(feature = "http")]
+impl<B> RequestInfo<http::Request<B>> for ExtensionRequestInfo {
+    fn arrival(&self, request: &http::Request<B>) -> Option<std::time::Instant> {
+        request
+            .extensions()
+            .get::<Arrival>()
+            .map(|arrival| arrival.0)
     }
 
-    /// Forget the permit, essentially reducing the size of the semaphore by one.
-    /// Note this does still decrement the size metric.
-    fn forget(mut self) {
-        self.permit.take().unwrap().forget()
+    fn is_retry(&self, request: &http::Request<B>) -> bool {
+        request.extensions().get::<Retry>().is_some()
     }
-}
 
-impl Drop for Permit {
-    fn drop(&mut self) {
-        #[cfg(feature = "metrics")]
-        decrement_gauge!("loadshedder.size", 1.0, "component" => self.component);
+    fn is_health_check(&self, request: &http::Request<B>) -> bool {
+        request.extensions().get::<HealthCheck>().is_some()
     }
 }
 
@@ -303,11 +289,26 @@ impl Drop for Permit {
 /// system capacity unless the queues have been at or above the concurrency for
 /// a while.
 ///
+/// Failed requests, either errors or responses marked as failures by the
+/// [`Classify`] implementation, are treated as a signal to reduce concurrency
+/// once they're more than the
+/// [`max_failure_rate`](LoadShedLayer::max_failure_rate) of the requests.
+///
+/// The service is always ready, so it's safe to call without calling
+/// [`poll_ready`](Service::poll_ready) first. Each call clones the inner
+/// service and waits for that clone to be ready once the request is admitted,
+/// so the inner service never needs to be ready ahead of time. The cost is a
+/// clone per request, and that admitted requests hold their place while the
+/// inner service gets ready, see [`LoadShedLayer::readiness_policy`] for
+/// other ways of handling that.
+///
 /// [Little's law]: https://en.wikipedia.org/wiki/Little%27s_law
 #[derive(Debug, Clone)]
-pub struct LoadShed<Inner> {
+pub struct LoadShed<Inner, C = DefaultClassifier, I = DefaultRequestInfo> {
     conf: LoadShedConf,
     inner: Inner,
+    classifier: C,
+    request_info: I,
 }
 
 impl<Inner> LoadShed<Inner> {
@@ -317,53 +318,934 @@ impl<Inner> LoadShed<Inner> {
     pub fn new(inner: Inner, ewma_param: f64, target: Duration) -> Self {
         Self {
             inner,
-            conf: LoadShedConf::new(ewma_param, target.as_secs_f64()),
+            conf: LoadShedConf::new(&LoadShedConfig::new(ewma_param, target)),
+            classifier: DefaultClassifier,
+            request_info: DefaultRequestInfo,
+        }
+    }
+
+    /// Wrap a service with this middleware, like [`new`](Self::new), but
+    /// holding the given `percentile` of the latency at the target rather than
+    /// the average, `0.99` keeps the p99 latency under the target.
+    ///
+    /// The percentile is estimated from a histogram of recent latencies, that
+    /// decays with the moving average parameter, and takes the place of the
+    /// average latency in the statistics.
+    pub fn with_percentile_target(
+        inner: Inner,
+        ewma_param: f64,
+        percentile: f64,
+        target: Duration,
+    ) -> Self {
+        let mut config = LoadShedConfig::new(ewma_param, target);
+        config.target_percentile = Some(percentile);
+        Self {
+            inner,
+            conf: LoadShedConf::new(&config),
+            classifier: DefaultClassifier,
+            request_info: DefaultRequestInfo,
+        }
+    }
+
+    /// Wrap a service with this middleware, like [`new`](Self::new), but
+    /// limiting the concurrency with a semaphore that's shared with something
+    /// else, such as another limiter.
+    ///
+    /// The permits available in the semaphore when this is called are taken as
+    /// the starting concurrency, it's topped up to at least one permit. From
+    /// then on the load shedder owns the number of permits, it adds and
+    /// forgets permits as it adjusts the concurrency. Anything else sharing the
+    /// semaphore should only acquire and release permits, permits it holds
+    /// reduce the concurrency available to this service, and the load shedder
+    /// corrects its view of the concurrency to match.
+    pub fn with_concurrency_semaphore(
+        inner: Inner,
+        ewma_param: f64,
+        target: Duration,
+        semaphore: Arc<Semaphore>,
+    ) -> Self {
+        let mut config = LoadShedConfig::new(ewma_param, target);
+        config.concurrency_semaphore = Some(semaphore);
+        Self {
+            inner,
+            conf: LoadShedConf::new(&config),
+            classifier: DefaultClassifier,
+            request_info: DefaultRequestInfo,
+        }
+    }
+
+    /// Wrap a service with this middleware using the given configuration,
+    /// which is validated first.
+    pub fn from_config(inner: Inner, config: LoadShedConfig) -> Result<Self, ConfigError> {
+        config.validate()?;
+        Ok(Self {
+            inner,
+            conf: LoadShedConf::new(&config),
+            classifier: DefaultClassifier,
+            request_info: DefaultRequestInfo,
+        })
+    }
+}
+
+impl<Inner, C, I> LoadShed<Inner, C, I> {
+    /// Use the given [`Classify`] implementation to decide which responses
+    /// from the inner service count as failures.
+    pub fn with_classifier<C2>(self, classifier: C2) -> LoadShed<Inner, C2, I> {
+        LoadShed {
+            conf: self.conf,
+            inner: self.inner,
+            classifier,
+            request_info: self.request_info,
+        }
+    }
+
+    /// Use the given [`RequestInfo`] implementation to extract information
+    /// about requests, such as their size.
+    pub fn with_request_info<I2>(self, request_info: I2) -> LoadShed<Inner, C, I2> {
+        LoadShed {
+            conf: self.conf,
+            inner: self.inner,
+            classifier: self.classifier,
+            request_info,
+        }
+    }
+
+    /// Create an independent load shedder with the same configuration and a
+    /// clone of the inner service.
+    ///
+    /// Clones of a load shedder share their queue, concurrency and statistics,
+    /// a fork starts afresh with its own, as if it had been created by the same
+    /// [`LoadShedLayer`], so it suits a second downstream that should be
+    /// limited separately. Anything shared through the configuration, such as
+    /// a [`global_concurrency`](LoadShedLayer::global_concurrency) limit or a
+    /// [`ResetSignal`], is still shared, and limits changed with
+    /// [`reconfigure`](Self::reconfigure) aren't carried over.
+    pub fn fork(&self) -> Self
+    where
+        Inner: Clone,
+        C: Clone,
+        I: Clone,
+    {
+        LoadShed {
+            conf: LoadShedConf::new(&self.conf.config),
+            inner: self.inner.clone(),
+            classifier: self.classifier.clone(),
+            request_info: self.request_info.clone(),
         }
     }
 
+    /// Swap the inner service for a new one, such as a client rebuilt after a
+    /// configuration change, keeping everything that's been learned about it,
+    /// and return the old one.
+    ///
+    /// Requests already admitted finish on the old service, later ones go to
+    /// the new one. Only this clone of the load shedder is changed, the others
+    /// keep calling the old service while still sharing the same limits and
+    /// statistics. Use [`reset`](Self::reset) as well if the new service is
+    /// expected to behave differently.
+    pub fn replace_inner(&mut self, inner: Inner) -> Inner {
+        std::mem::replace(&mut self.inner, inner)
+    }
+
+    /// How long a request arriving now would wait in the queue before calling
+    /// the inner service, for telling clients when to retry.
+    ///
+    /// This is capped at the [`max_estimated_wait`](LoadShedLayer::max_estimated_wait),
+    /// if there is one, as a very long wait is more likely to make clients
+    /// give up than to be right.
+    pub fn estimated_wait(&self) -> EstimatedWait {
+        self.conf.estimated_wait()
+    }
+
+    /// The internal state of this service, see [`internals`].
+    #[cfg(feature = "internals")]
+    pub fn internals(&self) -> &internals::LoadShedConf {
+        &self.conf
+    }
+
     /// The current average latency of requests through the inner service,
     /// that is ignoring the queue this service adds.
     pub fn average_latency(&self) -> Duration {
         Duration::from_secs_f64(self.conf.stats.lock().unwrap().average_latency)
     }
 
+    /// The current average latency of requests that completed while the inner
+    /// service was at its concurrency limit, this is what the queue capacity
+    /// is sized from.
+    pub fn average_latency_at_capacity(&self) -> Duration {
+        Duration::from_secs_f64(self.conf.stats.lock().unwrap().average_latency_at_capacity)
+    }
+
+    /// Set the average latency at capacity, such as to one observed before a
+    /// restart, rather than learning it from the target. The queue capacity
+    /// is sized from the new value as soon as the next request arrives.
+    ///
+    /// A zero latency is ignored as it would make the queue infinitely long.
+    pub fn seed_average_latency_at_capacity(&self, latency: Duration) {
+        if !latency.is_zero() {
+            self.conf.stats.lock().unwrap().average_latency_at_capacity = latency.as_secs_f64();
+        }
+    }
+
+    /// The rate requests are completing successfully within their deadline
+    /// at, per second, this is also emitted as the `loadshedder.goodput`
+    /// gauge.
+    ///
+    /// A request's deadline is the [`max_age`](LoadShedLayer::max_age) if
+    /// there is one, otherwise the target latency, and it's measured including
+    /// the time spent queued. Unlike the throughput this falls when the
+    /// service is busy with requests that fail or are too late to be useful.
+    pub fn goodput(&self) -> f64 {
+        self.with_stats(|view| view.goodput())
+    }
+
+    /// The rate requests are arriving at, per second, whether or not they're
+    /// admitted, this is also emitted as the `loadshedder.arrival_rate` gauge.
+    pub fn arrival_rate(&self) -> f64 {
+        self.conf.arrival_rate()
+    }
+
     /// The current maximum concurrency of requests to the inner service.
     pub fn concurrency(&self) -> usize {
         self.conf.stats.lock().unwrap().concurrency
     }
 
-    /// The current maximum capacity of this service (including the queue).
+    /// The concurrency the controller wants, which is above the
+    /// [`concurrency`](Self::concurrency) while the maximum concurrency holds
+    /// it back.
+    pub fn desired_concurrency(&self) -> usize {
+        self.with_stats(|view| view.desired_concurrency())
+    }
+
+    /// The concurrency that's actually available to requests, once permits
+    /// that are still waiting to be removed and the
+    /// [`global_concurrency`](LoadShedLayer::global_concurrency) limit are
+    /// accounted for.
+    ///
+    /// When this is below the [`desired_concurrency`](Self::desired_concurrency)
+    /// a limit is the binding constraint rather than the latency, the gap is
+    /// emitted as the `loadshedder.concurrency_constrained` gauge.
+    pub fn effective_concurrency(&self) -> usize {
+        self.with_stats(|view| view.effective_concurrency())
+    }
+
+    /// The current maximum capacity of this service, including the
+    /// concurrency as well as the queue, the same as
+    /// [`system_size`](Self::system_size).
+    ///
+    /// Unlike [`LoadShedStats::queue_capacity`] and
+    /// [`StatsView::queue_capacity`] this isn't only the queue.
+    #[deprecated = "this includes the concurrency, use `system_size` instead"]
     pub fn queue_capacity(&self) -> usize {
-        let stats = self.conf.stats.lock().unwrap();
-        stats.concurrency + stats.queue_capacity
+        self.system_size()
     }
 
     /// The current number of requests that have been accepted by this service.
     pub fn queue_len(&self) -> usize {
+        self.conf.queue_len(&self.conf.stats.lock().unwrap())
+    }
+
+    /// The total number of requests this service will currently admit, that
+    /// is the concurrency plus the capacity of the queue.
+    ///
+    /// This is the size of the system in [Little's law] terms, which the
+    /// load shedder manages to hold the latency at the target.
+    ///
+    /// [Little's law]: https://en.wikipedia.org/wiki/Little%27s_law
+    pub fn system_size(&self) -> usize {
+        let stats = self.conf.stats.lock().unwrap();
+        stats.concurrency + stats.queue_capacity
+    }
+
+    /// The fraction of the system size that's free, from `1.0` when nothing
+    /// is in flight to `0.0` when the queue is full and requests are being
+    /// shed.
+    ///
+    /// This is a single measure of how close to saturation the service is,
+    /// such as for dashboards or autoscaling.
+    pub fn headroom(&self) -> f64 {
         let stats = self.conf.stats.lock().unwrap();
-        let current_concurrency =
-            stats.concurrency - self.conf.available_concurrency.available_permits();
-        let current_queue = stats.queue_capacity - self.conf.available_queue.available_permits();
-        current_concurrency + current_queue
+        let system_size = stats.concurrency + stats.queue_capacity;
+        let in_flight = self.conf.queue_len(&stats);
+        (1.0 - in_flight as f64 / system_size as f64).clamp(0.0, 1.0)
+    }
+
+    /// Remove and return the decisions recorded since this was last called,
+    /// oldest first, see [`LoadShedLayer::decision_log`].
+    #[cfg(feature = "decision-log")]
+    pub fn take_decisions(&self) -> Vec<DecisionRecord> {
+        self.conf.take_decisions()
+    }
+
+    /// Permanently stop admitting requests, for shutting down cleanly.
+    ///
+    /// Every request from now on is shed with
+    /// [`LoadShedResponse::ShuttingDown`], across all clones of this service.
+    /// Requests that have already been admitted, including those in the
+    /// queue, carry on and complete as normal. This can't be undone.
+    pub fn shutdown(&self) {
+        self.conf.shutdown();
+    }
+
+    /// Whether [`shutdown`](Self::shutdown) has been called.
+    pub fn is_shut_down(&self) -> bool {
+        self.conf.available_queue.is_closed()
+    }
+
+    /// Whether a request would be admitted if it arrived right now, see
+    /// [`would_admit_cost`](Self::would_admit_cost).
+    pub fn would_admit(&self) -> bool {
+        self.would_admit_cost(1)
+    }
+
+    /// Whether there's room right now for `cost` requests, either to call the
+    /// inner service straight away or in the queue, for pre-checking an
+    /// expensive request that will be split into several.
+    ///
+    /// This is only advisory, other requests may take the room before this
+    /// request arrives, and it doesn't account for the rate limit, byte
+    /// budget, admission policy or the queue being resized.
+    pub fn would_admit_cost(&self, cost: u32) -> bool {
+        self.conf.would_admit(cost)
+    }
+
+    /// Change the target latency, overriding the configured, scheduled and
+    /// [runtime](LoadShedLayer::runtime_config) targets from now on.
+    pub fn set_target(&self, target: Duration) -> Result<(), ConfigError> {
+        self.conf.set_target(target)
+    }
+
+    /// Change the moving average parameter, overriding the configured and
+    /// [runtime](LoadShedLayer::runtime_config) parameters from now on.
+    pub fn set_ewma_param(&self, ewma_param: f64) -> Result<(), ConfigError> {
+        self.conf.set_ewma_param(ewma_param)
+    }
+
+    /// Shed every new request with [`LoadShedResponse::Overload`], whatever
+    /// the load, across all clones of this service, or stop doing so.
+    ///
+    /// Unlike [`shutdown`](Self::shutdown) this can be undone, for taking an
+    /// instance out of service for a while.
+    pub fn force_shed(&self, shed: bool) {
+        self.conf.set_force_shed(shed);
+    }
+
+    /// Whether every new request is being shed, see
+    /// [`force_shed`](Self::force_shed).
+    pub fn is_force_shedding(&self) -> bool {
+        self.conf.is_force_shedding()
+    }
+
+    /// [Shed every new request](Self::force_shed) and wait until the requests
+    /// already admitted, including those in the queue, have completed.
+    ///
+    /// Call `force_shed(false)` to start admitting requests again afterwards.
+    pub async fn drain(&self) {
+        self.conf.drain().await;
+    }
+
+    /// Forget what's been learned about the inner service's latency, to learn
+    /// it again from scratch, such as after it's been replaced or scaled.
+    ///
+    /// The averages go back to the target, the latency percentiles and
+    /// variance are cleared and the control loop starts afresh, the current
+    /// concurrency and queue capacity are kept as a starting point, unless
+    /// the concurrency is [warmed up](LoadShedLayer::warmup) again.
+    pub fn reset(&self) {
+        self.conf.reset();
+    }
+
+    /// What's been learned about the inner service, to restore after a
+    /// restart with [`import_state`](Self::import_state), or automatically
+    /// with [`LoadShedLayer::persist_state`].
+    pub fn export_state(&self) -> PersistedState {
+        LoadShedConf::export_state(&self.conf.stats.lock().unwrap())
+    }
+
+    /// Pick up from previously learned state, such as from before a restart.
+    /// The concurrency is kept within the current limits.
+    pub fn import_state(&self, state: PersistedState) {
+        let mut stats = self.conf.stats.lock().unwrap();
+        self.conf.import_state(&mut stats, state);
+    }
+
+    /// A snapshot of all the current statistics.
+    pub fn stats(&self) -> LoadShedStats {
+        self.with_stats(|view| LoadShedStats {
+            average_latency: view.average_latency(),
+            slow_average_latency: view.slow_average_latency(),
+            average_latency_at_capacity: view.average_latency_at_capacity(),
+            concurrency: view.concurrency(),
+            desired_concurrency: view.desired_concurrency(),
+            queue_capacity: view.queue_capacity(),
+            system_size: view.system_size(),
+            in_flight: view.in_flight(),
+            bytes_in_flight: view.bytes_in_flight(),
+            increase_halted: view.increase_halted(),
+            increases: view.increases(),
+            decreases: view.decreases(),
+            max_concurrency: view.max_concurrency(),
+            effective_concurrency: view.effective_concurrency(),
+            success_latency: view.success_latency(),
+            failure_latency: view.failure_latency(),
+            average_request_bytes: view.average_request_bytes(),
+            average_request_cost: view.average_request_cost(),
+            at_min_concurrency: view.at_min_concurrency(),
+            throughput: view.throughput(),
+            goodput: view.goodput(),
+            arrival_rate: view.arrival_rate(),
+        })
+    }
+
+    /// Change the limits on the concurrency and queue capacity together, so
+    /// there's never a moment where only some of them have changed.
+    ///
+    /// The limits are checked first and nothing changes if they're invalid.
+    /// The concurrency is brought within the new limits straight away, and the
+    /// queue capacity as requests arrive. These limits take precedence over any
+    /// [`runtime_config`](LoadShedLayer::runtime_config).
+    pub fn reconfigure(&self, limits: Limits) -> Result<(), ConfigError> {
+        self.conf.reconfigure(limits)
+    }
+
+    /// A one line summary of the current state, for admin endpoints and logs,
+    /// such as
+    /// `concurrency=8 queue=12 in_flight=15 avg=45.0ms target=50.0ms shedding=false phase=steady`.
+    ///
+    /// It's `shedding` if a new request would be shed, because the queue is
+    /// full, the service is [force shedding](Self::force_shed) or
+    /// [shut down](Self::shutdown), or the
+    /// [rate limit](LoadShedLayer::rate_limit) has been reached. The phase is
+    /// `increasing` if the concurrency was last increased,
+    /// `halted` if increases are halted, `at_min` if the service is
+    /// overloaded at the minimum concurrency, and otherwise `steady`. The
+    /// format may change, use [`stats`](Self::stats) to read the values.
+    pub fn status_line(&self) -> String {
+        self.with_stats(|view| {
+            let phase = if view.stats.at_min_concurrency {
+                "at_min"
+            } else if view.stats.increase_halted {
+                "halted"
+            } else if view.stats.last_increased {
+                "increasing"
+            } else {
+                "steady"
+            };
+            format!(
+                "concurrency={} queue={} in_flight={} avg={:.1?} target={:.1?} shedding={} phase={}",
+                view.concurrency(),
+                view.queue_capacity(),
+                view.in_flight(),
+                view.average_latency(),
+                view.stats.runtime.target,
+                self.conf.is_shedding(),
+                phase,
+            )
+        })
+    }
+
+    /// Call `f` with a view of the current statistics, without copying them
+    /// into a snapshot.
+    ///
+    /// The statistics are locked while `f` runs, so requests can't complete,
+    /// it should be quick and mustn't call back into this service.
+    pub fn with_stats<R>(&self, f: impl FnOnce(&StatsView<'_>) -> R) -> R {
+        let stats = self.conf.stats.lock().unwrap();
+        f(&StatsView {
+            conf: &self.conf,
+            stats: &stats,
+        })
+    }
+
+    /// Estimated percentiles of the time recent requests have spent waiting in
+    /// the queue and in the inner service, to show where latency is coming
+    /// from.
+    ///
+    /// These are estimated from fixed size histograms, so they're accurate to
+    /// within 5% for latencies between a microsecond and two hours.
+    pub fn latency_breakdown(&self) -> LatencyBreakdown {
+        let stats = self.conf.stats.lock().unwrap();
+        LatencyBreakdown {
+            queue: LatencyPercentiles::new(&stats.queue_latency),
+            service: LatencyPercentiles::new(&stats.service_latency),
+        }
+    }
+
+    /// Wait for a place in the load shedder without calling the inner service,
+    /// to protect work that doesn't go through the [`Service`] interface.
+    ///
+    /// The returned guard must be [completed](LoadShedGuard::complete) with
+    /// the outcome of the work once it's done. The guard doesn't know about the
+    /// request, so the [`RequestInfo`] isn't consulted.
+    pub async fn acquire(&self) -> LoadShedResponse<LoadShedGuard> {
+        let conf = self.conf.clone();
+        let ticket = conf.arrivals.as_ref().map(|arrivals| arrivals.ticket());
+        conf.start_control_task();
+        conf.start_persistence_task();
+        conf.arrive(false);
+        let arrived = Instant::now();
+        let entered = conf.enter(None, 0);
+        match conf.admit(entered, ticket).await {
+            Ok((permit, reservation)) => {
+                #[cfg(feature = "metrics")]
+                count_request(&conf.labels, "accepted", &None);
+                LoadShedResponse::Inner(LoadShedGuard::new(conf, arrived, permit, reservation))
+            }
+            Err(shed) => {
+                #[cfg(feature = "metrics")]
+                count_request(&conf.labels, shed.status(), &None);
+                shed.response()
+            }
+        }
+    }
+
+    /// Admit a single probe straight away, whether or not the service is
+    /// overloaded, to check the inner service is still alive while requests
+    /// are being shed.
+    ///
+    /// The probe doesn't count against the queue or concurrency limit, and its
+    /// latency is left out of the statistics, it's counted with the `probe`
+    /// status and its latency is emitted as the `loadshedder.probe_latency`
+    /// histogram instead. Requests are only refused once the service has been
+    /// [shut down](Self::shutdown).
+    pub fn admit_probe(&self) -> LoadShedResponse<LoadShedGuard> {
+        match self.conf.admit_probe() {
+            Ok(guard) => {
+                #[cfg(feature = "metrics")]
+                count_request(&self.conf.labels, "probe", &None);
+                LoadShedResponse::Inner(guard)
+            }
+            Err(shed) => shed.response(),
+        }
+    }
+
+    /// Spawn a task that emits the gauges describing this service every
+    /// interval, so metrics stay fresh even while the service is idle.
+    ///
+    /// This emits the `loadshedder.capacity` and `loadshedder.average_latency`
+    /// gauges, along with `loadshedder.average_latency_at_capacity` and
+    /// `loadshedder.shed_fraction`, the fraction of requests shed since the
+    /// last report. The task stops once this service and all its clones have
+    /// been dropped.
+    #[cfg(feature = "metrics")]
+    pub fn spawn_reporter(&self, interval: Duration) -> tokio::task::JoinHandle<()> {
+        self.conf.spawn_reporter(interval)
+    }
+
+    /// The current total size of the requests that are in flight, as
+    /// estimated by the [`RequestInfo`] implementation.
+    ///
+    /// This is always zero if there is no byte budget, including when a
+    /// [`cost_budget`](LoadShedLayer::cost_budget) takes its place.
+    pub fn bytes_in_flight(&self) -> u64 {
+        self.conf.bytes_in_flight()
+    }
+
+    /// The total cost of the requests in flight, in the units of the
+    /// [`LoadShedLayer::cost_budget`], counted at its resolution.
+    ///
+    /// This is always zero if there is no cost budget.
+    pub fn cost_in_flight(&self) -> f64 {
+        self.conf.cost_in_flight()
+    }
+}
+
+/// A snapshot of the statistics of a [`LoadShed`] service, see
+/// [`LoadShed::stats`].
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[non_exhaustive]
+pub struct LoadShedStats {
+    /// The average latency of requests through the inner service.
+    pub average_latency: Duration,
+    /// The slower moving average of the latency, see
+    /// [`LoadShedLayer::trend_backoff`].
+    pub slow_average_latency: Duration,
+    /// The average latency of requests that completed while the inner service
+    /// was at its concurrency limit, this is used to size the queue.
+    pub average_latency_at_capacity: Duration,
+    /// The maximum concurrency of requests to the inner service.
+    pub concurrency: usize,
+    /// The concurrency the controller wants, see
+    /// [`LoadShed::desired_concurrency`].
+    pub desired_concurrency: usize,
+    /// The capacity of the queue alone, unlike the deprecated
+    /// [`LoadShed::queue_capacity`] this doesn't include the concurrency. The
+    /// total is the [`system_size`](Self::system_size).
+    pub queue_capacity: usize,
+    /// The total number of requests that will be admitted, see
+    /// [`LoadShed::system_size`].
+    pub system_size: usize,
+    /// The number of requests currently queued or being processed.
+    pub in_flight: usize,
+    /// The total estimated size of the requests in flight, see
+    /// [`LoadShed::bytes_in_flight`].
+    pub bytes_in_flight: u64,
+    /// Whether increases in concurrency are halted because the throughput
+    /// stopped improving, see [`LoadShedLayer::throughput_halt_margin`].
+    pub increase_halted: bool,
+    /// The number of times the concurrency has been increased, a high rate of
+    /// adjustments suggests the controller is thrashing.
+    pub increases: u64,
+    /// The number of times the concurrency has been decreased.
+    pub decreases: u64,
+    /// The maximum concurrency, which changes if it's being probed, see
+    /// [`LoadShedLayer::probe_max_concurrency`].
+    pub max_concurrency: usize,
+    /// The concurrency that's actually available, see
+    /// [`LoadShed::effective_concurrency`].
+    pub effective_concurrency: usize,
+    /// The average latency of requests classified as successes, see
+    /// [`Classify`].
+    pub success_latency: Duration,
+    /// The average latency of requests classified as failures, these are often
+    /// much faster than successes.
+    pub failure_latency: Duration,
+    /// The average estimated size of requests, see [`RequestInfo::bytes`].
+    pub average_request_bytes: u64,
+    /// The average cost of requests in units, if there's a
+    /// [`LoadShedLayer::cost_budget`], see [`RequestInfo::cost`].
+    pub average_request_cost: f64,
+    /// Whether the service is overloaded but the concurrency is already at the
+    /// minimum, see [`LoadShedLayer::fail_fast_at_min_concurrency`].
+    pub at_min_concurrency: bool,
+    /// The rate requests are completing at, per second.
+    pub throughput: f64,
+    /// The rate requests are completing successfully within their deadline
+    /// at, per second, see [`LoadShed::goodput`].
+    pub goodput: f64,
+    /// The rate requests are arriving at, per second, see
+    /// [`LoadShed::arrival_rate`].
+    pub arrival_rate: f64,
+}
+
+/// Serializes with the units in the field names, durations as seconds, such as
+/// `average_latency_secs`, and rates per second, such as `throughput_per_sec`.
+#[cfg(feature = "serde")]
+impl serde::Serialize for LoadShedStats {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        use serde::ser::SerializeStruct;
+
+        let mut stats = serializer.serialize_struct("LoadShedStats", 22)?;
+        stats.serialize_field("average_latency_secs", &self.average_latency.as_secs_f64())?;
+        stats.serialize_field(
+            "slow_average_latency_secs",
+            &self.slow_average_latency.as_secs_f64(),
+        )?;
+        stats.serialize_field(
+            "average_latency_at_capacity_secs",
+            &self.average_latency_at_capacity.as_secs_f64(),
+        )?;
+        stats.serialize_field("concurrency", &self.concurrency)?;
+        stats.serialize_field("desired_concurrency", &self.desired_concurrency)?;
+        stats.serialize_field("queue_capacity", &self.queue_capacity)?;
+        stats.serialize_field("system_size", &self.system_size)?;
+        stats.serialize_field("in_flight", &self.in_flight)?;
+        stats.serialize_field("bytes_in_flight", &self.bytes_in_flight)?;
+        stats.serialize_field("increase_halted", &self.increase_halted)?;
+        stats.serialize_field("increases", &self.increases)?;
+        stats.serialize_field("decreases", &self.decreases)?;
+        stats.serialize_field("max_concurrency", &self.max_concurrency)?;
+        stats.serialize_field("effective_concurrency", &self.effective_concurrency)?;
+        stats.serialize_field("success_latency_secs", &self.success_latency.as_secs_f64())?;
+        stats.serialize_field("failure_latency_secs", &self.failure_latency.as_secs_f64())?;
+        stats.serialize_field("average_request_bytes", &self.average_request_bytes)?;
+        stats.serialize_field("average_request_cost", &self.average_request_cost)?;
+        stats.serialize_field("at_min_concurrency", &self.at_min_concurrency)?;
+        stats.serialize_field("throughput_per_sec", &self.throughput)?;
+        stats.serialize_field("goodput_per_sec", &self.goodput)?;
+        stats.serialize_field("arrival_rate_per_sec", &self.arrival_rate)?;
+        stats.end()
+    }
+}
+
+/// How long a request would wait in the queue, see
+/// [`LoadShed::estimated_wait`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub struct EstimatedWait {
+    /// The estimated wait, no longer than the maximum.
+    pub wait: Duration,
+    /// Whether the estimate was longer than the maximum, so `wait` is the
+    /// maximum instead.
+    pub capped: bool,
+}
+
+/// A borrowed view of the statistics of a [`LoadShed`] service, see
+/// [`LoadShed::with_stats`].
+///
+/// The methods return the same values as the fields of [`LoadShedStats`].
+pub struct StatsView<'a> {
+    conf: &'a LoadShedConf,
+    stats: &'a conf::ConfStats,
+}
+
+impl StatsView<'_> {
+    /// The average latency of requests through the inner service.
+    pub fn average_latency(&self) -> Duration {
+        Duration::from_secs_f64(self.stats.average_latency)
+    }
+
+    /// The slower moving average of the latency.
+    pub fn slow_average_latency(&self) -> Duration {
+        Duration::from_secs_f64(self.stats.slow_average_latency)
+    }
+
+    /// The average latency of requests that completed while the inner service
+    /// was at its concurrency limit.
+    pub fn average_latency_at_capacity(&self) -> Duration {
+        Duration::from_secs_f64(self.stats.average_latency_at_capacity)
+    }
+
+    /// The maximum concurrency of requests to the inner service.
+    pub fn concurrency(&self) -> usize {
+        self.stats.concurrency
+    }
+
+    /// The concurrency the controller wants, see
+    /// [`LoadShed::desired_concurrency`].
+    pub fn desired_concurrency(&self) -> usize {
+        self.stats.desired_concurrency
+    }
+
+    /// The capacity of the queue alone, unlike the deprecated
+    /// [`LoadShed::queue_capacity`] this doesn't include the concurrency. The
+    /// total is the [`system_size`](Self::system_size).
+    pub fn queue_capacity(&self) -> usize {
+        self.stats.queue_capacity
+    }
+
+    /// The total number of requests that will be admitted.
+    pub fn system_size(&self) -> usize {
+        self.stats.concurrency + self.stats.queue_capacity
+    }
+
+    /// The number of requests currently queued or being processed.
+    pub fn in_flight(&self) -> usize {
+        self.conf.queue_len(self.stats)
+    }
+
+    /// The total estimated size of the requests in flight.
+    pub fn bytes_in_flight(&self) -> u64 {
+        self.conf.bytes_in_flight()
+    }
+
+    /// Whether increases in concurrency are halted because the throughput
+    /// stopped improving.
+    pub fn increase_halted(&self) -> bool {
+        self.stats.increase_halted
+    }
+
+    /// The number of times the concurrency has been increased.
+    pub fn increases(&self) -> u64 {
+        self.stats.increases
+    }
+
+    /// The number of times the concurrency has been decreased.
+    pub fn decreases(&self) -> u64 {
+        self.stats.decreases
+    }
+
+    /// The maximum concurrency, which changes if it's being probed.
+    pub fn max_concurrency(&self) -> usize {
+        match self.stats.probed_max_concurrency {
+            Some(probed) if self.stats.probe_base == self.stats.runtime.max_concurrency => probed,
+            _ => self.stats.runtime.max_concurrency,
+        }
+    }
+
+    /// The concurrency that's actually available to requests.
+    pub fn effective_concurrency(&self) -> usize {
+        self.conf.effective_concurrency(self.stats)
+    }
+
+    /// The average latency of requests classified as successes.
+    pub fn success_latency(&self) -> Duration {
+        Duration::from_secs_f64(self.stats.success_latency)
+    }
+
+    /// The average latency of requests classified as failures.
+    pub fn failure_latency(&self) -> Duration {
+        Duration::from_secs_f64(self.stats.failure_latency)
+    }
+
+    /// The average estimated size of requests.
+    pub fn average_request_bytes(&self) -> u64 {
+        self.stats.average_request_bytes as u64
+    }
+
+    /// The average cost of requests in units, if there's a cost budget.
+    pub fn average_request_cost(&self) -> f64 {
+        self.stats.average_request_cost
+    }
+
+    /// Whether the service is overloaded but the concurrency is already at the
+    /// minimum.
+    pub fn at_min_concurrency(&self) -> bool {
+        self.stats.at_min_concurrency
+    }
+
+    /// The rate requests are completing at, per second.
+    pub fn throughput(&self) -> f64 {
+        self.stats.throughput()
+    }
+
+    /// The rate requests are completing successfully within their deadline
+    /// at, per second.
+    pub fn goodput(&self) -> f64 {
+        self.stats.throughput() * self.stats.good_fraction
+    }
+
+    /// The rate requests are arriving at, per second.
+    pub fn arrival_rate(&self) -> f64 {
+        self.conf.arrival_rate()
+    }
+}
+
+impl fmt::Debug for StatsView<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("StatsView")
+            .field("stats", self.stats)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Where the latency of recent requests has been spent, see
+/// [`LoadShed::latency_breakdown`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct LatencyBreakdown {
+    /// The time requests have spent waiting in the queue.
+    pub queue: LatencyPercentiles,
+    /// The time requests have spent in the inner service.
+    pub service: LatencyPercentiles,
+}
+
+/// Estimated percentiles of a latency distribution.
+///
+/// These are all zero if no requests have completed yet.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct LatencyPercentiles {
+    /// The median latency.
+    pub p50: Duration,
+    /// The 90th percentile latency.
+    pub p90: Duration,
+    /// The 99th percentile latency.
+    pub p99: Duration,
+}
+
+impl LatencyPercentiles {
+    fn new(histogram: &LatencyHistogram) -> Self {
+        let quantile = |q| Duration::from_secs_f64(histogram.quantile(q).unwrap_or(0.0));
+        Self {
+            p50: quantile(0.5),
+            p90: quantile(0.9),
+            p99: quantile(0.99),
+        }
     }
 }
 
 /// Either an error from the wrapped service or message that the request was shed
+///
+/// More reasons for shedding requests may be added, so matches need a
+/// wildcard arm.
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[non_exhaustive]
 pub enum LoadShedResponse<T> {
     /// A response from the inner service.
     Inner(T),
     /// The request was shed due to overload.
     Overload,
+    /// The request was shed because it was older than the maximum age when it
+    /// arrived, see [`LoadShedLayer::max_age`].
+    Expired,
+    /// The inner service panicked while handling the request, see
+    /// [`LoadShedLayer::catch_panics`].
+    Panicked,
+    /// The request was shed because requests are arriving faster than the
+    /// rate limit, see [`LoadShedLayer::rate_limit`].
+    RateLimited,
+    /// The request was shed because the service has been shut down, see
+    /// [`LoadShed::shutdown`].
+    ShuttingDown,
+}
+
+/// The reasons a request can be shed, see [`AdmissionDecision::Shed`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum ShedReason {
+    /// The queue or byte budget is full.
+    Overload,
+    /// The request is too old to be worth processing.
+    Expired,
+    /// Requests are arriving faster than the rate limit.
+    RateLimited,
+    /// The service has been shut down.
+    ShuttingDown,
+}
+
+impl ShedReason {
+    /// The response to return for a request shed for this reason.
+    fn response<T>(self) -> LoadShedResponse<T> {
+        match self {
+            ShedReason::Overload => LoadShedResponse::Overload,
+            ShedReason::Expired => LoadShedResponse::Expired,
+            ShedReason::RateLimited => LoadShedResponse::RateLimited,
+            ShedReason::ShuttingDown => LoadShedResponse::ShuttingDown,
+        }
+    }
+
+    /// The status to label the request metric with.
+    #[cfg(feature = "metrics")]
+    fn status(self) -> &'static str {
+        match self {
+            ShedReason::Overload => "rejected",
+            ShedReason::Expired => "expired",
+            ShedReason::RateLimited => "rate_limited",
+            ShedReason::ShuttingDown => "shutting_down",
+        }
+    }
+}
+
+/// Count a request in the request metric, with its label if it has one.
+#[cfg(feature = "metrics")]
+fn count_request(
+    labels: &conf::MetricLabels,
+    status: &'static str,
+    label: &Option<Cow<'static, str>>,
+) {
+    let mut labels = labels.with([("status", status)]);
+    if let Some(label) = label {
+        labels.push(metrics::Label::new("label", label.clone()));
+    }
+    increment_counter!("loadshedder.request", labels);
 }
 
 type BoxFuture<Output> = Pin<Box<dyn Future<Output = Output> + Send>>;
 
-impl<Request, Inner> Service<Request> for LoadShed<Inner>
+/// The payload of a caught panic.
+type PanicPayload = Box<dyn Any + Send>;
+
+/// A future that catches panics while it's polled.
+struct CatchUnwind<F>(Pin<Box<F>>);
+
+impl<F: Future> Future for CatchUnwind<F> {
+    type Output = Result<F::Output, PanicPayload>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        match std::panic::catch_unwind(AssertUnwindSafe(|| self.0.as_mut().poll(cx))) {
+            Ok(Poll::Pending) => Poll::Pending,
+            Ok(Poll::Ready(output)) => Poll::Ready(Ok(output)),
+            Err(panic) => Poll::Ready(Err(panic)),
+        }
+    }
+}
+
+impl<Request, Inner, C, I> Service<Request> for LoadShed<Inner, C, I>
 where
     Request: Send + 'static,
     Inner: Service<Request> + Clone + Send + 'static,
     Inner::Future: Send,
+    C: Classify<Inner::Response> + Clone + Send + 'static,
+    I: RequestInfo<Request>,
 {
     type Response = LoadShedResponse<Inner::Response>;
     type Error = Inner::Error;
@@ -375,29 +1257,193 @@ where
     }
 
     fn call(&mut self, req: Request) -> Self::Future {
+        self.shed(
+            req,
+            |response, completion| {
+                drop(completion);
+                LoadShedResponse::Inner(response)
+            },
+            |shed, _| shed.response(),
+            |_| LoadShedResponse::Panicked,
+        )
+    }
+}
+
+impl<Inner, C, I> LoadShed<Inner, C, I> {
+    /// Shed or call the inner service with the request, handing the response
+    /// and the [`Completion`] of the call to `finish` if it succeeds. The load
+    /// shedder counts the call as in flight until the completion is dropped.
+    /// Shed requests are handed to `on_shed` instead, and if panics are
+    /// caught then they're handed to `on_panic`.
+    fn shed<Request, Out>(
+        &mut self,
+        req: Request,
+        finish: impl FnOnce(Inner::Response, Completion) -> Out + Send + 'static,
+        on_shed: impl FnOnce(ShedReason, Request) -> Out + Send + 'static,
+        on_panic: impl FnOnce(PanicPayload) -> Out + Send + 'static,
+    ) -> BoxFuture<Result<Out, Inner::Error>>
+    where
+        Request: Send + 'static,
+        Inner: Service<Request> + Clone + Send + 'static,
+        Inner::Future: Send,
+        C: Classify<Inner::Response> + Clone + Send + 'static,
+        I: RequestInfo<Request>,
+    {
         // We're fine to use the clone because inner hasn't been polled to
         // readiness yet.
         let inner = self.inner.clone();
-        let mut conf = self.conf.clone();
+        let conf = self.conf.clone();
+        let classifier = self.classifier.clone();
+        let bytes = match self.request_info.cost(&req) {
+            Some(cost) => self.conf.cost_to_bytes(cost),
+            None => self.request_info.bytes(&req),
+        };
+        let arrival = self.request_info.arrival(&req).map(Instant::from_std);
+        let health_check = self.request_info.is_health_check(&req);
+        if !health_check {
+            self.conf.arrive(self.request_info.is_retry(&req));
+        }
+        #[cfg(feature = "metrics")]
+        let label = self.request_info.label(&req);
+        // Take a ticket now so that the request's place in line is decided by
+        // when it was called, not when its future is first polled. Health
+        // checks skip the queue, so they don't take one.
+        let ticket = conf
+            .arrivals
+            .as_ref()
+            .filter(|_| !health_check)
+            .map(|arrivals| arrivals.ticket());
+        conf.start_control_task();
+        conf.start_persistence_task();
+        let eager = (conf.config.eager_admission && !health_check)
+            .then(|| (Instant::now(), conf.enter(arrival, bytes)));
+        let origin = arrival.filter(|_| conf.config.latency_from_arrival);
+        let catch_panics = conf.config.catch_panics;
+        let readiness = conf.config.readiness;
+        // Under a readiness policy a request isn't counted as admitted until
+        // it's known whether it gets to the inner service, so keep hold of
+        // the conf to count it.
+        let deferred = (readiness != ReadinessPolicy::Wait).then(|| conf.clone());
         Box::pin(async move {
-            let permit = match conf.start().await {
-                Ok(permit) => {
-                    #[cfg(feature = "metrics")]
-                    increment_counter!("loadshedder.request", "status" => "accepted");
-                    permit
+            // Count the request as admitted or shed, once it's decided.
+            let count = |conf: &LoadShedConf, admitted: Result<u64, ShedReason>| {
+                conf.count_admission(admitted);
+                #[cfg(feature = "metrics")]
+                count_request(
+                    &conf.labels,
+                    admitted.map_or_else(ShedReason::status, |_| "accepted"),
+                    &label,
+                );
+            };
+            let (arrived, mut guard) = if health_check {
+                // Health checks skip the queue and are left out of the
+                // statistics, the same as probes.
+                match conf.admit_probe() {
+                    Ok(guard) => (Instant::now(), guard),
+                    Err(shed) => return Ok(on_shed(shed, req)),
                 }
-                Err(()) => {
-                    #[cfg(feature = "metrics")]
-                    increment_counter!("loadshedder.request", "status" => "rejected");
-                    return Ok(LoadShedResponse::Overload);
+            } else {
+                let (arrived, entered) = match eager {
+                    Some(eager) => eager,
+                    None => (Instant::now(), conf.enter(arrival, bytes)),
+                };
+                let admitted = conf.wait_admitted(entered, ticket).await;
+                if deferred.is_none() || admitted.is_err() {
+                    count(
+                        &conf,
+                        admitted
+                            .as_ref()
+                            .map(|(_, reservation)| reservation.bytes)
+                            .map_err(|shed| *shed),
+                    );
                 }
+                let guard = match admitted {
+                    Ok((permit, reservation)) => {
+                        LoadShedGuard::new(conf, arrived, permit, reservation).measured_from(origin)
+                    }
+                    Err(shed) => return Ok(on_shed(shed, req)),
+                };
+                (arrived, guard)
             };
-            let start = Instant::now();
+            let mut inner = inner;
+            if let (Some(conf), false) = (deferred, health_check) {
+                // Errors can't be held across an await, so deal with them in
+                // their own scope before waiting.
+                let pending = {
+                    let ready = std::future::poll_fn(|cx| Poll::Ready(inner.poll_ready(cx))).await;
+                    match ready {
+                        Poll::Ready(ready) => {
+                            count(&conf, Ok(guard.reserved_bytes()));
+                            if let Err(error) = ready {
+                                drop(guard.finish(Outcome::Failure));
+                                return Err(error);
+                            }
+                            false
+                        }
+                        Poll::Pending => true,
+                    }
+                };
+                if pending {
+                    // Give up the slot rather than hold it while the inner
+                    // service isn't ready.
+                    drop(guard);
+                    if readiness != ReadinessPolicy::Requeue {
+                        count(&conf, Err(ShedReason::Overload));
+                        return Ok(on_shed(ShedReason::Overload, req));
+                    }
+                    if let Err(error) = inner.ready().await {
+                        // It got as far as the inner service, the same as a
+                        // request whose readiness failed straight away.
+                        count(&conf, Ok(bytes));
+                        return Err(error);
+                    }
+                    // Once ready the inner service stays ready, so the request
+                    // can be called as soon as it's readmitted.
+                    let entered = conf.enter(arrival, bytes);
+                    let admitted = conf.wait_admitted(entered, None).await;
+                    count(
+                        &conf,
+                        admitted
+                            .as_ref()
+                            .map(|(_, reservation)| reservation.bytes)
+                            .map_err(|shed| *shed),
+                    );
+                    guard = match admitted {
+                        Ok((permit, reservation)) => {
+                            LoadShedGuard::new(conf, arrived, permit, reservation)
+                                .measured_from(origin)
+                        }
+                        Err(shed) => return Ok(on_shed(shed, req)),
+                    };
+                }
+            }
             // The elapsed time includes waiting for readiness which should help
             // us stay under any upstream concurrency limiters.
-            let response = inner.oneshot(req).await;
-            conf.stop(start.elapsed(), permit);
-            Ok(LoadShedResponse::Inner(response?))
+            let call = async {
+                inner.ready().await?;
+                guard.ready();
+                inner.call(req).await
+            };
+            let response = if catch_panics {
+                let caught = CatchUnwind(Box::pin(call)).await;
+                match caught {
+                    Ok(response) => response,
+                    Err(panic) => {
+                        // The panic is a failure of the inner service, so it
+                        // still counts towards reducing the concurrency.
+                        drop(guard.finish(Outcome::Failure));
+                        return Ok(on_panic(panic));
+                    }
+                }
+            } else {
+                call.await
+            };
+            let outcome = match &response {
+                Ok(response) => classifier.classify(response),
+                Err(_) => Outcome::Failure,
+            };
+            let completion = guard.finish(outcome);
+            Ok(finish(response?, completion))
         })
     }
 }
@@ -406,9 +1452,10 @@ where
 ///
 /// See [`LoadShed`] for details of the load shedding algorithm.
 #[derive(Debug, Clone)]
-pub struct LoadShedLayer {
-    ewma_param: f64,
-    target: Duration,
+pub struct LoadShedLayer<C = DefaultClassifier, I = DefaultRequestInfo> {
+    config: LoadShedConfig,
+    classifier: C,
+    request_info: I,
 }
 
 impl LoadShedLayer {
@@ -416,50 +1463,850 @@ impl LoadShedLayer {
     /// computing the current average latency using an exponentially weighted
     /// moving average with the given parameter.
     pub fn new(ewma_param: f64, target: Duration) -> Self {
-        Self { ewma_param, target }
+        Self {
+            config: LoadShedConfig::new(ewma_param, target),
+            classifier: DefaultClassifier,
+            request_info: DefaultRequestInfo,
+        }
+    }
+
+    /// Create a new layer using the given configuration, which is validated
+    /// first.
+    pub fn from_config(config: LoadShedConfig) -> Result<Self, ConfigError> {
+        config.validate()?;
+        Ok(Self {
+            config,
+            classifier: DefaultClassifier,
+            request_info: DefaultRequestInfo,
+        })
+    }
+}
+
+impl<C, I> LoadShedLayer<C, I> {
+    /// Use the given [`Classify`] implementation to decide which responses
+    /// from the inner service count as failures.
+    pub fn classifier<C2>(self, classifier: C2) -> LoadShedLayer<C2, I> {
+        LoadShedLayer {
+            config: self.config,
+            classifier,
+            request_info: self.request_info,
+        }
+    }
+
+    /// Use the given [`RequestInfo`] implementation to extract information
+    /// about requests, such as their size.
+    pub fn request_info<I2>(self, request_info: I2) -> LoadShedLayer<C, I2> {
+        LoadShedLayer {
+            config: self.config,
+            classifier: self.classifier,
+            request_info,
+        }
+    }
+
+    /// Limit the total size of the requests in flight (queued or being
+    /// processed by the inner service) to the given number of bytes.
+    ///
+    /// Request sizes are estimated by [`RequestInfo::bytes`], requests that
+    /// would take the total over budget are shed, even if there is room in
+    /// the queue.
+    pub fn byte_budget(mut self, bytes: u64) -> Self {
+        self.config.byte_budget = Some(bytes);
+        self
+    }
+
+    /// Limit the total cost of the requests in flight to `units`, where costs
+    /// can be fractional, such as half a unit for a cheap request.
+    ///
+    /// Request costs are given by [`RequestInfo::cost`], and are counted in
+    /// steps of `1 / resolution` of a unit, rounded up, so a resolution of 100
+    /// counts costs to the nearest hundredth. This takes the place of the
+    /// [`byte_budget`](Self::byte_budget), with the budget and the bytes in
+    /// flight counted in these steps. A resolution of zero counts whole units,
+    /// and a budget that isn't a non-negative number admits nothing.
+    pub fn cost_budget(mut self, units: f64, resolution: u32) -> Self {
+        let resolution = resolution.max(1);
+        let budget = units * f64::from(resolution);
+        // Too big a budget to count in steps is no limit at all.
+        self.config.byte_budget = Some(if budget >= u64::MAX as f64 {
+            u64::MAX
+        } else if budget >= 0.0 {
+            budget as u64
+        } else {
+            0
+        });
+        self.config.cost_resolution = Some(resolution);
+        self
+    }
+
+    /// Ask the given policy whether to admit each request to the queue, which
+    /// can override the load shedder's own decision, see [`AdmissionPolicy`].
+    pub fn admission_policy(mut self, policy: impl AdmissionPolicy) -> Self {
+        self.config.admission_policy = Some(Arc::new(policy));
+        self
+    }
+
+    /// Also limit the concurrency by a process wide semaphore, shared between
+    /// load shedders, so that together they can't overcommit the host.
+    ///
+    /// Admitted requests wait for a permit from the semaphore before calling
+    /// the inner service, and hold it until they complete. Each load shedder
+    /// still adapts its own concurrency, but the total across all of them never
+    /// exceeds the permits in the semaphore. The load shedders only acquire
+    /// and release permits, they never add or forget them.
+    pub fn global_concurrency(mut self, semaphore: Arc<Semaphore>) -> Self {
+        self.config.global_concurrency = Some(semaphore);
+        self
+    }
+
+    /// Limit how far a single request's latency can move the averages, to
+    /// at most `max_error` times the target over the target.
+    ///
+    /// A single huge latency spike can push the average latency so far over
+    /// the target that the concurrency is decreased again and again while it
+    /// decays. With this each request's latency is clamped to within the
+    /// error before it's averaged, so it takes a sustained error to move the
+    /// concurrency far. Requests faster than the target are averaged as they
+    /// are, so the queue is still sized for how fast the service really is.
+    pub fn max_target_error(mut self, max_error: f64) -> Self {
+        self.config.max_target_error = Some(max_error);
+        self
+    }
+
+    /// Limit the rate requests are admitted at to `rate` per second, allowing
+    /// bursts of up to `burst` requests, for services with a strict limit on
+    /// their request rate.
+    ///
+    /// Requests over the limit get [`LoadShedResponse::RateLimited`], even if
+    /// there's room in the queue. This is checked before the byte budget and
+    /// the queue, so requests shed by those still count towards the rate.
+    pub fn rate_limit(mut self, rate: f64, burst: u32) -> Self {
+        self.config.rate_limit = Some(rate);
+        self.config.rate_burst = f64::from(burst);
+        self
+    }
+
+    /// Leave failed requests out of the average latency measured at capacity,
+    /// which is used to size the queue.
+    ///
+    /// Failures are often much faster than successful requests, so counting
+    /// them would grow the queue just as the inner service starts failing.
+    pub fn exclude_failures_at_capacity(mut self, exclude: bool) -> Self {
+        self.config.exclude_failures_at_capacity = exclude;
+        self
+    }
+
+    /// Adjust the concurrency from a background task that runs at the given
+    /// interval, rather than as requests complete.
+    ///
+    /// This takes the work out of the request path and makes the rate of
+    /// adjustment independent of the traffic, completed requests only update
+    /// the latency averages. The task is spawned on the first call to the
+    /// service, so that must happen within a Tokio runtime, and stops once all
+    /// the clones of the service have been dropped.
+    pub fn control_interval(mut self, interval: Duration) -> Self {
+        self.config.control_interval = Some(interval);
+        self
+    }
+
+    /// Use a custom [`AdmissionQueue`] to decide the order queued requests are
+    /// sent to the inner service in, by default this is first-in first-out.
+    ///
+    /// The function is called to create a new queue for each service this
+    /// layer wraps.
+    pub fn queue<Q, F>(mut self, queue: F) -> Self
+    where
+        Q: AdmissionQueue,
+        F: Fn() -> Q + Send + Sync + 'static,
+    {
+        self.config.queue = Some(QueueFactory(Arc::new(move || Box::new(queue()))));
+        self
+    }
+
+    /// Shed requests that are already older than the given age when they
+    /// arrive, these have most likely missed their deadline so processing them
+    /// would be wasted work.
+    ///
+    /// The age of a request is measured from [`RequestInfo::arrival`], requests
+    /// where that isn't known are never shed for being too old.
+    pub fn max_age(mut self, max_age: Duration) -> Self {
+        self.config.max_age = Some(max_age);
+        self
+    }
+
+    /// Measure each request's latency from when it originally arrived, given
+    /// by [`RequestInfo::arrival`], rather than from when it was admitted, so
+    /// the target applies to the end to end latency including time spent
+    /// before the load shedder. Requests where that isn't known are measured
+    /// as usual.
+    ///
+    /// With the `http` feature `ExtensionRequestInfo` reads the arrival time
+    /// from an `Arrival` request extension.
+    pub fn latency_from_arrival(mut self, enabled: bool) -> Self {
+        self.config.latency_from_arrival = enabled;
+        self
+    }
+
+    /// Leave requests that are retries, according to
+    /// [`RequestInfo::is_retry`], out of the arrival rate, so that clients
+    /// retrying shed requests don't make the load look higher than it is.
+    pub fn exclude_retries(mut self, exclude: bool) -> Self {
+        self.config.exclude_retries = exclude;
+        self
+    }
+
+    /// Make admitted requests enter the inner service in exactly the order the
+    /// service was called with them, which some stateful services require.
+    ///
+    /// Without this requests are let through roughly in order, but requests
+    /// whose futures are polled sooner can overtake older ones. Note that with
+    /// this enabled, a request whose future isn't polled holds up all the
+    /// requests behind it. This has no effect if a custom queue is being used.
+    pub fn strict_fifo(mut self, strict_fifo: bool) -> Self {
+        self.config.strict_fifo = strict_fifo;
+        self
+    }
+
+    /// Adapt the moving average parameter to how volatile the latency is.
+    ///
+    /// The configured parameter is used while the latency is stable, so the
+    /// averages react quickly to real changes, and it's reduced as the variance
+    /// of the latency grows, so noisy latencies are smoothed out more. The
+    /// variance is tracked as requests complete.
+    pub fn adaptive_ewma(mut self, adaptive: bool) -> Self {
+        self.config.adaptive_ewma = adaptive;
+        self
+    }
+
+    /// Weight each latency sample by the time since the previous one, so the
+    /// averages decay exponentially with this time constant rather than by
+    /// the moving average parameter.
+    ///
+    /// With a fixed parameter the averages react quickly under heavy traffic
+    /// and slowly under light traffic, with a time constant they react at the
+    /// same speed whatever the request rate. The parameter is still used to
+    /// rate limit changes to the concurrency.
+    pub fn ewma_time_constant(mut self, constant: Duration) -> Self {
+        self.config.ewma_time_constant = Some(constant);
+        self
+    }
+
+    /// Control the concurrency on the latency samples from the trailing
+    /// `window`, rather than on the moving average.
+    ///
+    /// The average is the mean of the window, kept current as each sample
+    /// arrives, so a step change in latency is fully reflected once the
+    /// window has passed and a burst of outliers drops out once it's over.
+    /// The [`target_percentile`](Self::target_percentile), if there is one,
+    /// is only found from the window when the concurrency is adjusted. To
+    /// bound the cost the window holds at most the latest 1024 samples.
+    pub fn latency_window(mut self, window: Duration) -> Self {
+        self.config.latency_window = Some(window);
+        self
+    }
+
+    /// Never report a [`LoadShed::estimated_wait`] longer than `max`, a
+    /// retry hint of several minutes during a deep overload is more likely to
+    /// make clients give up than to help them.
+    pub fn max_estimated_wait(mut self, max: Duration) -> Self {
+        self.config.max_estimated_wait = Some(max);
+        self
+    }
+
+    /// Add these labels to every metric the load shedder emits, such as the
+    /// name of the service or the region it's running in, to match the labels
+    /// used by the rest of your metrics.
+    pub fn metric_labels(mut self, labels: Vec<(&'static str, String)>) -> Self {
+        self.config.metric_labels = labels;
+        self
+    }
+
+    /// Hold the given `percentile` of the latency at the target rather than
+    /// the average, see [`LoadShed::with_percentile_target`].
+    pub fn target_percentile(mut self, percentile: f64) -> Self {
+        self.config.target_percentile = Some(percentile);
+        self
+    }
+
+    /// Stop increasing the concurrency once doing so no longer improves the
+    /// throughput.
+    ///
+    /// If an increase fails to raise the throughput by at least `margin` (as
+    /// a fraction, `0.05` is 5%) then further increases are halted until the
+    /// throughput or average latency moves by more than `margin`. Decreases
+    /// carry on as normal. The throughput is emitted as the
+    /// `loadshedder.throughput` gauge.
+    pub fn throughput_halt_margin(mut self, margin: f64) -> Self {
+        self.config.throughput_halt_margin = Some(margin);
+        self
+    }
+
+    /// Never shrink the queue capacity below `min_queue` requests, so short
+    /// bursts can be absorbed even when the service is close to the target
+    /// latency. This is at least 1, which is the default, and the
+    /// [`max_queue`](Self::max_queue) is raised to match if it's smaller.
+    ///
+    /// A custom [`AdmissionQueue`] with a smaller
+    /// [`capacity`](AdmissionQueue::capacity) still sheds requests beyond its
+    /// own capacity.
+    pub fn min_queue(mut self, min_queue: usize) -> Self {
+        self.config.min_queue = min_queue.max(1);
+        self.config.max_queue = self.config.max_queue.max(self.config.min_queue);
+        self
+    }
+
+    /// Never grow the queue capacity above `max_queue` requests. This is at
+    /// least the [`min_queue`](Self::min_queue), whichever is set first,
+    /// where [`LoadShed::from_config`] would reject a smaller maximum.
+    pub fn max_queue(mut self, max_queue: usize) -> Self {
+        self.config.max_queue = max_queue.max(self.config.min_queue);
+        self
+    }
+
+    /// Never reduce the concurrency below `min_concurrency`, this is also the
+    /// concurrency the service starts at. This is at least 1, which is the
+    /// default.
+    pub fn min_concurrency(mut self, min_concurrency: usize) -> Self {
+        self.config.min_concurrency = min_concurrency.max(1);
+        self
+    }
+
+    /// Never increase the concurrency above `max_concurrency`.
+    pub fn max_concurrency(mut self, max_concurrency: usize) -> Self {
+        self.config.max_concurrency = max_concurrency;
+        self
+    }
+
+    /// Only decrease the concurrency for failures once they're more than
+    /// `rate` of the requests completed since the last adjustment, `0.05` by
+    /// default.
+    ///
+    /// An occasional failure is normal and says little about the load, so
+    /// this stops a single error among many successes from reducing the
+    /// concurrency. A rate of zero decreases it for any failure.
+    pub fn max_failure_rate(mut self, rate: f64) -> Self {
+        self.config.max_failure_rate = rate;
+        self
+    }
+
+    /// Count failed requests as taking at least `penalty` when updating the
+    /// average latencies, for example twice the target latency.
+    ///
+    /// This ties the error rate to the concurrency, a burst of failures pushes
+    /// the average latency over the target and the concurrency down. The
+    /// latency histograms and metrics still record the real latency.
+    pub fn failure_latency_penalty(mut self, penalty: Duration) -> Self {
+        self.config.failure_latency_penalty = Some(penalty);
+        self
+    }
+
+    /// Drive the runtime tunable options from a [`watch`] channel, so they can
+    /// all be changed together while the service is running.
+    ///
+    /// The latest value is picked up as requests start and complete, it
+    /// overrides the matching options set on this layer, including the initial
+    /// value. Values that fail [`LoadShedRuntimeConfig::validate`] are ignored.
+    ///
+    /// [`watch`]: tokio::sync::watch
+    pub fn runtime_config(mut self, runtime: watch::Receiver<LoadShedRuntimeConfig>) -> Self {
+        self.config.runtime = Some(runtime);
+        self
+    }
+
+    /// Shrink the queue while the average ratio of total latency (including
+    /// the time spent queued) to service latency is above `max`.
+    ///
+    /// When the service has a slow period every queued request inherits the
+    /// backlog, which amplifies the tail latency. With this the queue capacity
+    /// is scaled down while the amplification is too high, and back up once
+    /// it's under control. This is emitted as the
+    /// `loadshedder.tail_amplification` gauge.
+    pub fn max_tail_amplification(mut self, max: f64) -> Self {
+        self.config.max_tail_amplification = Some(max);
+        self
+    }
+
+    /// Decide whether to shed a request when the service is called, rather
+    /// than when the response future is first polled.
+    ///
+    /// A shed request's future is then immediately ready, and an admitted
+    /// request already has its place in the queue, its future is pending
+    /// until it gets through without anything needing to be spawned. This is
+    /// useful for custom schedulers that poll futures some time after
+    /// creating them.
+    pub fn eager_admission(mut self, eager: bool) -> Self {
+        self.config.eager_admission = eager;
+        self
+    }
+
+    /// Tune the maximum concurrency automatically, for services whose capacity
+    /// changes over time.
+    ///
+    /// Every `interval`, if the concurrency has reached the maximum since the
+    /// last probe, starting from [`max_concurrency`](Self::max_concurrency),
+    /// the maximum is raised by 10%. If the latency is noticeably worse, or over the target, by the
+    /// next probe then it's lowered back again. The current maximum is emitted
+    /// as the `loadshedder.max_concurrency` gauge. Probing starts again from
+    /// the configured maximum whenever that's changed at runtime or by
+    /// [`LoadShed::reconfigure`].
+    pub fn probe_max_concurrency(mut self, interval: Duration) -> Self {
+        self.config.probe_interval = Some(interval);
+        self
+    }
+
+    /// Let requests skip the queue when there's a concurrency permit free,
+    /// without resizing the queue first.
+    ///
+    /// This cuts the overhead of each call for services that usually respond
+    /// quickly, such as caches. The queue is only resized by requests that
+    /// have to wait, which is when its size matters. The latency of requests
+    /// that skip the queue and complete with capacity to spare is only
+    /// emitted as a metric, it's left out of the statistics that control the
+    /// concurrency as there's nothing to learn from them. Those that fail, or
+    /// that complete once the inner service is at its concurrency limit, are
+    /// measured as usual. This has no effect with a custom queue
+    /// or [`strict_fifo`](Self::strict_fifo), where requests must take their
+    /// turn.
+    pub fn fast_path(mut self, fast_path: bool) -> Self {
+        self.config.fast_path = fast_path;
+        self
+    }
+
+    /// Limit the queue capacity to growing by at most `growth` every
+    /// `interval`.
+    ///
+    /// When the latency is well under the target the queue can grow large,
+    /// and if the service then suddenly slows down the deep queue amplifies
+    /// the latency until it shrinks again. Limiting the growth stops a brief
+    /// fast period from inflating the queue. The queue can still shrink
+    /// straight away.
+    pub fn max_queue_growth(mut self, growth: usize, interval: Duration) -> Self {
+        self.config.max_queue_growth = growth;
+        self.config.queue_growth_interval = Some(interval);
+        self
+    }
+
+    /// Shrink the queue to its minimum once the average latency has stayed
+    /// below `fraction` of the target for `window`.
+    ///
+    /// When the service is much faster than the target the queue can grow
+    /// very long, which absorbs bursts but becomes a deep backlog if the
+    /// service suddenly slows down. This trades the burst absorption for a
+    /// better tail latency, the queue grows again as soon as the average
+    /// latency rises above the fraction.
+    pub fn fast_response_shrink(mut self, fraction: f64, window: Duration) -> Self {
+        self.config.fast_response_fraction = Some(fraction);
+        self.config.fast_response_window = window;
+        self
+    }
+
+    /// Decrease the concurrency when the queue is more than `fraction` full
+    /// and still growing, even if the latency is below the target.
+    ///
+    /// A filling queue is a leading sign of overload, the latency only rises
+    /// once requests have waited in it, so this backs off sooner at the cost
+    /// of sometimes backing off from a burst the service could have handled.
+    pub fn queue_backoff(mut self, fraction: f64) -> Self {
+        self.config.queue_backoff_fraction = Some(fraction);
+        self
+    }
+
+    /// Stop increasing the concurrency while the average latency is below
+    /// `floor`.
+    ///
+    /// An inner service that responds almost instantly, such as from a cache,
+    /// never gets near the target latency, so without this the concurrency
+    /// keeps climbing to the maximum and can exhaust whatever is downstream.
+    pub fn latency_floor(mut self, floor: Duration) -> Self {
+        self.config.latency_floor = Some(floor);
+        self
+    }
+
+    /// Increase the concurrency in steps proportional to how far the latency
+    /// is below the target, up to `max_step` at a time, rather than by one.
+    ///
+    /// The step is the number of times the latency would fit into the target
+    /// minus one, so a service at a tenth of the target ramps up nine at a
+    /// time, while one near the target still only steps by one. This ramps up
+    /// much faster after a restart without overshooting near the target.
+    pub fn proportional_increase(mut self, max_step: usize) -> Self {
+        self.config.max_increase_step = Some(max_step);
+        self
+    }
+
+    /// Track a second, slower, moving average of the latency with the given
+    /// parameter, which should be smaller than the main one, and decrease the
+    /// concurrency when the latency is rising fast enough to cross the target
+    /// soon.
+    ///
+    /// The gap between the two averages shows which way the latency is heading
+    /// and how fast, so this backs off before the main average reaches the
+    /// target. Both averages are in the [`LoadShedStats`].
+    pub fn trend_backoff(mut self, slow_ewma_param: f64) -> Self {
+        self.config.slow_ewma_param = Some(slow_ewma_param);
+        self
+    }
+
+    /// Favour admitting more requests while the latency is clearly falling,
+    /// that is while the main average is below the slow one from
+    /// [`trend_backoff`](Self::trend_backoff) by more than `margin` of it.
+    ///
+    /// A falling latency means the service is recovering from a transient and
+    /// has capacity to spare, so while it lasts the concurrency is increased by
+    /// twice the usual step, and a dip in throughput alone doesn't decrease it.
+    /// This does nothing without `trend_backoff`.
+    pub fn trend_recovery(mut self, margin: f64) -> Self {
+        self.config.recovery_margin = Some(margin);
+        self
+    }
+
+    /// Start the concurrency at the minimum after the service starts, is
+    /// [reset](LoadShed::reset) or has its state
+    /// [imported](LoadShed::import_state), and ramp the maximum back up
+    /// linearly over `warmup`.
+    ///
+    /// This protects an inner service that's recovering at the same time,
+    /// such as after both have restarted, from a sudden jump to the
+    /// concurrency that it could handle before.
+    pub fn warmup(mut self, warmup: Duration) -> Self {
+        self.config.warmup = Some(warmup);
+        self
+    }
+
+    /// Let up to `probes` requests past a full queue every `interval`, to check
+    /// whether the service has recovered.
+    ///
+    /// After heavy shedding the queue can be so short that it takes a long
+    /// time for the load shedder to see the service is responding quickly
+    /// again. Probe requests are queued as normal and counted in the
+    /// `loadshedder.recovery_probe` counter. Probes are only let through when
+    /// the queue is full, they're a small controlled increase in load, similar
+    /// to a circuit breaker's half-open state.
+    pub fn recovery_probes(mut self, interval: Duration, probes: usize) -> Self {
+        self.config.recovery_probe_interval = Some(interval);
+        self.config.recovery_probes = probes;
+        self
+    }
+
+    /// Stop queueing requests while the service is overloaded at the minimum
+    /// concurrency, only admitting requests that can go straight to the inner
+    /// service.
+    ///
+    /// Normally the concurrency is decreased when the latency is over the
+    /// target, but that can't happen at the minimum, so the queue keeps adding
+    /// latency. Whether or not this is enabled, reaching this state is counted
+    /// by the `loadshedder.at_min_concurrency` counter and reported as
+    /// [`LoadShedEvent::AtMinConcurrency`].
+    pub fn fail_fast_at_min_concurrency(mut self, fail_fast: bool) -> Self {
+        self.config.fail_fast_at_min_concurrency = fail_fast;
+        self
+    }
+
+    /// Choose what happens to an admitted request when the inner service isn't
+    /// ready for it, see [`ReadinessPolicy`].
+    pub fn readiness_policy(mut self, policy: ReadinessPolicy) -> Self {
+        self.config.readiness = policy;
+        self
+    }
+
+    /// Choose when a queued request gives up its place in the queue, see
+    /// [`QueueSlotPolicy`].
+    pub fn queue_slot_policy(mut self, policy: QueueSlotPolicy) -> Self {
+        self.config.queue_slot = policy;
+        self
+    }
+
+    /// Catch panics from the inner service, returning
+    /// [`LoadShedResponse::Panicked`] instead of letting the panic take down
+    /// the task polling the response.
+    ///
+    /// The request is counted as a failure and its permit released as usual. A
+    /// [`FallbackLoadShed`] has nothing to return in its place, so it resumes
+    /// the panic once the permit's been released.
+    pub fn catch_panics(mut self, catch_panics: bool) -> Self {
+        self.config.catch_panics = catch_panics;
+        self
+    }
+
+    /// Preset the tuning options for the given goal, see [`OptimizationGoal`]
+    /// for the options each goal sets. Options set explicitly, whether before
+    /// or after this, take precedence over the preset.
+    pub fn optimize_for(mut self, goal: OptimizationGoal) -> Self {
+        self.config.optimize_for(goal);
+        self
+    }
+
+    /// Forget what's been learned about the inner service's latency whenever
+    /// `signal` is triggered, such as by a `ResetOnChange` when the backends
+    /// behind the inner service change.
+    ///
+    /// See [`LoadShed::reset`] for what's forgotten.
+    pub fn reset_on(mut self, signal: ResetSignal) -> Self {
+        self.config.reset_signal = Some(signal);
+        self
+    }
+
+    /// Save the learned state to `store` every `interval`, and load it when
+    /// each service is created, so that restarts don't have to learn the
+    /// latency and concurrency again.
+    ///
+    /// Saving starts with the first request.
+    pub fn persist_state(mut self, store: impl StatePersistence, interval: Duration) -> Self {
+        self.config.persistence = Some(Persistence {
+            store: Arc::new(store),
+            interval,
+        });
+        self
+    }
+
+    /// Make the target latency follow a daily schedule, such as a tighter
+    /// target at peak times.
+    ///
+    /// The schedule's target takes the place of the one the layer was created
+    /// with, and of the target in any [`runtime_config`](Self::runtime_config).
+    pub fn target_schedule(mut self, schedule: TargetSchedule) -> Self {
+        self.config.target_schedule = Some(schedule);
+        self
+    }
+
+    /// Keep a record of up to `capacity` of the most recent decisions made,
+    /// every admitted and shed request, every latency sample and every change
+    /// to the concurrency, see [`LoadShed::take_decisions`].
+    ///
+    /// The records are timestamped so production traffic can be replayed
+    /// offline, such as to try out a different configuration. The oldest
+    /// records are dropped once the log is full, so take them regularly.
+    #[cfg(feature = "decision-log")]
+    pub fn decision_log(mut self, capacity: usize) -> Self {
+        self.config.decision_log = Some(capacity);
+        self
+    }
+
+    /// Call `on_event` with each [`LoadShedEvent`] as it happens, such as the
+    /// [`LatencyAttribution`] of every completed request.
+    ///
+    /// This is called synchronously on the request path, so it should be
+    /// quick. Some events are emitted while the statistics are locked, so it
+    /// mustn't call back into the load shedder.
+    pub fn on_event<F>(mut self, on_event: F) -> Self
+    where
+        F: Fn(&LoadShedEvent) + Send + Sync + 'static,
+    {
+        self.config.on_event = Some(EventHandler(Arc::new(on_event)));
+        self
+    }
+
+    /// Only report [`LoadShedEvent::OverloadStarted`] and
+    /// [`LoadShedEvent::OverloadEnded`] once requests have been shed, or
+    /// admitted, for this long, so a service on the edge of overload doesn't
+    /// flap between the two, this is zero by default.
+    ///
+    /// Only requests shed because the queue is full count as overload, those
+    /// shed for other reasons, such as [`LoadShed::force_shed`] or the rate
+    /// limit, leave the state as it is. While overloaded the state is also
+    /// checked periodically, so the end of an overload is reported even if
+    /// requests stop arriving.
+    pub fn overload_debounce(mut self, debounce: Duration) -> Self {
+        self.config.overload_debounce = debounce;
+        self
     }
 }
 
-impl<Inner> Layer<Inner> for LoadShedLayer {
-    type Service = LoadShed<Inner>;
+impl<Inner, C: Clone, I: Clone> Layer<Inner> for LoadShedLayer<C, I> {
+    type Service = LoadShed<Inner, C, I>;
 
     fn layer(&self, inner: Inner) -> Self::Service {
-        LoadShed::new(inner, self.ewma_param, self.target)
+        LoadShed {
+            conf: LoadShedConf::new(&self.config),
+            inner,
+            classifier: self.classifier.clone(),
+            request_info: self.request_info.clone(),
+        }
     }
 }
 
 #[cfg(feature = "axum")]
 mod axum_integration {
-    use crate::LoadShedResponse;
+    use crate::{
+        BoxFuture, DefaultClassifier, DefaultRequestInfo, LoadShed, LoadShedLayer, LoadShedResponse,
+    };
     use axum::body::Body;
-    use axum::http;
+    use axum::http::{self, header, HeaderValue, StatusCode};
     use axum::response::{IntoResponse, Response};
     use lazy_static::lazy_static;
     use std::sync::Mutex;
+    use std::task::{Context, Poll};
+    use std::time::Duration;
+    use tower::{Layer, Service};
 
     lazy_static! {
         static ref OVERLOAD_BODY: Mutex<String> =
             Mutex::new("The service is currently overloaded. Please try again later.".to_string());
     }
 
+    /// Set the body of the responses sent for shed requests.
     pub fn set_overload_body(body: &str) {
         *OVERLOAD_BODY.lock().unwrap() = body.to_string();
     }
 
+    /// Build the response sent for a shed request.
+    fn shed_response(status: StatusCode, retry_after: Option<Duration>) -> Response<Body> {
+        let custom_body = OVERLOAD_BODY.lock().unwrap().clone();
+        let mut response = http::Response::builder()
+            .status(status)
+            .body(Body::from(custom_body))
+            .unwrap();
+        if let Some(retry_after) = retry_after {
+            // Retry-After is in whole seconds, round up so clients don't retry
+            // too early.
+            let seconds = retry_after.as_secs() + u64::from(retry_after.subsec_nanos() > 0);
+            response
+                .headers_mut()
+                .insert(header::RETRY_AFTER, HeaderValue::from(seconds));
+        }
+        response
+    }
+
     impl IntoResponse for LoadShedResponse<Response<Body>> {
         fn into_response(self) -> Response<Body> {
             match self {
                 LoadShedResponse::Inner(inner) => inner,
-                LoadShedResponse::Overload => {
-                    let custom_body = OVERLOAD_BODY.lock().unwrap().clone();
-                    http::Response::builder()
-                        .status(http::StatusCode::SERVICE_UNAVAILABLE)
-                        .body(Body::from(custom_body))
-                        .unwrap()
+                LoadShedResponse::Overload
+                | LoadShedResponse::Expired
+                | LoadShedResponse::ShuttingDown => {
+                    shed_response(StatusCode::SERVICE_UNAVAILABLE, None)
                 }
+                LoadShedResponse::Panicked => StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+                LoadShedResponse::RateLimited => shed_response(StatusCode::TOO_MANY_REQUESTS, None),
             }
         }
     }
+
+    /// A [`Layer`] that wraps services in a [`LoadShed`] middleware and turns
+    /// shed requests into HTTP responses.
+    ///
+    /// Shed requests get a `503 Service Unavailable` response by default, with
+    /// the body set by [`set_overload_body`].
+    #[derive(Debug, Clone)]
+    pub struct HttpLoadShedLayer<C = DefaultClassifier, I = DefaultRequestInfo> {
+        layer: LoadShedLayer<C, I>,
+        status: StatusCode,
+        retry_after: Option<Duration>,
+        estimate_retry_after: bool,
+    }
+
+    impl HttpLoadShedLayer {
+        /// Create a new layer with the given target average latency and
+        /// computing the current average latency using an exponentially weighted
+        /// moving average with the given parameter.
+        pub fn new(ewma_param: f64, target: Duration) -> Self {
+            LoadShedLayer::new(ewma_param, target).into()
+        }
+    }
+
+    impl<C, I> From<LoadShedLayer<C, I>> for HttpLoadShedLayer<C, I> {
+        fn from(layer: LoadShedLayer<C, I>) -> Self {
+            Self {
+                layer,
+                status: StatusCode::SERVICE_UNAVAILABLE,
+                retry_after: None,
+                estimate_retry_after: false,
+            }
+        }
+    }
+
+    impl<C, I> HttpLoadShedLayer<C, I> {
+        /// Set the status code of the responses sent for shed requests, for
+        /// example `429 Too Many Requests`.
+        pub fn status(mut self, status: StatusCode) -> Self {
+            self.status = status;
+            self
+        }
+
+        /// Add a `Retry-After` header to the responses sent for shed requests.
+        pub fn retry_after(mut self, retry_after: Duration) -> Self {
+            self.retry_after = Some(retry_after);
+            self
+        }
+
+        /// Add a `Retry-After` header to the responses sent for overloaded
+        /// requests, of the [`LoadShed::estimated_wait`] when they were shed.
+        ///
+        /// Cap it with [`LoadShedLayer::max_estimated_wait`], rate limited
+        /// requests still use the fixed [`retry_after`](Self::retry_after).
+        pub fn retry_after_estimated(mut self) -> Self {
+            self.estimate_retry_after = true;
+            self
+        }
+    }
+
+    impl<Inner, C: Clone, I: Clone> Layer<Inner> for HttpLoadShedLayer<C, I> {
+        type Service = HttpLoadShed<Inner, C, I>;
+
+        fn layer(&self, inner: Inner) -> Self::Service {
+            HttpLoadShed {
+                inner: self.layer.layer(inner),
+                status: self.status,
+                retry_after: self.retry_after,
+                estimate_retry_after: self.estimate_retry_after,
+            }
+        }
+    }
+
+    /// A [`LoadShed`] service that turns shed requests into HTTP responses,
+    /// see [`HttpLoadShedLayer`].
+    #[derive(Debug, Clone)]
+    pub struct HttpLoadShed<Inner, C = DefaultClassifier, I = DefaultRequestInfo> {
+        inner: LoadShed<Inner, C, I>,
+        status: StatusCode,
+        retry_after: Option<Duration>,
+        estimate_retry_after: bool,
+    }
+
+    impl<Inner, C, I> HttpLoadShed<Inner, C, I> {
+        /// The load shedder whose shed requests become these HTTP responses,
+        /// for example to report its statistics alongside them.
+        pub fn load_shed(&self) -> &LoadShed<Inner, C, I> {
+            &self.inner
+        }
+    }
+
+    impl<Request, Inner, C, I> Service<Request> for HttpLoadShed<Inner, C, I>
+    where
+        LoadShed<Inner, C, I>: Service<Request, Response = LoadShedResponse<Response<Body>>>,
+        <LoadShed<Inner, C, I> as Service<Request>>::Future: Send + 'static,
+    {
+        type Response = Response<Body>;
+        type Error = <LoadShed<Inner, C, I> as Service<Request>>::Error;
+        type Future = BoxFuture<Result<Self::Response, Self::Error>>;
+
+        fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+            self.inner.poll_ready(cx)
+        }
+
+        fn call(&mut self, req: Request) -> Self::Future {
+            let response = self.inner.call(req);
+            let status = self.status;
+            let retry_after = self.retry_after;
+            let conf = self.estimate_retry_after.then(|| self.inner.conf.clone());
+            Box::pin(async move {
+                Ok(match response.await? {
+                    LoadShedResponse::Inner(inner) => inner,
+                    LoadShedResponse::Overload | LoadShedResponse::Expired => {
+                        let estimated = conf.map(|conf| conf.estimated_wait().wait);
+                        shed_response(status, estimated.or(retry_after))
+                    }
+                    // Retrying won't help, the service isn't coming back.
+                    LoadShedResponse::ShuttingDown => {
+                        shed_response(StatusCode::SERVICE_UNAVAILABLE, None)
+                    }
+                    LoadShedResponse::Panicked => StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+                    LoadShedResponse::RateLimited => {
+                        shed_response(StatusCode::TOO_MANY_REQUESTS, retry_after)
+                    }
+                })
+            })
+        }
+    }
 }
 
 #[cfg(feature = "axum")]
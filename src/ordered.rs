@@ -0,0 +1,117 @@
+//! Returning responses in the order their requests arrived.
+
+use std::{
+    sync::{atomic, Arc},
+    task::{Context, Poll},
+};
+
+use tower::Service;
+
+use crate::{
+    sequence::Sequencer, BoxFuture, Classify, DefaultClassifier, DefaultRequestInfo, LoadShed,
+    LoadShedResponse, RequestInfo, ShedReason,
+};
+
+/// A [`LoadShed`] service that returns responses in the order it was called,
+/// see [`LoadShed::ordered`].
+#[derive(Debug, Clone)]
+pub struct OrderedLoadShed<Inner, C = DefaultClassifier, I = DefaultRequestInfo> {
+    inner: LoadShed<Inner, C, I>,
+    responses: Arc<Sequencer>,
+}
+
+impl<Inner, C, I> LoadShed<Inner, C, I> {
+    /// Return responses in the order the service was called, while still
+    /// calling the inner service concurrently, for protocols that need their
+    /// responses in order.
+    ///
+    /// Requests give up their place in the service as soon as the inner
+    /// service responds, so a slow response can't hold up the ones ahead of it
+    /// by keeping their permits, and the latency measured doesn't include the
+    /// wait for their turn. The responses waiting for their turn are limited
+    /// to the queue capacity instead, requests are shed with
+    /// [`LoadShedResponse::Overload`] while that many are waiting. This is a
+    /// soft limit as it's checked when the service is called, requests
+    /// already called still wait their turn however many are waiting. Shed
+    /// requests and errors also wait for their turn. Clones of the returned
+    /// service share one order.
+    pub fn ordered(self) -> OrderedLoadShed<Inner, C, I> {
+        OrderedLoadShed {
+            inner: self,
+            responses: Arc::default(),
+        }
+    }
+}
+
+impl<Inner, C, I> OrderedLoadShed<Inner, C, I> {
+    /// The load shedder behind the ordering, shared by every clone of this
+    /// service.
+    pub fn load_shed(&self) -> &LoadShed<Inner, C, I> {
+        &self.inner
+    }
+}
+
+impl<Request, Inner, C, I> Service<Request> for OrderedLoadShed<Inner, C, I>
+where
+    Request: Send + 'static,
+    Inner: Service<Request> + Clone + Send + 'static,
+    Inner::Response: Send,
+    Inner::Error: Send,
+    Inner::Future: Send,
+    C: Classify<Inner::Response> + Clone + Send + 'static,
+    I: RequestInfo<Request>,
+{
+    type Response = LoadShedResponse<Inner::Response>;
+    type Error = Inner::Error;
+    type Future = BoxFuture<Result<Self::Response, Self::Error>>;
+
+    /// Always ready because there's a queue between this service and the inner one.
+    fn poll_ready(&mut self, _: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, req: Request) -> Self::Future {
+        // Take the ticket now so the order is the order of calls, not of the
+        // futures first being polled.
+        let ticket = self.responses.ticket();
+        let capacity = self
+            .inner
+            .conf
+            .queue_capacity
+            .load(atomic::Ordering::Relaxed);
+        // Health checks are always admitted, so they go to the load shedder
+        // like any other admitted request. The responses parked are only
+        // checked here, those of the requests already called can still park
+        // over the limit.
+        let request_info = &self.inner.request_info;
+        let response: Self::Future =
+            if self.responses.parked() >= capacity.max(1) && !request_info.is_health_check(&req) {
+                let conf = &self.inner.conf;
+                // This is still an arrival, and one of the most telling.
+                conf.arrive(request_info.is_retry(&req));
+                conf.count_admission(Err(ShedReason::Overload));
+                #[cfg(feature = "metrics")]
+                crate::count_request(
+                    &conf.labels,
+                    ShedReason::Overload.status(),
+                    &self.inner.request_info.label(&req),
+                );
+                Box::pin(std::future::ready(Ok(LoadShedResponse::Overload)))
+            } else {
+                // The completion is dropped as soon as the response is ready, so
+                // the request's place in the service isn't held while it waits.
+                self.inner.shed(
+                    req,
+                    |response, _| LoadShedResponse::Inner(response),
+                    |shed, _| shed.response(),
+                    |_| LoadShedResponse::Panicked,
+                )
+            };
+        Box::pin(async move {
+            let response = response.await;
+            ticket.turn().await;
+            ticket.finish();
+            response
+        })
+    }
+}
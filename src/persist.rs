@@ -0,0 +1,50 @@
+//! Saving what the load shedder has learned so it survives restarts.
+
+use std::{fmt::Debug, sync::Arc, time::Duration};
+
+/// What a load shedder has learned about its inner service, see
+/// [`LoadShed::export_state`](crate::LoadShed::export_state).
+///
+/// Restoring this lets a restarted service pick up where it left off, rather
+/// than learning the latency and concurrency again from the defaults.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct PersistedState {
+    /// The average latency of requests through the inner service.
+    pub average_latency: Duration,
+    /// The average latency of requests that completed while the inner service
+    /// was at its concurrency limit.
+    pub average_latency_at_capacity: Duration,
+    /// The concurrency limit.
+    pub concurrency: usize,
+}
+
+/// Somewhere to keep a load shedder's [`PersistedState`] between restarts,
+/// such as a file or a key-value store, see
+/// [`LoadShedLayer::persist_state`](crate::LoadShedLayer::persist_state).
+///
+/// The state is loaded when a service is created and saved from a background
+/// task on the Tokio runtime, so slow storage should be written to from
+/// another thread.
+pub trait StatePersistence: Send + Sync + 'static {
+    /// Load the saved state, if there is any. This is called whenever a load
+    /// shedder is created.
+    fn load(&self) -> Option<PersistedState>;
+
+    /// Save the current state, this is called periodically.
+    fn save(&self, state: &PersistedState);
+}
+
+/// A [`StatePersistence`] and how often to save to it.
+#[derive(Clone)]
+pub(crate) struct Persistence {
+    pub(crate) store: Arc<dyn StatePersistence>,
+    pub(crate) interval: Duration,
+}
+
+impl Debug for Persistence {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Persistence")
+            .field("interval", &self.interval)
+            .finish_non_exhaustive()
+    }
+}
@@ -0,0 +1,88 @@
+//! Pluggable decisions about which requests to admit.
+
+use std::{
+    fmt::Debug,
+    time::{Duration, Instant},
+};
+
+use crate::ShedReason;
+
+/// Decides whether to admit each request to the queue, see
+/// [`LoadShedLayer::admission_policy`](crate::LoadShedLayer::admission_policy).
+///
+/// The policy is asked once the load shedder has sized the queue from the
+/// latency and tried to give the request a place in it, with
+/// [`overloaded`](AdmissionContext::overloaded) saying whether the queue was
+/// full. The [`DefaultAdmissionPolicy`] sheds exactly those requests, a
+/// custom policy can shed more or admit them anyway, in which case the queue
+/// is stretched to fit them. Requests that have expired or are over the rate
+/// limit are shed before the policy is asked, and the cost it gives is still
+/// limited by the [`byte_budget`](crate::LoadShedLayer::byte_budget).
+pub trait AdmissionPolicy: Debug + Send + Sync + 'static {
+    /// Decide what to do with the request described by `ctx`.
+    fn admit(&self, ctx: AdmissionContext) -> AdmissionDecision;
+}
+
+/// The state of the load shedder and a request, given to an
+/// [`AdmissionPolicy`] to decide whether to admit it.
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[non_exhaustive]
+pub struct AdmissionContext {
+    /// The concurrency limit.
+    pub concurrency: usize,
+    /// The number of requests currently in the inner service.
+    pub in_flight: usize,
+    /// The capacity of the queue.
+    pub queue_capacity: usize,
+    /// The number of requests currently in the queue.
+    pub queued: usize,
+    /// The average latency of requests through the inner service.
+    pub average_latency: Duration,
+    /// The target average latency.
+    pub target: Duration,
+    /// The estimated size of the request, see
+    /// [`RequestInfo::bytes`](crate::RequestInfo::bytes).
+    pub bytes: u64,
+    /// When the request arrived, if known, see
+    /// [`RequestInfo::arrival`](crate::RequestInfo::arrival).
+    pub arrival: Option<Instant>,
+    /// Whether the load shedder would shed the request because the queue is
+    /// full. Otherwise the request already has its place, so it's counted in
+    /// [`queued`](Self::queued) or [`in_flight`](Self::in_flight).
+    pub overloaded: bool,
+}
+
+/// What an [`AdmissionPolicy`] decided to do with a request.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum AdmissionDecision {
+    /// Let the request into the queue, even if it's
+    /// [`overloaded`](AdmissionContext::overloaded).
+    Admit {
+        /// The size to count the request as in the
+        /// [`byte_budget`](crate::LoadShedLayer::byte_budget), usually its
+        /// [`bytes`](AdmissionContext::bytes).
+        cost: u64,
+    },
+    /// Shed the request.
+    Shed {
+        /// Why it's being shed, this decides the response.
+        reason: ShedReason,
+    },
+}
+
+/// The default [`AdmissionPolicy`], this sheds requests when the load
+/// shedder is overloaded and admits the rest at their estimated size.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct DefaultAdmissionPolicy;
+
+impl AdmissionPolicy for DefaultAdmissionPolicy {
+    fn admit(&self, ctx: AdmissionContext) -> AdmissionDecision {
+        if ctx.overloaded {
+            AdmissionDecision::Shed {
+                reason: ShedReason::Overload,
+            }
+        } else {
+            AdmissionDecision::Admit { cost: ctx.bytes }
+        }
+    }
+}
@@ -0,0 +1,150 @@
+//! Pluggable queue disciplines for requests waiting for the inner service.
+
+use std::{
+    collections::VecDeque,
+    fmt::Debug,
+    sync::{Arc, Mutex},
+    time::Instant,
+};
+
+use tokio::sync::{oneshot, OwnedSemaphorePermit};
+
+use crate::conf::{HeldPermits, MetricLabels, Permit};
+
+/// A queue of requests waiting for a chance to call the inner service.
+///
+/// By default the load shedder queues requests in a first-in first-out order,
+/// implementing this trait allows any other discipline to be used instead,
+/// such as last-in first-out or priority queues. See
+/// [`LoadShedLayer::queue`](crate::LoadShedLayer::queue).
+///
+/// The load shedder still decides how many requests can be queued, the queue
+/// only decides the order they are let through in.
+pub trait AdmissionQueue: Debug + Send + 'static {
+    /// Add a request to the queue.
+    fn enqueue(&mut self, waiter: Waiter);
+
+    /// Remove the next request that should be sent to the inner service.
+    fn dequeue(&mut self) -> Option<Waiter>;
+
+    /// The number of requests in the queue.
+    fn len(&self) -> usize;
+
+    /// Whether the queue is empty.
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The maximum number of requests this queue can hold, requests are shed
+    /// rather than queued beyond this, regardless of the load shedder's own
+    /// queue capacity.
+    fn capacity(&self) -> usize {
+        usize::MAX
+    }
+}
+
+/// A request waiting in an [`AdmissionQueue`].
+#[derive(Debug)]
+pub struct Waiter {
+    arrived: Instant,
+    sender: oneshot::Sender<Permit>,
+}
+
+impl Waiter {
+    /// Create a new waiter and the receiver it will send its permit on.
+    pub(crate) fn new() -> (Self, oneshot::Receiver<Permit>) {
+        let (sender, receiver) = oneshot::channel();
+        let waiter = Self {
+            arrived: tokio::time::Instant::now().into_std(),
+            sender,
+        };
+        (waiter, receiver)
+    }
+
+    /// When this request joined the queue.
+    pub fn arrived(&self) -> Instant {
+        self.arrived
+    }
+
+    /// Whether the request has been cancelled, dequeuing a cancelled request
+    /// is harmless, it's skipped over, but queues may want to discard them
+    /// early.
+    pub fn is_cancelled(&self) -> bool {
+        self.sender.is_closed()
+    }
+}
+
+/// A first-in first-out [`AdmissionQueue`], this has the same behaviour as the
+/// load shedder's default queue.
+#[derive(Debug, Default)]
+pub struct FifoQueue(VecDeque<Waiter>);
+
+impl AdmissionQueue for FifoQueue {
+    fn enqueue(&mut self, waiter: Waiter) {
+        self.0.push_back(waiter);
+    }
+
+    fn dequeue(&mut self) -> Option<Waiter> {
+        self.0.pop_front()
+    }
+
+    fn len(&self) -> usize {
+        self.0.len()
+    }
+}
+
+/// A last-in first-out [`AdmissionQueue`].
+///
+/// Under sustained overload this serves the newest requests first, which are
+/// the ones whose clients are most likely to still be waiting for them.
+#[derive(Debug, Default)]
+pub struct LifoQueue(Vec<Waiter>);
+
+impl AdmissionQueue for LifoQueue {
+    fn enqueue(&mut self, waiter: Waiter) {
+        self.0.push(waiter);
+    }
+
+    fn dequeue(&mut self) -> Option<Waiter> {
+        self.0.pop()
+    }
+
+    fn len(&self) -> usize {
+        self.0.len()
+    }
+}
+
+/// A queue shared between the load shedder and its permits.
+pub(crate) type SharedQueue = Arc<Mutex<Box<dyn AdmissionQueue>>>;
+
+/// Creates a fresh queue for each service created by a layer.
+#[derive(Clone)]
+pub(crate) struct QueueFactory(pub(crate) Arc<dyn Fn() -> Box<dyn AdmissionQueue> + Send + Sync>);
+
+impl Debug for QueueFactory {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("QueueFactory").finish_non_exhaustive()
+    }
+}
+
+/// Hand a concurrency permit to the next live waiter in the queue, returning
+/// it if there's nobody waiting.
+pub(crate) fn hand_off(
+    queue: &SharedQueue,
+    waiting: &mut dyn AdmissionQueue,
+    mut permit: OwnedSemaphorePermit,
+    held: &Arc<HeldPermits>,
+    labels: &MetricLabels,
+) -> Option<OwnedSemaphorePermit> {
+    while let Some(waiter) = waiting.dequeue() {
+        match waiter
+            .sender
+            .send(Permit::queued(permit, queue.clone(), held.clone(), labels))
+        {
+            Ok(()) => return None,
+            // The waiter gave up, so take the permit back and try the next one.
+            Err(mut rejected) => permit = rejected.permit.take().unwrap(),
+        }
+    }
+    Some(permit)
+}
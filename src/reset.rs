@@ -0,0 +1,116 @@
+//! Resetting what the load shedder has learned about the inner service.
+
+use std::sync::{
+    atomic::{self, AtomicU64},
+    Arc,
+};
+#[cfg(feature = "discover")]
+use std::{
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+#[cfg(feature = "discover")]
+use futures_core::Stream;
+#[cfg(feature = "discover")]
+use tower::discover::{Change, Discover};
+
+/// Tells load shedders to forget what they've learned about the latency of
+/// their inner service, see
+/// [`LoadShedLayer::reset_on`](crate::LoadShedLayer::reset_on).
+///
+/// This is useful when the inner service has changed, such as when backends
+/// join or leave a balancer, so that the averages learned from the old service
+/// don't hold back the load shedder.
+#[derive(Debug, Clone, Default)]
+pub struct ResetSignal(Arc<AtomicU64>);
+
+impl ResetSignal {
+    /// Create a signal that hasn't been triggered.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Reset every load shedder watching this signal, they pick this up when
+    /// the next request arrives or completes.
+    pub fn trigger(&self) {
+        self.0.fetch_add(1, atomic::Ordering::Release);
+    }
+
+    /// The number of times this signal has been triggered.
+    pub(crate) fn generation(&self) -> u64 {
+        self.0.load(atomic::Ordering::Acquire)
+    }
+}
+
+#[cfg(feature = "discover")]
+pin_project_lite::pin_project! {
+    /// Wraps a [`Discover`] to trigger a [`ResetSignal`] when the set of
+    /// services it discovers changes.
+    ///
+    /// By default any change triggers the signal, use
+    /// [`threshold`](Self::threshold) to only trigger on larger changes.
+    #[derive(Debug)]
+    pub struct ResetOnChange<D> {
+        #[pin]
+        discover: D,
+        signal: ResetSignal,
+        threshold: f64,
+        services: usize,
+        changes: usize,
+    }
+}
+
+#[cfg(feature = "discover")]
+impl<D> ResetOnChange<D> {
+    /// Wrap the discover stream, triggering a new signal, see
+    /// [`signal`](Self::signal).
+    pub fn new(discover: D) -> Self {
+        Self {
+            discover,
+            signal: ResetSignal::new(),
+            threshold: 0.0,
+            services: 0,
+            changes: 0,
+        }
+    }
+
+    /// Only trigger the signal once the number of services that have been
+    /// inserted or removed since it was last triggered is more than this
+    /// fraction of the services there are.
+    pub fn threshold(mut self, fraction: f64) -> Self {
+        self.threshold = fraction;
+        self
+    }
+
+    /// The signal triggered by changes, to give to the load shedders in front
+    /// of the discovered services.
+    pub fn signal(&self) -> ResetSignal {
+        self.signal.clone()
+    }
+}
+
+#[cfg(feature = "discover")]
+impl<D: Discover> Stream for ResetOnChange<D> {
+    type Item = Result<Change<D::Key, D::Service>, D::Error>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.project();
+        let change = futures_core::ready!(this.discover.poll_discover(cx));
+        if let Some(Ok(change)) = &change {
+            let services = *this.services;
+            *this.services = match change {
+                Change::Insert(..) => services + 1,
+                Change::Remove(..) => services.saturating_sub(1),
+            };
+            *this.changes += 1;
+            // Compare against the larger of the sizes so it doesn't matter
+            // which way the set changed.
+            if *this.changes as f64 > *this.threshold * services.max(*this.services) as f64 {
+                *this.changes = 0;
+                this.signal.trigger();
+            }
+        }
+        Poll::Ready(change)
+    }
+}
@@ -0,0 +1,154 @@
+//! Target latencies that follow a daily schedule.
+
+use std::{
+    fmt::Debug,
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+
+/// The length of a day.
+const DAY: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// A target latency that changes with the time of day, see
+/// [`LoadShedLayer::target_schedule`](crate::LoadShedLayer::target_schedule).
+///
+/// Times of day are measured from midnight UTC, unless a different
+/// [`clock`](Self::clock) is used.
+#[derive(Clone)]
+pub struct TargetSchedule {
+    /// The target used outside of any window.
+    default: Duration,
+    /// The start and end of each window and the target within it.
+    windows: Vec<(Duration, Duration, Duration)>,
+    /// Returns the current time of day.
+    clock: Arc<dyn Fn() -> Duration + Send + Sync>,
+}
+
+impl TargetSchedule {
+    /// Create a schedule that uses the `default` target outside of any of its
+    /// windows.
+    pub fn new(default: Duration) -> Self {
+        Self {
+            default,
+            windows: Vec::new(),
+            clock: Arc::new(utc_time_of_day),
+        }
+    }
+
+    /// Use `target` from the `start` time of day until the `end`. If the end
+    /// is before the start then the window wraps around midnight.
+    ///
+    /// Where windows overlap the first one added is used.
+    pub fn window(mut self, start: Duration, end: Duration, target: Duration) -> Self {
+        self.windows.push((start, end, target));
+        self
+    }
+
+    /// Use a different clock to tell the time of day, such as one in local
+    /// time or a fake one, it should return the time since midnight.
+    pub fn clock<F>(mut self, clock: F) -> Self
+    where
+        F: Fn() -> Duration + Send + Sync + 'static,
+    {
+        self.clock = Arc::new(clock);
+        self
+    }
+
+    /// The target that applies right now.
+    pub fn current_target(&self) -> Duration {
+        let now = (self.clock)();
+        self.windows
+            .iter()
+            .find(|&&(start, end, _)| {
+                if start <= end {
+                    start <= now && now < end
+                } else {
+                    start <= now || now < end
+                }
+            })
+            .map_or(self.default, |&(_, _, target)| target)
+    }
+
+    /// Every target the schedule can use.
+    pub(crate) fn targets(&self) -> impl Iterator<Item = Duration> + '_ {
+        std::iter::once(self.default).chain(self.windows.iter().map(|&(_, _, target)| target))
+    }
+}
+
+impl Debug for TargetSchedule {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("TargetSchedule")
+            .field("default", &self.default)
+            .field("windows", &self.windows)
+            .finish_non_exhaustive()
+    }
+}
+
+/// The time since midnight UTC.
+fn utc_time_of_day() -> Duration {
+    let since_epoch = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default();
+    Duration::new(
+        since_epoch.as_secs() % DAY.as_secs(),
+        since_epoch.subsec_nanos(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicU64, Ordering};
+
+    use super::*;
+
+    const HOUR: u64 = 60 * 60;
+
+    fn hours(hours: u64) -> Duration {
+        Duration::from_secs(hours * HOUR)
+    }
+
+    /// A schedule with a fake clock, set to the given number of seconds
+    /// since midnight.
+    fn schedule(now: &Arc<AtomicU64>) -> TargetSchedule {
+        let now = now.clone();
+        TargetSchedule::new(Duration::from_millis(100))
+            .clock(move || Duration::from_secs(now.load(Ordering::Relaxed)))
+    }
+
+    #[test]
+    fn the_target_switches_at_the_window_boundaries() {
+        let now = Arc::new(AtomicU64::new(0));
+        let schedule = schedule(&now).window(hours(9), hours(17), Duration::from_millis(50));
+        let target_at = |seconds| {
+            now.store(seconds, Ordering::Relaxed);
+            schedule.current_target().as_millis()
+        };
+        assert_eq!(target_at(9 * HOUR - 1), 100);
+        assert_eq!(target_at(9 * HOUR), 50);
+        assert_eq!(target_at(17 * HOUR - 1), 50);
+        assert_eq!(target_at(17 * HOUR), 100);
+    }
+
+    #[test]
+    fn a_window_can_wrap_around_midnight() {
+        let now = Arc::new(AtomicU64::new(0));
+        let schedule = schedule(&now).window(hours(22), hours(6), Duration::from_millis(500));
+        let target_at = |seconds| {
+            now.store(seconds, Ordering::Relaxed);
+            schedule.current_target().as_millis()
+        };
+        assert_eq!(target_at(21 * HOUR), 100);
+        assert_eq!(target_at(23 * HOUR), 500);
+        assert_eq!(target_at(HOUR), 500);
+        assert_eq!(target_at(6 * HOUR), 100);
+    }
+
+    #[test]
+    fn the_first_overlapping_window_wins() {
+        let now = Arc::new(AtomicU64::new(12 * HOUR));
+        let schedule = schedule(&now)
+            .window(hours(10), hours(14), Duration::from_millis(20))
+            .window(hours(11), hours(13), Duration::from_millis(30));
+        assert_eq!(schedule.current_target(), Duration::from_millis(20));
+    }
+}
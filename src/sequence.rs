@@ -0,0 +1,122 @@
+//! Ticketing to make requests take turns in the order they arrived.
+
+use std::{
+    collections::BTreeSet,
+    sync::{Arc, Mutex},
+};
+
+use tokio::sync::Notify;
+
+/// Hands out numbered tickets and lets their holders through one at a time in
+/// ticket order.
+#[derive(Debug, Default)]
+pub(crate) struct Sequencer {
+    state: Mutex<SequencerState>,
+    /// Notified whenever the ticket being served changes.
+    advanced: Notify,
+}
+
+#[derive(Debug, Default)]
+struct SequencerState {
+    /// The number of the next ticket to hand out.
+    next_ticket: u64,
+    /// The number of the ticket whose turn it is.
+    serving: u64,
+    /// Tickets that were given up before their turn, to be skipped.
+    abandoned: BTreeSet<u64>,
+    /// The number of tickets waiting for their turn.
+    parked: usize,
+}
+
+impl Sequencer {
+    /// Take the next ticket.
+    pub(crate) fn ticket(self: &Arc<Self>) -> Ticket {
+        let mut state = self.state.lock().unwrap();
+        let number = state.next_ticket;
+        state.next_ticket += 1;
+        Ticket {
+            sequencer: self.clone(),
+            number,
+            finished: false,
+        }
+    }
+
+    /// The number of tickets waiting for their turn.
+    pub(crate) fn parked(&self) -> usize {
+        self.state.lock().unwrap().parked
+    }
+
+    /// Move on to the next ticket that hasn't been abandoned.
+    fn advance(&self, state: &mut SequencerState) {
+        state.serving += 1;
+        while state.abandoned.remove(&state.serving) {
+            state.serving += 1;
+        }
+        self.advanced.notify_waiters();
+    }
+}
+
+/// A place in a [`Sequencer`]'s line, dropping this without finishing it gives
+/// up the place so the tickets behind it aren't held up.
+#[derive(Debug)]
+pub(crate) struct Ticket {
+    sequencer: Arc<Sequencer>,
+    number: u64,
+    finished: bool,
+}
+
+impl Ticket {
+    /// Wait until it's this ticket's turn, counting it as parked meanwhile.
+    pub(crate) async fn turn(&self) {
+        let mut parked = None;
+        loop {
+            // Create the notification before checking so that an advance in
+            // between can't be missed.
+            let advanced = self.sequencer.advanced.notified();
+            {
+                let mut state = self.sequencer.state.lock().unwrap();
+                if state.serving == self.number {
+                    return;
+                }
+                if parked.is_none() {
+                    state.parked += 1;
+                    parked = Some(Parked(&self.sequencer));
+                }
+            }
+            advanced.await;
+        }
+    }
+
+    /// Finish this ticket's turn, letting the next ticket through.
+    ///
+    /// This must only be called once it's this ticket's turn.
+    pub(crate) fn finish(mut self) {
+        self.finished = true;
+        let mut state = self.sequencer.state.lock().unwrap();
+        debug_assert_eq!(state.serving, self.number);
+        self.sequencer.advance(&mut state);
+    }
+}
+
+/// Counts a ticket as parked until it's dropped.
+struct Parked<'a>(&'a Sequencer);
+
+impl Drop for Parked<'_> {
+    fn drop(&mut self) {
+        self.0.state.lock().unwrap().parked -= 1;
+    }
+}
+
+impl Drop for Ticket {
+    fn drop(&mut self) {
+        if self.finished {
+            return;
+        }
+        let mut state = self.sequencer.state.lock().unwrap();
+        if state.serving == self.number {
+            self.sequencer.advance(&mut state);
+        } else {
+            state.abandoned.insert(self.number);
+        }
+    }
+}
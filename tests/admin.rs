@@ -0,0 +1,132 @@
+//! Controlling a load shedder through its admin service.
+#![cfg(feature = "admin")]
+
+mod common;
+
+use std::{sync::Arc, time::Duration};
+
+use little_loadshedder::{
+    AdminCommand, AdminResponse, ConfigError, LoadShed, LoadShedLayer, LoadShedResponse,
+    RequestInfo,
+};
+use tokio::sync::Semaphore;
+use tower::{Layer, ServiceExt};
+
+const TARGET: Duration = Duration::from_millis(100);
+const FAST: Duration = Duration::from_millis(10);
+
+/// Send `command` through the admin service of `service`.
+async fn admin(
+    service: &LoadShed<common::BoxSleeper>,
+    command: AdminCommand,
+) -> Result<AdminResponse, ConfigError> {
+    service.admin().oneshot(command).await
+}
+
+#[tokio::test(start_paused = true)]
+async fn the_target_and_moving_average_can_be_changed() {
+    let service = LoadShed::new(common::sleeper(), 0.1, TARGET);
+    let invalid = admin(&service, AdminCommand::SetTarget(Duration::ZERO)).await;
+    assert_eq!(invalid, Err(ConfigError::Target));
+    let done = admin(&service, AdminCommand::SetTarget(TARGET / 2)).await;
+    assert_eq!(done, Ok(AdminResponse::Done));
+    assert!(
+        service.status_line().contains("target=50.0ms"),
+        "{}",
+        service.status_line()
+    );
+
+    let invalid = admin(&service, AdminCommand::SetEwmaParam(2.0)).await;
+    assert_eq!(invalid, Err(ConfigError::EwmaParam(2.0)));
+    let done = admin(&service, AdminCommand::SetEwmaParam(0.5)).await;
+    assert_eq!(done, Ok(AdminResponse::Done));
+    // The first sample now moves the average half way.
+    service.clone().oneshot(FAST).await.unwrap();
+    assert_eq!(service.stats().success_latency, (TARGET + FAST) / 2);
+}
+
+#[tokio::test(start_paused = true)]
+async fn shedding_can_be_forced_and_stopped() {
+    let service = LoadShed::new(common::sleeper(), 0.1, TARGET);
+    admin(&service, AdminCommand::ForceShed(true))
+        .await
+        .unwrap();
+    let shed = service.clone().oneshot(FAST).await.unwrap();
+    assert!(matches!(shed, LoadShedResponse::Overload), "{shed:?}");
+    admin(&service, AdminCommand::ForceShed(false))
+        .await
+        .unwrap();
+    let admitted = service.clone().oneshot(FAST).await.unwrap();
+    assert!(matches!(admitted, LoadShedResponse::Inner(_)));
+}
+
+/// Treats requests that take no time as health checks.
+#[derive(Debug, Clone, Copy)]
+struct InstantHealthChecks;
+
+impl RequestInfo<Duration> for InstantHealthChecks {
+    fn is_health_check(&self, request: &Duration) -> bool {
+        request.is_zero()
+    }
+}
+
+#[tokio::test(start_paused = true)]
+async fn health_checks_are_admitted_while_shedding_is_forced() {
+    let service = LoadShedLayer::new(0.1, TARGET)
+        .request_info(InstantHealthChecks)
+        .layer(common::sleeper());
+    service
+        .admin()
+        .oneshot(AdminCommand::ForceShed(true))
+        .await
+        .unwrap();
+    let shed = service.clone().oneshot(FAST).await.unwrap();
+    assert!(matches!(shed, LoadShedResponse::Overload), "{shed:?}");
+    let health_check = service.clone().oneshot(Duration::ZERO).await.unwrap();
+    assert!(matches!(health_check, LoadShedResponse::Inner(_)));
+    let stats = service.stats();
+    assert_eq!(stats.success_latency, TARGET, "{stats:?}");
+}
+
+#[tokio::test(start_paused = true)]
+async fn draining_waits_for_the_requests_in_flight() {
+    let service = LoadShed::new(common::sleeper(), 0.1, TARGET);
+    let request = tokio::spawn(service.clone().oneshot(TARGET));
+    tokio::task::yield_now().await;
+    let start = tokio::time::Instant::now();
+    let done = admin(&service, AdminCommand::Drain).await;
+    assert_eq!(done, Ok(AdminResponse::Done));
+    assert!(start.elapsed() >= TARGET, "{:?}", start.elapsed());
+    assert!(request.is_finished());
+    assert!(service.is_force_shedding());
+}
+
+#[tokio::test(start_paused = true)]
+async fn draining_ignores_permits_held_by_others() {
+    let semaphore = Arc::new(Semaphore::new(2));
+    let service =
+        LoadShed::with_concurrency_semaphore(common::sleeper(), 0.1, TARGET, semaphore.clone());
+    let _elsewhere = semaphore.acquire_owned().await.unwrap();
+    let request = tokio::spawn(service.clone().oneshot(TARGET));
+    tokio::task::yield_now().await;
+    let drained = tokio::time::timeout(TARGET * 2, service.drain()).await;
+    assert!(drained.is_ok());
+    assert!(request.is_finished());
+}
+
+#[tokio::test(start_paused = true)]
+async fn resetting_forgets_the_learned_latency() {
+    let service = LoadShed::new(common::sleeper(), 0.1, TARGET);
+    common::drive(service.clone(), 5, Duration::from_secs(2), || FAST).await;
+    assert!(service.average_latency() < TARGET / 2);
+    admin(&service, AdminCommand::Reset).await.unwrap();
+    assert_eq!(service.average_latency(), TARGET);
+}
+
+#[tokio::test(start_paused = true)]
+async fn the_stats_are_a_snapshot() {
+    let service = LoadShed::new(common::sleeper(), 0.1, TARGET);
+    common::drive(service.clone(), 5, Duration::from_secs(2), || FAST).await;
+    let stats = admin(&service, AdminCommand::Stats).await;
+    assert_eq!(stats, Ok(AdminResponse::Stats(Box::new(service.stats()))));
+}
@@ -0,0 +1,495 @@
+//! Which requests are admitted and which are shed.
+
+mod common;
+
+use std::{
+    convert::Infallible,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+use futures::FutureExt;
+use little_loadshedder::{
+    AdmissionContext, AdmissionDecision, AdmissionPolicy, ConfigError, LoadShed, LoadShedConfig,
+    LoadShedLayer, LoadShedResponse, RequestInfo, ShedReason,
+};
+use tokio::sync::Semaphore;
+use tower::{Layer, Service, ServiceExt};
+
+const TARGET: Duration = Duration::from_millis(100);
+const SLOW: Duration = Duration::from_millis(50);
+const FAST: Duration = Duration::from_millis(10);
+
+/// Every request is 600 bytes.
+#[derive(Debug, Clone, Copy)]
+struct Large;
+
+impl RequestInfo<Duration> for Large {
+    fn bytes(&self, _: &Duration) -> u64 {
+        600
+    }
+}
+
+#[tokio::test(start_paused = true)]
+async fn large_requests_exhaust_the_byte_budget_first() {
+    let service = LoadShedLayer::new(0.1, TARGET)
+        .request_info(Large)
+        .byte_budget(1000)
+        .min_concurrency(10)
+        .min_queue(10)
+        .layer(common::sleeper());
+    let first = tokio::spawn(service.clone().oneshot(SLOW));
+    tokio::time::sleep(Duration::from_millis(1)).await;
+    assert_eq!(service.bytes_in_flight(), 600);
+    let second = service.clone().oneshot(SLOW).await.unwrap();
+    assert_eq!(second, LoadShedResponse::Overload);
+    assert_eq!(first.await.unwrap().unwrap(), LoadShedResponse::Inner(SLOW));
+    assert_eq!(service.bytes_in_flight(), 0);
+    let third = service.clone().oneshot(SLOW).await.unwrap();
+    assert_eq!(third, LoadShedResponse::Inner(SLOW));
+}
+
+/// A request that can't be cloned, so it can't have been hedged or retried.
+#[derive(Debug)]
+struct NotClone(Duration);
+
+#[tokio::test(start_paused = true)]
+async fn requests_do_not_need_to_be_clone() {
+    let inner = tower::service_fn(|NotClone(latency)| async move {
+        tokio::time::sleep(latency).await;
+        Ok::<_, Infallible>(latency)
+    });
+    let service = LoadShed::new(inner, 0.1, TARGET);
+    let response = service.oneshot(NotClone(SLOW)).await.unwrap();
+    assert_eq!(response, LoadShedResponse::Inner(SLOW));
+}
+
+#[tokio::test(start_paused = true)]
+async fn a_service_slower_than_the_target_gets_a_single_queue_place() {
+    let service = LoadShed::new(common::sleeper(), 0.1, TARGET);
+    common::drive(service.clone(), 10, Duration::from_secs(5), || TARGET * 2).await;
+    assert_eq!(service.stats().queue_capacity, 1);
+}
+
+#[tokio::test(start_paused = true)]
+async fn the_queue_stays_between_the_minimum_and_maximum() {
+    let service = LoadShedLayer::new(0.1, TARGET)
+        .min_queue(3)
+        .max_queue(5)
+        .layer(common::sleeper());
+    assert_eq!(service.stats().queue_capacity, 3);
+    // Slower than the target the capacity would be nothing.
+    let load = common::drive(service.clone(), 10, Duration::from_secs(5), || TARGET * 2).await;
+    assert_eq!(service.stats().queue_capacity, 3, "{load:?}");
+    // Far faster, it would be many times the concurrency.
+    let load = common::drive(service.clone(), 10, Duration::from_secs(5), || FAST).await;
+    assert_eq!(service.stats().queue_capacity, 5, "{load:?}");
+}
+
+#[tokio::test(start_paused = true)]
+async fn a_maximum_queue_below_the_minimum_is_raised_or_rejected() {
+    for layer in [
+        LoadShedLayer::new(0.1, TARGET).min_queue(5).max_queue(3),
+        LoadShedLayer::new(0.1, TARGET).max_queue(3).min_queue(5),
+    ] {
+        let service = layer.layer(common::sleeper());
+        let load = common::drive(service.clone(), 10, Duration::from_secs(5), || FAST).await;
+        assert_eq!(service.stats().queue_capacity, 5, "{load:?}");
+    }
+    let mut config = LoadShedConfig::new(0.1, TARGET);
+    config.min_queue = 5;
+    config.max_queue = 3;
+    assert_eq!(
+        LoadShed::from_config(common::sleeper(), config).err(),
+        Some(ConfigError::Queue { min: 5, max: 3 })
+    );
+}
+
+/// Requests arrived as long ago as they say.
+#[derive(Debug, Clone, Copy)]
+struct Aged;
+
+impl RequestInfo<Duration> for Aged {
+    fn arrival(&self, age: &Duration) -> Option<std::time::Instant> {
+        Some(std::time::Instant::now() - *age)
+    }
+}
+
+#[tokio::test(start_paused = true)]
+async fn requests_older_than_the_maximum_age_are_shed_unprocessed() {
+    let calls = Arc::new(AtomicU64::new(0));
+    let inner = tower::service_fn({
+        let calls = calls.clone();
+        move |age: Duration| {
+            calls.fetch_add(1, Ordering::Relaxed);
+            async move { Ok::<_, Infallible>(age) }
+        }
+    });
+    let service = LoadShedLayer::new(0.1, TARGET)
+        .request_info(Aged)
+        .max_age(Duration::from_secs(1))
+        .layer(inner);
+    let old = service
+        .clone()
+        .oneshot(Duration::from_secs(10))
+        .await
+        .unwrap();
+    assert_eq!(old, LoadShedResponse::Expired);
+    assert_eq!(calls.load(Ordering::Relaxed), 0);
+    let fresh = service.oneshot(Duration::from_millis(10)).await.unwrap();
+    assert_eq!(fresh, LoadShedResponse::Inner(Duration::from_millis(10)));
+    assert_eq!(calls.load(Ordering::Relaxed), 1);
+}
+
+#[tokio::test(start_paused = true)]
+async fn permits_held_elsewhere_reduce_the_capacity() {
+    let semaphore = Arc::new(Semaphore::new(4));
+    let in_flight = Arc::new(AtomicU64::new(0));
+    let most = Arc::new(AtomicU64::new(0));
+    let inner = tower::service_fn({
+        let (in_flight, most) = (in_flight.clone(), most.clone());
+        move |()| {
+            let (in_flight, most) = (in_flight.clone(), most.clone());
+            async move {
+                most.fetch_max(
+                    in_flight.fetch_add(1, Ordering::Relaxed) + 1,
+                    Ordering::Relaxed,
+                );
+                tokio::time::sleep(SLOW).await;
+                in_flight.fetch_sub(1, Ordering::Relaxed);
+                Ok::<_, Infallible>(())
+            }
+        }
+    });
+    let service = LoadShed::with_concurrency_semaphore(inner, 0.1, TARGET, semaphore.clone());
+    assert_eq!(service.concurrency(), 4);
+    let burst = || {
+        let requests: Vec<_> = (0..8)
+            .map(|_| tokio::spawn(service.clone().oneshot(())))
+            .collect();
+        futures::future::join_all(requests)
+    };
+
+    let held = semaphore.clone().acquire_many_owned(3).await.unwrap();
+    burst().await;
+    assert_eq!(most.swap(0, Ordering::Relaxed), 1);
+    drop(held);
+    burst().await;
+    // All four permits are back, along with any the controller has added.
+    assert!(most.load(Ordering::Relaxed) >= 4);
+}
+
+#[tokio::test(start_paused = true)]
+async fn eager_admission_decides_when_called() {
+    let mut service = LoadShedLayer::new(0.1, TARGET)
+        .eager_admission(true)
+        .layer(common::sleeper());
+    let first = tokio::spawn(service.clone().oneshot(SLOW));
+    tokio::time::sleep(Duration::from_millis(1)).await;
+
+    // The earlier call takes the only place in the queue, even though the
+    // later one is polled first.
+    let mut queued = service.call(SLOW);
+    let shed = service.call(SLOW);
+    assert_eq!(
+        shed.now_or_never().unwrap().unwrap(),
+        LoadShedResponse::Overload
+    );
+    // It's pending until the first request is done with the concurrency
+    // permit.
+    assert!(futures::poll!(&mut queued).is_pending());
+
+    assert_eq!(first.await.unwrap().unwrap(), LoadShedResponse::Inner(SLOW));
+    assert_eq!(queued.await.unwrap(), LoadShedResponse::Inner(SLOW));
+}
+
+#[tokio::test(start_paused = true)]
+async fn recovery_probes_let_requests_past_a_full_queue() {
+    let layer = || LoadShedLayer::new(0.1, TARGET);
+    let probing = layer()
+        .recovery_probes(Duration::from_millis(100), 2)
+        .layer(common::sleeper());
+    let plain = layer().layer(common::sleeper());
+    let drive = |slow| {
+        let latency = if slow {
+            TARGET * 3
+        } else {
+            Duration::from_millis(5)
+        };
+        futures::future::join(
+            common::drive(probing.clone(), 20, Duration::from_secs(2), move || latency),
+            common::drive(plain.clone(), 20, Duration::from_secs(2), move || latency),
+        )
+    };
+    // While overloaded the probes get through on top of the usual queue.
+    let (probed, unprobed) = drive(true).await;
+    assert!(
+        probed.admitted > unprobed.admitted * 2,
+        "{probed:?} vs {unprobed:?}"
+    );
+    // Once the service recovers the shedding stops.
+    drive(false).await;
+    let (probed, _) = drive(false).await;
+    assert_eq!(probed.shed, 0, "{probed:?}");
+}
+
+#[tokio::test(start_paused = true)]
+async fn the_rate_limit_sheds_excess_requests_despite_spare_concurrency() {
+    let service = LoadShedLayer::new(0.1, TARGET)
+        .min_concurrency(100)
+        .rate_limit(10.0, 5)
+        .layer(tower::service_fn(|()| async { Ok::<_, Infallible>(()) }));
+    // A burst is admitted, then the rest are over the rate.
+    let mut responses = Vec::new();
+    for _ in 0..20 {
+        responses.push(service.clone().oneshot(()).await.unwrap());
+    }
+    let admitted = responses
+        .iter()
+        .filter(|response| matches!(response, LoadShedResponse::Inner(())))
+        .count();
+    assert_eq!(admitted, 5);
+    assert!(responses[5..]
+        .iter()
+        .all(|response| matches!(response, LoadShedResponse::RateLimited)));
+
+    // Afterwards requests are admitted at the rate.
+    let load = common::drive(service.clone(), 10, Duration::from_secs(10), || ()).await;
+    assert!((95..=105).contains(&load.admitted), "{load:?}");
+    assert_eq!(load.errors, 0);
+}
+
+#[tokio::test(start_paused = true)]
+async fn shutting_down_sheds_new_requests_and_lets_admitted_ones_finish() {
+    let service = LoadShedLayer::new(0.1, TARGET)
+        .max_concurrency(1)
+        .min_queue(2)
+        .layer(common::sleeper());
+    // One request in the service and one queued behind it.
+    let admitted: Vec<_> = (0..2)
+        .map(|_| tokio::spawn(service.clone().oneshot(SLOW)))
+        .collect();
+    tokio::task::yield_now().await;
+    service.shutdown();
+    assert!(service.is_shut_down());
+    assert!(!service.would_admit());
+    let response = service.clone().oneshot(SLOW).await.unwrap();
+    assert!(matches!(response, LoadShedResponse::ShuttingDown));
+
+    for request in admitted {
+        let response = request.await.unwrap().unwrap();
+        assert!(matches!(response, LoadShedResponse::Inner(_)));
+    }
+    assert_eq!(service.stats().in_flight, 0);
+
+    // Nothing brings it back.
+    service.force_shed(false);
+    service.reset();
+    for _ in 0..3 {
+        let response = service.clone().oneshot(SLOW).await.unwrap();
+        assert!(matches!(response, LoadShedResponse::ShuttingDown));
+    }
+    assert!(service.is_shut_down());
+}
+
+#[tokio::test(start_paused = true)]
+async fn a_global_limit_caps_the_combined_concurrency() {
+    let global = Arc::new(Semaphore::new(5));
+    let in_flight = Arc::new(AtomicU64::new(0));
+    let most = Arc::new(AtomicU64::new(0));
+    let instance = || {
+        let (in_flight, most) = (in_flight.clone(), most.clone());
+        LoadShedLayer::new(0.1, TARGET)
+            .global_concurrency(global.clone())
+            .layer(tower::service_fn(move |()| {
+                let (in_flight, most) = (in_flight.clone(), most.clone());
+                async move {
+                    let now = in_flight.fetch_add(1, Ordering::Relaxed) + 1;
+                    most.fetch_max(now, Ordering::Relaxed);
+                    tokio::time::sleep(Duration::from_millis(10)).await;
+                    in_flight.fetch_sub(1, Ordering::Relaxed);
+                    Ok::<_, Infallible>(())
+                }
+            }))
+    };
+    let (first, second) = (instance(), instance());
+    let (first_load, second_load) = tokio::join!(
+        common::drive(first.clone(), 30, Duration::from_secs(3), || ()),
+        common::drive(second.clone(), 30, Duration::from_secs(3), || ()),
+    );
+    assert!(first_load.admitted > 100 && second_load.admitted > 100);
+    assert_eq!(most.load(Ordering::Relaxed), 5);
+    // Each adapted on its own, but together they couldn't go over the limit.
+    assert!(first.concurrency() + second.concurrency() > 5);
+    assert_eq!(global.available_permits(), 5);
+}
+
+/// Admits every request, however overloaded the load shedder is.
+#[derive(Debug)]
+struct AdmitAll;
+
+impl AdmissionPolicy for AdmitAll {
+    fn admit(&self, ctx: AdmissionContext) -> AdmissionDecision {
+        AdmissionDecision::Admit { cost: ctx.bytes }
+    }
+}
+
+/// Sheds any request that would have to wait in the queue, recording what it
+/// was told.
+#[derive(Debug, Clone, Default)]
+struct NoQueue(Arc<Mutex<Vec<AdmissionContext>>>);
+
+impl AdmissionPolicy for NoQueue {
+    fn admit(&self, ctx: AdmissionContext) -> AdmissionDecision {
+        self.0.lock().unwrap().push(ctx);
+        if ctx.in_flight >= ctx.concurrency {
+            AdmissionDecision::Shed {
+                reason: ShedReason::RateLimited,
+            }
+        } else {
+            AdmissionDecision::Admit { cost: ctx.bytes }
+        }
+    }
+}
+
+/// Send ten requests at once through a load shedder with room for two, and
+/// count how many get through.
+async fn burst(layer: LoadShedLayer) -> (usize, Vec<LoadShedResponse<Duration>>) {
+    let service = layer
+        .max_concurrency(1)
+        .min_queue(1)
+        .max_queue(1)
+        .layer(common::sleeper());
+    let requests: Vec<_> = (0..10)
+        .map(|_| tokio::spawn(service.clone().oneshot(SLOW)))
+        .collect();
+    let mut responses = Vec::new();
+    for request in requests {
+        responses.push(request.await.unwrap().unwrap());
+    }
+    let admitted = responses
+        .iter()
+        .filter(|response| matches!(response, LoadShedResponse::Inner(_)))
+        .count();
+    (admitted, responses)
+}
+
+#[tokio::test(start_paused = true)]
+async fn a_custom_policy_overrides_the_default_admission() {
+    let (admitted, _) = burst(LoadShedLayer::new(0.1, TARGET)).await;
+    assert_eq!(admitted, 2);
+    let (admitted, _) = burst(LoadShedLayer::new(0.1, TARGET).admission_policy(AdmitAll)).await;
+    assert_eq!(admitted, 10);
+
+    let policy = NoQueue::default();
+    let (admitted, responses) =
+        burst(LoadShedLayer::new(0.1, TARGET).admission_policy(policy.clone())).await;
+    assert_eq!(admitted, 1);
+    assert!(responses[1..]
+        .iter()
+        .all(|response| matches!(response, LoadShedResponse::RateLimited)));
+    let contexts = policy.0.lock().unwrap();
+    assert_eq!(contexts.len(), 10);
+    // Each request is counted in the queue while it's decided on, then the
+    // first goes into the service. The queue isn't full as each shed request
+    // gives its place back.
+    assert_eq!(contexts[0].in_flight, 0);
+    assert!(contexts[1..].iter().all(|ctx| ctx.in_flight == 1));
+    assert!(contexts.iter().all(|ctx| ctx.queued == 1
+        && !ctx.overloaded
+        && ctx.concurrency == 1
+        && ctx.target == TARGET));
+}
+
+#[tokio::test(start_paused = true)]
+async fn a_cost_is_only_admitted_while_there_are_enough_permits() {
+    let service = LoadShedLayer::new(0.1, TARGET)
+        .max_concurrency(1)
+        .min_queue(3)
+        .layer(common::sleeper());
+    // One permit to call the service and three in the queue.
+    assert!(service.would_admit_cost(4));
+    assert!(!service.would_admit_cost(5));
+
+    let request = tokio::spawn(service.clone().oneshot(SLOW));
+    tokio::task::yield_now().await;
+    assert!(service.would_admit_cost(3));
+    assert!(!service.would_admit_cost(4));
+    request.await.unwrap().unwrap();
+    assert!(service.would_admit_cost(4));
+}
+
+#[tokio::test(start_paused = true)]
+async fn forks_have_their_own_budget_while_clones_share_one() {
+    let service = LoadShedLayer::new(0.1, TARGET)
+        .max_concurrency(1)
+        .min_queue(1)
+        .layer(common::sleeper());
+    let fork = service.fork();
+    // Fill the service and the queue.
+    let requests: Vec<_> = (0..2)
+        .map(|_| tokio::spawn(service.clone().oneshot(SLOW)))
+        .collect();
+    tokio::task::yield_now().await;
+
+    let shared = service.clone().oneshot(SLOW).await.unwrap();
+    assert!(matches!(shared, LoadShedResponse::Overload), "{shared:?}");
+    let independent = fork.clone().oneshot(SLOW).await.unwrap();
+    assert!(matches!(independent, LoadShedResponse::Inner(_)));
+    // Nor do they share their statistics.
+    assert_eq!(service.stats().in_flight, 2);
+    assert_eq!(fork.stats().in_flight, 0);
+    for request in requests {
+        request.await.unwrap().unwrap();
+    }
+}
+
+/// Requests that cost as many units as they say.
+#[derive(Debug, Clone, Copy)]
+struct ByCost;
+
+impl RequestInfo<f64> for ByCost {
+    fn cost(&self, request: &f64) -> Option<f64> {
+        Some(*request)
+    }
+}
+
+#[tokio::test(start_paused = true)]
+async fn fractional_costs_take_a_proportional_share_of_the_budget() {
+    let service = LoadShedLayer::new(0.1, TARGET)
+        .min_queue(10)
+        .cost_budget(2.0, 100)
+        .request_info(ByCost)
+        .layer(tower::service_fn(|_: f64| async {
+            tokio::time::sleep(SLOW).await;
+            Ok::<_, Infallible>(())
+        }));
+    let mut requests = Vec::new();
+    // Counted in hundredths, with a third rounded up to 0.34.
+    for (cost, in_flight) in [(0.5, 0.5), (0.25, 0.75), (1.0 / 3.0, 1.09), (0.75, 1.84)] {
+        requests.push(tokio::spawn(service.clone().oneshot(cost)));
+        tokio::task::yield_now().await;
+        let cost_in_flight = service.cost_in_flight();
+        assert!(
+            (cost_in_flight - in_flight).abs() < 1e-9,
+            "{cost_in_flight}"
+        );
+    }
+    // Only 0.16 of a unit is left.
+    let shed = service.clone().oneshot(0.17).await.unwrap();
+    assert!(matches!(shed, LoadShedResponse::Overload), "{shed:?}");
+    requests.push(tokio::spawn(service.clone().oneshot(0.16)));
+    tokio::task::yield_now().await;
+    assert_eq!(service.cost_in_flight(), 2.0);
+    // Costs aren't bytes.
+    assert_eq!(service.bytes_in_flight(), 0);
+
+    for request in requests {
+        let response = request.await.unwrap().unwrap();
+        assert!(matches!(response, LoadShedResponse::Inner(())));
+    }
+    assert_eq!(service.cost_in_flight(), 0.0);
+}
@@ -0,0 +1,72 @@
+//! Turning shed requests into HTTP responses in an axum router.
+#![cfg(feature = "axum")]
+
+use std::time::Duration;
+
+use axum::{
+    body::Body,
+    http::{header, Request, StatusCode},
+    routing::get,
+    Router,
+};
+use little_loadshedder::{HttpLoadShedLayer, LoadShedLayer};
+use tower::ServiceExt;
+
+const TARGET: Duration = Duration::from_millis(100);
+
+async fn slow() -> &'static str {
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    "done"
+}
+
+#[tokio::test(start_paused = true)]
+async fn shed_requests_get_the_configured_status() {
+    let layer = HttpLoadShedLayer::from(
+        LoadShedLayer::new(0.1, TARGET)
+            .max_concurrency(1)
+            .max_queue(1),
+    )
+    .status(StatusCode::TOO_MANY_REQUESTS)
+    .retry_after(Duration::from_millis(1500));
+    // Supplying the state turns the handler into a service once, so every
+    // request shares one load shedder rather than building its own.
+    let router = Router::new()
+        .route("/", get(slow))
+        .layer(layer)
+        .with_state(());
+    let get = || {
+        let router = router.clone();
+        tokio::spawn(router.oneshot(Request::get("/").body(Body::empty()).unwrap()))
+    };
+    let first = get();
+    tokio::time::sleep(Duration::from_millis(1)).await;
+    let second = get();
+    tokio::time::sleep(Duration::from_millis(1)).await;
+    let shed = get().await.unwrap().unwrap();
+    assert_eq!(shed.status(), StatusCode::TOO_MANY_REQUESTS);
+    assert_eq!(shed.headers()[header::RETRY_AFTER], "2");
+    for admitted in [first, second] {
+        assert_eq!(admitted.await.unwrap().unwrap().status(), StatusCode::OK);
+    }
+}
+
+#[tokio::test(start_paused = true)]
+async fn forced_and_shutdown_sheds_are_unavailable() {
+    let inner = tower::service_fn(|_: Request<Body>| async {
+        Ok::<_, std::convert::Infallible>(axum::response::Response::new(Body::empty()))
+    });
+    let service = tower::Layer::layer(&HttpLoadShedLayer::new(0.1, TARGET), inner);
+    let request = || Request::get("/").body(Body::empty()).unwrap();
+    let ok = service.clone().oneshot(request()).await.unwrap();
+    assert_eq!(ok.status(), StatusCode::OK);
+
+    service.load_shed().force_shed(true);
+    let forced = service.clone().oneshot(request()).await.unwrap();
+    assert_eq!(forced.status(), StatusCode::SERVICE_UNAVAILABLE);
+    assert!(!forced.headers().contains_key(header::RETRY_AFTER));
+
+    service.load_shed().force_shed(false);
+    service.load_shed().shutdown();
+    let closed = service.clone().oneshot(request()).await.unwrap();
+    assert_eq!(closed.status(), StatusCode::SERVICE_UNAVAILABLE);
+}
@@ -0,0 +1,117 @@
+//! Counting requests as in flight until their response body is finished.
+#![cfg(feature = "http")]
+
+use std::{
+    collections::VecDeque,
+    convert::Infallible,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use futures::channel::mpsc;
+use http::Response;
+use http_body::{Body, Frame};
+use http_body_util::{BodyExt, StreamBody};
+use little_loadshedder::{BodyLoadShedLayer, LoadShedResponse};
+use tower::{util::BoxCloneService, Layer, ServiceExt};
+
+const TARGET: Duration = Duration::from_millis(100);
+
+type Chunk = Result<Frame<&'static [u8]>, Infallible>;
+type Streaming =
+    BoxCloneService<(), Response<StreamBody<mpsc::UnboundedReceiver<Chunk>>>, Infallible>;
+
+/// A service whose single response streams whatever is sent into the
+/// returned sender.
+fn streaming() -> (mpsc::UnboundedSender<Chunk>, Streaming) {
+    let (sender, receiver) = mpsc::unbounded();
+    let receiver = Arc::new(Mutex::new(Some(receiver)));
+    let service = tower::service_fn(move |()| {
+        let body = receiver.lock().unwrap().take().unwrap();
+        async move { Ok(Response::new(StreamBody::new(body))) }
+    });
+    (sender, BoxCloneService::new(service))
+}
+
+#[tokio::test(start_paused = true)]
+async fn the_permit_is_held_until_the_body_completes() {
+    let (sender, inner) = streaming();
+    let service = BodyLoadShedLayer::new(0.1, TARGET).layer(inner);
+    let LoadShedResponse::Inner(response) = service.clone().oneshot(()).await.unwrap() else {
+        panic!("the request was shed");
+    };
+    let mut body = response.into_body();
+    assert_eq!(service.load_shed().queue_len(), 1);
+
+    sender.unbounded_send(Ok(Frame::data(b"chunk"))).unwrap();
+    let chunk = body.frame().await.unwrap().unwrap();
+    assert_eq!(chunk.into_data().unwrap(), b"chunk");
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    assert_eq!(service.load_shed().queue_len(), 1);
+
+    drop(sender);
+    assert!(body.frame().await.is_none());
+    assert_eq!(service.load_shed().queue_len(), 0);
+    // The latency is to the last byte, 50ms, which moves the average from the
+    // target to 95ms, whereas the first byte at 0ms would have made it 90ms.
+    let latency = service.load_shed().average_latency();
+    assert_eq!(latency, Duration::from_millis(95), "{latency:?}");
+}
+
+#[tokio::test(start_paused = true)]
+async fn dropping_the_body_releases_the_permit() {
+    let (_sender, inner) = streaming();
+    let service = BodyLoadShedLayer::new(0.1, TARGET).layer(inner);
+    let response = service.clone().oneshot(()).await.unwrap();
+    assert_eq!(service.load_shed().queue_len(), 1);
+    drop(response);
+    assert_eq!(service.load_shed().queue_len(), 0);
+}
+
+/// A body of fixed chunks that knows when it's been read to the end.
+#[derive(Debug)]
+struct Chunks(VecDeque<&'static [u8]>);
+
+impl Body for Chunks {
+    type Data = &'static [u8];
+    type Error = Infallible;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        _: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        Poll::Ready(self.0.pop_front().map(|chunk| Ok(Frame::data(chunk))))
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+#[tokio::test(start_paused = true)]
+async fn the_permit_is_released_with_the_last_chunk() {
+    let inner = tower::service_fn(|()| async {
+        Ok::<_, Infallible>(Response::new(Chunks(VecDeque::from([
+            &b"first"[..],
+            b"second",
+            b"last",
+        ]))))
+    });
+    let service = BodyLoadShedLayer::new(0.1, TARGET).layer(inner);
+    let LoadShedResponse::Inner(response) = service.clone().oneshot(()).await.unwrap() else {
+        panic!("the request was shed");
+    };
+    let mut body = response.into_body();
+    for expected in [&b"first"[..], b"second"] {
+        let chunk = body.frame().await.unwrap().unwrap();
+        assert_eq!(chunk.into_data().unwrap(), expected);
+        assert_eq!(service.load_shed().queue_len(), 1);
+    }
+    let chunk = body.frame().await.unwrap().unwrap();
+    assert_eq!(chunk.into_data().unwrap(), b"last");
+    // Released without waiting for the end to be polled or the body dropped.
+    assert_eq!(service.load_shed().queue_len(), 0);
+    assert!(body.is_end_stream());
+}
@@ -0,0 +1,146 @@
+//! Helpers shared by the integration tests.
+#![allow(dead_code)]
+
+use std::{
+    convert::Infallible,
+    future::Future,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use little_loadshedder::LoadShedResponse;
+use tokio::{
+    sync::Semaphore,
+    time::{Instant, Sleep},
+};
+use tower::{util::BoxCloneService, Service, ServiceExt};
+
+/// How long a [`SlowToReady`] takes to become ready.
+pub const READY: Duration = Duration::from_millis(10);
+/// How long a [`SlowToReady`] takes to respond once it's ready.
+pub const SERVICE: Duration = Duration::from_millis(30);
+
+/// The service returned by [`sleeper`].
+pub type BoxSleeper = BoxCloneService<Duration, Duration, Infallible>;
+
+/// A service that takes as long to respond as each request says, and responds
+/// with the request.
+pub fn sleeper() -> BoxSleeper {
+    BoxCloneService::new(tower::service_fn(|latency: Duration| async move {
+        tokio::time::sleep(latency).await;
+        Ok(latency)
+    }))
+}
+
+/// A service that handles `parallelism` requests at a time, each taking
+/// `service_time`, so its latency rises once it's sent more than that.
+pub fn downstream(
+    parallelism: Arc<Semaphore>,
+    service_time: Duration,
+) -> BoxCloneService<(), (), Infallible> {
+    BoxCloneService::new(tower::service_fn(move |()| {
+        let parallelism = parallelism.clone();
+        async move {
+            let _permit = parallelism.acquire().await.unwrap();
+            tokio::time::sleep(service_time).await;
+            Ok(())
+        }
+    }))
+}
+
+/// A service that takes `READY` to become ready and then `SERVICE` to
+/// respond.
+#[derive(Debug, Default)]
+pub struct SlowToReady {
+    ready: Option<Pin<Box<Sleep>>>,
+}
+
+impl Clone for SlowToReady {
+    fn clone(&self) -> Self {
+        Self::default()
+    }
+}
+
+impl Service<()> for SlowToReady {
+    type Response = ();
+    type Error = Infallible;
+    type Future = Pin<Box<dyn Future<Output = Result<(), Infallible>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Infallible>> {
+        let ready = self
+            .ready
+            .get_or_insert_with(|| Box::pin(tokio::time::sleep(READY)));
+        ready.as_mut().poll(cx).map(Ok)
+    }
+
+    fn call(&mut self, (): ()) -> Self::Future {
+        self.ready = None;
+        Box::pin(async {
+            tokio::time::sleep(SERVICE).await;
+            Ok(())
+        })
+    }
+}
+
+/// How many requests a run of [`drive`] got through and how many were shed.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Load {
+    pub admitted: u64,
+    pub shed: u64,
+    pub errors: u64,
+}
+
+/// Send requests from `clients` concurrent clients, each waiting for its last
+/// response before sending the next, for `duration`.
+///
+/// Clients back off for a millisecond after a shed request, so that paused
+/// time still advances.
+pub async fn drive<S, Request, Response>(
+    service: S,
+    clients: usize,
+    duration: Duration,
+    request: impl Fn() -> Request + Clone + Send + 'static,
+) -> Load
+where
+    S: Service<Request, Response = LoadShedResponse<Response>> + Clone + Send + 'static,
+    S::Future: Send,
+    S::Error: Send,
+    Request: Send + 'static,
+    Response: Send + 'static,
+{
+    let counts = Arc::new([AtomicU64::new(0), AtomicU64::new(0), AtomicU64::new(0)]);
+    let end = Instant::now() + duration;
+    let clients: Vec<_> = (0..clients)
+        .map(|_| {
+            let service = service.clone();
+            let request = request.clone();
+            let counts = counts.clone();
+            tokio::spawn(async move {
+                while Instant::now() < end {
+                    let index = match service.clone().oneshot(request()).await {
+                        Ok(LoadShedResponse::Inner(_)) => 0,
+                        Ok(_) => 1,
+                        Err(_) => 2,
+                    };
+                    counts[index].fetch_add(1, Ordering::Relaxed);
+                    if index != 0 {
+                        tokio::time::sleep(Duration::from_millis(1)).await;
+                    }
+                }
+            })
+        })
+        .collect();
+    for client in clients {
+        client.await.unwrap();
+    }
+    Load {
+        admitted: counts[0].load(Ordering::Relaxed),
+        shed: counts[1].load(Ordering::Relaxed),
+        errors: counts[2].load(Ordering::Relaxed),
+    }
+}
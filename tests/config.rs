@@ -0,0 +1,133 @@
+//! Validating a load shedder's configuration when it's constructed.
+
+use std::{convert::Infallible, time::Duration};
+
+use little_loadshedder::{ConfigError, LoadShed, LoadShedConfig, LoadShedLayer, OptimizationGoal};
+
+/// Build a load shedder from the default configuration changed by
+/// `configure`.
+fn build(configure: impl FnOnce(&mut LoadShedConfig)) -> Result<(), ConfigError> {
+    let mut config = LoadShedConfig::new(0.1, Duration::from_millis(100));
+    configure(&mut config);
+    let inner = tower::service_fn(|()| async { Ok::<_, Infallible>(()) });
+    LoadShed::from_config(inner, config).map(drop)
+}
+
+#[test]
+fn the_default_configuration_is_valid() {
+    assert_eq!(build(|_| {}), Ok(()));
+    assert!(LoadShedLayer::from_config(LoadShedConfig::new(0.1, Duration::from_secs(1))).is_ok());
+}
+
+#[test]
+fn the_minimum_concurrency_must_not_exceed_the_maximum() {
+    let error = build(|config| {
+        config.min_concurrency = 10;
+        config.max_concurrency = 5;
+    });
+    assert_eq!(error, Err(ConfigError::Concurrency { min: 10, max: 5 }));
+}
+
+#[test]
+fn the_minimum_concurrency_must_be_positive() {
+    let error = build(|config| config.min_concurrency = 0);
+    assert_eq!(
+        error,
+        Err(ConfigError::Concurrency {
+            min: 0,
+            max: usize::MAX
+        })
+    );
+}
+
+#[test]
+fn the_minimum_queue_must_not_exceed_the_maximum() {
+    let error = build(|config| {
+        config.min_queue = 8;
+        config.max_queue = 2;
+    });
+    assert_eq!(error, Err(ConfigError::Queue { min: 8, max: 2 }));
+    assert!(LoadShedLayer::from_config({
+        let mut config = LoadShedConfig::new(0.1, Duration::from_secs(1));
+        config.max_queue = 0;
+        config
+    })
+    .is_err());
+}
+
+#[test]
+fn the_ewma_param_must_be_between_zero_and_one() {
+    for param in [0.0, 1.0, -0.5, f64::NAN] {
+        let error = build(|config| config.ewma_param = param).unwrap_err();
+        assert!(matches!(error, ConfigError::EwmaParam(_)), "{error}");
+    }
+}
+
+#[test]
+fn the_target_must_not_be_zero() {
+    let error = build(|config| config.target = Duration::ZERO);
+    assert_eq!(error, Err(ConfigError::Target));
+}
+
+#[test]
+fn intervals_must_not_be_zero() {
+    let error = build(|config| config.control_interval = Some(Duration::ZERO));
+    assert_eq!(error, Err(ConfigError::ControlInterval));
+    let error = build(|config| config.latency_window = Some(Duration::ZERO));
+    assert_eq!(error, Err(ConfigError::ControlInterval));
+}
+
+#[test]
+fn fractions_must_be_in_range() {
+    assert_eq!(
+        build(|config| config.max_failure_rate = 1.0),
+        Err(ConfigError::FailureRate(1.0))
+    );
+    assert_eq!(
+        build(|config| config.target_percentile = Some(1.5)),
+        Err(ConfigError::Percentile(1.5))
+    );
+    assert_eq!(
+        build(|config| config.throughput_halt_margin = Some(-0.1)),
+        Err(ConfigError::ThroughputHaltMargin)
+    );
+    assert_eq!(
+        build(|config| config.max_tail_amplification = Some(0.5)),
+        Err(ConfigError::TailAmplification)
+    );
+    assert_eq!(
+        build(|config| config.max_target_error = Some(0.0)),
+        Err(ConfigError::TargetError)
+    );
+}
+
+#[test]
+fn the_rate_limit_needs_a_positive_rate_and_burst() {
+    assert_eq!(
+        build(|config| config.rate_limit = Some(-1.0)),
+        Err(ConfigError::RateLimit)
+    );
+    assert_eq!(
+        build(|config| {
+            config.rate_limit = Some(10.0);
+            config.rate_burst = 0.5;
+        }),
+        Err(ConfigError::RateLimit)
+    );
+}
+
+#[test]
+fn an_optimization_goal_only_fills_in_unset_options() {
+    let mut config = LoadShedConfig::new(0.1, Duration::from_millis(100));
+    config.throughput_halt_margin = Some(0.2);
+    config.optimize_for(OptimizationGoal::MaximizeThroughput);
+    assert_eq!(config.throughput_halt_margin, Some(0.2));
+    assert_eq!(config.max_increase_step, Some(16));
+
+    config.optimize_for(OptimizationGoal::MinimizeLatency);
+    assert_eq!(config.throughput_halt_margin, Some(0.2));
+    assert_eq!(config.max_tail_amplification, Some(2.0));
+    assert_eq!(config.max_increase_step, Some(16));
+    assert_eq!(config.slow_ewma_param, Some(0.01));
+    assert_eq!(build(|built| *built = config), Ok(()));
+}
@@ -0,0 +1,492 @@
+//! How the controller adjusts the concurrency.
+
+mod common;
+
+use std::{
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use little_loadshedder::{LoadShed, LoadShedLayer, LoadShedResponse, OptimizationGoal, Outcome};
+use tokio::sync::Semaphore;
+use tower::{Layer, ServiceExt};
+
+const TARGET: Duration = Duration::from_millis(100);
+const FAST: Duration = Duration::from_millis(10);
+
+#[tokio::test(start_paused = true)]
+async fn fast_successes_increase_the_concurrency() {
+    let service = LoadShed::new(common::sleeper(), 0.1, TARGET);
+    common::drive(service.clone(), 20, Duration::from_secs(10), || FAST).await;
+    assert!(service.concurrency() > 5, "{}", service.status_line());
+}
+
+#[tokio::test(start_paused = true)]
+async fn ok_responses_classified_as_failures_stop_increases() {
+    let service = LoadShed::new(common::sleeper(), 0.1, TARGET)
+        .with_classifier(|_: &Duration| Outcome::Failure);
+    common::drive(service.clone(), 20, Duration::from_secs(10), || FAST).await;
+    assert_eq!(service.concurrency(), 1, "{}", service.status_line());
+}
+
+#[tokio::test(start_paused = true)]
+async fn occasional_failures_below_the_maximum_rate_are_tolerated() {
+    let calls = Arc::new(AtomicU64::new(0));
+    let classifier = move |_: &Duration| {
+        if calls.fetch_add(1, Ordering::Relaxed).is_multiple_of(100) {
+            Outcome::Failure
+        } else {
+            Outcome::Success
+        }
+    };
+    let service = LoadShed::new(common::sleeper(), 0.1, TARGET).with_classifier(classifier);
+    common::drive(service.clone(), 20, Duration::from_secs(10), || FAST).await;
+    assert!(service.concurrency() > 5, "{}", service.status_line());
+}
+
+#[tokio::test(start_paused = true)]
+async fn a_control_interval_adjusts_once_per_tick() {
+    let service = LoadShedLayer::new(0.1, TARGET)
+        .control_interval(Duration::from_secs(1))
+        .layer(common::sleeper());
+    common::drive(service.clone(), 20, Duration::from_secs(10), || FAST).await;
+    let stats = service.stats();
+    let adjustments = stats.increases + stats.decreases;
+    assert!((5..=11).contains(&adjustments), "{stats:?}");
+    assert!(service.concurrency() > 1, "{stats:?}");
+}
+
+#[tokio::test(start_paused = true)]
+async fn without_a_control_interval_requests_drive_adjustments() {
+    let service = LoadShed::new(common::sleeper(), 0.1, TARGET);
+    common::drive(service.clone(), 20, Duration::from_secs(10), || FAST).await;
+    let stats = service.stats();
+    assert!(stats.increases + stats.decreases > 20, "{stats:?}");
+}
+
+#[tokio::test(start_paused = true)]
+async fn increases_halt_at_a_throughput_plateau() {
+    let plateau = || common::downstream(Arc::new(Semaphore::new(4)), FAST);
+    let unhalted = LoadShedLayer::new(0.1, TARGET).layer(plateau());
+    let halted = LoadShedLayer::new(0.1, TARGET)
+        .throughput_halt_margin(0.05)
+        .layer(plateau());
+    tokio::join!(
+        common::drive(unhalted.clone(), 40, Duration::from_secs(10), || ()),
+        common::drive(halted.clone(), 40, Duration::from_secs(10), || ()),
+    );
+    let stats = halted.stats();
+    assert!(stats.increase_halted, "{stats:?}");
+    assert!(
+        stats.concurrency * 2 < unhalted.concurrency(),
+        "{stats:?} vs {}",
+        unhalted.status_line()
+    );
+}
+
+#[tokio::test(start_paused = true)]
+async fn a_failure_latency_penalty_decreases_the_concurrency() {
+    let failing = Arc::new(AtomicBool::new(false));
+    let layer = || {
+        let failing = failing.clone();
+        let calls = Arc::new(AtomicU64::new(0));
+        // Fail nine in ten requests in the burst and tolerate that many, so
+        // only the penalty can push the concurrency down.
+        LoadShedLayer::new(0.1, TARGET)
+            .max_failure_rate(0.95)
+            .classifier(move |_: &Duration| {
+                let call = calls.fetch_add(1, Ordering::Relaxed);
+                if failing.load(Ordering::Relaxed) && !call.is_multiple_of(10) {
+                    Outcome::Failure
+                } else {
+                    Outcome::Success
+                }
+            })
+    };
+    let penalised = layer()
+        .failure_latency_penalty(TARGET * 2)
+        .layer(common::sleeper());
+    let unpenalised = layer().layer(common::sleeper());
+    let run = |services: [LoadShed<_, _>; 2]| {
+        futures::future::join_all(
+            services.map(|service| common::drive(service, 20, Duration::from_secs(5), || FAST)),
+        )
+    };
+    run([penalised.clone(), unpenalised.clone()]).await;
+    let before = penalised.concurrency();
+    assert!(before > 5, "{}", penalised.status_line());
+    failing.store(true, Ordering::Relaxed);
+    run([penalised.clone(), unpenalised.clone()]).await;
+    assert!(
+        penalised.concurrency() < before / 2,
+        "{}",
+        penalised.status_line()
+    );
+    assert!(
+        unpenalised.concurrency() > before / 2,
+        "{}",
+        unpenalised.status_line()
+    );
+}
+
+#[tokio::test(start_paused = true)]
+async fn drift_in_a_shared_semaphore_is_reconciled() {
+    let semaphore = Arc::new(Semaphore::new(1));
+    let service = LoadShed::with_concurrency_semaphore(
+        common::downstream(Arc::new(Semaphore::new(4)), FAST),
+        0.1,
+        TARGET,
+        semaphore.clone(),
+    );
+    common::drive(service.clone(), 100, Duration::from_secs(2), || ()).await;
+    let before = service.concurrency();
+    assert_eq!(semaphore.available_permits(), before);
+
+    // Something else adds permits behind the load shedder's back.
+    semaphore.add_permits(5);
+    common::drive(service.clone(), 100, Duration::from_secs(2), || ()).await;
+    assert_eq!(service.concurrency(), semaphore.available_permits());
+    // Or forgets them.
+    semaphore.forget_permits(3);
+    common::drive(service.clone(), 100, Duration::from_secs(2), || ()).await;
+    assert_eq!(service.concurrency(), semaphore.available_permits());
+}
+
+#[tokio::test(start_paused = true)]
+async fn the_maximum_concurrency_rises_with_the_downstream_capacity() {
+    let capacity = Arc::new(Semaphore::new(4));
+    let service = LoadShedLayer::new(0.1, TARGET)
+        .max_concurrency(4)
+        .probe_max_concurrency(Duration::from_secs(1))
+        .layer(common::downstream(capacity.clone(), FAST));
+    common::drive(service.clone(), 100, Duration::from_secs(10), || ()).await;
+    let before = service.stats().max_concurrency;
+    assert!(before <= 5, "{:?}", service.stats());
+
+    capacity.add_permits(36);
+    common::drive(service.clone(), 100, Duration::from_secs(10), || ()).await;
+    let stats = service.stats();
+    assert!(stats.max_concurrency > before + 2, "{stats:?}");
+    assert!(stats.concurrency > before + 2, "{stats:?}");
+}
+
+#[tokio::test(start_paused = true)]
+async fn each_optimization_goal_trades_latency_for_throughput() {
+    let goals = [
+        OptimizationGoal::MinimizeLatency,
+        OptimizationGoal::Balanced,
+        OptimizationGoal::MaximizeThroughput,
+    ];
+    let services = goals.map(|goal| {
+        LoadShedLayer::new(0.1, TARGET)
+            .optimize_for(goal)
+            .layer(common::downstream(Arc::new(Semaphore::new(4)), FAST))
+    });
+    let loads = futures::future::join_all(
+        services
+            .clone()
+            .map(|service| common::drive(service, 60, Duration::from_secs(5), || ())),
+    )
+    .await;
+    let [latency, balanced, throughput] = services.map(|service| service.stats());
+    // Every goal gets all the throughput the downstream has.
+    let admitted = loads.iter().map(|load| load.admitted);
+    assert!(
+        admitted.clone().max().unwrap() - admitted.min().unwrap() <= 2,
+        "{loads:?}"
+    );
+    // Minimising latency stops increasing as soon as it doesn't help.
+    assert!(latency.increase_halted, "{latency:?}");
+    assert!(
+        latency.average_latency * 3 < balanced.average_latency * 2,
+        "{latency:?} vs {balanced:?}"
+    );
+    assert!(latency.concurrency < throughput.concurrency);
+}
+
+#[tokio::test(start_paused = true)]
+async fn goals_favouring_throughput_ramp_up_faster() {
+    let goals = [
+        OptimizationGoal::MinimizeLatency,
+        OptimizationGoal::Balanced,
+        OptimizationGoal::MaximizeThroughput,
+    ];
+    let services = goals.map(|goal| {
+        LoadShedLayer::new(0.1, TARGET)
+            .optimize_for(goal)
+            .layer(common::sleeper())
+    });
+    futures::future::join_all(
+        services
+            .clone()
+            .map(|service| common::drive(service, 100, TARGET * 3, || FAST)),
+    )
+    .await;
+    let [latency, balanced, throughput] = services.map(|service| service.concurrency());
+    assert!(
+        latency < balanced && balanced < throughput,
+        "{latency} {balanced} {throughput}"
+    );
+}
+
+#[tokio::test(start_paused = true)]
+async fn a_latency_spike_can_only_move_the_concurrency_so_far() {
+    let unclamped = LoadShed::new(common::sleeper(), 0.1, TARGET);
+    let clamped = LoadShedLayer::new(0.1, TARGET)
+        .max_target_error(1.0)
+        .layer(common::sleeper());
+    let run = |service: LoadShed<_>| async move {
+        common::drive(service.clone(), 50, Duration::from_secs(5), || FAST).await;
+        let before = service.concurrency();
+        // One request takes fifty times the target.
+        let spike = service.clone().oneshot(TARGET * 50).await.unwrap();
+        assert!(matches!(spike, LoadShedResponse::Inner(_)));
+        let peak = service.average_latency();
+        // Then the load carries on as before.
+        let load = tokio::spawn(common::drive(service.clone(), 50, TARGET * 10, || FAST));
+        let mut lowest = before;
+        while !load.is_finished() {
+            tokio::time::sleep(Duration::from_millis(1)).await;
+            lowest = lowest.min(service.concurrency());
+        }
+        (before, lowest, peak)
+    };
+    let (unclamped, clamped) = tokio::join!(run(unclamped), run(clamped));
+    // Without a limit the average is thrown far over the target.
+    assert!(unclamped.2 > TARGET * 4, "{unclamped:?}");
+    // With one it stays under the target, and the concurrency stays put.
+    assert!(clamped.2 < TARGET, "{clamped:?}");
+    assert!(clamped.0 > 5, "{clamped:?}");
+    assert!(clamped.1 * 4 > clamped.0 * 3, "{clamped:?}");
+}
+
+#[tokio::test(start_paused = true)]
+async fn limiting_the_target_error_leaves_fast_requests_alone() {
+    let unclamped = LoadShed::new(common::sleeper(), 0.1, TARGET);
+    let clamped = LoadShedLayer::new(0.1, TARGET)
+        .max_target_error(0.5)
+        .layer(common::sleeper());
+    tokio::join!(
+        common::drive(unclamped.clone(), 20, Duration::from_secs(5), || FAST),
+        common::drive(clamped.clone(), 20, Duration::from_secs(5), || FAST),
+    );
+    let (unclamped, clamped) = (unclamped.stats(), clamped.stats());
+    // The service is far faster than half the target, and is measured so.
+    assert!(
+        clamped.average_latency_at_capacity < TARGET / 4,
+        "{clamped:?}"
+    );
+    assert_eq!(
+        clamped.average_latency_at_capacity,
+        unclamped.average_latency_at_capacity
+    );
+    assert_eq!(clamped.queue_capacity, unclamped.queue_capacity);
+}
+
+/// Warm up a load shedder in front of a service that handles four requests
+/// at a time, then flood it for a second, returning the concurrency near the
+/// end of the flood.
+async fn after_flood(layer: LoadShedLayer) -> usize {
+    let service = layer
+        .min_queue(20)
+        .layer(common::downstream(Arc::new(Semaphore::new(4)), FAST));
+    common::drive(service.clone(), 4, Duration::from_secs(3), || ()).await;
+    let flood = tokio::spawn(common::drive(
+        service.clone(),
+        100,
+        Duration::from_secs(1),
+        || (),
+    ));
+    tokio::time::sleep(Duration::from_millis(900)).await;
+    let concurrency = service.concurrency();
+    flood.await.unwrap();
+    concurrency
+}
+
+#[tokio::test(start_paused = true)]
+async fn a_filling_queue_backs_off_before_the_latency_rises() {
+    // The latency stays well under the target throughout, so only the queue
+    // shows the overload.
+    let latency_only = after_flood(LoadShedLayer::new(0.1, TARGET)).await;
+    let queue_backoff = after_flood(LoadShedLayer::new(0.1, TARGET).queue_backoff(0.5)).await;
+    assert!(queue_backoff <= 6, "{queue_backoff}");
+    assert!(
+        queue_backoff * 2 < latency_only,
+        "{queue_backoff} vs {latency_only}"
+    );
+}
+
+#[tokio::test(start_paused = true)]
+async fn a_latency_floor_stops_an_instant_service_climbing() {
+    // Paused time rounds this up to a millisecond, still far under the target.
+    let instant = Duration::from_micros(10);
+    let unfloored = LoadShed::new(common::sleeper(), 0.1, TARGET);
+    let floored = LoadShedLayer::new(0.1, TARGET)
+        .latency_floor(Duration::from_millis(5))
+        .layer(common::sleeper());
+    let mut concurrencies = Vec::new();
+    for _ in 0..3 {
+        tokio::join!(
+            common::drive(unfloored.clone(), 50, Duration::from_secs(1), move || {
+                instant
+            }),
+            common::drive(floored.clone(), 50, Duration::from_secs(1), move || instant),
+        );
+        concurrencies.push((floored.concurrency(), unfloored.concurrency()));
+    }
+    // The floored service stays put while the other climbs as far as the
+    // load lets it.
+    assert!(
+        concurrencies.iter().all(|&(floored, _)| floored == 1),
+        "{concurrencies:?}"
+    );
+    assert!(concurrencies[2].1 > 20, "{concurrencies:?}");
+    assert_eq!(floored.stats().desired_concurrency, 1);
+}
+
+/// The average step the concurrency was increased by, under a load of fast
+/// requests.
+async fn average_step(layer: LoadShedLayer) -> f64 {
+    let service = layer.layer(common::sleeper());
+    common::drive(service.clone(), 40, Duration::from_secs(1), || FAST).await;
+    let stats = service.stats();
+    assert!(stats.increases > 0, "{stats:?}");
+    (stats.concurrency - 1 + stats.decreases as usize) as f64 / stats.increases as f64
+}
+
+#[tokio::test(start_paused = true)]
+async fn proportional_increases_step_further_below_the_target() {
+    let single = average_step(LoadShedLayer::new(0.1, TARGET)).await;
+    assert_eq!(single, 1.0);
+    let proportional = average_step(LoadShedLayer::new(0.1, TARGET).proportional_increase(4)).await;
+    assert!(proportional > 2.0, "{proportional}");
+}
+
+#[tokio::test(start_paused = true)]
+async fn proportional_increases_are_single_steps_near_the_target() {
+    let service = LoadShedLayer::new(0.1, TARGET)
+        .proportional_increase(4)
+        .layer(common::sleeper());
+    // Close to the target, so there's no room for more than one at a time.
+    let near = TARGET.mul_f64(0.6);
+    common::drive(service.clone(), 20, Duration::from_secs(5), move || near).await;
+    let stats = service.stats();
+    assert!(stats.increases > 0, "{stats:?}");
+    assert_eq!(
+        stats.concurrency - 1 + stats.decreases as usize,
+        stats.increases as usize,
+        "{stats:?}"
+    );
+}
+
+/// How far into a steadily rising latency, from 20ms to three times the
+/// target over 20s, the concurrency is first decreased, and the average
+/// latency then.
+///
+/// The concurrency starts at the maximum, so only the latency can decrease it.
+async fn first_decrease_on_rise(layer: LoadShedLayer) -> (Duration, Duration) {
+    let service = layer.max_concurrency(10).layer(common::sleeper());
+    common::drive(service.clone(), 20, Duration::from_secs(5), || FAST * 2).await;
+    let start = tokio::time::Instant::now();
+    let rising = move || {
+        let progress = start.elapsed().as_secs_f64() / 20.0;
+        FAST * 2 + (TARGET * 3 - FAST * 2).mul_f64(progress.min(1.0))
+    };
+    let load = tokio::spawn(common::drive(
+        service.clone(),
+        20,
+        Duration::from_secs(20),
+        rising,
+    ));
+    // Give the load a moment to settle after restarting.
+    tokio::time::sleep(Duration::from_secs(1)).await;
+    assert_eq!(service.concurrency(), 10, "{}", service.status_line());
+    let decreases = service.stats().decreases;
+    while service.stats().decreases == decreases && !load.is_finished() {
+        tokio::time::sleep(Duration::from_millis(1)).await;
+    }
+    load.abort();
+    (start.elapsed(), service.average_latency())
+}
+
+#[tokio::test(start_paused = true)]
+async fn a_rising_trend_backs_off_before_the_target_is_crossed() {
+    let single = first_decrease_on_rise(LoadShedLayer::new(0.1, TARGET)).await;
+    let trend = first_decrease_on_rise(LoadShedLayer::new(0.1, TARGET).trend_backoff(0.01)).await;
+    assert!(
+        trend.0 + Duration::from_millis(500) < single.0,
+        "{trend:?} vs {single:?}"
+    );
+    assert!(trend.1 < TARGET.mul_f64(0.95), "{trend:?}");
+}
+
+/// The concurrency every half second for four seconds after a reset, under
+/// a load of fast requests that can use up to forty.
+async fn after_reset(layer: LoadShedLayer) -> Vec<usize> {
+    let service = layer
+        .max_concurrency(40)
+        .proportional_increase(40)
+        .layer(common::sleeper());
+    common::drive(service.clone(), 50, Duration::from_secs(5), || FAST).await;
+    assert_eq!(service.concurrency(), 40, "{}", service.status_line());
+    service.reset();
+    let load = tokio::spawn(common::drive(
+        service.clone(),
+        50,
+        Duration::from_secs(4),
+        || FAST,
+    ));
+    let mut concurrencies = Vec::new();
+    for _ in 0..8 {
+        tokio::time::sleep(Duration::from_millis(500)).await;
+        concurrencies.push(service.concurrency());
+    }
+    load.await.unwrap();
+    concurrencies
+}
+
+#[tokio::test(start_paused = true)]
+async fn a_warmup_ramps_the_concurrency_up_after_a_reset() {
+    let jumped = after_reset(LoadShedLayer::new(0.1, TARGET)).await;
+    assert!(jumped[0] > 30, "{jumped:?}");
+    let ramped = after_reset(LoadShedLayer::new(0.1, TARGET).warmup(Duration::from_secs(4))).await;
+    // The maximum rises by nearly five every half second.
+    for (step, &concurrency) in ramped.iter().enumerate() {
+        let max = 1 + 39 * (step + 1) / 8;
+        assert!(concurrency <= max, "{ramped:?}");
+    }
+    assert!(ramped[0] < 10, "{ramped:?}");
+    assert!(ramped[7] > 30, "{ramped:?}");
+}
+
+/// The concurrency 200ms after the latency falls back from a slow spike,
+/// and the lowest it got to, under more load than it can use.
+async fn recovery_after_spike(layer: LoadShedLayer) -> (usize, usize) {
+    let service = layer
+        .trend_backoff(0.01)
+        .max_concurrency(20)
+        .layer(common::sleeper());
+    common::drive(service.clone(), 40, Duration::from_secs(5), || FAST).await;
+    common::drive(service.clone(), 40, Duration::from_secs(3), || TARGET * 2).await;
+    let lowest = service.concurrency();
+    common::drive(service.clone(), 40, Duration::from_millis(200), || FAST).await;
+    (service.concurrency(), lowest)
+}
+
+#[tokio::test(start_paused = true)]
+async fn falling_latency_recovers_the_concurrency_faster() {
+    let blind = recovery_after_spike(LoadShedLayer::new(0.1, TARGET)).await;
+    let recovering =
+        recovery_after_spike(LoadShedLayer::new(0.1, TARGET).trend_recovery(0.1)).await;
+    assert!(
+        blind.1 < 10 && recovering.1 < 10,
+        "{blind:?} vs {recovering:?}"
+    );
+    assert!(
+        recovering.0 - recovering.1 > (blind.0 - blind.1) * 3 / 2,
+        "{blind:?} vs {recovering:?}"
+    );
+}
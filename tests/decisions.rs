@@ -0,0 +1,135 @@
+//! The log of the decisions a load shedder makes.
+#![cfg(feature = "decision-log")]
+
+mod common;
+
+use std::{
+    future::Future,
+    pin::Pin,
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use little_loadshedder::{Decision, LoadShedLayer, LoadShedResponse, ReadinessPolicy};
+use tokio::time::Sleep;
+use tower::{Layer, Service, ServiceExt};
+
+const TARGET: Duration = Duration::from_millis(100);
+const FAST: Duration = Duration::from_millis(10);
+
+#[tokio::test(start_paused = true)]
+async fn each_decision_is_logged_in_order() {
+    let service = LoadShedLayer::new(0.1, TARGET)
+        .decision_log(100)
+        .layer(common::sleeper());
+    // One request goes straight in, one queues behind it and one is shed.
+    let requests: Vec<_> = (0..3)
+        .map(|_| tokio::spawn(service.clone().oneshot(FAST)))
+        .collect();
+    for request in requests {
+        request.await.unwrap().unwrap();
+    }
+    let records = service.take_decisions();
+    let decisions: Vec<_> = records.iter().map(|record| record.decision).collect();
+    let sample = |queued| Decision::Sample {
+        queued,
+        latency: FAST,
+        success: true,
+    };
+    assert_eq!(
+        decisions,
+        [
+            Decision::Admitted,
+            Decision::Shed(LoadShedResponse::Overload),
+            sample(Duration::ZERO),
+            // The queued request is admitted once the first completes.
+            Decision::Admitted,
+            sample(FAST),
+        ]
+    );
+    let start = records[0].at;
+    let times: Vec<_> = records.iter().map(|record| record.at - start).collect();
+    assert_eq!(
+        times,
+        [Duration::ZERO, Duration::ZERO, FAST, FAST, FAST * 2]
+    );
+    // Taking the decisions empties the log.
+    assert!(service.take_decisions().is_empty());
+}
+
+#[tokio::test(start_paused = true)]
+async fn the_log_keeps_the_latest_decisions_and_replays_the_concurrency() {
+    let service = LoadShedLayer::new(0.1, TARGET)
+        .decision_log(10_000)
+        .layer(common::sleeper());
+    common::drive(service.clone(), 20, Duration::from_secs(2), || FAST).await;
+    let records = service.take_decisions();
+    assert!(records.len() < 10_000, "{}", records.len());
+    assert!(records.windows(2).all(|pair| pair[0].at <= pair[1].at));
+    // Replaying the changes to the concurrency ends up where it is now.
+    let mut concurrency = 1;
+    for record in &records {
+        if let Decision::Concurrency { from, to } = record.decision {
+            assert_eq!(from, concurrency);
+            concurrency = to;
+        }
+    }
+    assert!(concurrency > 1);
+    assert_eq!(concurrency, service.concurrency());
+
+    // A full log drops the oldest.
+    let service = LoadShedLayer::new(0.1, TARGET)
+        .decision_log(10)
+        .layer(common::sleeper());
+    for _ in 0..20 {
+        service.clone().oneshot(FAST).await.unwrap();
+    }
+    let records = service.take_decisions();
+    assert_eq!(records.len(), 10);
+    assert!(matches!(records[9].decision, Decision::Sample { .. }));
+}
+
+/// A service that fails to become ready after `TARGET`.
+#[derive(Debug, Default)]
+struct NeverReady {
+    failed: Option<Pin<Box<Sleep>>>,
+}
+
+impl Clone for NeverReady {
+    fn clone(&self) -> Self {
+        Self::default()
+    }
+}
+
+impl Service<()> for NeverReady {
+    type Response = ();
+    type Error = &'static str;
+    type Future = std::future::Ready<Result<(), &'static str>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), &'static str>> {
+        let failed = self
+            .failed
+            .get_or_insert_with(|| Box::pin(tokio::time::sleep(TARGET)));
+        failed.as_mut().poll(cx).map(|()| Err("never ready"))
+    }
+
+    fn call(&mut self, (): ()) -> Self::Future {
+        unreachable!("never ready")
+    }
+}
+
+#[tokio::test(start_paused = true)]
+async fn requeued_requests_are_logged_when_readiness_fails() {
+    let service = LoadShedLayer::new(0.1, TARGET)
+        .decision_log(100)
+        .readiness_policy(ReadinessPolicy::Requeue)
+        .layer(NeverReady::default());
+    assert_eq!(
+        service.clone().oneshot(()).await.unwrap_err(),
+        "never ready"
+    );
+    // It gave up its slot to wait, but still reached the inner service.
+    let records = service.take_decisions();
+    let decisions: Vec<_> = records.iter().map(|record| record.decision).collect();
+    assert_eq!(decisions, [Decision::Admitted]);
+}
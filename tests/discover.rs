@@ -0,0 +1,90 @@
+//! Resetting what's been learned when the discovered backends change.
+#![cfg(feature = "discover")]
+
+mod common;
+
+use std::{convert::Infallible, time::Duration};
+
+use futures::{channel::mpsc, FutureExt, StreamExt};
+use little_loadshedder::{LoadShed, LoadShedLayer, ResetOnChange};
+use tower::{discover::Change, util::BoxCloneService, Layer, ServiceExt};
+
+const TARGET: Duration = Duration::from_millis(100);
+const FAST: Duration = Duration::from_millis(10);
+
+type Changes = Result<Change<usize, ()>, Infallible>;
+type Discover = ResetOnChange<mpsc::UnboundedReceiver<Changes>>;
+type Shed = LoadShed<BoxCloneService<Duration, Duration, Infallible>>;
+
+/// A load shedder that resets when the backends from the returned sender
+/// change, and the stream of those changes.
+fn with_backends(threshold: f64) -> (Shed, mpsc::UnboundedSender<Changes>, Discover) {
+    let (backends, discover) = mpsc::unbounded();
+    let discover = ResetOnChange::new(discover).threshold(threshold);
+    let service = LoadShedLayer::new(0.1, TARGET)
+        .reset_on(discover.signal())
+        .layer(common::sleeper());
+    (service, backends, discover)
+}
+
+/// Learn the latency of fast requests.
+async fn learn(service: &Shed) {
+    for _ in 0..100 {
+        service.clone().oneshot(FAST).await.unwrap();
+    }
+    assert!(
+        service.stats().success_latency < FAST * 2,
+        "{:?}",
+        service.stats()
+    );
+}
+
+/// Whether the next request finds that the load shedder has forgotten what
+/// it learned.
+async fn was_reset(service: &Shed) -> bool {
+    service.clone().oneshot(FAST).await.unwrap();
+    let reset = service.stats().success_latency > TARGET / 2;
+    learn(service).await;
+    reset
+}
+
+#[tokio::test(start_paused = true)]
+async fn a_backend_change_makes_the_controller_relearn() {
+    let (service, backends, mut discover) = with_backends(0.0);
+    learn(&service).await;
+    assert!(!was_reset(&service).await);
+
+    backends.unbounded_send(Ok(Change::Insert(1, ()))).unwrap();
+    assert!(matches!(
+        discover.next().await,
+        Some(Ok(Change::Insert(1, ())))
+    ));
+    service.clone().oneshot(FAST).await.unwrap();
+    let stats = service.stats();
+    assert!(stats.success_latency > TARGET / 2, "{stats:?}");
+    assert!(stats.average_latency_at_capacity > TARGET / 2, "{stats:?}");
+
+    // And then learns the new backends.
+    learn(&service).await;
+}
+
+#[tokio::test(start_paused = true)]
+async fn small_changes_below_the_threshold_are_ignored() {
+    let (service, backends, mut discover) = with_backends(0.5);
+    let mut change = |change| {
+        backends.unbounded_send(Ok(change)).unwrap();
+        discover.next().now_or_never().unwrap();
+    };
+    learn(&service).await;
+    for backend in 0..4 {
+        change(Change::Insert(backend, ()));
+    }
+    learn(&service).await;
+
+    // One of four backends leaving isn't enough.
+    change(Change::Remove(0));
+    assert!(!was_reset(&service).await);
+    // But two are.
+    change(Change::Remove(1));
+    assert!(was_reset(&service).await);
+}
@@ -0,0 +1,238 @@
+//! The events the load shedder reports to its callback.
+
+mod common;
+
+use std::{
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use common::{SlowToReady, READY, SERVICE};
+use little_loadshedder::{LatencyAttribution, LoadShed, LoadShedEvent, LoadShedLayer};
+use tower::{Layer, ServiceExt};
+
+const TARGET: Duration = Duration::from_millis(100);
+
+/// A layer that records the attribution of every completed request.
+fn recording() -> (LoadShedLayer, Arc<Mutex<Vec<LatencyAttribution>>>) {
+    let completed = Arc::new(Mutex::new(Vec::new()));
+    let layer = LoadShedLayer::new(0.1, TARGET).on_event({
+        let completed = completed.clone();
+        move |event| {
+            if let LoadShedEvent::Completed(attribution) = event {
+                completed.lock().unwrap().push(*attribution);
+            }
+        }
+    });
+    (layer, completed)
+}
+
+#[tokio::test(start_paused = true)]
+async fn completed_requests_are_attributed_to_each_delay() {
+    let (layer, completed) = recording();
+    let service = layer
+        .max_concurrency(1)
+        .min_queue(5)
+        .layer(SlowToReady::default());
+    let first = tokio::spawn(service.clone().oneshot(()));
+    let second = tokio::spawn(service.clone().oneshot(()));
+    first.await.unwrap().unwrap();
+    second.await.unwrap().unwrap();
+
+    let completed = completed.lock().unwrap().clone();
+    let unqueued = LatencyAttribution {
+        queue: Duration::ZERO,
+        ready: READY,
+        service: SERVICE,
+    };
+    // The second waits in the queue for the whole of the first.
+    let queued = LatencyAttribution {
+        queue: READY + SERVICE,
+        ..unqueued
+    };
+    assert_eq!(completed, [unqueued, queued]);
+    assert_eq!(completed[1].total(), (READY + SERVICE) * 2);
+}
+
+#[tokio::test(start_paused = true)]
+async fn overload_at_the_minimum_concurrency_is_reported_once() {
+    let reported = Arc::new(Mutex::new(0));
+    let service = LoadShedLayer::new(0.1, TARGET)
+        .on_event({
+            let reported = reported.clone();
+            move |event| {
+                if let LoadShedEvent::AtMinConcurrency = event {
+                    *reported.lock().unwrap() += 1;
+                }
+            }
+        })
+        .layer(common::sleeper());
+    common::drive(service.clone(), 5, Duration::from_secs(5), || TARGET * 2).await;
+    let stats = service.stats();
+    assert_eq!(stats.concurrency, 1, "{stats:?}");
+    assert!(stats.at_min_concurrency, "{stats:?}");
+    assert_eq!(*reported.lock().unwrap(), 1);
+}
+
+#[tokio::test(start_paused = true)]
+async fn failing_fast_at_the_minimum_concurrency_stops_queueing() {
+    let layer = || LoadShedLayer::new(0.1, TARGET).min_queue(5);
+    let queueing = layer().layer(common::sleeper());
+    let failing_fast = layer()
+        .fail_fast_at_min_concurrency(true)
+        .layer(common::sleeper());
+    let (queueing_load, failing_fast_load) = tokio::join!(
+        common::drive(queueing.clone(), 5, Duration::from_secs(5), || TARGET * 2),
+        common::drive(failing_fast.clone(), 5, Duration::from_secs(5), || TARGET
+            * 2),
+    );
+    // Much the same number of requests get through, but without waiting in
+    // the queue.
+    assert!(
+        failing_fast_load.admitted.abs_diff(queueing_load.admitted) <= 5,
+        "{failing_fast_load:?} vs {queueing_load:?}"
+    );
+    assert!(
+        failing_fast_load.shed > queueing_load.shed * 10,
+        "{failing_fast_load:?} vs {queueing_load:?}"
+    );
+    let (queueing, failing_fast) = (
+        queueing.latency_breakdown().queue,
+        failing_fast.latency_breakdown().queue,
+    );
+    assert!(queueing.p50 > TARGET, "{queueing:?}");
+    assert!(
+        failing_fast.p50 < Duration::from_millis(1),
+        "{failing_fast:?}"
+    );
+}
+
+/// A load shedder with room for one request in the service and one in the
+/// queue, recording when it reports overload starting and ending.
+fn overload_events(
+    debounce: Duration,
+) -> (LoadShed<common::BoxSleeper>, Arc<Mutex<Vec<LoadShedEvent>>>) {
+    let events = Arc::new(Mutex::new(Vec::new()));
+    let service = LoadShedLayer::new(0.1, TARGET)
+        .max_concurrency(1)
+        .min_queue(1)
+        .overload_debounce(debounce)
+        .on_event({
+            let events = events.clone();
+            move |event| {
+                if matches!(
+                    event,
+                    LoadShedEvent::OverloadStarted | LoadShedEvent::OverloadEnded
+                ) {
+                    events.lock().unwrap().push(event.clone());
+                }
+            }
+        })
+        .layer(common::sleeper());
+    (service, events)
+}
+
+/// Alternate between a burst that has a request shed and a request that's
+/// admitted, ten times over.
+async fn flap(service: &LoadShed<common::BoxSleeper>) {
+    for _ in 0..10 {
+        let burst = (0..3).map(|_| service.clone().oneshot(TARGET));
+        futures::future::join_all(burst).await;
+        service.clone().oneshot(TARGET).await.unwrap();
+    }
+}
+
+#[tokio::test(start_paused = true)]
+async fn overload_transitions_are_debounced() {
+    let (undebounced, flapped) = overload_events(Duration::ZERO);
+    flap(&undebounced).await;
+    assert_eq!(flapped.lock().unwrap().len(), 20);
+
+    let (service, events) = overload_events(Duration::from_secs(1));
+    flap(&service).await;
+    assert!(events.lock().unwrap().is_empty());
+
+    // Sustained overload is reported once, and so is its end. Two slow
+    // requests fill the service and the queue, so every other one is shed.
+    let slow: Vec<_> = (0..2)
+        .map(|_| tokio::spawn(service.clone().oneshot(TARGET * 20)))
+        .collect();
+    tokio::task::yield_now().await;
+    common::drive(service.clone(), 5, Duration::from_secs(3), || TARGET).await;
+    assert_eq!(*events.lock().unwrap(), [LoadShedEvent::OverloadStarted]);
+    for request in slow {
+        request.await.unwrap().unwrap();
+    }
+    common::drive(service.clone(), 1, Duration::from_secs(3), || TARGET).await;
+    assert_eq!(
+        *events.lock().unwrap(),
+        [LoadShedEvent::OverloadStarted, LoadShedEvent::OverloadEnded]
+    );
+}
+
+#[tokio::test(start_paused = true)]
+async fn overload_ends_without_further_traffic() {
+    let (service, events) = overload_events(Duration::from_secs(1));
+    let slow: Vec<_> = (0..2)
+        .map(|_| tokio::spawn(service.clone().oneshot(TARGET * 20)))
+        .collect();
+    tokio::task::yield_now().await;
+    common::drive(service.clone(), 5, Duration::from_secs(3), || TARGET).await;
+    assert_eq!(*events.lock().unwrap(), [LoadShedEvent::OverloadStarted]);
+    for request in slow {
+        request.await.unwrap().unwrap();
+    }
+    // Nothing else arrives, but the overload still ends.
+    tokio::time::sleep(Duration::from_secs(3)).await;
+    assert_eq!(
+        *events.lock().unwrap(),
+        [LoadShedEvent::OverloadStarted, LoadShedEvent::OverloadEnded]
+    );
+}
+
+#[tokio::test(start_paused = true)]
+async fn only_a_full_queue_is_overload() {
+    let (service, events) = overload_events(Duration::ZERO);
+    service.force_shed(true);
+    common::drive(service.clone(), 5, Duration::from_secs(1), || TARGET).await;
+    service.force_shed(false);
+    assert!(events.lock().unwrap().is_empty());
+
+    // Once overloaded, requests shed for other reasons don't end it.
+    let slow: Vec<_> = (0..2)
+        .map(|_| tokio::spawn(service.clone().oneshot(TARGET * 20)))
+        .collect();
+    tokio::task::yield_now().await;
+    service.clone().oneshot(TARGET).await.unwrap();
+    service.force_shed(true);
+    common::drive(service.clone(), 5, Duration::from_secs(1), || TARGET).await;
+    assert_eq!(*events.lock().unwrap(), [LoadShedEvent::OverloadStarted]);
+    drop(slow);
+}
+
+/// The average fraction of their latency completed requests spent queued,
+/// from `clients` clients sending requests that take `FAST`.
+async fn average_queue_ratio(clients: usize) -> f64 {
+    let (layer, completed) = recording();
+    let service = layer
+        .max_concurrency(1)
+        .min_queue(20)
+        .layer(common::sleeper());
+    common::drive(service, clients, Duration::from_secs(2), || TARGET / 10).await;
+    let completed = completed.lock().unwrap();
+    completed
+        .iter()
+        .map(LatencyAttribution::queue_ratio)
+        .sum::<f64>()
+        / completed.len() as f64
+}
+
+#[tokio::test(start_paused = true)]
+async fn the_queue_ratio_shows_where_the_time_went() {
+    // Queue heavy, nine in ten requests are waiting for the one in service.
+    let queue_heavy = average_queue_ratio(10).await;
+    assert!(queue_heavy > 0.8, "{queue_heavy}");
+    // Service heavy, nothing waits.
+    let service_heavy = average_queue_ratio(1).await;
+    assert!(service_heavy < 0.01, "{service_heavy}");
+}
@@ -0,0 +1,31 @@
+//! Sending shed requests to a fallback service.
+
+use std::{convert::Infallible, time::Duration};
+
+use little_loadshedder::LoadShed;
+use tower::ServiceExt;
+
+const TARGET: Duration = Duration::from_millis(100);
+
+#[tokio::test(start_paused = true)]
+async fn shed_requests_are_served_by_the_fallback() {
+    let primary = tower::service_fn(|request: u32| async move {
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        Ok::<_, Infallible>(format!("primary {request}"))
+    });
+    let fallback =
+        tower::service_fn(|request: u32| async move { Ok(format!("fallback {request}")) });
+    let service = LoadShed::new(primary, 0.1, TARGET).with_fallback_service(fallback);
+    // One request in the service and one queued, so the third is shed.
+    let requests: Vec<_> = (0..3)
+        .map(|request| tokio::spawn(service.clone().oneshot(request)))
+        .collect();
+    let mut responses = Vec::new();
+    for request in requests {
+        responses.push(request.await.unwrap().unwrap());
+    }
+    assert_eq!(responses, ["primary 0", "primary 1", "fallback 2"]);
+
+    service.load_shed().force_shed(true);
+    assert_eq!(service.clone().oneshot(3).await.unwrap(), "fallback 3");
+}
@@ -0,0 +1,102 @@
+//! Skipping the queue when the inner service has capacity to spare.
+
+use std::{convert::Infallible, time::Duration};
+
+use little_loadshedder::{LoadShedLayer, LoadShedResponse};
+use tower::{Layer, ServiceExt};
+
+const TARGET: Duration = Duration::from_millis(100);
+const SLOW: Duration = Duration::from_millis(50);
+
+fn layer() -> LoadShedLayer {
+    LoadShedLayer::new(0.1, TARGET).fast_path(true)
+}
+
+#[tokio::test(start_paused = true)]
+async fn the_fast_path_still_enforces_the_limits() {
+    let service = layer().layer(tower::service_fn(|latency: Duration| async move {
+        tokio::time::sleep(latency).await;
+        Ok::<_, Infallible>(latency)
+    }));
+    // The first takes the free permit, the second the only place in the
+    // queue, so the third is shed.
+    let requests: Vec<_> = (0..3)
+        .map(|_| tokio::spawn(service.clone().oneshot(SLOW)))
+        .collect();
+    let mut responses = Vec::new();
+    for request in requests {
+        responses.push(request.await.unwrap().unwrap());
+    }
+    assert_eq!(
+        responses,
+        [
+            LoadShedResponse::Inner(SLOW),
+            LoadShedResponse::Inner(SLOW),
+            LoadShedResponse::Overload
+        ]
+    );
+    assert_eq!(service.queue_len(), 0);
+    // Both admitted requests completed at capacity, so they were measured.
+    assert!(service.stats().success_latency < TARGET);
+}
+
+#[tokio::test(start_paused = true)]
+async fn the_fast_path_serves_sequential_requests() {
+    let service = layer().layer(tower::service_fn(|request: u32| async move {
+        Ok::<_, Infallible>(request)
+    }));
+    for request in 0..100 {
+        assert_eq!(
+            service.clone().oneshot(request).await.unwrap(),
+            LoadShedResponse::Inner(request)
+        );
+    }
+    assert_eq!(service.queue_len(), 0);
+}
+
+#[tokio::test(start_paused = true)]
+async fn the_fast_path_leaves_the_statistics_alone() {
+    let service =
+        layer()
+            .min_concurrency(4)
+            .layer(tower::service_fn(|latency: Duration| async move {
+                tokio::time::sleep(latency).await;
+                Ok::<_, Infallible>(latency)
+            }));
+    for _ in 0..10 {
+        service.clone().oneshot(SLOW).await.unwrap();
+    }
+    // There was always a permit to spare, so none of them were measured.
+    let stats = service.stats();
+    assert_eq!(stats.average_latency, TARGET);
+    assert_eq!(stats.success_latency, TARGET);
+}
+
+#[tokio::test(start_paused = true)]
+async fn the_fast_path_measures_requests_at_capacity() {
+    let service = layer().layer(tower::service_fn(|latency: Duration| async move {
+        tokio::time::sleep(latency).await;
+        Ok::<_, Infallible>(latency)
+    }));
+    // Each request takes the only permit, so it completes at capacity.
+    for _ in 0..10 {
+        service.clone().oneshot(SLOW).await.unwrap();
+    }
+    assert!(service.stats().success_latency < TARGET);
+}
+
+#[tokio::test(start_paused = true)]
+async fn the_fast_path_measures_failures() {
+    let service =
+        layer()
+            .min_concurrency(4)
+            .layer(tower::service_fn(|latency: Duration| async move {
+                tokio::time::sleep(latency).await;
+                Err::<Duration, _>("failed")
+            }));
+    for _ in 0..10 {
+        service.clone().oneshot(SLOW).await.unwrap_err();
+    }
+    // There was capacity to spare, but the controller has to see failures.
+    assert!(service.stats().failure_latency < TARGET);
+}
@@ -0,0 +1,112 @@
+//! How the load shedder reacts to faults injected into the inner service.
+#![cfg(feature = "test-util")]
+
+mod common;
+
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use little_loadshedder::{Fault, FaultError, FaultInjection, LoadShedLayer, LoadShedResponse};
+use tower::{Layer, ServiceExt};
+
+const TARGET: Duration = Duration::from_millis(100);
+const FAST: Duration = Duration::from_millis(10);
+
+/// Inject `fault` into every call while `faulty` is set.
+fn faulty(faulty: &Arc<AtomicBool>, fault: Fault) -> FaultInjection<common::BoxSleeper> {
+    let faulty = faulty.clone();
+    FaultInjection::new(common::sleeper(), move |_| {
+        if faulty.load(Ordering::Relaxed) {
+            fault
+        } else {
+            Fault::Pass
+        }
+    })
+}
+
+#[tokio::test(start_paused = true)]
+async fn a_slow_downstream_sheds_load_and_recovers() {
+    let slow = Arc::new(AtomicBool::new(false));
+    let service = LoadShedLayer::new(0.1, TARGET).layer(faulty(&slow, Fault::Delay(TARGET * 3)));
+    common::drive(service.clone(), 20, Duration::from_secs(10), || FAST).await;
+    let healthy = service.concurrency();
+    assert!(healthy > 5, "{}", service.status_line());
+
+    slow.store(true, Ordering::Relaxed);
+    let overloaded = common::drive(service.clone(), 20, Duration::from_secs(10), || FAST).await;
+    assert!(overloaded.shed > 0, "{overloaded:?}");
+    assert!(
+        service.concurrency() < healthy / 2,
+        "{}",
+        service.status_line()
+    );
+
+    slow.store(false, Ordering::Relaxed);
+    let recovered = common::drive(service.clone(), 20, Duration::from_secs(10), || FAST).await;
+    assert!(service.concurrency() > 5, "{}", service.status_line());
+    assert!(
+        service.average_latency() < TARGET,
+        "{}",
+        service.status_line()
+    );
+    assert!(
+        recovered.admitted > overloaded.admitted * 2,
+        "{recovered:?} vs {overloaded:?}"
+    );
+}
+
+#[tokio::test(start_paused = true)]
+async fn failures_drop_the_concurrency_until_they_stop() {
+    let failing = Arc::new(AtomicBool::new(false));
+    // Fail every other call, the rest keep the service at capacity so the
+    // failures are seen there.
+    let inner = {
+        let failing = failing.clone();
+        FaultInjection::new(common::sleeper(), move |call| {
+            if failing.load(Ordering::Relaxed) && call.is_multiple_of(2) {
+                Fault::Fail
+            } else {
+                Fault::Pass
+            }
+        })
+    };
+    let service = LoadShedLayer::new(0.1, TARGET).layer(inner);
+    common::drive(service.clone(), 50, Duration::from_secs(10), || FAST).await;
+    let healthy = service.concurrency();
+    assert!(healthy > 5, "{}", service.status_line());
+
+    failing.store(true, Ordering::Relaxed);
+    let failed = common::drive(service.clone(), 50, Duration::from_secs(10), || FAST).await;
+    assert!(failed.errors > 0, "{failed:?}");
+    assert!(
+        service.concurrency() < healthy / 2,
+        "{}",
+        service.status_line()
+    );
+    let errors = futures::future::join_all((0..2).map(|_| service.clone().oneshot(FAST))).await;
+    assert!(errors.contains(&Err(FaultError::Injected)), "{errors:?}");
+
+    failing.store(false, Ordering::Relaxed);
+    common::drive(service.clone(), 50, Duration::from_secs(10), || FAST).await;
+    assert!(service.concurrency() > 5, "{}", service.status_line());
+}
+
+#[tokio::test(start_paused = true)]
+async fn abandoned_hung_requests_release_their_permits() {
+    let hanging = Arc::new(AtomicBool::new(true));
+    let inner = faulty(&hanging, Fault::Hang);
+    let service = LoadShedLayer::new(0.1, TARGET).layer(inner.clone());
+    let hung = tokio::time::timeout(TARGET * 5, service.clone().oneshot(FAST)).await;
+    assert!(hung.is_err());
+    assert_eq!(service.stats().in_flight, 0, "{:?}", service.stats());
+
+    hanging.store(false, Ordering::Relaxed);
+    let response = service.clone().oneshot(FAST).await.unwrap();
+    assert!(matches!(response, LoadShedResponse::Inner(_)));
+    assert_eq!(inner.calls(), 2);
+}
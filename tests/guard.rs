@@ -0,0 +1,71 @@
+//! Protecting work outside the `Service` interface with a guard.
+
+use std::time::Duration;
+
+use little_loadshedder::{LoadShed, LoadShedLayer, LoadShedResponse, Outcome};
+use tower::Layer;
+
+const TARGET: Duration = Duration::from_millis(100);
+
+#[tokio::test(start_paused = true)]
+async fn completing_a_guard_records_its_latency() {
+    let service = LoadShed::new((), 0.5, TARGET);
+    let LoadShedResponse::Inner(guard) = service.acquire().await else {
+        panic!("the guard was shed");
+    };
+    assert_eq!(service.queue_len(), 1);
+    tokio::time::sleep(Duration::from_millis(40)).await;
+    guard.complete(Outcome::Success);
+    assert_eq!(service.queue_len(), 0);
+    assert_eq!(service.stats().success_latency, Duration::from_millis(70));
+}
+
+#[tokio::test(start_paused = true)]
+async fn dropping_a_guard_records_nothing() {
+    let service = LoadShed::new((), 0.5, TARGET);
+    let LoadShedResponse::Inner(guard) = service.acquire().await else {
+        panic!("the guard was shed");
+    };
+    tokio::time::sleep(Duration::from_millis(40)).await;
+    drop(guard);
+    assert_eq!(service.queue_len(), 0);
+    assert_eq!(service.stats().success_latency, TARGET);
+}
+
+#[tokio::test(start_paused = true)]
+async fn a_probe_is_admitted_while_full_and_kept_out_of_the_stats() {
+    let service = LoadShedLayer::new(0.5, TARGET)
+        .max_concurrency(1)
+        .min_queue(1)
+        .layer(());
+    // Fill the service and the queue.
+    let LoadShedResponse::Inner(running) = service.acquire().await else {
+        panic!("the guard was shed");
+    };
+    let queued = tokio::spawn({
+        let service = service.clone();
+        async move { service.acquire().await }
+    });
+    tokio::task::yield_now().await;
+    assert!(matches!(
+        service.acquire().await,
+        LoadShedResponse::Overload
+    ));
+
+    let LoadShedResponse::Inner(probe) = service.admit_probe() else {
+        panic!("the probe was shed");
+    };
+    // It takes no part in the queue or the latency.
+    assert_eq!(service.queue_len(), 2);
+    tokio::time::sleep(Duration::from_millis(40)).await;
+    probe.complete(Outcome::Success);
+    assert_eq!(service.stats().success_latency, TARGET);
+
+    drop(running);
+    assert!(matches!(queued.await.unwrap(), LoadShedResponse::Inner(_)));
+    service.shutdown();
+    assert!(matches!(
+        service.admit_probe(),
+        LoadShedResponse::ShuttingDown
+    ));
+}
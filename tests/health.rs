@@ -0,0 +1,58 @@
+//! Keeping health checks out of the statistics.
+#![cfg(feature = "http")]
+
+use std::{convert::Infallible, time::Duration};
+
+use little_loadshedder::{ExtensionRequestInfo, HealthCheck, LoadShedLayer, LoadShedResponse};
+use tower::{Layer, ServiceExt};
+
+const TARGET: Duration = Duration::from_millis(100);
+const FAST: Duration = Duration::from_millis(10);
+
+/// A request that takes as long as its body says, marked as a health check
+/// if `health_check` is set.
+fn request(latency: Duration, health_check: bool) -> http::Request<Duration> {
+    let mut request = http::Request::new(latency);
+    if health_check {
+        request.extensions_mut().insert(HealthCheck);
+    }
+    request
+}
+
+#[tokio::test(start_paused = true)]
+async fn health_checks_are_admitted_without_affecting_the_statistics() {
+    let service = LoadShedLayer::new(0.1, TARGET)
+        .request_info(ExtensionRequestInfo)
+        .layer(tower::service_fn(
+            |request: http::Request<Duration>| async move {
+                tokio::time::sleep(*request.body()).await;
+                Ok::<_, Infallible>(())
+            },
+        ));
+    for _ in 0..10 {
+        service.clone().oneshot(request(FAST, false)).await.unwrap();
+    }
+    let before = service.stats();
+
+    // Slow health checks.
+    for _ in 0..10 {
+        let response = service
+            .clone()
+            .oneshot(request(TARGET * 10, true))
+            .await
+            .unwrap();
+        assert!(matches!(response, LoadShedResponse::Inner(())));
+    }
+    let after = service.stats();
+    assert_eq!(after.average_latency, before.average_latency);
+    assert_eq!(after.success_latency, before.success_latency);
+    assert_eq!(
+        after.increases + after.decreases,
+        before.increases + before.decreases
+    );
+    // Nothing's arrived since, so the rate falls off rather than rising.
+    assert!(
+        after.arrival_rate < before.arrival_rate,
+        "{after:?} vs {before:?}"
+    );
+}
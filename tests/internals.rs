@@ -0,0 +1,20 @@
+//! The internal types exposed by the `internals` feature.
+#![cfg(feature = "internals")]
+
+use std::{convert::Infallible, time::Duration};
+
+use little_loadshedder::{
+    internals::{ConfStats, LoadShedConf, Permit},
+    LoadShedLayer,
+};
+use tower::Layer;
+
+#[test]
+fn internal_types_are_reachable() {
+    let service = LoadShedLayer::new(0.1, Duration::from_millis(100))
+        .layer(tower::service_fn(|()| async { Ok::<_, Infallible>(()) }));
+    let conf: &LoadShedConf = service.internals();
+    assert!(format!("{conf:?}").contains("ConfStats"));
+    let _: Option<ConfStats> = None;
+    let _: Option<Permit> = None;
+}
@@ -0,0 +1,289 @@
+//! How the latency the load shedder controls on is measured and averaged.
+
+mod common;
+
+use std::{
+    convert::Infallible,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use little_loadshedder::{LoadShed, LoadShedLayer, Outcome, RequestInfo};
+use tower::{util::BoxCloneService, Layer, ServiceExt};
+
+const TARGET: Duration = Duration::from_secs(1);
+const FAST: Duration = Duration::from_millis(10);
+
+/// Send requests taking each of `latencies` one after the other, and return
+/// the average latency in milliseconds after each one.
+async fn averages(layer: LoadShedLayer, latencies: impl IntoIterator<Item = u64>) -> Vec<f64> {
+    // A concurrency of one keeps the controller from rescaling the average.
+    let service = layer.max_concurrency(1).layer(common::sleeper());
+    let mut averages = Vec::new();
+    for latency in latencies {
+        let latency = Duration::from_millis(latency);
+        service.clone().oneshot(latency).await.unwrap();
+        averages.push(service.average_latency().as_secs_f64() * 1000.0);
+    }
+    averages
+}
+
+fn spread(averages: &[f64]) -> f64 {
+    let max = averages.iter().copied().fold(f64::MIN, f64::max);
+    let min = averages.iter().copied().fold(f64::MAX, f64::min);
+    max - min
+}
+
+#[tokio::test(start_paused = true)]
+async fn adaptive_ewma_smooths_volatile_latency_more() {
+    let volatile = || (0..200).map(|i| if i % 2 == 0 { 20 } else { 180 });
+    let fixed = averages(LoadShedLayer::new(0.2, TARGET), volatile()).await;
+    let adaptive = averages(
+        LoadShedLayer::new(0.2, TARGET).adaptive_ewma(true),
+        volatile(),
+    )
+    .await;
+    let (fixed, adaptive) = (spread(&fixed[100..]), spread(&adaptive[100..]));
+    assert!(adaptive < fixed * 0.7, "{adaptive} vs {fixed}");
+}
+
+#[tokio::test(start_paused = true)]
+async fn adaptive_ewma_tracks_stable_latency_quickly() {
+    let step = || std::iter::repeat_n(50, 200).chain(std::iter::repeat_n(80, 100));
+    let fixed = averages(LoadShedLayer::new(0.2, TARGET), step()).await;
+    let adaptive = averages(LoadShedLayer::new(0.2, TARGET).adaptive_ewma(true), step()).await;
+    // How many requests after the step the average is within 2ms of it.
+    let settled = |averages: &[f64]| {
+        averages[200..]
+            .iter()
+            .position(|average| (average - 80.0).abs() < 2.0)
+            .unwrap()
+    };
+    assert!(
+        settled(&adaptive) <= settled(&fixed) + 1,
+        "{} vs {}",
+        settled(&adaptive),
+        settled(&fixed)
+    );
+}
+
+#[tokio::test(start_paused = true)]
+async fn successes_and_failures_are_averaged_separately() {
+    let service = LoadShedLayer::new(0.2, TARGET)
+        .classifier(|latency: &Duration| {
+            if *latency < Duration::from_millis(50) {
+                Outcome::Failure
+            } else {
+                Outcome::Success
+            }
+        })
+        .layer(common::sleeper());
+    for request in 0..100 {
+        let latency = if request % 2 == 0 { 20 } else { 80 };
+        service
+            .clone()
+            .oneshot(Duration::from_millis(latency))
+            .await
+            .unwrap();
+    }
+    let stats = service.stats();
+    let near =
+        |latency: Duration, millis: f64| (latency.as_secs_f64() * 1000.0 - millis).abs() < 1.0;
+    assert!(near(stats.success_latency, 80.0), "{stats:?}");
+    assert!(near(stats.failure_latency, 20.0), "{stats:?}");
+}
+
+/// Send a request every `interval`, taking 50ms for 10s and then 150ms for
+/// 2s, and return the average latency in milliseconds at the end.
+async fn after_step(layer: LoadShedLayer, interval: Duration) -> f64 {
+    let service = layer.max_concurrency(1).layer(common::sleeper());
+    for (latency, duration) in [(50, 10), (150, 2)] {
+        let latency = Duration::from_millis(latency);
+        for _ in 0..(Duration::from_secs(duration).as_millis() / interval.as_millis()) {
+            service.clone().oneshot(latency).await.unwrap();
+            tokio::time::sleep(interval - latency).await;
+        }
+    }
+    service.average_latency().as_secs_f64() * 1000.0
+}
+
+#[tokio::test(start_paused = true)]
+async fn a_time_constant_smooths_the_same_at_any_request_rate() {
+    let (dense, sparse) = (Duration::from_millis(200), Duration::from_secs(1));
+    let layer = || LoadShedLayer::new(0.1, TARGET).ewma_time_constant(Duration::from_secs(1));
+    let (dense_average, sparse_average) = (
+        after_step(layer(), dense).await,
+        after_step(layer(), sparse).await,
+    );
+    assert!(
+        (dense_average - sparse_average).abs() < 0.1,
+        "{dense_average} vs {sparse_average}"
+    );
+    // About two time constants after the step the average has moved all but
+    // e^-2 of the way.
+    let expected = 150.0 - 100.0 * (-2.0f64).exp();
+    assert!((dense_average - expected).abs() < 2.0, "{dense_average}");
+
+    // With a fixed parameter the sparse stream is still catching up, even
+    // with the latency from before the step.
+    let layer = || LoadShedLayer::new(0.1, TARGET);
+    let (dense_average, sparse_average) = (
+        after_step(layer(), dense).await,
+        after_step(layer(), sparse).await,
+    );
+    assert!(
+        (sparse_average - dense_average).abs() > 50.0,
+        "{sparse_average} vs {dense_average}"
+    );
+}
+
+/// A service that slows down the more requests it's handling, one in five
+/// of which take three times as long as the rest, so its tail latency is far
+/// above its mean.
+fn long_tailed() -> BoxCloneService<(), (), Infallible> {
+    let in_flight = Arc::new(AtomicU64::new(0));
+    let calls = Arc::new(AtomicU64::new(0));
+    BoxCloneService::new(tower::service_fn(move |()| {
+        let in_flight = in_flight.clone();
+        let slow = calls.fetch_add(1, Ordering::Relaxed).is_multiple_of(5);
+        async move {
+            let load = in_flight.fetch_add(1, Ordering::Relaxed) + 1;
+            let latency = Duration::from_millis(2 * load) * if slow { 3 } else { 1 };
+            tokio::time::sleep(latency).await;
+            in_flight.fetch_sub(1, Ordering::Relaxed);
+            Ok(())
+        }
+    }))
+}
+
+#[tokio::test(start_paused = true)]
+async fn a_percentile_target_holds_that_percentile_at_the_target() {
+    let target = Duration::from_millis(100);
+    let mean = LoadShed::new(long_tailed(), 0.1, target);
+    let percentile = LoadShed::with_percentile_target(long_tailed(), 0.1, 0.9, target);
+    tokio::join!(
+        common::drive(mean.clone(), 60, Duration::from_secs(5), || ()),
+        common::drive(percentile.clone(), 60, Duration::from_secs(5), || ()),
+    );
+    let p90 = |service: &LoadShed<_>| service.latency_breakdown().service.p90;
+    // Holding the mean at the target leaves the tail far over it.
+    assert!(p90(&mean) > target * 3 / 2, "{}", mean.status_line());
+    let (p90, status) = (p90(&percentile), percentile.status_line());
+    assert!(
+        p90 > target / 2 && p90 < target * 13 / 10,
+        "{p90:?} {status}"
+    );
+    assert!(percentile.concurrency() < mean.concurrency(), "{status}");
+}
+
+/// Says each request arrived `earlier` than it reached the load shedder, if
+/// set.
+#[derive(Clone, Copy)]
+struct ArrivedEarlier(Option<Duration>);
+
+impl RequestInfo<Duration> for ArrivedEarlier {
+    fn arrival(&self, _request: &Duration) -> Option<std::time::Instant> {
+        self.0
+            .map(|earlier| (tokio::time::Instant::now() - earlier).into_std())
+    }
+}
+
+/// The success latency after a request taking 20ms that arrived `earlier`.
+async fn measured(from_arrival: bool, earlier: Option<Duration>) -> Duration {
+    let service = LoadShedLayer::new(0.1, TARGET)
+        .max_concurrency(1)
+        .latency_from_arrival(from_arrival)
+        .request_info(ArrivedEarlier(earlier))
+        .layer(common::sleeper());
+    service
+        .clone()
+        .oneshot(Duration::from_millis(20))
+        .await
+        .unwrap();
+    service.stats().success_latency
+}
+
+#[tokio::test(start_paused = true)]
+async fn latency_can_be_measured_from_the_arrival() {
+    let earlier = Some(Duration::from_millis(50));
+    // The first sample moves the average from the target a tenth of the way.
+    let expected = |millis: u64| TARGET.mul_f64(0.9) + Duration::from_millis(millis) / 10;
+    let near = |latency: Duration, expected: Duration| {
+        latency.abs_diff(expected) < Duration::from_micros(100)
+    };
+    let local = measured(false, earlier).await;
+    assert!(near(local, expected(20)), "{local:?}");
+    let from_arrival = measured(true, earlier).await;
+    assert!(near(from_arrival, expected(70)), "{from_arrival:?}");
+    // Without an arrival time the local timing is used.
+    let fallback = measured(true, None).await;
+    assert!(near(fallback, expected(20)), "{fallback:?}");
+}
+
+/// The control signal, in milliseconds, after sending requests one at a time
+/// taking 50ms for 5s, then 150ms for `after`.
+async fn signal_after_step(layer: LoadShedLayer, after: Duration) -> f64 {
+    let service = layer.max_concurrency(1).layer(common::sleeper());
+    for (latency, duration) in [(50, Duration::from_secs(5)), (150, after)] {
+        let latency = Duration::from_millis(latency);
+        for _ in 0..(duration.as_millis() / latency.as_millis()) {
+            service.clone().oneshot(latency).await.unwrap();
+        }
+    }
+    service.average_latency().as_secs_f64() * 1000.0
+}
+
+#[tokio::test(start_paused = true)]
+async fn a_window_fully_reflects_a_step_once_it_has_passed() {
+    let window = Duration::from_secs(1);
+    let windowed = signal_after_step(
+        LoadShedLayer::new(0.05, TARGET).latency_window(window),
+        window * 2,
+    )
+    .await;
+    let ewma = signal_after_step(LoadShedLayer::new(0.05, TARGET), window * 2).await;
+    assert!((windowed - 150.0).abs() < 1.0, "{windowed}");
+    // Thirteen samples into the step the moving average is only halfway there.
+    assert!(ewma < 120.0, "{ewma}");
+}
+
+#[tokio::test(start_paused = true)]
+async fn a_window_keeps_the_average_current_between_adjustments() {
+    let service = LoadShedLayer::new(0.1, TARGET)
+        .latency_window(Duration::from_secs(1))
+        .max_concurrency(1)
+        .layer(common::sleeper());
+    // The average is the mean of the window after every sample, whether or
+    // not the concurrency was adjusted (which it can't be here, so it doesn't
+    // scale the average).
+    for (latency, mean) in [(FAST, FAST), (FAST * 3, FAST * 2), (FAST * 5, FAST * 3)] {
+        service.clone().oneshot(latency).await.unwrap();
+        let average = service.average_latency();
+        assert!(
+            average.abs_diff(mean) < Duration::from_micros(1),
+            "{average:?} != {mean:?}"
+        );
+    }
+}
+
+#[tokio::test(start_paused = true)]
+async fn a_percentile_target_controls_on_the_penalised_latency() {
+    let service = LoadShedLayer::new(0.1, TARGET)
+        .target_percentile(0.5)
+        .failure_latency_penalty(TARGET * 3)
+        .classifier(|_: &Duration| Outcome::Failure)
+        .layer(common::sleeper());
+    service.clone().oneshot(FAST).await.unwrap();
+    let average = service.average_latency();
+    assert!(
+        average.abs_diff(TARGET * 3) < TARGET * 3 / 20,
+        "{}",
+        service.status_line()
+    );
+    // The breakdown still shows how long the service really took.
+    let breakdown = service.latency_breakdown();
+    assert!(breakdown.service.p50 < FAST * 2, "{breakdown:?}");
+}
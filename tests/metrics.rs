@@ -0,0 +1,530 @@
+//! The metrics the load shedder emits.
+#![cfg(feature = "metrics")]
+
+mod common;
+
+use std::{
+    borrow::Cow,
+    collections::HashMap,
+    convert::Infallible,
+    sync::{Arc, Mutex, OnceLock},
+    time::Duration,
+};
+
+use little_loadshedder::{LoadShedLayer, LoadShedResponse, Outcome, RequestInfo};
+use metrics::{
+    Counter, CounterFn, Gauge, GaugeFn, Histogram, HistogramFn, Key, KeyName, Recorder,
+    SharedString, Unit,
+};
+use tokio::sync::Semaphore;
+use tower::{Layer, ServiceExt};
+
+const TARGET: Duration = Duration::from_millis(100);
+
+/// The values recorded for one metric series.
+#[derive(Debug, Default)]
+struct Series {
+    value: Mutex<f64>,
+    samples: Mutex<Vec<f64>>,
+    /// How many times a gauge has been set.
+    sets: Mutex<u64>,
+}
+
+impl CounterFn for Series {
+    fn increment(&self, value: u64) {
+        *self.value.lock().unwrap() += value as f64;
+    }
+
+    fn absolute(&self, value: u64) {
+        *self.value.lock().unwrap() = value as f64;
+    }
+}
+
+impl GaugeFn for Series {
+    fn increment(&self, value: f64) {
+        *self.value.lock().unwrap() += value;
+    }
+
+    fn decrement(&self, value: f64) {
+        *self.value.lock().unwrap() -= value;
+    }
+
+    fn set(&self, value: f64) {
+        *self.value.lock().unwrap() = value;
+        *self.sets.lock().unwrap() += 1;
+    }
+}
+
+impl HistogramFn for Series {
+    fn record(&self, value: f64) {
+        self.samples.lock().unwrap().push(value);
+    }
+}
+
+/// A recorder that keeps every series in memory.
+#[derive(Debug, Default)]
+struct Capture(Mutex<HashMap<Key, Arc<Series>>>);
+
+impl Capture {
+    fn series(&self, key: &Key) -> Arc<Series> {
+        self.0
+            .lock()
+            .unwrap()
+            .entry(key.clone())
+            .or_default()
+            .clone()
+    }
+}
+
+impl Recorder for Capture {
+    fn describe_counter(&self, _: KeyName, _: Option<Unit>, _: SharedString) {}
+    fn describe_gauge(&self, _: KeyName, _: Option<Unit>, _: SharedString) {}
+    fn describe_histogram(&self, _: KeyName, _: Option<Unit>, _: SharedString) {}
+
+    fn register_counter(&self, key: &Key) -> Counter {
+        Counter::from_arc(self.series(key))
+    }
+
+    fn register_gauge(&self, key: &Key) -> Gauge {
+        Gauge::from_arc(self.series(key))
+    }
+
+    fn register_histogram(&self, key: &Key) -> Histogram {
+        Histogram::from_arc(self.series(key))
+    }
+}
+
+fn capture() -> &'static Capture {
+    static CAPTURE: OnceLock<&'static Capture> = OnceLock::new();
+    CAPTURE.get_or_init(|| {
+        let capture: &'static Capture = Box::leak(Box::default());
+        metrics::set_recorder(capture).unwrap();
+        capture
+    })
+}
+
+/// A layer whose metrics are labelled with the name of the test, so that
+/// tests running at the same time don't see each other's metrics.
+fn layer(test: &str) -> LoadShedLayer {
+    capture();
+    LoadShedLayer::new(0.1, TARGET).metric_labels(vec![("test", test.into())])
+}
+
+/// The series called `name` from `test` that have all of `labels`.
+fn series(test: &str, name: &str, labels: &[(&str, &str)]) -> Vec<Arc<Series>> {
+    let has = |key: &Key, (label, value): (&str, &str)| {
+        key.labels()
+            .any(|found| found.key() == label && found.value() == value)
+    };
+    capture()
+        .0
+        .lock()
+        .unwrap()
+        .iter()
+        .filter(|(key, _)| key.name() == name && has(key, ("test", test)))
+        .filter(|(key, _)| labels.iter().all(|&label| has(key, label)))
+        .map(|(_, series)| series.clone())
+        .collect()
+}
+
+/// The total of the counters called `name` from `test` with `labels`.
+fn counter(test: &str, name: &str, labels: &[(&str, &str)]) -> f64 {
+    series(test, name, labels)
+        .iter()
+        .map(|series| *series.value.lock().unwrap())
+        .sum()
+}
+
+/// The path a request is for, which labels its metrics, each request is a
+/// byte.
+#[derive(Debug, Clone, Copy)]
+struct ByPath;
+
+impl RequestInfo<&'static str> for ByPath {
+    fn bytes(&self, _: &&'static str) -> u64 {
+        1
+    }
+
+    fn label(&self, request: &&'static str) -> Option<Cow<'static, str>> {
+        Some(Cow::Borrowed(request))
+    }
+}
+
+#[tokio::test(start_paused = true)]
+async fn shed_requests_are_counted_by_label() {
+    let test = "shed_requests_are_counted_by_label";
+    let service = layer(test)
+        .request_info(ByPath)
+        .byte_budget(1)
+        .layer(tower::service_fn(|path: &'static str| async move {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+            Ok::<_, Infallible>(path)
+        }));
+    // The first request uses the whole byte budget until it completes.
+    let first = tokio::spawn(service.clone().oneshot("/a"));
+    tokio::time::sleep(Duration::from_millis(1)).await;
+    for path in ["/a", "/b", "/b"] {
+        service.clone().oneshot(path).await.unwrap();
+    }
+    first.await.unwrap().unwrap();
+    let count = |status, path| {
+        counter(
+            test,
+            "loadshedder.request",
+            &[("status", status), ("label", path)],
+        )
+    };
+    assert_eq!(count("accepted", "/a"), 1.0);
+    assert_eq!(count("rejected", "/a"), 1.0);
+    assert_eq!(count("rejected", "/b"), 2.0);
+    assert_eq!(count("accepted", "/b"), 0.0);
+}
+
+/// The value of the gauge called `name` from `test` with `labels`.
+fn gauge(test: &str, name: &str, labels: &[(&str, &str)]) -> f64 {
+    counter(test, name, labels)
+}
+
+#[tokio::test(start_paused = true)]
+async fn throughput_is_a_gauge() {
+    let test = "throughput_is_a_gauge";
+    let service = layer(test).layer(tower::service_fn(|()| async {
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        Ok::<_, Infallible>(())
+    }));
+    for _ in 0..100 {
+        service.clone().oneshot(()).await.unwrap();
+    }
+    let throughput = gauge(test, "loadshedder.throughput", &[]);
+    // One request of 10ms at a time can never get more than 100 a second.
+    assert!(throughput > 0.0 && throughput <= 100.0, "{throughput}");
+}
+
+#[tokio::test(start_paused = true)]
+async fn the_concurrency_does_not_drift_under_load() {
+    let test = "the_concurrency_does_not_drift_under_load";
+    let inner = common::downstream(Arc::new(Semaphore::new(4)), Duration::from_millis(10));
+    let service = layer(test).layer(inner);
+    common::drive(service.clone(), 100, Duration::from_secs(2), || ()).await;
+    assert!(service.concurrency() > 1, "{:?}", service.stats());
+    assert_eq!(counter(test, "loadshedder.concurrency_drift", &[]), 0.0);
+}
+
+#[tokio::test(start_paused = true)]
+async fn adjustments_are_counted_by_direction() {
+    let test = "adjustments_are_counted_by_direction";
+    let service = layer(test).layer(common::downstream(
+        Arc::new(Semaphore::new(4)),
+        Duration::from_millis(10),
+    ));
+    common::drive(service.clone(), 100, Duration::from_secs(5), || ()).await;
+    let stats = service.stats();
+    assert!(stats.increases > 0 && stats.decreases > 0, "{stats:?}");
+    let adjustments =
+        |direction| counter(test, "loadshedder.adjustment", &[("direction", direction)]);
+    assert_eq!(adjustments("up"), stats.increases as f64);
+    assert_eq!(adjustments("down"), stats.decreases as f64);
+}
+
+#[tokio::test(start_paused = true)]
+async fn success_and_failure_latencies_are_gauges() {
+    let test = "success_and_failure_latencies_are_gauges";
+    let service = layer(test)
+        .classifier(|failed: &bool| {
+            if *failed {
+                Outcome::Failure
+            } else {
+                Outcome::Success
+            }
+        })
+        .layer(tower::service_fn(|failed: bool| async move {
+            let latency = if failed { 10 } else { 50 };
+            tokio::time::sleep(Duration::from_millis(latency)).await;
+            Ok::<_, Infallible>(failed)
+        }));
+    for request in 0..200 {
+        service.clone().oneshot(request % 2 == 0).await.unwrap();
+    }
+    let latency = |outcome| gauge(test, "loadshedder.outcome_latency", &[("outcome", outcome)]);
+    assert!(
+        (latency("success") - 0.05).abs() < 0.001,
+        "{}",
+        latency("success")
+    );
+    assert!(
+        (latency("failure") - 0.01).abs() < 0.001,
+        "{}",
+        latency("failure")
+    );
+}
+
+#[tokio::test(start_paused = true)]
+async fn the_reporter_emits_on_its_interval() {
+    let test = "the_reporter_emits_on_its_interval";
+    let service = layer(test)
+        .request_info(ByPath)
+        .byte_budget(1)
+        .layer(tower::service_fn(|path: &'static str| async move {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+            Ok::<_, Infallible>(path)
+        }));
+    let reporter = service.spawn_reporter(Duration::from_secs(1));
+    // Ticks at 0s, 1s, 2s and 3s.
+    tokio::time::sleep(Duration::from_millis(3500)).await;
+    let shed_fraction = || series(test, "loadshedder.shed_fraction", &[])[0].clone();
+    assert_eq!(*shed_fraction().sets.lock().unwrap(), 4);
+    assert_eq!(gauge(test, "loadshedder.shed_fraction", &[]), 0.0);
+
+    // The first request uses the whole byte budget, so the rest are shed.
+    let first = tokio::spawn(service.clone().oneshot("/a"));
+    tokio::time::sleep(Duration::from_millis(1)).await;
+    for _ in 0..3 {
+        service.clone().oneshot("/a").await.unwrap();
+    }
+    first.await.unwrap().unwrap();
+    tokio::time::sleep(Duration::from_secs(1)).await;
+    assert_eq!(*shed_fraction().sets.lock().unwrap(), 5);
+    assert_eq!(gauge(test, "loadshedder.shed_fraction", &[]), 0.75);
+
+    // The reporter stops once the service is dropped.
+    drop(service);
+    reporter.await.unwrap();
+}
+
+/// The samples recorded in the histograms called `name` from `test`.
+fn samples(test: &str, name: &str) -> Vec<f64> {
+    series(test, name, &[])
+        .iter()
+        .flat_map(|series| series.samples.lock().unwrap().clone())
+        .collect()
+}
+
+// Real time, as the overhead is the time spent running the admission code.
+#[tokio::test]
+async fn admission_overhead_is_small_without_contention() {
+    let test = "admission_overhead_is_small_without_contention";
+    let service = layer(test).layer(tower::service_fn(|()| async { Ok::<_, Infallible>(()) }));
+    for _ in 0..10 {
+        service.clone().oneshot(()).await.unwrap();
+    }
+    let overheads = samples(test, "loadshedder.admission_overhead");
+    assert_eq!(overheads.len(), 10);
+    assert!(
+        overheads.iter().all(|&overhead| overhead < 0.01),
+        "{overheads:?}"
+    );
+}
+
+#[tokio::test(start_paused = true)]
+async fn admission_overhead_includes_shed_requests() {
+    let test = "admission_overhead_includes_shed_requests";
+    let service = layer(test)
+        .rate_limit(1.0, 1)
+        .layer(tower::service_fn(|()| async { Ok::<_, Infallible>(()) }));
+    for _ in 0..10 {
+        service.clone().oneshot(()).await.unwrap();
+    }
+    service.force_shed(true);
+    service.clone().oneshot(()).await.unwrap();
+    // One admitted, nine rate limited and one shed outright.
+    assert_eq!(samples(test, "loadshedder.admission_overhead").len(), 11);
+}
+
+#[tokio::test(start_paused = true)]
+async fn target_errors_are_signed_relative_to_the_target() {
+    let test = "target_errors_are_signed_relative_to_the_target";
+    let service = layer(test).layer(common::sleeper());
+    for latency in [50, 150] {
+        service
+            .clone()
+            .oneshot(Duration::from_millis(latency))
+            .await
+            .unwrap();
+    }
+    let errors = samples(test, "loadshedder.target_error");
+    assert_eq!(errors.len(), 2, "{errors:?}");
+    assert!((errors[0] + 0.5).abs() < 0.01, "{errors:?}");
+    assert!((errors[1] - 0.5).abs() < 0.01, "{errors:?}");
+}
+
+#[tokio::test(start_paused = true)]
+async fn time_at_capacity_is_observable() {
+    let test = "time_at_capacity_is_observable";
+    let service = layer(test).max_concurrency(1).layer(common::sleeper());
+    let at_capacity = || gauge(test, "loadshedder.at_capacity", &[]);
+    let transitions = || counter(test, "loadshedder.at_capacity_transitions", &[]);
+    assert_eq!(at_capacity(), 0.0);
+    for round in 1..=2 {
+        let request = tokio::spawn(service.clone().oneshot(TARGET));
+        tokio::task::yield_now().await;
+        assert_eq!(at_capacity(), 1.0);
+        assert_eq!(transitions(), f64::from(round));
+        request.await.unwrap().unwrap();
+        assert_eq!(at_capacity(), 0.0);
+        assert_eq!(transitions(), f64::from(round));
+    }
+}
+
+/// Requests that are as many bytes as they say.
+#[derive(Debug, Clone, Copy)]
+struct BySize;
+
+impl RequestInfo<u64> for BySize {
+    fn bytes(&self, request: &u64) -> u64 {
+        *request
+    }
+}
+
+#[tokio::test(start_paused = true)]
+async fn request_sizes_are_recorded_as_admitted() {
+    let test = "request_sizes_are_recorded_as_admitted";
+    let service = layer(test)
+        .request_info(BySize)
+        .byte_budget(500)
+        .layer(tower::service_fn(|size: u64| async move {
+            Ok::<_, Infallible>(size)
+        }));
+    let sizes = [100, 200, 300, 400];
+    for size in sizes {
+        service.clone().oneshot(size).await.unwrap();
+    }
+    // Shed requests aren't recorded, this one is over the byte budget.
+    service.clone().oneshot(1000).await.unwrap();
+
+    let recorded = samples(test, "loadshedder.request_bytes");
+    assert_eq!(recorded, sizes.map(|size| size as f64));
+    let average = service.stats().average_request_bytes;
+    assert!(average > 0 && average < 400, "{average}");
+}
+
+/// Requests that cost as many units as they say.
+#[derive(Debug, Clone, Copy)]
+struct ByCost;
+
+impl RequestInfo<f64> for ByCost {
+    fn cost(&self, request: &f64) -> Option<f64> {
+        Some(*request)
+    }
+}
+
+#[tokio::test(start_paused = true)]
+async fn request_costs_are_recorded_in_units() {
+    let test = "request_costs_are_recorded_in_units";
+    let service = layer(test)
+        .cost_budget(10.0, 100)
+        .request_info(ByCost)
+        .layer(tower::service_fn(|cost: f64| async move {
+            Ok::<_, Infallible>(cost)
+        }));
+    let costs = [0.25, 0.5, 1.0, 2.0];
+    for cost in costs {
+        service.clone().oneshot(cost).await.unwrap();
+    }
+
+    assert_eq!(samples(test, "loadshedder.request_cost"), costs);
+    assert!(samples(test, "loadshedder.request_bytes").is_empty());
+    let stats = service.stats();
+    assert!(
+        stats.average_request_cost > 0.0 && stats.average_request_cost < 2.0,
+        "{stats:?}"
+    );
+    assert_eq!(stats.average_request_bytes, 0);
+}
+
+#[tokio::test(start_paused = true)]
+async fn custom_labels_are_on_every_series() {
+    let test = "custom_labels_are_on_every_series";
+    capture();
+    let service = LoadShedLayer::new(0.1, TARGET)
+        .metric_labels(vec![
+            ("test", test.into()),
+            ("service", "api".into()),
+            ("region", "eu-west".into()),
+        ])
+        .layer(common::sleeper());
+    common::drive(service.clone(), 10, Duration::from_secs(1), || TARGET / 10).await;
+    let custom = [("service", "api"), ("region", "eu-west")];
+    for name in [
+        "loadshedder.request",
+        "loadshedder.average_latency",
+        "loadshedder.capacity",
+        "loadshedder.target_error",
+        "loadshedder.adjustment",
+    ] {
+        assert!(!series(test, name, &[]).is_empty(), "{name} wasn't emitted");
+        assert_eq!(
+            series(test, name, &custom).len(),
+            series(test, name, &[]).len(),
+            "{name} is missing the custom labels"
+        );
+    }
+    // Labels the load shedder adds itself are kept alongside them.
+    let accepted = counter(
+        test,
+        "loadshedder.request",
+        &[("status", "accepted"), ("region", "eu-west")],
+    );
+    assert!(accepted > 0.0);
+}
+
+#[tokio::test(start_paused = true)]
+async fn an_external_cap_is_reported_as_a_constraint() {
+    let test = "an_external_cap_is_reported_as_a_constraint";
+    let service = layer(test)
+        .global_concurrency(Arc::new(Semaphore::new(3)))
+        .layer(common::sleeper());
+    common::drive(service.clone(), 20, Duration::from_secs(5), || {
+        Duration::from_millis(10)
+    })
+    .await;
+    let stats = service.stats();
+    assert!(stats.effective_concurrency <= 3, "{stats:?}");
+    assert!(
+        stats.desired_concurrency > stats.effective_concurrency + 5,
+        "{stats:?}"
+    );
+    // Requests waiting for a global permit are counted as holding one while
+    // under load, so the gap reported then is smaller.
+    let constrained = gauge(test, "loadshedder.concurrency_constrained", &[]);
+    assert!(constrained >= 1.0, "{constrained} {stats:?}");
+}
+
+#[tokio::test(start_paused = true)]
+async fn probes_are_counted_separately() {
+    let test = "probes_are_counted_separately";
+    let service = layer(test).layer(common::sleeper());
+    service.clone().oneshot(TARGET).await.unwrap();
+    let LoadShedResponse::Inner(probe) = service.admit_probe() else {
+        panic!("the probe was shed");
+    };
+    probe.complete(Outcome::Success);
+    let count = |status| counter(test, "loadshedder.request", &[("status", status)]);
+    assert_eq!(count("probe"), 1.0);
+    assert_eq!(count("accepted"), 1.0);
+    assert_eq!(samples(test, "loadshedder.probe_latency").len(), 1);
+}
+
+#[tokio::test(start_paused = true)]
+async fn the_queue_ratio_is_a_histogram() {
+    let test = "the_queue_ratio_is_a_histogram";
+    let service = layer(test).max_concurrency(1).layer(common::sleeper());
+    // The second request waits as long as it's served.
+    let requests = (0..2).map(|_| service.clone().oneshot(TARGET / 2));
+    futures::future::join_all(requests).await;
+    let ratios = samples(test, "loadshedder.queue_ratio");
+    assert_eq!(ratios.len(), 2, "{ratios:?}");
+    assert!(ratios[0] < 0.01, "{ratios:?}");
+    assert!((ratios[1] - 0.5).abs() < 0.01, "{ratios:?}");
+}
+
+#[tokio::test(start_paused = true)]
+async fn the_arrival_rate_gauge_is_finite_for_simultaneous_arrivals() {
+    let test = "the_arrival_rate_gauge_is_finite_for_simultaneous_arrivals";
+    let service = layer(test).layer(common::sleeper());
+    let arrive = || service.clone().oneshot(Duration::from_millis(10));
+    let (first, second) = tokio::join!(arrive(), arrive());
+    first.unwrap();
+    second.unwrap();
+    let rate = gauge(test, "loadshedder.arrival_rate", &[]);
+    assert!(rate.is_finite(), "{rate}");
+}
@@ -0,0 +1,149 @@
+//! Returning responses in the order their requests arrived.
+
+mod common;
+
+use std::{
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use futures::future::join_all;
+use little_loadshedder::{LoadShedLayer, LoadShedResponse, RequestInfo};
+use tower::{Layer, Service, ServiceExt};
+
+const TARGET: Duration = Duration::from_millis(100);
+
+fn millis(latencies: [u64; 4]) -> [Duration; 4] {
+    latencies.map(Duration::from_millis)
+}
+
+#[tokio::test(start_paused = true)]
+async fn out_of_order_completions_are_returned_in_order() {
+    let mut service = LoadShedLayer::new(0.1, TARGET)
+        .min_concurrency(4)
+        .layer(common::sleeper())
+        .ordered();
+    let returned = Arc::new(Mutex::new(Vec::new()));
+    let requests = millis([50, 10, 30, 20]).map(|latency| {
+        let response = service.call(latency);
+        let returned = returned.clone();
+        tokio::spawn(async move {
+            match response.await.unwrap() {
+                LoadShedResponse::Inner(latency) => returned.lock().unwrap().push(latency),
+                shed => panic!("{shed:?}"),
+            }
+        })
+    });
+    for request in requests {
+        request.await.unwrap();
+    }
+    // The inner service finished them in order of latency, but they're
+    // returned in the order they were called.
+    assert_eq!(*returned.lock().unwrap(), millis([50, 10, 30, 20]));
+    // Every request held its permit only while it was in the inner service.
+    assert_eq!(service.load_shed().stats().in_flight, 0);
+}
+
+#[tokio::test(start_paused = true)]
+async fn one_at_a_time_doesnt_deadlock_when_polled_out_of_order() {
+    let mut service = LoadShedLayer::new(0.1, TARGET)
+        .max_concurrency(1)
+        .min_queue(4)
+        .layer(common::sleeper())
+        .ordered();
+    let mut responses: Vec<_> = millis([10, 20, 30, 40])
+        .map(|latency| service.call(latency))
+        .into();
+    // The last request is polled first, so it gets the only permit, but it
+    // mustn't keep it while it waits for the others' turn.
+    responses.reverse();
+    let responses = tokio::time::timeout(Duration::from_secs(1), join_all(responses))
+        .await
+        .expect("the responses to all be returned");
+    assert!(responses
+        .iter()
+        .all(|response| matches!(response, Ok(LoadShedResponse::Inner(_)))));
+}
+
+#[tokio::test(start_paused = true)]
+async fn responses_waiting_their_turn_are_limited_to_the_queue() {
+    let service = LoadShedLayer::new(0.1, TARGET)
+        .min_concurrency(10)
+        .max_queue(2)
+        .layer(common::sleeper())
+        .ordered();
+    // The first response is slow, so the fast ones after it wait their turn.
+    let slow = tokio::spawn(service.clone().oneshot(TARGET * 10));
+    let mut fast = Vec::new();
+    for _ in 0..2 {
+        fast.push(tokio::spawn(service.clone().oneshot(TARGET / 10)));
+    }
+    tokio::time::sleep(TARGET).await;
+    let response = service.clone().oneshot(TARGET / 10).await.unwrap();
+    assert!(matches!(response, LoadShedResponse::Overload));
+
+    assert!(matches!(
+        slow.await.unwrap().unwrap(),
+        LoadShedResponse::Inner(_)
+    ));
+    for fast in fast {
+        assert!(matches!(
+            fast.await.unwrap().unwrap(),
+            LoadShedResponse::Inner(_)
+        ));
+    }
+}
+
+/// Requests taking exactly `HEALTH_CHECK` are health checks.
+#[derive(Debug, Clone, Copy)]
+struct HealthChecks;
+
+const HEALTH_CHECK: Duration = Duration::from_millis(7);
+
+impl RequestInfo<Duration> for HealthChecks {
+    fn is_health_check(&self, latency: &Duration) -> bool {
+        *latency == HEALTH_CHECK
+    }
+}
+
+#[tokio::test(start_paused = true)]
+async fn requests_shed_while_responses_wait_still_arrive() {
+    let mut service = LoadShedLayer::new(0.1, TARGET)
+        .min_concurrency(10)
+        .max_queue(2)
+        .request_info(HealthChecks)
+        .layer(common::sleeper())
+        .ordered();
+    let slow = tokio::spawn(service.clone().oneshot(TARGET * 10));
+    let fast: Vec<_> = (0..2)
+        .map(|_| tokio::spawn(service.clone().oneshot(TARGET / 10)))
+        .collect();
+    tokio::time::sleep(TARGET).await;
+    let before = service.load_shed().arrival_rate();
+
+    // Shed because the responses waiting their turn are at the limit, but
+    // they still count as arrivals. Their responses wait their turn too.
+    let mut shed = Vec::new();
+    for _ in 0..10 {
+        shed.push(service.call(TARGET / 10));
+        tokio::time::sleep(Duration::from_millis(1)).await;
+    }
+    let after = service.load_shed().arrival_rate();
+    assert!(after > before * 10.0, "{after} vs {before}");
+
+    // Health checks are always admitted, and don't arrive.
+    let health_check = service.call(HEALTH_CHECK);
+    tokio::time::sleep(Duration::from_millis(10)).await;
+    assert!(service.load_shed().arrival_rate() < after);
+    slow.await.unwrap().unwrap();
+    for fast in fast {
+        fast.await.unwrap().unwrap();
+    }
+    for shed in join_all(shed).await {
+        assert!(matches!(shed, Ok(LoadShedResponse::Overload)));
+    }
+    assert!(matches!(
+        health_check.await,
+        Ok(LoadShedResponse::Inner(HEALTH_CHECK))
+    ));
+}
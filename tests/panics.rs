@@ -0,0 +1,53 @@
+//! Catching panics from the inner service.
+
+use std::{convert::Infallible, time::Duration};
+
+use little_loadshedder::{LoadShed, LoadShedLayer, LoadShedResponse};
+use tower::{util::BoxCloneService, Layer, ServiceExt};
+
+const TARGET: Duration = Duration::from_millis(100);
+
+/// A service that panics part way through requests for `true`.
+fn panicker() -> BoxCloneService<bool, bool, Infallible> {
+    BoxCloneService::new(tower::service_fn(|panic: bool| async move {
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        assert!(!panic, "the inner service panicked");
+        Ok(panic)
+    }))
+}
+
+#[tokio::test(start_paused = true)]
+async fn caught_panics_release_the_permit_and_count_as_failures() {
+    let service = LoadShedLayer::new(0.1, TARGET)
+        .catch_panics(true)
+        .layer(panicker());
+    let response = service.clone().oneshot(true).await.unwrap();
+    assert!(matches!(response, LoadShedResponse::Panicked));
+    let stats = service.stats();
+    assert_eq!(stats.in_flight, 0, "{stats:?}");
+    assert!(stats.failure_latency < TARGET, "{stats:?}");
+    assert_eq!(stats.success_latency, TARGET, "{stats:?}");
+
+    // The load shedder carries on as normal.
+    let response = service.clone().oneshot(false).await.unwrap();
+    assert!(matches!(response, LoadShedResponse::Inner(false)));
+}
+
+#[tokio::test(start_paused = true)]
+async fn panics_propagate_unless_caught() {
+    let service = LoadShed::new(panicker(), 0.1, TARGET);
+    let result = tokio::spawn(service.clone().oneshot(true)).await;
+    assert!(result.unwrap_err().is_panic());
+}
+
+#[tokio::test(start_paused = true)]
+async fn a_fallback_resumes_caught_panics_after_releasing_the_permit() {
+    let fallback = tower::service_fn(|_: bool| async { Ok::<_, Infallible>(false) });
+    let service = LoadShedLayer::new(0.1, TARGET)
+        .catch_panics(true)
+        .layer(panicker())
+        .with_fallback_service(fallback);
+    let result = tokio::spawn(service.clone().oneshot(true)).await;
+    assert!(result.unwrap_err().is_panic());
+    assert_eq!(service.load_shed().stats().in_flight, 0);
+}
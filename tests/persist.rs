@@ -0,0 +1,59 @@
+//! Keeping what's been learned across restarts.
+
+mod common;
+
+use std::{
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use little_loadshedder::{LoadShedLayer, PersistedState, StatePersistence};
+use tower::Layer;
+
+const TARGET: Duration = Duration::from_millis(100);
+const FAST: Duration = Duration::from_millis(10);
+
+/// Keeps the state in memory, shared between clones.
+#[derive(Debug, Clone, Default)]
+struct InMemory(Arc<Mutex<Option<PersistedState>>>);
+
+impl StatePersistence for InMemory {
+    fn load(&self) -> Option<PersistedState> {
+        *self.0.lock().unwrap()
+    }
+
+    fn save(&self, state: &PersistedState) {
+        *self.0.lock().unwrap() = Some(*state);
+    }
+}
+
+fn layer(store: &InMemory) -> LoadShedLayer {
+    LoadShedLayer::new(0.1, TARGET).persist_state(store.clone(), Duration::from_secs(1))
+}
+
+#[tokio::test(start_paused = true)]
+async fn the_learned_state_survives_a_restart() {
+    let store = InMemory::default();
+    let service = layer(&store).layer(common::sleeper());
+    common::drive(service.clone(), 20, Duration::from_secs(5), || FAST).await;
+    // Let the next save happen once the load has finished.
+    tokio::time::sleep(Duration::from_secs(1)).await;
+    let saved = store.load().expect("the state to have been saved");
+    assert_eq!(saved, service.export_state());
+    assert!(saved.concurrency > 1, "{saved:?}");
+    drop(service);
+
+    let restarted = layer(&store).layer(common::sleeper());
+    let stats = restarted.stats();
+    assert_eq!(stats.concurrency, saved.concurrency, "{stats:?}");
+    assert_eq!(stats.average_latency, saved.average_latency, "{stats:?}");
+    assert_eq!(restarted.export_state(), saved);
+}
+
+#[tokio::test(start_paused = true)]
+async fn nothing_is_saved_without_requests() {
+    let store = InMemory::default();
+    let _service = layer(&store).layer(common::sleeper());
+    tokio::time::sleep(Duration::from_secs(5)).await;
+    assert_eq!(store.load(), None);
+}
@@ -0,0 +1,269 @@
+//! The order queued requests get through in, and how long the queue is.
+
+mod common;
+
+use std::{
+    convert::Infallible,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use little_loadshedder::{
+    EstimatedWait, LifoQueue, LoadShedLayer, LoadShedResponse, QueueSlotPolicy,
+};
+use tokio::sync::Semaphore;
+use tower::{Layer, ServiceExt};
+
+const TARGET: Duration = Duration::from_millis(100);
+const FAST: Duration = Duration::from_millis(10);
+
+/// Send four numbered requests, each after the previous one has been queued,
+/// through a load shedder with a concurrency of one, and return the order
+/// they reached the inner service in.
+async fn entry_order(layer: LoadShedLayer) -> Vec<u32> {
+    let entered = Arc::new(Mutex::new(Vec::new()));
+    let inner = tower::service_fn({
+        let entered = entered.clone();
+        move |request: u32| {
+            entered.lock().unwrap().push(request);
+            async move {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+                Ok::<_, Infallible>(request)
+            }
+        }
+    });
+    let service = layer.max_concurrency(1).min_queue(10).layer(inner);
+    let mut requests = Vec::new();
+    for request in 0..4 {
+        requests.push(tokio::spawn(service.clone().oneshot(request)));
+        tokio::time::sleep(Duration::from_millis(1)).await;
+    }
+    for (request, response) in (0..).zip(requests) {
+        assert_eq!(
+            response.await.unwrap().unwrap(),
+            LoadShedResponse::Inner(request)
+        );
+    }
+    let entered = entered.lock().unwrap().clone();
+    entered
+}
+
+#[tokio::test(start_paused = true)]
+async fn requests_are_first_in_first_out_by_default() {
+    let order = entry_order(LoadShedLayer::new(0.1, TARGET)).await;
+    assert_eq!(order, [0, 1, 2, 3]);
+}
+
+#[tokio::test(start_paused = true)]
+async fn a_lifo_queue_serves_the_newest_request_first() {
+    let order = entry_order(LoadShedLayer::new(0.1, TARGET).queue(LifoQueue::default)).await;
+    assert_eq!(order, [0, 3, 2, 1]);
+}
+
+#[tokio::test(start_paused = true)]
+async fn strict_fifo_enters_in_arrival_order() {
+    let order = entry_order(LoadShedLayer::new(0.1, TARGET).strict_fifo(true)).await;
+    assert_eq!(order, [0, 1, 2, 3]);
+}
+
+/// Send thirty numbered requests all at once, taking slightly different
+/// times to respond, with strict FIFO ordering and a long queue, and return
+/// the order they reached the inner service in.
+async fn sent_together_order(layer: LoadShedLayer) -> Vec<u32> {
+    let entered = Arc::new(Mutex::new(Vec::new()));
+    let inner = tower::service_fn({
+        let entered = entered.clone();
+        move |request: u32| {
+            entered.lock().unwrap().push(request);
+            async move {
+                tokio::time::sleep(Duration::from_millis(u64::from(10 + request % 3))).await;
+                Ok::<_, Infallible>(request)
+            }
+        }
+    });
+    let service = layer.strict_fifo(true).min_queue(50).layer(inner);
+    let requests: Vec<_> = (0..30)
+        .map(|request| tokio::spawn(service.clone().oneshot(request)))
+        .collect();
+    for response in requests {
+        assert!(matches!(
+            response.await.unwrap().unwrap(),
+            LoadShedResponse::Inner(_)
+        ));
+    }
+    let entered = entered.lock().unwrap().clone();
+    entered
+}
+
+#[tokio::test(start_paused = true)]
+async fn strict_fifo_holds_for_requests_sent_together() {
+    let order = sent_together_order(LoadShedLayer::new(0.1, TARGET).max_concurrency(1)).await;
+    assert_eq!(order, (0..30).collect::<Vec<_>>());
+}
+
+#[tokio::test(start_paused = true)]
+async fn strict_fifo_holds_with_several_permits() {
+    let order = sent_together_order(
+        LoadShedLayer::new(0.1, TARGET)
+            .min_concurrency(3)
+            .max_concurrency(3),
+    )
+    .await;
+    assert_eq!(order, (0..30).collect::<Vec<_>>());
+}
+
+#[tokio::test(start_paused = true)]
+async fn the_queue_shrinks_to_cap_tail_amplification() {
+    let layer = || LoadShedLayer::new(0.1, TARGET).max_concurrency(2);
+    let uncapped = layer().layer(common::sleeper());
+    let capped = layer().max_tail_amplification(2.0).layer(common::sleeper());
+    tokio::join!(
+        common::drive(uncapped.clone(), 50, Duration::from_secs(5), || FAST),
+        common::drive(capped.clone(), 50, Duration::from_secs(5), || FAST),
+    );
+    let (uncapped, capped) = (uncapped.stats(), capped.stats());
+    assert!(
+        capped.queue_capacity * 4 <= uncapped.queue_capacity,
+        "{capped:?} vs {uncapped:?}"
+    );
+}
+
+#[tokio::test(start_paused = true)]
+async fn a_huge_queue_is_capped_rather_than_panicking() {
+    for min_queue in [usize::MAX, Semaphore::MAX_PERMITS - 1] {
+        let service = LoadShedLayer::new(0.1, TARGET)
+            .min_queue(min_queue)
+            .layer(common::sleeper());
+        common::drive(service.clone(), 10, Duration::from_secs(1), || FAST).await;
+        let stats = service.stats();
+        assert!(stats.queue_capacity <= Semaphore::MAX_PERMITS, "{stats:?}");
+        assert!(
+            stats.queue_capacity >= Semaphore::MAX_PERMITS - 1,
+            "{stats:?}"
+        );
+    }
+}
+
+#[tokio::test(start_paused = true)]
+async fn capped_queue_growth_is_gradual() {
+    let service = LoadShedLayer::new(0.1, TARGET)
+        .max_queue_growth(2, Duration::from_millis(100))
+        .layer(common::sleeper());
+    let load = tokio::spawn(common::drive(
+        service.clone(),
+        50,
+        Duration::from_secs(3),
+        || FAST,
+    ));
+    let mut capacities = vec![service.stats().queue_capacity];
+    for _ in 0..30 {
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        capacities.push(service.stats().queue_capacity);
+    }
+    load.await.unwrap();
+    assert!(
+        capacities.windows(2).all(|pair| pair[1] <= pair[0] + 2),
+        "{capacities:?}"
+    );
+    // It still gets there in the end.
+    assert!(capacities.last().unwrap() > &20, "{capacities:?}");
+}
+
+#[tokio::test(start_paused = true)]
+async fn persistently_fast_responses_shrink_the_queue() {
+    let layer = || {
+        LoadShedLayer::new(0.1, TARGET)
+            .max_concurrency(2)
+            .min_queue(2)
+    };
+    let unshrunk = layer().layer(common::sleeper());
+    let shrunk = layer()
+        .fast_response_shrink(0.5, Duration::from_secs(1))
+        .layer(common::sleeper());
+    tokio::join!(
+        common::drive(unshrunk.clone(), 50, Duration::from_secs(3), || FAST),
+        common::drive(shrunk.clone(), 50, Duration::from_secs(3), || FAST),
+    );
+    let (unshrunk, shrunk) = (unshrunk.stats(), shrunk.stats());
+    assert!(unshrunk.queue_capacity > 10, "{unshrunk:?}");
+    assert_eq!(shrunk.queue_capacity, 2, "{shrunk:?}");
+}
+
+#[tokio::test(start_paused = true)]
+async fn a_seeded_latency_at_capacity_sizes_the_queue_straight_away() {
+    let service = LoadShedLayer::new(0.1, TARGET).layer(common::sleeper());
+    assert_eq!(service.average_latency_at_capacity(), TARGET);
+    // Zero would make the queue endless, so it's ignored.
+    service.seed_average_latency_at_capacity(Duration::ZERO);
+    assert_eq!(service.average_latency_at_capacity(), TARGET);
+
+    service.seed_average_latency_at_capacity(FAST);
+    assert_eq!(service.average_latency_at_capacity(), FAST);
+    let request = tokio::spawn(service.clone().oneshot(TARGET));
+    tokio::task::yield_now().await;
+    // A request takes a tenth of the target, so nine can queue behind the
+    // one in the service.
+    assert_eq!(service.stats().queue_capacity, 9, "{:?}", service.stats());
+    request.await.unwrap().unwrap();
+}
+
+/// Send ten requests at once through a load shedder with room for one in the
+/// service and two in the queue, and return how many were admitted.
+async fn admitted_burst(policy: QueueSlotPolicy) -> usize {
+    let service = LoadShedLayer::new(0.1, TARGET)
+        .max_concurrency(1)
+        .min_queue(2)
+        .queue_slot_policy(policy)
+        .layer(common::sleeper());
+    let requests: Vec<_> = (0..10)
+        .map(|_| {
+            let request = tokio::spawn(service.clone().oneshot(FAST));
+            async { request.await.unwrap().unwrap() }
+        })
+        .collect();
+    futures::future::join_all(requests)
+        .await
+        .into_iter()
+        .filter(|response| matches!(response, LoadShedResponse::Inner(_)))
+        .count()
+}
+
+#[tokio::test(start_paused = true)]
+async fn holding_the_queue_slot_bounds_the_waiting_requests() {
+    assert_eq!(admitted_burst(QueueSlotPolicy::Hold).await, 3);
+    // Each queued request frees its slot for the next one straight away.
+    assert_eq!(admitted_burst(QueueSlotPolicy::ReleaseEarly).await, 10);
+}
+
+/// The estimated wait once a load shedder with room for one request in the
+/// service and ten in the queue is full.
+async fn estimated_wait_when_full(layer: LoadShedLayer) -> EstimatedWait {
+    let service = layer
+        .max_concurrency(1)
+        .min_queue(10)
+        .layer(common::sleeper());
+    let requests: Vec<_> = (0..11)
+        .map(|_| tokio::spawn(service.clone().oneshot(TARGET)))
+        .collect();
+    tokio::task::yield_now().await;
+    assert!(!service.would_admit(), "{:?}", service.stats());
+    let wait = service.estimated_wait();
+    for request in requests {
+        request.await.unwrap().unwrap();
+    }
+    wait
+}
+
+#[tokio::test(start_paused = true)]
+async fn the_estimated_wait_is_capped() {
+    // Eleven requests ahead, each taking the target.
+    let uncapped = estimated_wait_when_full(LoadShedLayer::new(0.1, TARGET)).await;
+    assert!(!uncapped.capped, "{uncapped:?}");
+    assert!(uncapped.wait > TARGET * 10, "{uncapped:?}");
+
+    let max = Duration::from_millis(500);
+    let capped =
+        estimated_wait_when_full(LoadShedLayer::new(0.1, TARGET).max_estimated_wait(max)).await;
+    assert!(capped.capped, "{capped:?}");
+    assert_eq!(capped.wait, max);
+}
@@ -0,0 +1,106 @@
+//! What happens to admitted requests while the inner service isn't ready.
+
+mod common;
+
+use std::{
+    convert::Infallible,
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use common::{SlowToReady, READY, SERVICE};
+use little_loadshedder::{LoadShedLayer, LoadShedResponse, ReadinessPolicy};
+use tokio::time::Instant;
+use tower::{Layer, Service, ServiceExt};
+
+const TARGET: Duration = Duration::from_millis(100);
+
+fn layer(policy: ReadinessPolicy) -> LoadShedLayer {
+    LoadShedLayer::new(0.1, TARGET).readiness_policy(policy)
+}
+
+#[tokio::test(start_paused = true)]
+async fn waiting_holds_the_slot_and_counts_the_wait_as_latency() {
+    let service = layer(ReadinessPolicy::Wait).layer(SlowToReady::default());
+    let start = Instant::now();
+    let request = tokio::spawn(service.clone().oneshot(()));
+    tokio::time::sleep(READY / 2).await;
+    assert_eq!(service.stats().in_flight, 1);
+    let response = request.await.unwrap().unwrap();
+    assert!(matches!(response, LoadShedResponse::Inner(())));
+    assert_eq!(start.elapsed(), READY + SERVICE);
+    let latency = service.stats().success_latency;
+    // The first sample moves the average a tenth of the way from the target.
+    let expected = TARGET.mul_f64(0.9) + (READY + SERVICE).mul_f64(0.1);
+    assert!(
+        latency.abs_diff(expected) < Duration::from_millis(1),
+        "{latency:?}"
+    );
+}
+
+#[tokio::test(start_paused = true)]
+async fn requeueing_releases_the_slot_until_the_service_is_ready() {
+    let service = layer(ReadinessPolicy::Requeue).layer(SlowToReady::default());
+    let start = Instant::now();
+    let request = tokio::spawn(service.clone().oneshot(()));
+    tokio::time::sleep(READY / 2).await;
+    assert_eq!(service.stats().in_flight, 0);
+    let response = request.await.unwrap().unwrap();
+    assert!(matches!(response, LoadShedResponse::Inner(())));
+    assert_eq!(start.elapsed(), READY + SERVICE);
+    assert_eq!(service.stats().in_flight, 0);
+}
+
+#[tokio::test(start_paused = true)]
+async fn shedding_rejects_requests_the_service_isnt_ready_for() {
+    let service = layer(ReadinessPolicy::Shed).layer(SlowToReady::default());
+    let start = Instant::now();
+    let response = service.clone().oneshot(()).await.unwrap();
+    assert!(matches!(response, LoadShedResponse::Overload));
+    assert_eq!(start.elapsed(), Duration::ZERO);
+    let stats = service.stats();
+    assert_eq!(stats.in_flight, 0, "{stats:?}");
+    // Nothing reached the inner service, so nothing was learned.
+    assert_eq!(stats.success_latency, TARGET, "{stats:?}");
+}
+
+/// A [`SlowToReady`] service that panics if it's called before it's ready.
+#[derive(Debug, Clone, Default)]
+struct Strict {
+    inner: SlowToReady,
+    ready: bool,
+}
+
+impl Service<()> for Strict {
+    type Response = ();
+    type Error = Infallible;
+    type Future = <SlowToReady as Service<()>>::Future;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Infallible>> {
+        let ready = self.inner.poll_ready(cx);
+        self.ready = ready.is_ready();
+        ready
+    }
+
+    fn call(&mut self, (): ()) -> Self::Future {
+        assert!(
+            std::mem::take(&mut self.ready),
+            "called before it was ready"
+        );
+        self.inner.call(())
+    }
+}
+
+#[tokio::test(start_paused = true)]
+async fn calls_without_poll_ready_wait_for_the_inner_service() {
+    let mut service = LoadShedLayer::new(0.1, TARGET)
+        .min_queue(3)
+        .layer(Strict::default());
+    let start = Instant::now();
+    let responses: Vec<_> = (0..3).map(|_| service.call(())).collect();
+    for response in futures::future::join_all(responses).await {
+        assert!(matches!(response, Ok(LoadShedResponse::Inner(()))));
+    }
+    // The inner service was made ready for each of them, one at a time.
+    assert_eq!(start.elapsed(), (READY + SERVICE) * 3);
+}
@@ -0,0 +1,145 @@
+//! Changing a load shedder's options while it's running.
+
+mod common;
+
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use little_loadshedder::{
+    ConfigError, Limits, LoadShedLayer, LoadShedRuntimeConfig, TargetSchedule,
+};
+use tokio::sync::watch;
+use tower::{Layer, ServiceExt};
+
+const TARGET: Duration = Duration::from_millis(100);
+const FAST: Duration = Duration::from_millis(20);
+
+#[tokio::test(start_paused = true)]
+async fn a_new_runtime_config_is_adopted_all_at_once() {
+    let (sender, receiver) = watch::channel(LoadShedRuntimeConfig::new(0.1, TARGET));
+    let service = LoadShedLayer::new(0.1, TARGET)
+        .runtime_config(receiver)
+        .layer(common::sleeper());
+    service.clone().oneshot(FAST).await.unwrap();
+    assert_eq!(service.stats().success_latency, Duration::from_millis(92));
+
+    let mut config = LoadShedRuntimeConfig::new(0.5, TARGET * 2);
+    config.max_concurrency = 3;
+    config.min_queue = 4;
+    sender.send(config).unwrap();
+    service.clone().oneshot(FAST).await.unwrap();
+    let stats = service.stats();
+    // The success latency isn't rescaled when the concurrency changes, so it
+    // shows the new moving average parameter directly.
+    assert_eq!(
+        stats.success_latency,
+        Duration::from_millis(56),
+        "{stats:?}"
+    );
+    assert_eq!(stats.max_concurrency, 3, "{stats:?}");
+    assert!(stats.queue_capacity >= 4, "{stats:?}");
+    assert!(service.status_line().contains("target=200.0ms"));
+
+    // An invalid config is ignored, keeping the last valid one.
+    sender
+        .send(LoadShedRuntimeConfig::new(2.0, TARGET))
+        .unwrap();
+    service.clone().oneshot(FAST).await.unwrap();
+    assert!(service.status_line().contains("target=200.0ms"));
+    assert_eq!(service.stats().max_concurrency, 3);
+}
+
+#[tokio::test(start_paused = true)]
+async fn the_target_follows_its_schedule() {
+    let peak = Arc::new(AtomicBool::new(false));
+    let schedule = TargetSchedule::new(TARGET)
+        .window(
+            Duration::from_secs(9 * 60 * 60),
+            Duration::from_secs(17 * 60 * 60),
+            TARGET / 2,
+        )
+        .clock({
+            let peak = peak.clone();
+            move || {
+                Duration::from_secs(if peak.load(Ordering::Relaxed) { 12 } else { 20 } * 60 * 60)
+            }
+        });
+    let service = LoadShedLayer::new(0.1, TARGET)
+        .target_schedule(schedule)
+        .layer(common::sleeper());
+    service.clone().oneshot(FAST).await.unwrap();
+    assert!(service.status_line().contains("target=100.0ms"));
+    peak.store(true, Ordering::Relaxed);
+    service.clone().oneshot(FAST).await.unwrap();
+    assert!(service.status_line().contains("target=50.0ms"));
+}
+
+#[tokio::test(start_paused = true)]
+async fn limits_are_reconfigured_all_at_once() {
+    let low = Limits {
+        min_concurrency: 1,
+        max_concurrency: 4,
+        min_queue: 1,
+        max_queue: 2,
+    };
+    let high = Limits {
+        min_concurrency: 10,
+        max_concurrency: 20,
+        min_queue: 5,
+        max_queue: 10,
+    };
+    let service = LoadShedLayer::new(0.1, TARGET).layer(common::sleeper());
+    service.reconfigure(low).unwrap();
+    common::drive(service.clone(), 10, Duration::from_secs(1), || FAST).await;
+
+    // Watch from another thread while the limits flip back and forth, every
+    // snapshot must be entirely within one set of limits or the other.
+    let done = Arc::new(AtomicBool::new(false));
+    let watcher = std::thread::spawn({
+        let (service, done) = (service.clone(), done.clone());
+        move || {
+            let mut seen = Vec::new();
+            while !done.load(Ordering::Relaxed) {
+                let stats = service.stats();
+                seen.push((stats.concurrency, stats.max_concurrency));
+            }
+            seen
+        }
+    });
+    for _ in 0..1000 {
+        for limits in [high, low] {
+            service.reconfigure(limits).unwrap();
+            service.clone().oneshot(Duration::ZERO).await.unwrap();
+        }
+    }
+    done.store(true, Ordering::Relaxed);
+    let seen = watcher.join().unwrap();
+    assert!(!seen.is_empty());
+    let within = |(concurrency, max): (usize, usize), limits: Limits| {
+        max == limits.max_concurrency
+            && (limits.min_concurrency..=limits.max_concurrency).contains(&concurrency)
+    };
+    for state in seen {
+        assert!(within(state, low) || within(state, high), "{state:?}");
+    }
+
+    // Invalid limits change nothing.
+    let invalid = Limits {
+        min_concurrency: 30,
+        ..high
+    };
+    assert_eq!(
+        service.reconfigure(invalid),
+        Err(ConfigError::Concurrency { min: 30, max: 20 })
+    );
+    let stats = service.stats();
+    assert!(
+        within((stats.concurrency, stats.max_concurrency), low),
+        "{stats:?}"
+    );
+}
@@ -0,0 +1,48 @@
+//! Serializing the statistics for remote inspection.
+#![cfg(feature = "serde")]
+
+mod common;
+
+use std::time::Duration;
+
+use little_loadshedder::LoadShed;
+
+const TARGET: Duration = Duration::from_millis(100);
+
+#[tokio::test(start_paused = true)]
+async fn the_stats_serialize_with_units_in_the_field_names() {
+    let service = LoadShed::new(common::sleeper(), 0.1, TARGET);
+    let json = serde_json::to_value(service.stats()).unwrap();
+    let fields: Vec<_> = json.as_object().unwrap().keys().cloned().collect();
+    let mut expected = [
+        "average_latency_secs",
+        "slow_average_latency_secs",
+        "average_latency_at_capacity_secs",
+        "concurrency",
+        "desired_concurrency",
+        "queue_capacity",
+        "system_size",
+        "in_flight",
+        "bytes_in_flight",
+        "increase_halted",
+        "increases",
+        "decreases",
+        "max_concurrency",
+        "effective_concurrency",
+        "success_latency_secs",
+        "failure_latency_secs",
+        "average_request_bytes",
+        "average_request_cost",
+        "at_min_concurrency",
+        "throughput_per_sec",
+        "goodput_per_sec",
+        "arrival_rate_per_sec",
+    ];
+    expected.sort_unstable();
+    assert_eq!(fields, expected);
+
+    assert_eq!(json["average_latency_secs"], 0.1);
+    assert_eq!(json["concurrency"], 1);
+    assert_eq!(json["in_flight"], 0);
+    assert_eq!(json["increase_halted"], false);
+}
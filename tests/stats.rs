@@ -0,0 +1,336 @@
+//! The statistics the load shedder exposes.
+
+mod common;
+
+use std::{convert::Infallible, time::Duration};
+
+use common::BoxSleeper;
+use little_loadshedder::{LoadShed, LoadShedLayer, LoadShedResponse, Outcome, RequestInfo};
+use tower::{util::BoxCloneService, Layer, ServiceExt};
+
+const TARGET: Duration = Duration::from_millis(100);
+const FAST: Duration = Duration::from_millis(10);
+
+#[tokio::test(start_paused = true)]
+async fn latency_breakdown_shows_queue_heavy_load() {
+    let service = LoadShedLayer::new(0.1, TARGET)
+        .max_concurrency(1)
+        .min_queue(20)
+        .layer(common::sleeper());
+    common::drive(service.clone(), 10, Duration::from_secs(2), || FAST).await;
+    let breakdown = service.latency_breakdown();
+    assert!(
+        breakdown.queue.p50 > breakdown.service.p50 * 5,
+        "{breakdown:?}"
+    );
+}
+
+#[tokio::test(start_paused = true)]
+async fn latency_breakdown_shows_service_heavy_load() {
+    let service = LoadShedLayer::new(0.1, TARGET)
+        .min_queue(20)
+        .layer(common::sleeper());
+    common::drive(service.clone(), 1, Duration::from_secs(2), || FAST * 5).await;
+    let breakdown = service.latency_breakdown();
+    assert!(
+        breakdown.queue.p50 < breakdown.service.p50 / 5,
+        "{breakdown:?}"
+    );
+    let error = breakdown.service.p50.abs_diff(FAST * 5);
+    assert!(error < FAST / 2, "{breakdown:?}");
+}
+
+/// The average latency at capacity after slow successes then fast failures,
+/// with failures left out of it or not.
+#[cfg(feature = "test-util")]
+async fn latency_at_capacity_after_failures(exclude: bool) -> Duration {
+    use std::sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    };
+
+    use little_loadshedder::{Fault, FaultInjection};
+
+    let failing = Arc::new(AtomicBool::new(false));
+    let inner = FaultInjection::new(common::sleeper(), {
+        let failing = failing.clone();
+        move |_| {
+            if failing.load(Ordering::Relaxed) {
+                Fault::Fail
+            } else {
+                Fault::Pass
+            }
+        }
+    });
+    let service = LoadShedLayer::new(0.1, TARGET)
+        .max_concurrency(2)
+        .min_queue(20)
+        .exclude_failures_at_capacity(exclude)
+        .layer(inner);
+    common::drive(service.clone(), 10, Duration::from_secs(2), || FAST * 5).await;
+    failing.store(true, Ordering::Relaxed);
+    common::drive(service.clone(), 10, Duration::from_secs(2), || FAST * 5).await;
+    service.average_latency_at_capacity()
+}
+
+#[cfg(feature = "test-util")]
+#[tokio::test(start_paused = true)]
+async fn fast_failures_drag_down_the_latency_at_capacity() {
+    let latency = latency_at_capacity_after_failures(false).await;
+    assert!(latency < FAST, "{latency:?}");
+}
+
+#[cfg(feature = "test-util")]
+#[tokio::test(start_paused = true)]
+async fn excluded_failures_leave_the_latency_at_capacity() {
+    let latency = latency_at_capacity_after_failures(true).await;
+    assert!(latency > FAST * 4, "{latency:?}");
+}
+
+#[tokio::test(start_paused = true)]
+async fn system_size_is_concurrency_plus_queue() {
+    let service = LoadShedLayer::new(0.1, TARGET)
+        .min_queue(3)
+        .layer(common::sleeper());
+    assert_eq!(service.system_size(), 1 + 3);
+    common::drive(service.clone(), 20, Duration::from_secs(2), || FAST).await;
+    let stats = service.stats();
+    assert!(stats.concurrency > 1, "{stats:?}");
+    assert_eq!(stats.system_size, stats.concurrency + stats.queue_capacity);
+    // The old name for the system size, not the queue alone.
+    #[allow(deprecated)]
+    let queue_capacity = service.queue_capacity();
+    assert_eq!(queue_capacity, service.system_size());
+}
+
+#[tokio::test(start_paused = true)]
+async fn headroom_drops_as_load_rises() {
+    let service = LoadShedLayer::new(0.1, TARGET)
+        .max_concurrency(1)
+        .min_queue(3)
+        .layer(common::sleeper());
+    assert_eq!(service.headroom(), 1.0);
+    let mut requests = Vec::new();
+    for expected in [0.75, 0.5, 0.25, 0.0] {
+        requests.push(tokio::spawn(service.clone().oneshot(TARGET)));
+        tokio::task::yield_now().await;
+        assert_eq!(service.headroom(), expected, "{:?}", service.stats());
+    }
+    // Saturated, so the next request is shed.
+    assert!(!service.would_admit());
+    for request in requests {
+        request.await.unwrap().unwrap();
+    }
+    assert_eq!(service.headroom(), 1.0);
+}
+
+#[tokio::test(start_paused = true)]
+async fn a_stats_view_matches_the_snapshot() {
+    let service = LoadShedLayer::new(0.1, TARGET).layer(common::sleeper());
+    common::drive(service.clone(), 20, Duration::from_secs(2), || FAST).await;
+    let stats = service.stats();
+    assert!(stats.increases > 0, "{stats:?}");
+    service.with_stats(|view| {
+        assert_eq!(view.average_latency(), stats.average_latency);
+        assert_eq!(view.concurrency(), stats.concurrency);
+        assert_eq!(view.queue_capacity(), stats.queue_capacity);
+        assert_eq!(view.system_size(), stats.system_size);
+        assert_eq!(view.increases(), stats.increases);
+        assert_eq!(view.decreases(), stats.decreases);
+        assert_eq!(view.success_latency(), stats.success_latency);
+    });
+    let concurrency = service.with_stats(|view| view.concurrency());
+    assert_eq!(concurrency, service.concurrency());
+}
+
+#[tokio::test(start_paused = true)]
+async fn the_status_line_summarises_the_state() {
+    let service = LoadShedLayer::new(0.1, TARGET)
+        .min_queue(3)
+        .layer(common::sleeper());
+    assert_eq!(
+        service.status_line(),
+        "concurrency=1 queue=3 in_flight=0 avg=100.0ms target=100.0ms shedding=false phase=steady"
+    );
+
+    // Overload the service at the minimum concurrency.
+    let load = tokio::spawn(common::drive(
+        service.clone(),
+        10,
+        Duration::from_secs(2),
+        || TARGET * 2,
+    ));
+    tokio::time::sleep(Duration::from_secs(1)).await;
+    let status = service.status_line();
+    for field in [
+        "concurrency=1 ",
+        "in_flight=4 ",
+        "target=100.0ms ",
+        "shedding=true ",
+        "phase=at_min",
+    ] {
+        assert!(status.contains(field), "{status}");
+    }
+    load.await.unwrap();
+}
+
+#[tokio::test(start_paused = true)]
+async fn the_status_line_shows_shedding_for_every_reason() {
+    let shedding = |service: &LoadShed<BoxSleeper>| service.status_line().contains("shedding=true");
+    let service = LoadShed::new(common::sleeper(), 0.1, TARGET);
+    assert!(!shedding(&service));
+    service.force_shed(true);
+    assert!(shedding(&service));
+    service.force_shed(false);
+    assert!(!shedding(&service));
+    service.shutdown();
+    assert!(shedding(&service));
+
+    let limited = LoadShedLayer::new(0.1, TARGET)
+        .rate_limit(10.0, 1)
+        .layer(common::sleeper());
+    limited.clone().oneshot(FAST).await.unwrap();
+    assert!(shedding(&limited), "{}", limited.status_line());
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    assert!(!shedding(&limited), "{}", limited.status_line());
+}
+
+#[tokio::test(start_paused = true)]
+async fn goodput_leaves_out_failed_and_late_requests() {
+    let failed = Duration::from_millis(30);
+    let service = LoadShedLayer::new(0.1, TARGET)
+        .classifier(move |latency: &Duration| {
+            if *latency == failed {
+                Outcome::Failure
+            } else {
+                Outcome::Success
+            }
+        })
+        .layer(common::sleeper());
+    // While every request is good the two are the same.
+    common::drive(service.clone(), 1, Duration::from_secs(5), || FAST).await;
+    let (throughput, goodput) = (service.stats().throughput, service.goodput());
+    assert!(
+        (goodput - throughput).abs() < throughput * 0.01,
+        "{goodput} vs {throughput}"
+    );
+
+    // Then a third succeed in time, a third fail and a third are too late.
+    let latencies = [FAST, failed, TARGET * 3 / 2];
+    let mut requests = latencies.iter().copied().cycle();
+    for _ in 0..300 {
+        service
+            .clone()
+            .oneshot(requests.next().unwrap())
+            .await
+            .unwrap();
+    }
+    let (throughput, goodput) = (service.stats().throughput, service.goodput());
+    assert!(
+        goodput > throughput * 0.2 && goodput < throughput * 0.5,
+        "{goodput} vs {throughput}"
+    );
+}
+
+/// A request that may be a retry of one that was shed.
+#[derive(Debug, Clone, Copy)]
+struct Attempt {
+    retry: bool,
+}
+
+/// Reads the retry marker from an [`Attempt`].
+#[derive(Debug, Clone, Copy)]
+struct Retries;
+
+impl RequestInfo<Attempt> for Retries {
+    fn is_retry(&self, request: &Attempt) -> bool {
+        request.retry
+    }
+}
+
+/// The arrival rate after ten fresh requests a second, each followed by four
+/// retries, with or without excluding the retries.
+async fn arrival_rate(exclude_retries: bool) -> f64 {
+    let service = LoadShedLayer::new(0.1, TARGET)
+        .exclude_retries(exclude_retries)
+        .request_info(Retries)
+        .layer(tower::service_fn(|_: Attempt| async {
+            Ok::<_, Infallible>(())
+        }));
+    for attempt in 0..500 {
+        let retry = attempt % 5 != 0;
+        service.clone().oneshot(Attempt { retry }).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    service.arrival_rate()
+}
+
+#[tokio::test(start_paused = true)]
+async fn marked_retries_can_be_left_out_of_the_arrival_rate() {
+    let all = arrival_rate(false).await;
+    assert!((all - 50.0).abs() < 1.0, "{all}");
+    let fresh = arrival_rate(true).await;
+    assert!((fresh - 10.0).abs() < 1.0, "{fresh}");
+}
+
+/// The arrival rate seen as each of `arrivals` requests arrives, 10ms apart.
+async fn rates_on_arrival(service: LoadShed<BoxSleeper>, arrivals: usize) -> Vec<f64> {
+    let mut rates = Vec::new();
+    for _ in 0..arrivals {
+        let request = tokio::spawn(service.clone().oneshot(FAST));
+        tokio::task::yield_now().await;
+        rates.push(service.arrival_rate());
+        request.await.unwrap().unwrap();
+    }
+    rates
+}
+
+#[tokio::test(start_paused = true)]
+async fn the_first_arrivals_dont_inflate_the_rate() {
+    let service = LoadShedLayer::new(0.1, TARGET).layer(common::sleeper());
+    let rates = rates_on_arrival(service, 5).await;
+    // One arrival alone has no rate, after that it's 100 a second throughout.
+    assert_eq!(rates[0], 0.0);
+    assert!(
+        rates[1..].iter().all(|rate| (rate - 100.0).abs() < 1.0),
+        "{rates:?}"
+    );
+}
+
+#[tokio::test(start_paused = true)]
+async fn the_arrival_rate_is_smoothed_with_the_runtime_parameter() {
+    let service = LoadShedLayer::new(0.1, TARGET).layer(common::sleeper());
+    rates_on_arrival(service.clone(), 5).await;
+    service.set_ewma_param(0.9).unwrap();
+    // A single interval of 100ms gets most of the way to 10 a second.
+    tokio::time::sleep(FAST * 9).await;
+    let rates = rates_on_arrival(service, 1).await;
+    assert!(rates[0] < 15.0, "{rates:?}");
+}
+
+/// A service that sleeps for each request and responds with its `name`.
+fn named(name: &'static str) -> BoxCloneService<Duration, &'static str, Infallible> {
+    BoxCloneService::new(tower::service_fn(move |latency: Duration| async move {
+        tokio::time::sleep(latency).await;
+        Ok(name)
+    }))
+}
+
+#[tokio::test(start_paused = true)]
+async fn replacing_the_inner_service_keeps_the_stats() {
+    let mut service = LoadShedLayer::new(0.1, TARGET).layer(named("old"));
+    common::drive(service.clone(), 20, Duration::from_secs(5), || FAST).await;
+    let (concurrency, average) = (service.concurrency(), service.average_latency());
+    assert!(concurrency > 5, "{}", service.status_line());
+
+    let in_flight = tokio::spawn(service.clone().oneshot(FAST));
+    tokio::task::yield_now().await;
+    service.replace_inner(named("new"));
+    assert_eq!(service.concurrency(), concurrency);
+    assert_eq!(service.average_latency(), average);
+
+    let response = service.clone().oneshot(FAST).await.unwrap();
+    assert!(matches!(response, LoadShedResponse::Inner("new")));
+    let response = in_flight.await.unwrap().unwrap();
+    assert!(matches!(response, LoadShedResponse::Inner("old")));
+}